@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+
+use candid::{CandidType, Principal};
+use ic_cdk::api::time;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{profiles::AccountRole, shadow_principal::DerivationScheme, STATE};
+
+const MAX_AUDIT_PAGE: usize = 100;
+
+#[derive(Error, Debug, Serialize, Deserialize, CandidType, Clone)]
+pub enum AdminError {
+    #[error("Caller is not authorized")]
+    Unauthorized,
+    #[error("Invalid configuration")]
+    InvalidConfig,
+    #[error("Principal has no admin role")]
+    RoleNotFound,
+    #[error("Cannot remove the last owner")]
+    LastOwner,
+}
+
+/// Roles are ordered: each one includes the permissions of those below it.
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AdminRole {
+    // Read the config audit log and admin list
+    Auditor,
+    // Change identity providers, passkey and recovery code delivery settings
+    ConfigManager,
+    // Grant and revoke roles
+    Owner,
+}
+
+/// A config change as recorded in the audit log. Secrets (client secrets,
+/// delivery headers) are never recorded.
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub enum ConfigChange {
+    OidcProviderUpserted { issuer: String, audiences: Vec<String> },
+    OidcProviderRemoved { issuer: String },
+    DeliveryConfigSet { endpoint: String },
+    WebAuthnConfigSet { rp_id: String, origins: Vec<String> },
+    PrincipalDerivationSet { scheme: DerivationScheme },
+    PrincipalsMigrated { migrated: u32, remaining: u32 },
+    PrincipalMigrationFailed { principal: Principal, error: String },
+    RoleGranted { principal: Principal, role: AdminRole },
+    RoleRevoked { principal: Principal, role: AdminRole },
+    AccountRoleSet { principal: Principal, role: Option<AccountRole> },
+    AdminOrganisationSet { principal: Principal, organisation: Option<Principal> },
+    PiiConfigSet { vetkd_key_name: String },
+    VerifierAdded { verifier: Principal },
+    VerifierRemoved { verifier: Principal },
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct ConfigAuditEntry {
+    pub seq: u64,
+    pub actor: Principal,
+    pub change: ConfigChange,
+    pub timestamp: u64,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Default)]
+pub struct AdminStore {
+    pub roles: HashMap<Principal, AdminRole>,
+    // Append-only; entries are never edited or removed
+    pub audit_log: Vec<ConfigAuditEntry>,
+}
+
+impl AdminStore {
+    /// Controllers always act as owners so the canister can't be locked out.
+    fn role_of(&self, principal: &Principal) -> Option<AdminRole> {
+        if ic_cdk::api::is_controller(principal) {
+            return Some(AdminRole::Owner);
+        }
+        self.roles.get(principal).copied()
+    }
+
+    fn record(&mut self, actor: Principal, change: ConfigChange) {
+        let seq = self.audit_log.len() as u64;
+        self.audit_log.push(ConfigAuditEntry {
+            seq,
+            actor,
+            change,
+            timestamp: time(),
+        });
+    }
+}
+
+/// Returns the caller if they hold at least `required`.
+pub fn require_role(required: AdminRole) -> Result<Principal, AdminError> {
+    let caller = ic_cdk::caller();
+    let role = STATE.with(|s| s.borrow().admin.role_of(&caller));
+    match role {
+        Some(role) if role >= required => Ok(caller),
+        _ => Err(AdminError::Unauthorized),
+    }
+}
+
+pub fn log_config_change(actor: Principal, change: ConfigChange) {
+    STATE.with(|s| s.borrow_mut().admin.record(actor, change));
+}
+
+pub fn grant_role(principal: Principal, role: AdminRole) -> Result<(), AdminError> {
+    let actor = require_role(AdminRole::Owner)?;
+    if principal == Principal::anonymous() {
+        return Err(AdminError::InvalidConfig);
+    }
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        state.admin.roles.insert(principal, role);
+        state.admin.record(actor, ConfigChange::RoleGranted { principal, role });
+    });
+    Ok(())
+}
+
+pub fn revoke_role(principal: Principal) -> Result<(), AdminError> {
+    let actor = require_role(AdminRole::Owner)?;
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        let role = *state.admin.roles.get(&principal).ok_or(AdminError::RoleNotFound)?;
+        let owners = state.admin.roles.values().filter(|r| **r == AdminRole::Owner).count();
+        if role == AdminRole::Owner && owners == 1 {
+            return Err(AdminError::LastOwner);
+        }
+        state.admin.roles.remove(&principal);
+        state.admin.record(actor, ConfigChange::RoleRevoked { principal, role });
+        Ok(())
+    })
+}
+
+pub fn list_roles() -> Result<Vec<(Principal, AdminRole)>, AdminError> {
+    require_role(AdminRole::Auditor)?;
+    Ok(STATE.with(|s| {
+        s.borrow().admin.roles.iter().map(|(p, r)| (*p, *r)).collect()
+    }))
+}
+
+pub fn audit_page(offset: u64, limit: u32) -> Result<Vec<ConfigAuditEntry>, AdminError> {
+    require_role(AdminRole::Auditor)?;
+    Ok(STATE.with(|s| {
+        s.borrow()
+            .admin
+            .audit_log
+            .iter()
+            .skip(offset as usize)
+            .take((limit as usize).min(MAX_AUDIT_PAGE))
+            .cloned()
+            .collect()
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roles_are_ordered_by_privilege() {
+        assert!(AdminRole::Owner > AdminRole::ConfigManager);
+        assert!(AdminRole::ConfigManager > AdminRole::Auditor);
+    }
+}
@@ -0,0 +1,161 @@
+use candid::{CandidType, Principal};
+use ic_cdk::api::time;
+use serde::{Deserialize, Serialize};
+
+use crate::{oidc_verifier::OidcSubject, AuthError, State, STATE};
+
+// How long the II principal has to confirm a link request
+const LINK_REQUEST_TTL_NS: u64 = 10 * 60 * 1_000_000_000;
+
+/// A way of signing in to an account.
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Authenticator {
+    Oidc(OidcSubject),
+    InternetIdentity(Principal),
+    Passkey { credential_id: Vec<u8> },
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct LinkedAuthenticator {
+    pub authenticator: Authenticator,
+    pub linked_at: u64,
+}
+
+/// A link started by the account holder, waiting for the II principal to
+/// confirm it owns that identity.
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct PendingLink {
+    pub account: Principal,
+    pub expires_at: u64,
+}
+
+/// Looks up the account an authenticator signs in to.
+pub fn account_for(authenticator: &Authenticator) -> Option<Principal> {
+    STATE.with(|s| s.borrow().authenticators.get(authenticator))
+}
+
+/// Attaches `authenticator` to `account`. Fails if it already signs in to a
+/// different account; linking it again to the same account is a no-op.
+pub fn attach(state: &mut State, account: Principal, authenticator: Authenticator) -> Result<(), AuthError> {
+    match state.authenticators.get(&authenticator) {
+        Some(owner) if owner == account => return Ok(()),
+        Some(_) => return Err(AuthError::IdentityInUse),
+        None => {}
+    }
+    state
+        .users
+        .update(&account, |user| {
+            user.authenticators.push(LinkedAuthenticator {
+                authenticator: authenticator.clone(),
+                linked_at: time(),
+            })
+        })
+        .ok_or(AuthError::UserNotFound)?;
+    state.authenticators.insert(authenticator, account);
+    Ok(())
+}
+
+/// Step 1: the signed-in account asks to link an II principal.
+pub fn request_ii_link(account: Principal, ii_principal: Principal) -> Result<u64, AuthError> {
+    if ii_principal == Principal::anonymous() {
+        return Err(AuthError::InvalidSession);
+    }
+    STATE.with(|s| request_link(&mut s.borrow_mut(), account, ii_principal, time()))
+}
+
+/// Records the request unless another account's request for the same II
+/// principal is still live; the account's own request is renewed.
+fn request_link(state: &mut State, account: Principal, ii_principal: Principal, now: u64) -> Result<u64, AuthError> {
+    let authenticator = Authenticator::InternetIdentity(ii_principal);
+    if state.authenticators.get(&authenticator).is_some_and(|owner| owner != account) {
+        return Err(AuthError::IdentityInUse);
+    }
+    if state
+        .pending_links
+        .get(&ii_principal)
+        .is_some_and(|pending| pending.account != account && pending.expires_at >= now)
+    {
+        return Err(AuthError::LinkPending);
+    }
+    let expires_at = now + LINK_REQUEST_TTL_NS;
+    state.pending_links.insert(ii_principal, PendingLink { account, expires_at });
+    Ok(expires_at)
+}
+
+/// Drops link requests that were never confirmed. Run by the session
+/// maintenance timer; returns how many were removed.
+pub fn remove_expired_links(state: &mut State, now: u64) -> u64 {
+    state.pending_links.retain(|_, pending| pending.expires_at >= now)
+}
+
+/// Step 2: the II principal itself confirms, proving it owns the identity.
+pub fn confirm_ii_link(ii_principal: Principal, account: Principal) -> Result<(), AuthError> {
+    STATE.with(|s| {
+        let mut guard = s.borrow_mut();
+        let state = &mut *guard;
+        let pending = state.pending_links.remove(&ii_principal).ok_or(AuthError::LinkNotFound)?;
+        if pending.account != account || pending.expires_at < time() {
+            return Err(AuthError::LinkNotFound);
+        }
+        attach(state, account, Authenticator::InternetIdentity(ii_principal))
+    })
+}
+
+/// Removes a sign-in method, refusing to remove the account's last one.
+pub fn detach(account: Principal, authenticator: &Authenticator) -> Result<(), AuthError> {
+    STATE.with(|s| {
+        let mut guard = s.borrow_mut();
+        let state = &mut *guard;
+        let mut user = state.users.get(&account).ok_or(AuthError::UserNotFound)?;
+        let position = user
+            .authenticators
+            .iter()
+            .position(|linked| &linked.authenticator == authenticator)
+            .ok_or(AuthError::LinkNotFound)?;
+        if user.authenticators.len() == 1 {
+            return Err(AuthError::LastAuthenticator);
+        }
+        user.authenticators.remove(position);
+        state.users.insert(account, user);
+        state.authenticators.remove(authenticator);
+        Ok(())
+    })
+}
+
+pub fn list(account: Principal) -> Result<Vec<LinkedAuthenticator>, AuthError> {
+    STATE.with(|s| {
+        s.borrow()
+            .users
+            .get(&account)
+            .map(|user| user.authenticators)
+            .ok_or(AuthError::UserNotFound)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id; 29])
+    }
+
+    #[test]
+    fn test_pending_link_is_not_overwritten_by_another_account() {
+        let mut state = State::new();
+        let (ana, mallory, ii) = (principal(1), principal(2), principal(9));
+
+        let expires_at = request_link(&mut state, ana, ii, 100).unwrap();
+        assert!(matches!(request_link(&mut state, mallory, ii, 200), Err(AuthError::LinkPending)));
+        assert_eq!(state.pending_links.get(&ii).unwrap().account, ana);
+
+        // Ana may renew her own request
+        assert!(request_link(&mut state, ana, ii, 200).unwrap() > expires_at);
+
+        // Once it lapses, the maintenance timer clears it and others may ask
+        let lapsed = 200 + LINK_REQUEST_TTL_NS + 1;
+        assert_eq!(remove_expired_links(&mut state, lapsed), 1);
+        assert!(state.pending_links.get(&ii).is_none());
+        assert!(request_link(&mut state, mallory, ii, lapsed).is_ok());
+    }
+}
@@ -0,0 +1,578 @@
+use candid::{CandidType, Encode, Principal};
+use ic_cdk::api::{call::call_with_payment128, management_canister::main::raw_rand, time};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::{
+    profiles::{AccountRole, EmployeeProfileData},
+    AuthError, State, UserData, STATE,
+};
+
+// Separates profile keys from any other use of the canister's vetKD key
+const PII_KEY_CONTEXT: &[u8] = b"social-fund-member-pii";
+// Fee for `vetkd_derive_key` on the 34-node key, enough for the test keys too
+const VETKD_DERIVE_CYCLES: u128 = 26_153_846_153;
+// Derivations each viewer may trigger per window, so a caller can't drain
+// the canister's cycles by rotating transport keys
+const MAX_KEY_DERIVATIONS_PER_WINDOW: u32 = 60;
+const KEY_DERIVATION_WINDOW_NS: u64 = 3_600 * 1_000_000_000;
+// Compressed BLS12-381 G1 point
+const TRANSPORT_KEY_LEN: usize = 48;
+const MAX_PENDING_PAGE: usize = 50;
+const MAX_EVIDENCE_LEN: usize = 256;
+
+#[derive(Error, Debug, Serialize, Deserialize, CandidType, Clone)]
+pub enum KycError {
+    #[error("Profile encryption is not configured")]
+    NotConfigured,
+    #[error("Caller is not a registered verifier")]
+    NotAVerifier,
+    #[error("Caller may not decrypt this profile")]
+    Unauthorized,
+    #[error("No employee profile to verify")]
+    ProfileNotFound,
+    #[error("Cannot move verification from {0:?} to {1:?}")]
+    InvalidTransition(VerificationStatus, VerificationStatus),
+    #[error("Profile changed since it was submitted")]
+    ProfileChanged,
+    #[error("Invalid attestation: {0}")]
+    InvalidAttestation(String),
+    #[error("Invalid transport public key")]
+    InvalidTransportKey,
+    #[error("Key derivation failed: {0}")]
+    KeyDerivationFailed(String),
+    #[error("Too many key requests, try again later")]
+    RateLimited,
+    #[error("Verification is not pending")]
+    NotPending,
+    #[error("Case is assigned to another verifier")]
+    AssignedElsewhere,
+    #[error(transparent)]
+    Auth(#[from] AuthError),
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VerificationStatus {
+    Unverified,
+    Pending,
+    Verified,
+    Rejected,
+}
+
+/// Where a member's verification stands, bound to the profile it covers.
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct Verification {
+    pub status: VerificationStatus,
+    // `profile_hash` of the submitted profile; editing it resets the status
+    pub profile_hash: Vec<u8>,
+    pub submitted_at: u64,
+    pub decided_at: Option<u64>,
+    pub verifier: Option<Principal>,
+    // The verifier's case or document reference
+    pub evidence: Option<String>,
+    pub reason: Option<String>,
+}
+
+/// A verifier's decision on a pending (or, to revoke, verified) member.
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct VerificationAttestation {
+    pub account: Principal,
+    pub approved: bool,
+    // Must match the profile the verifier actually checked
+    pub profile_hash: Vec<u8>,
+    pub evidence: String,
+    pub reason: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct PendingVerification {
+    pub account: Principal,
+    pub profile: EmployeeProfileData,
+    pub profile_hash: Vec<u8>,
+    pub submitted_at: u64,
+    // Verifier that claimed the case, if any
+    pub assigned_to: Option<Principal>,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct VerifierInfo {
+    pub name: String,
+    pub added_at: u64,
+}
+
+/// Profile key derivations a viewer started in the current window.
+#[derive(Clone, Copy, Debug)]
+pub struct KeyRequestWindow {
+    started_at: u64,
+    count: u32,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct PiiConfig {
+    // "key_1" on mainnet, "test_key_1" or "dfx_test_key" for testing
+    pub vetkd_key_name: String,
+}
+
+#[derive(CandidType, Deserialize)]
+enum VetKdCurve {
+    #[serde(rename = "bls12_381_g2")]
+    Bls12381G2,
+}
+
+#[derive(CandidType, Deserialize)]
+struct VetKdKeyId {
+    curve: VetKdCurve,
+    name: String,
+}
+
+#[derive(CandidType)]
+struct VetKdPublicKeyArgs {
+    canister_id: Option<Principal>,
+    context: Vec<u8>,
+    key_id: VetKdKeyId,
+}
+
+#[derive(Deserialize, CandidType)]
+struct VetKdPublicKeyResult {
+    public_key: Vec<u8>,
+}
+
+#[derive(CandidType)]
+struct VetKdDeriveKeyArgs {
+    input: Vec<u8>,
+    context: Vec<u8>,
+    transport_public_key: Vec<u8>,
+    key_id: VetKdKeyId,
+}
+
+#[derive(Deserialize, CandidType)]
+struct VetKdDeriveKeyResult {
+    encrypted_key: Vec<u8>,
+}
+
+pub fn status_of(user: &UserData) -> VerificationStatus {
+    user.verification
+        .as_ref()
+        .map(|verification| verification.status)
+        .unwrap_or(VerificationStatus::Unverified)
+}
+
+/// The state machine: members submit from unverified or rejected, verifiers
+/// decide pending cases and may later revoke a verification.
+fn can_transition(from: VerificationStatus, to: VerificationStatus) -> bool {
+    use VerificationStatus::*;
+    matches!(
+        (from, to),
+        (Unverified, Pending) | (Rejected, Pending) | (Pending, Verified) | (Pending, Rejected) | (Verified, Rejected)
+    )
+}
+
+/// Identifies the exact profile contents an attestation covers.
+pub fn profile_hash(profile: &EmployeeProfileData) -> Vec<u8> {
+    let encoded = Encode!(profile).expect("failed to encode profile");
+    Sha256::digest(encoded).to_vec()
+}
+
+fn is_verifier(state: &State, principal: &Principal) -> bool {
+    state.kyc_verifiers.contains_key(principal)
+}
+
+/// Member submits their current employee profile for verification.
+pub fn request_verification(account: Principal) -> Result<VerificationStatus, KycError> {
+    STATE.with(|s| {
+        s.borrow_mut()
+            .users
+            .update(&account, |user| {
+                let profile = match (user.role, &user.employee_profile) {
+                    (Some(AccountRole::Employee), Some(profile)) => profile,
+                    _ => return Err(KycError::ProfileNotFound),
+                };
+                let from = status_of(user);
+                if !can_transition(from, VerificationStatus::Pending) {
+                    return Err(KycError::InvalidTransition(from, VerificationStatus::Pending));
+                }
+                user.verification = Some(Verification {
+                    status: VerificationStatus::Pending,
+                    profile_hash: profile_hash(profile),
+                    submitted_at: time(),
+                    decided_at: None,
+                    verifier: None,
+                    evidence: None,
+                    reason: None,
+                });
+                Ok(VerificationStatus::Pending)
+            })
+            .unwrap_or(Err(KycError::Auth(AuthError::UserNotFound)))
+    })
+}
+
+/// Whether a verifier other than `verifier` holds the case. Cases of removed
+/// verifiers are free to be taken over.
+fn assigned_elsewhere(state: &State, verification: &Verification, verifier: Principal) -> bool {
+    verification
+        .verifier
+        .is_some_and(|current| current != verifier && is_verifier(state, &current))
+}
+
+/// Assigns a pending case to `verifier`. Only the assigned verifier may
+/// decrypt the member's profile or decide the case.
+pub fn claim_verification(verifier: Principal, account: Principal) -> Result<(), KycError> {
+    STATE.with(|s| {
+        let mut guard = s.borrow_mut();
+        let state = &mut *guard;
+        if !is_verifier(state, &verifier) {
+            return Err(KycError::NotAVerifier);
+        }
+        let user = state.users.get(&account).ok_or(AuthError::UserNotFound)?;
+        let verification = user
+            .verification
+            .filter(|verification| verification.status == VerificationStatus::Pending)
+            .ok_or(KycError::NotPending)?;
+        if assigned_elsewhere(state, &verification, verifier) {
+            return Err(KycError::AssignedElsewhere);
+        }
+        state.users.update(&account, |user| {
+            if let Some(verification) = user.verification.as_mut() {
+                verification.verifier = Some(verifier);
+            }
+        });
+        Ok(())
+    })
+}
+
+/// Records a verifier's decision. The hash ties it to the profile the
+/// verifier saw, so an edit made meanwhile invalidates the attestation.
+pub fn attest(verifier: Principal, attestation: VerificationAttestation) -> Result<VerificationStatus, KycError> {
+    if attestation.evidence.trim().is_empty() || attestation.evidence.len() > MAX_EVIDENCE_LEN {
+        return Err(KycError::InvalidAttestation("evidence reference is required".to_string()));
+    }
+    if !attestation.approved && attestation.reason.is_none() {
+        return Err(KycError::InvalidAttestation("rejections need a reason".to_string()));
+    }
+    let to = if attestation.approved {
+        VerificationStatus::Verified
+    } else {
+        VerificationStatus::Rejected
+    };
+    STATE.with(|s| {
+        let mut guard = s.borrow_mut();
+        let state = &mut *guard;
+        if !is_verifier(state, &verifier) {
+            return Err(KycError::NotAVerifier);
+        }
+        let assigned_elsewhere = state
+            .users
+            .get(&attestation.account)
+            .and_then(|user| user.verification)
+            .is_some_and(|verification| assigned_elsewhere(state, &verification, verifier));
+        if assigned_elsewhere {
+            return Err(KycError::AssignedElsewhere);
+        }
+        state
+            .users
+            .update(&attestation.account, |user| {
+                let current = user.employee_profile.as_ref().map(profile_hash);
+                let verification = user
+                    .verification
+                    .as_mut()
+                    .ok_or(KycError::InvalidTransition(VerificationStatus::Unverified, to))?;
+                if current.as_ref() != Some(&verification.profile_hash)
+                    || verification.profile_hash != attestation.profile_hash
+                {
+                    return Err(KycError::ProfileChanged);
+                }
+                if !can_transition(verification.status, to) {
+                    return Err(KycError::InvalidTransition(verification.status, to));
+                }
+                verification.status = to;
+                verification.decided_at = Some(time());
+                verification.verifier = Some(verifier);
+                verification.evidence = Some(attestation.evidence);
+                verification.reason = attestation.reason;
+                Ok(to)
+            })
+            .unwrap_or(Err(KycError::Auth(AuthError::UserNotFound)))
+    })
+}
+
+/// Oldest pending submissions first, for verifiers working the queue.
+pub fn pending_verifications(verifier: Principal, limit: u32) -> Result<Vec<PendingVerification>, KycError> {
+    STATE.with(|s| {
+        let state = s.borrow();
+        if !is_verifier(&state, &verifier) {
+            return Err(KycError::NotAVerifier);
+        }
+        let mut pending: Vec<PendingVerification> = state
+            .users
+            .values()
+            .filter_map(|user| {
+                let verification = user.verification?;
+                if verification.status != VerificationStatus::Pending {
+                    return None;
+                }
+                Some(PendingVerification {
+                    account: user.principal,
+                    profile: user.employee_profile?,
+                    profile_hash: verification.profile_hash,
+                    submitted_at: verification.submitted_at,
+                    assigned_to: verification.verifier,
+                })
+            })
+            .collect();
+        pending.sort_by_key(|request| request.submitted_at);
+        pending.truncate((limit as usize).min(MAX_PENDING_PAGE));
+        Ok(pending)
+    })
+}
+
+pub fn verification_of(account: Principal) -> Result<Option<Verification>, KycError> {
+    STATE.with(|s| {
+        s.borrow()
+            .users
+            .get(&account)
+            .map(|user| user.verification)
+            .ok_or(KycError::Auth(AuthError::UserNotFound))
+    })
+}
+
+/// The member, the member's employer, admins scoped to that employer, and
+/// the verifier the member's case is assigned to may decrypt.
+fn may_decrypt(state: &State, viewer: Principal, user: &UserData) -> bool {
+    if viewer == user.principal {
+        return true;
+    }
+    let employer = state
+        .employments
+        .get(&user.principal)
+        .map(|employment| employment.employer);
+    let viewer_is_employer = employer == Some(viewer);
+    // An admin sees its organisation's own account and its employees
+    let viewer_is_admin = state
+        .users
+        .get(&viewer)
+        .is_some_and(|account| account.role == Some(AccountRole::Admin))
+        && state
+            .admin_organisations
+            .get(&viewer)
+            .is_some_and(|organisation| organisation == user.principal || Some(organisation) == employer);
+    let viewer_is_verifier = is_verifier(state, &viewer)
+        && matches!(status_of(user), VerificationStatus::Pending | VerificationStatus::Verified)
+        && user
+            .verification
+            .as_ref()
+            .is_some_and(|verification| verification.verifier == Some(viewer));
+    viewer_is_admin || viewer_is_employer || viewer_is_verifier
+}
+
+/// Counts a derivation against the viewer's budget for the current window.
+fn record_key_request(state: &mut State, viewer: Principal, now: u64) -> Result<(), KycError> {
+    state
+        .pii_key_requests
+        .retain(|_, window| window.started_at + KEY_DERIVATION_WINDOW_NS > now);
+    let window = state
+        .pii_key_requests
+        .entry(viewer)
+        .or_insert(KeyRequestWindow { started_at: now, count: 0 });
+    if window.count >= MAX_KEY_DERIVATIONS_PER_WINDOW {
+        return Err(KycError::RateLimited);
+    }
+    window.count += 1;
+    Ok(())
+}
+
+fn key_id(state: &State) -> Result<VetKdKeyId, KycError> {
+    let config = state.pii.as_ref().ok_or(KycError::NotConfigured)?;
+    Ok(VetKdKeyId {
+        curve: VetKdCurve::Bls12381G2,
+        name: config.vetkd_key_name.clone(),
+    })
+}
+
+/// Master public key clients use to verify the keys they are handed.
+pub async fn pii_public_key() -> Result<Vec<u8>, KycError> {
+    let key_id = STATE.with(|s| key_id(&s.borrow()))?;
+    let args = VetKdPublicKeyArgs {
+        canister_id: None,
+        context: PII_KEY_CONTEXT.to_vec(),
+        key_id,
+    };
+    let (result,): (VetKdPublicKeyResult,) =
+        ic_cdk::call(Principal::management_canister(), "vetkd_public_key", (args,))
+            .await
+            .map_err(|(_, message)| KycError::KeyDerivationFailed(message))?;
+    Ok(result.public_key)
+}
+
+/// Derives the key the member's PII fields are encrypted under, encrypted to
+/// `transport_public_key` so only the requesting client can open it. The
+/// derivation input is a random per-account id rather than the principal, so
+/// the key survives recovery and principal migration.
+pub async fn derive_pii_key(
+    viewer: Principal,
+    account: Principal,
+    transport_public_key: Vec<u8>,
+) -> Result<Vec<u8>, KycError> {
+    if transport_public_key.len() != TRANSPORT_KEY_LEN {
+        return Err(KycError::InvalidTransportKey);
+    }
+    let existing = STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        key_id(&state)?;
+        let user = state.users.get(&account).ok_or(AuthError::UserNotFound)?;
+        if !may_decrypt(&state, viewer, &user) {
+            return Err(KycError::Unauthorized);
+        }
+        record_key_request(&mut state, viewer, time())?;
+        Ok(user.pii_key_id)
+    })?;
+
+    let input = match existing {
+        Some(input) => input,
+        None => {
+            let (random_bytes,) = raw_rand()
+                .await
+                .map_err(|(_, message)| KycError::KeyDerivationFailed(message))?;
+            // Another call may have raced us across the await; the first id wins
+            STATE.with(|s| {
+                s.borrow_mut()
+                    .users
+                    .update(&account, |user| {
+                        user.pii_key_id
+                            .get_or_insert_with(|| random_bytes[..32].to_vec())
+                            .clone()
+                    })
+                    .ok_or(KycError::Auth(AuthError::UserNotFound))
+            })?
+        }
+    };
+
+    let key_id = STATE.with(|s| key_id(&s.borrow()))?;
+    let args = VetKdDeriveKeyArgs {
+        input,
+        context: PII_KEY_CONTEXT.to_vec(),
+        transport_public_key,
+        key_id,
+    };
+    let (result,): (VetKdDeriveKeyResult,) = call_with_payment128(
+        Principal::management_canister(),
+        "vetkd_derive_key",
+        (args,),
+        VETKD_DERIVE_CYCLES,
+    )
+    .await
+    .map_err(|(_, message)| KycError::KeyDerivationFailed(message))?;
+    Ok(result.encrypted_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profiles::Employment;
+    use VerificationStatus::*;
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id; 29])
+    }
+
+    fn account(state: &mut State, principal: Principal, role: Option<AccountRole>) -> UserData {
+        let user = UserData {
+            principal,
+            authenticators: Vec::new(),
+            origin: None,
+            linked_at: 1,
+            role,
+            employee_profile: None,
+            employer_profile: None,
+            verification: None,
+            pii_key_id: None,
+        };
+        state.users.insert(principal, user.clone());
+        user
+    }
+
+    fn pending(verifier: Option<Principal>) -> Verification {
+        Verification {
+            status: Pending,
+            profile_hash: vec![0; 32],
+            submitted_at: 1,
+            decided_at: None,
+            verifier,
+            evidence: None,
+            reason: None,
+        }
+    }
+
+    #[test]
+    fn test_verification_transitions() {
+        assert!(can_transition(Unverified, Pending));
+        assert!(can_transition(Pending, Verified));
+        assert!(can_transition(Pending, Rejected));
+        assert!(can_transition(Verified, Rejected));
+        assert!(can_transition(Rejected, Pending));
+        assert!(!can_transition(Unverified, Verified));
+        assert!(!can_transition(Rejected, Verified));
+        assert!(!can_transition(Verified, Pending));
+        assert!(!can_transition(Pending, Pending));
+    }
+
+    #[test]
+    fn test_admins_decrypt_only_their_organisation() {
+        let mut state = State::new();
+        let (employer, other_employer, admin) = (principal(1), principal(2), principal(3));
+        let member = account(&mut state, principal(4), Some(AccountRole::Employee));
+        let outsider = account(&mut state, principal(5), Some(AccountRole::Employee));
+        let employer_account = account(&mut state, employer, Some(AccountRole::Employer));
+        account(&mut state, admin, Some(AccountRole::Admin));
+        state.employments.insert(member.principal, Employment { employer, since: 1 });
+        state.employments.insert(outsider.principal, Employment { employer: other_employer, since: 1 });
+
+        // Unscoped admins see nothing
+        assert!(!may_decrypt(&state, admin, &member));
+
+        state.admin_organisations.insert(admin, employer);
+        assert!(may_decrypt(&state, admin, &member));
+        assert!(may_decrypt(&state, admin, &employer_account));
+        assert!(!may_decrypt(&state, admin, &outsider));
+        assert!(may_decrypt(&state, employer, &member));
+        assert!(!may_decrypt(&state, employer, &outsider));
+    }
+
+    #[test]
+    fn test_verifiers_decrypt_only_assigned_cases() {
+        let mut state = State::new();
+        let (verifier, other_verifier) = (principal(1), principal(2));
+        for id in [verifier, other_verifier] {
+            state.kyc_verifiers.insert(id, VerifierInfo { name: "KYC".to_string(), added_at: 1 });
+        }
+        let mut member = account(&mut state, principal(4), Some(AccountRole::Employee));
+
+        member.verification = Some(pending(None));
+        assert!(!may_decrypt(&state, verifier, &member));
+
+        member.verification = Some(pending(Some(verifier)));
+        assert!(may_decrypt(&state, verifier, &member));
+        assert!(!may_decrypt(&state, other_verifier, &member));
+        let verification = member.verification.clone().unwrap();
+        assert!(assigned_elsewhere(&state, &verification, other_verifier));
+        assert!(!assigned_elsewhere(&state, &verification, verifier));
+
+        // A removed verifier's cases can be taken over
+        state.kyc_verifiers.remove(&verifier);
+        assert!(!may_decrypt(&state, verifier, &member));
+        assert!(!assigned_elsewhere(&state, &verification, other_verifier));
+    }
+
+    #[test]
+    fn test_key_requests_are_limited_per_window() {
+        let mut state = State::new();
+        let (viewer, other) = (principal(1), principal(2));
+        for _ in 0..MAX_KEY_DERIVATIONS_PER_WINDOW {
+            record_key_request(&mut state, viewer, 10).unwrap();
+        }
+        assert!(matches!(record_key_request(&mut state, viewer, 20), Err(KycError::RateLimited)));
+        assert!(record_key_request(&mut state, other, 20).is_ok());
+        assert!(record_key_request(&mut state, viewer, 20 + KEY_DERIVATION_WINDOW_NS).is_ok());
+        assert_eq!(state.pii_key_requests.len(), 1);
+    }
+}
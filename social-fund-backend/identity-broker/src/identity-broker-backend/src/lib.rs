@@ -1,58 +1,172 @@
 use std::{cell::RefCell, collections::HashMap};
 
 use candid::{CandidType, Principal};
-use ic_cdk::{api::time, export_candid, init, post_upgrade, pre_upgrade, query, update};
+use ic_cdk::{api::{management_canister::http_request::{HttpResponse, TransformArgs}, time}, export_candid, init, post_upgrade, pre_upgrade, query, update};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use crate::{google_verifier::verify_google_token, recovery::{multi_factor::{MultiFactorRecovery, RecoveryError, RecoveryMethod}, social::SocialRecovery}, session_manager::{create_session, rotate_session, Session, SessionError}, shadow_principal::generate_shadow_principal};
+use crate::{admin::{log_config_change, require_role, AdminError, AdminRole, AdminStore, ConfigAuditEntry, ConfigChange}, identity_links::{Authenticator, LinkedAuthenticator, PendingLink}, kyc::{KeyRequestWindow, KycError, PendingVerification, PiiConfig, Verification, VerificationAttestation, VerificationStatus, VerifierInfo}, oidc_verifier::{session_nonce, start_jwks_refresh, verify_id_token, JwksCache, OidcProvider}, profiles::{AccountProfile, AccountRole, EmployeeProfileData, EmployeeSummary, EmployerProfileData, Employment, EmploymentInvite, InviteSummary, InviteTicket, ProfileError}, recovery::{delivery::{DeliveryConfig, DeliveryRecord, DeliveryStore}, multi_factor::{MultiFactorRecovery, RecoveryError, RecoveryMethod, TotpProvisioning}, social::{RecoveryAuditEntry, RecoveryReceipt, RecoveryStatus, SocialRecovery}}, session_manager::{create_session, rotate_session, start_session_maintenance, verify_session_proof, Session, SessionError, SessionMetrics, SessionProof, SessionStore, SessionSummary}, shadow_principal::{generate_shadow_principal, DerivationScheme, DerivationState, MigrationReport, PrincipalOrigin}, storage::{HeapState, StableMap}, webauthn::{Passkey, PasskeyAssertion, PasskeyChallenge, PasskeyRegistration, PasskeySummary, WebAuthnConfig, WebAuthnError, WebAuthnStore}};
 
+mod admin;
+mod identity_links;
+mod kyc;
 mod session_manager;
-mod google_verifier;
+mod oidc_verifier;
+mod profiles;
 mod shadow_principal;
+mod storage;
+mod webauthn;
 mod recovery {
     pub mod social;
     pub mod multi_factor;
+    pub mod delivery;
+    pub mod totp;
 }
 
 thread_local! {
-    static STATE: RefCell<State> = RefCell::new(State::default());
+    static STATE: RefCell<State> = RefCell::new(State::new());
 }
 
-#[derive(Serialize, Deserialize, Default, CandidType, Clone)]
 struct State {
-    // Google ID -> Shadow Principal mapping
-    google_mappings: HashMap<String, Principal>,
+    // Sign-in method -> Shadow Principal mapping
+    authenticators: StableMap<Authenticator, Principal>,
+    // II principal -> link request awaiting its confirmation
+    pending_links: StableMap<Principal, PendingLink>,
     // Principal -> UserData mapping
-    users: HashMap<Principal, UserData>,
+    users: StableMap<Principal, UserData>,
     // Session keys
-    sessions: HashMap<Vec<u8>, SessionInfo>,
+    sessions: SessionStore,
     // Recovery data
     recovery: RecoveryStore,
-    // Google OAuth config
-    google_config: GoogleConfig,
-    // Cached public key for principal generation
-    public_key: Option<Vec<u8>>,
+    // Accepted OpenID Connect providers, keyed by issuer
+    oidc_providers: HashMap<String, OidcProvider>,
+    // Shadow principal derivation scheme and salt
+    derivation: DerivationState,
+    // Old principal -> principal it was migrated to
+    principal_migrations: StableMap<Principal, Principal>,
+    // Recovery code delivery provider
+    delivery: DeliveryStore,
+    // Principal -> status of its latest recovery code delivery
+    delivery_records: StableMap<Principal, DeliveryRecord>,
+    // Counters from the session maintenance timer
+    session_metrics: SessionMetrics,
+    // Provider signing keys by issuer, refreshed by timer
+    jwks: HashMap<String, JwksCache>,
+    // Issuer -> start of its in-flight JWKS fetch; not kept across upgrades
+    jwks_fetches: HashMap<String, u64>,
+    // Admin roles and the config change audit log
+    admin: AdminStore,
+    // Passkey challenges and relying party config
+    webauthn: WebAuthnStore,
+    // Credential id -> registered passkey
+    passkeys: StableMap<Vec<u8>, Passkey>,
+    // Employee -> employer they work for
+    employments: StableMap<Principal, Employment>,
+    // Invite id -> open employment invite
+    employment_invites: StableMap<u64, EmploymentInvite>,
+    // Principals allowed to attest member verification
+    kyc_verifiers: StableMap<Principal, VerifierInfo>,
+    // Admin account -> employer whose members it may decrypt
+    admin_organisations: StableMap<Principal, Principal>,
+    // vetKD key used for profile encryption
+    pii: Option<PiiConfig>,
+    // Viewer -> profile key derivations this hour; not kept across upgrades
+    pii_key_requests: HashMap<Principal, KeyRequestWindow>,
+}
+
+impl State {
+    fn new() -> Self {
+        State {
+            authenticators: StableMap::init(storage::AUTHENTICATORS_MEMORY),
+            pending_links: StableMap::init(storage::PENDING_LINKS_MEMORY),
+            users: StableMap::init(storage::USERS_MEMORY),
+            sessions: SessionStore::init(),
+            recovery: RecoveryStore {
+                social: StableMap::init(storage::SOCIAL_RECOVERY_MEMORY),
+                multi_factor: StableMap::init(storage::MULTI_FACTOR_MEMORY),
+                receipts: StableMap::init(storage::RECEIPTS_MEMORY),
+                audit: StableMap::init(storage::RECOVERY_AUDIT_MEMORY),
+            },
+            oidc_providers: HashMap::new(),
+            derivation: DerivationState::default(),
+            principal_migrations: StableMap::init(storage::MIGRATIONS_MEMORY),
+            delivery: DeliveryStore::default(),
+            delivery_records: StableMap::init(storage::DELIVERY_RECORDS_MEMORY),
+            session_metrics: SessionMetrics::default(),
+            jwks: HashMap::new(),
+            jwks_fetches: HashMap::new(),
+            admin: AdminStore::default(),
+            webauthn: WebAuthnStore::default(),
+            passkeys: StableMap::init(storage::PASSKEYS_MEMORY),
+            employments: StableMap::init(storage::EMPLOYMENTS_MEMORY),
+            employment_invites: StableMap::init(storage::EMPLOYMENT_INVITES_MEMORY),
+            kyc_verifiers: StableMap::init(storage::KYC_VERIFIERS_MEMORY),
+            admin_organisations: StableMap::init(storage::ADMIN_ORGANISATIONS_MEMORY),
+            pii: None,
+            pii_key_requests: HashMap::new(),
+        }
+    }
+
+    fn heap_state(&self) -> HeapState {
+        HeapState {
+            oidc_providers: self.oidc_providers.clone(),
+            delivery: self.delivery.clone(),
+            session_metrics: self.session_metrics.clone(),
+            jwks: self.jwks.clone(),
+            admin: self.admin.clone(),
+            webauthn: self.webauthn.clone(),
+            derivation: self.derivation.clone(),
+            pii: self.pii.clone(),
+        }
+    }
+
+    fn restore_heap_state(&mut self, heap: HeapState) {
+        self.oidc_providers = heap.oidc_providers;
+        self.delivery = heap.delivery;
+        self.session_metrics = heap.session_metrics;
+        self.jwks = heap.jwks;
+        self.admin = heap.admin;
+        self.webauthn = heap.webauthn;
+        self.derivation = heap.derivation;
+        self.pii = heap.pii;
+    }
 }
 
 #[derive(Serialize, Deserialize, CandidType, Clone)]
 struct UserData {
     principal: Principal,
-    google_id: Option<String>,
-    ii_principal: Option<Principal>,
+    // Every way of signing in to this account, never empty
+    authenticators: Vec<LinkedAuthenticator>,
+    // None for accounts derived from the root test key before this was tracked
+    origin: Option<PrincipalOrigin>,
     linked_at: u64,
+    role: Option<AccountRole>,
+    // At most one of these is set, matching `role`
+    employee_profile: Option<EmployeeProfileData>,
+    employer_profile: Option<EmployerProfileData>,
+    // None until the member first submits for verification
+    verification: Option<Verification>,
+    // vetKD derivation input for the profile key, created on first use
+    pii_key_id: Option<Vec<u8>>,
 }
 
-#[derive(Serialize, Deserialize, Default, CandidType, Clone)]
 struct RecoveryStore {
-    social: HashMap<Principal, SocialRecovery>,
-    multi_factor: HashMap<Principal, MultiFactorRecovery>,
+    social: StableMap<Principal, SocialRecovery>,
+    multi_factor: StableMap<Principal, MultiFactorRecovery>,
+    // Old principal -> receipt of the timelocked recovery that moved it
+    receipts: StableMap<Principal, RecoveryReceipt>,
+    // Account -> recovery audit trail
+    audit: StableMap<Principal, Vec<RecoveryAuditEntry>>,
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Default, Debug)]
 pub struct GoogleConfig {
     pub client_id: String,
     pub client_secret: String,
+    // Tolerance for exp/iat/nbf checks, defaults to 60 seconds
+    pub clock_skew_secs: Option<u64>,
+    // Only accept Workspace accounts from these domains (`hd` claim)
+    pub allowed_hosted_domains: Option<Vec<String>>,
 }
 
 #[derive(Serialize, Deserialize, Clone, CandidType)]
@@ -60,6 +174,14 @@ pub struct SessionInfo {
     pub principal: Principal,
     pub created_at: u64,
     pub expires_at: u64,
+    // Highest proof nonce accepted so far
+    pub last_nonce: u64,
+    // Client opted in to having the session extended before it expires
+    pub auto_renew: bool,
+    // Supplied by the client at sign-in, e.g. "Pixel 8 / Chrome"
+    pub device_label: Option<String>,
+    // Last successful update call with this session
+    pub last_used_at: u64,
 }
 
 #[derive(Serialize, Deserialize, CandidType)]
@@ -68,120 +190,251 @@ pub struct SessionResponse {
     pub expires_at: u64,
 }
 
-#[derive(CandidType, Serialize, Deserialize)]
-struct EmployeeProfileData {
-    name: Option<String>,
-    email: Option<String>,
-    address: Option<String>,
-    position: Option<String>,
-    department: Option<String>,
-    role: Option<String>,
-    salary: u128,
-    start_date: u64,
-}
-
-#[derive(CandidType, Serialize, Deserialize)]
-struct EmployerProfileData {
-    company_name: Option<String>,
-    company_id: Option<String>,
-    email: Option<String>,
-    address: Option<String>,
-    industry: Option<String>,
-    employee_count: u128,
-    registration_date: u64,
-    contact_person: Option<String>,
+#[derive(CandidType, Serialize, Deserialize, Clone, Default, Debug)]
+pub struct InitArgs {
+    pub google_config: Option<GoogleConfig>,
+    // Defaults to the salted HMAC scheme; pass `EcdsaKey { key_name = "key_1" }`
+    // on mainnet to derive from a threshold key instead
+    pub principal_derivation: Option<DerivationScheme>,
 }
 
 #[init]
-fn init(google_config: Option<GoogleConfig>) {
+fn init(args: Option<InitArgs>) {
+    let args = args.unwrap_or_default();
+    let installer = ic_cdk::caller();
+    storage::init_schema();
     STATE.with(|s| {
         let mut state = s.borrow_mut();
-        if let Some(config) = google_config {
-            state.google_config = config;
+        if installer != Principal::anonymous() {
+            state.admin.roles.insert(installer, AdminRole::Owner);
+        }
+        if let Some(scheme) = args.principal_derivation {
+            state.derivation.scheme = scheme;
+        }
+        if let Some(config) = args.google_config {
+            let provider = OidcProvider::google(&config);
+            state.oidc_providers.insert(provider.issuer.clone(), provider);
         }
     });
+    start_session_maintenance();
+    start_jwks_refresh();
 }
 
+// Shorthand for registering Google as an OIDC provider. The client secret is
+// not needed for ID token verification and is neither stored nor echoed back.
 #[update]
-fn set_google_config(google_config: GoogleConfig) -> GoogleConfig {
+fn set_google_config(google_config: GoogleConfig) -> Result<(), AdminError> {
+    upsert_oidc_provider(OidcProvider::google(&google_config))
+}
+
+#[update]
+fn upsert_oidc_provider(provider: OidcProvider) -> Result<(), AdminError> {
+    let actor = require_role(AdminRole::ConfigManager)?;
+    if provider.issuer.is_empty()
+        || !provider.jwks_url.starts_with("https://")
+        || provider.audiences.is_empty()
+        || provider.algorithms.is_empty()
+    {
+        return Err(AdminError::InvalidConfig);
+    }
+    let change = ConfigChange::OidcProviderUpserted {
+        issuer: provider.issuer.clone(),
+        audiences: provider.audiences.clone(),
+    };
     STATE.with(|s| {
         let mut state = s.borrow_mut();
-        state.google_config = google_config.clone();
+        // Keys from a previous JWKS URL must not be trusted
+        state.jwks.remove(&provider.issuer);
+        state.oidc_providers.insert(provider.issuer.clone(), provider);
     });
-    google_config
+    log_config_change(actor, change);
+    Ok(())
 }
 
-#[pre_upgrade]
-fn pre_upgrade() {
+#[update]
+fn remove_oidc_provider(issuer: String) -> Result<(), AdminError> {
+    let actor = require_role(AdminRole::ConfigManager)?;
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        state.jwks.remove(&issuer);
+        state.oidc_providers.remove(&issuer).map(|_| ()).ok_or(AdminError::InvalidConfig)
+    })?;
+    log_config_change(actor, ConfigChange::OidcProviderRemoved { issuer });
+    Ok(())
+}
+
+#[query]
+fn list_oidc_providers() -> Vec<OidcProvider> {
+    STATE.with(|s| s.borrow().oidc_providers.values().cloned().collect())
+}
+
+// Points recovery codes at a provider. The headers carry the provider's API
+// key, so the config is write-only: no endpoint returns it.
+#[update]
+fn set_delivery_config(config: DeliveryConfig) -> Result<(), AdminError> {
+    let actor = require_role(AdminRole::ConfigManager)?;
+    if !config.is_valid() {
+        return Err(AdminError::InvalidConfig);
+    }
+    let change = ConfigChange::DeliveryConfigSet { endpoint: config.endpoint.clone() };
+    STATE.with(|s| {
+        s.borrow_mut().delivery.config = Some(config);
+    });
+    log_config_change(actor, change);
+    Ok(())
+}
+
+#[update]
+fn set_webauthn_config(config: WebAuthnConfig) -> Result<(), AdminError> {
+    let actor = require_role(AdminRole::ConfigManager)?;
+    if config.rp_id.is_empty()
+        || config.origins.is_empty()
+        || config.origins.iter().any(|origin| !origin.starts_with("https://"))
+    {
+        return Err(AdminError::InvalidConfig);
+    }
+    let change = ConfigChange::WebAuthnConfigSet {
+        rp_id: config.rp_id.clone(),
+        origins: config.origins.clone(),
+    };
+    STATE.with(|s| {
+        s.borrow_mut().webauthn.config = Some(config);
+    });
+    log_config_change(actor, change);
+    Ok(())
+}
+
+// Changing the scheme only affects new accounts until
+// `migrate_shadow_principals` is run
+#[update]
+fn set_principal_derivation(scheme: DerivationScheme) -> Result<(), AdminError> {
+    let actor = require_role(AdminRole::Owner)?;
+    if matches!(&scheme, DerivationScheme::EcdsaKey { key_name } if key_name.is_empty()) {
+        return Err(AdminError::InvalidConfig);
+    }
     STATE.with(|s| {
-        let state = s.borrow().clone();
-        ic_cdk::storage::stable_save((state,)).unwrap();
+        s.borrow_mut().derivation.scheme = scheme.clone();
     });
+    log_config_change(actor, ConfigChange::PrincipalDerivationSet { scheme });
+    Ok(())
+}
+
+// Moves up to `limit` accounts to principals under the current scheme. Call
+// repeatedly until `remaining` is 0; `get_migrated_principal` lets other
+// canisters follow the moves.
+#[update]
+async fn migrate_shadow_principals(limit: u32) -> Result<MigrationReport, AdminError> {
+    let actor = require_role(AdminRole::Owner)?;
+    let report = shadow_principal::migrate_principals(limit).await;
+    log_config_change(actor, ConfigChange::PrincipalsMigrated {
+        migrated: report.migrated,
+        remaining: report.remaining,
+    });
+    for failure in &report.failed {
+        log_config_change(actor, ConfigChange::PrincipalMigrationFailed {
+            principal: failure.principal,
+            error: failure.error.clone(),
+        });
+    }
+    Ok(report)
+}
+
+#[query]
+fn get_migrated_principal(old_principal: Principal) -> Option<Principal> {
+    STATE.with(|s| s.borrow().principal_migrations.get(&old_principal))
+}
+
+#[update]
+fn grant_admin_role(principal: Principal, role: AdminRole) -> Result<(), AdminError> {
+    admin::grant_role(principal, role)
+}
+
+#[update]
+fn revoke_admin_role(principal: Principal) -> Result<(), AdminError> {
+    admin::revoke_role(principal)
+}
+
+#[query]
+fn list_admins() -> Result<Vec<(Principal, AdminRole)>, AdminError> {
+    admin::list_roles()
+}
+
+#[query]
+fn get_config_audit_log(offset: u64, limit: u32) -> Result<Vec<ConfigAuditEntry>, AdminError> {
+    admin::audit_page(offset, limit)
+}
+
+// The large maps already live in stable memory; only the small heap part
+// is written out here
+#[pre_upgrade]
+fn pre_upgrade() {
+    let heap = STATE.with(|s| s.borrow().heap_state());
+    storage::save_heap_state(heap);
 }
 
 #[post_upgrade]
 fn post_upgrade() {
-    let (old_state,): (State,) = ic_cdk::storage::stable_restore().unwrap();
+    let heap = storage::migrate();
     STATE.with(|s| {
-        *s.borrow_mut() = old_state;
+        s.borrow_mut().restore_heap_state(heap);
     });
+    recovery::social::reschedule_pending_recoveries();
+    start_session_maintenance();
+    start_jwks_refresh();
 }
 
-// Main authentication endpoint
+// Main authentication endpoint, for any registered OpenID Connect provider
 #[update]
-async fn authenticate_with_google(id_token: String) -> Result<AuthResponse, AuthError> {
-    let config = STATE.with(|s| s.borrow().google_config.clone());
-    
-    // Verify Google token
-    let google_user = verify_google_token(&id_token, &config).await
+async fn authenticate_with_oidc(
+    id_token: String,
+    session_public_key: Vec<u8>,
+    device_label: Option<String>,
+) -> Result<AuthResponse, AuthError> {
+    // Verify the ID token, bound to the session key through its nonce
+    let nonce = session_nonce(&session_public_key);
+    let identity = verify_id_token(&id_token, &nonce).await
         .map_err(|_| AuthError::InvalidToken)?;
     
     // Check if user already exists
-    let existing_principal = STATE.with(|s| {
-        let state = s.borrow();
-        state.google_mappings.get(&google_user.id).copied()
-    });
+    let authenticator = Authenticator::Oidc(identity.subject.clone());
+    let existing_principal = identity_links::account_for(&authenticator);
     
     let shadow_principal = if let Some(principal) = existing_principal {
         // User exists, return existing principal
         principal
     } else {
         // New user, generate shadow principal
-        let principal = generate_shadow_principal(&google_user.id).await
+        let principal = generate_shadow_principal(&identity.subject).await
             .map_err(|_| AuthError::PrincipalError)?;
         
         // Store new user data
         STATE.with(|s| {
             let mut state = s.borrow_mut();
-            state.google_mappings.insert(google_user.id.clone(), principal);
+            let scheme = state.derivation.scheme.clone();
+            state.authenticators.insert(authenticator.clone(), principal);
             state.users.insert(principal, UserData {
                 principal,
-                google_id: Some(google_user.id),
-                ii_principal: None,
+                authenticators: vec![LinkedAuthenticator { authenticator, linked_at: time() }],
+                origin: Some(PrincipalOrigin::Derived(scheme)),
                 linked_at: time(),
+                role: None,
+                employee_profile: None,
+                employer_profile: None,
+                verification: None,
+                pii_key_id: None,
             });
         });
         
         principal
     };
     
-    // Create session
-    let session = create_session(shadow_principal).await
+    // Create session for the client-held key
+    let session = create_session(shadow_principal, session_public_key, device_label)
         .map_err(|e| {
-            ic_cdk::print(&format!("Session creation failed: {:?}", e));
+            ic_cdk::print(format!("Session creation failed: {:?}", e));
             AuthError::from(e)
         })?;
     
-    // Store session in state
-    STATE.with(|s| {
-        let mut state = s.borrow_mut();
-        state.sessions.insert(session.key.clone(), SessionInfo {
-            principal: shadow_principal,
-            created_at: time(),
-            expires_at: session.expires_at,
-        });
-    });
-    
     Ok(AuthResponse {
         principal: shadow_principal,
         session_key: session.key,
@@ -189,31 +442,329 @@ async fn authenticate_with_google(id_token: String) -> Result<AuthResponse, Auth
     })
 }
 
-// Link Internet Identity
+// Kept for existing clients; Google is just one of the registered providers
+#[update]
+async fn authenticate_with_google(
+    id_token: String,
+    session_public_key: Vec<u8>,
+    device_label: Option<String>,
+) -> Result<AuthResponse, AuthError> {
+    authenticate_with_oidc(id_token, session_public_key, device_label).await
+}
+
+// Sign in with an Internet Identity principal already linked to an account
+#[update]
+fn authenticate_with_internet_identity(
+    session_public_key: Vec<u8>,
+    device_label: Option<String>,
+) -> Result<AuthResponse, AuthError> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err(AuthError::InvalidSession);
+    }
+    let principal = identity_links::account_for(&Authenticator::InternetIdentity(caller))
+        .ok_or(AuthError::UserNotFound)?;
+    let session = create_session(principal, session_public_key, device_label)?;
+    Ok(AuthResponse {
+        principal,
+        session_key: session.key,
+        expires_at: session.expires_at,
+    })
+}
+
+// Step 1 of linking Internet Identity: returns when the request expires.
+// The II principal must then call `confirm_internet_identity_link`.
 #[update]
 fn link_internet_identity(
-    session_key: Vec<u8>, 
+    proof: SessionProof, 
     ii_principal: Principal
-) -> Result<(), AuthError> {
-    let session = validate_session(&session_key)?;
-    
+) -> Result<u64, AuthError> {
+    let session = validate_session(&proof, "link_internet_identity")?;
+    identity_links::request_ii_link(session.principal, ii_principal)
+}
+
+// Step 2, called by the II principal itself
+#[update]
+fn confirm_internet_identity_link(account: Principal) -> Result<(), AuthError> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err(AuthError::InvalidSession);
+    }
+    identity_links::confirm_ii_link(caller, account)
+}
+
+// Link another OIDC identity; the ID token's nonce must be bound to the
+// current session key, which proves the token was obtained for this link.
+#[update]
+async fn link_oidc_identity(proof: SessionProof, id_token: String) -> Result<(), AuthError> {
+    let session = validate_session(&proof, "link_oidc_identity")?;
+    let nonce = session_nonce(&proof.session_key);
+    let identity = verify_id_token(&id_token, &nonce).await
+        .map_err(|_| AuthError::InvalidToken)?;
     STATE.with(|s| {
         let mut state = s.borrow_mut();
-        if let Some(user) = state.users.get_mut(&session.principal) {
-            user.ii_principal = Some(ii_principal);
-            Ok(())
-        } else {
-            Err(AuthError::UserNotFound)
-        }
+        identity_links::attach(&mut state, session.principal, Authenticator::Oidc(identity.subject))
     })
 }
 
+#[update]
+fn unlink_identity(proof: SessionProof, authenticator: Authenticator) -> Result<(), AuthError> {
+    let session = validate_session(&proof, "unlink_identity")?;
+    identity_links::detach(session.principal, &authenticator)?;
+    if let Authenticator::Passkey { credential_id } = &authenticator {
+        webauthn::forget_credential(credential_id);
+    }
+    Ok(())
+}
+
+// Passkeys: registration adds a credential to the signed-in account
+#[update]
+async fn start_passkey_registration(proof: SessionProof) -> Result<PasskeyChallenge, WebAuthnError> {
+    let session = validate_session(&proof, "start_passkey_registration")?;
+    webauthn::start_registration(session.principal).await
+}
+
+#[update]
+fn finish_passkey_registration(
+    proof: SessionProof,
+    registration: PasskeyRegistration,
+) -> Result<(), WebAuthnError> {
+    let session = validate_session(&proof, "finish_passkey_registration")?;
+    webauthn::finish_registration(session.principal, registration)
+}
+
+// The challenge is bound to the session key `authenticate_with_passkey`
+// will be called with
+#[update]
+async fn start_passkey_authentication(session_public_key: Vec<u8>) -> Result<PasskeyChallenge, WebAuthnError> {
+    webauthn::start_authentication(&session_public_key).await
+}
+
+// Phishing-resistant sign-in that needs no identity provider outcalls
+#[update]
+fn authenticate_with_passkey(
+    assertion: PasskeyAssertion,
+    session_public_key: Vec<u8>,
+    device_label: Option<String>,
+) -> Result<AuthResponse, WebAuthnError> {
+    let principal = webauthn::finish_authentication(&assertion, &session_public_key)?;
+    let session = create_session(principal, session_public_key, device_label)
+        .map_err(AuthError::from)?;
+    Ok(AuthResponse {
+        principal,
+        session_key: session.key,
+        expires_at: session.expires_at,
+    })
+}
+
+#[query]
+fn list_passkeys(proof: SessionProof) -> Result<Vec<PasskeySummary>, AuthError> {
+    let session = validate_session(&proof, "list_passkeys")?;
+    Ok(webauthn::list_passkeys(session.principal))
+}
+
+#[query]
+fn list_linked_identities(proof: SessionProof) -> Result<Vec<LinkedAuthenticator>, AuthError> {
+    let session = validate_session(&proof, "list_linked_identities")?;
+    identity_links::list(session.principal)
+}
+
+// Profiles: an account becomes an employee or employer by saving a profile
+#[update]
+fn set_employee_profile(proof: SessionProof, profile: EmployeeProfileData) -> Result<(), ProfileError> {
+    let session = validate_session(&proof, "set_employee_profile")?;
+    profiles::set_employee_profile(session.principal, profile)
+}
+
+#[update]
+fn set_employer_profile(proof: SessionProof, profile: EmployerProfileData) -> Result<(), ProfileError> {
+    let session = validate_session(&proof, "set_employer_profile")?;
+    profiles::set_employer_profile(session.principal, profile)
+}
+
+#[query]
+fn get_my_profile(proof: SessionProof) -> Result<AccountProfile, ProfileError> {
+    let session = validate_session(&proof, "get_my_profile")?;
+    profiles::get_profile(session.principal)
+}
+
+// For admins, and employers looking up their own employees
+#[query]
+fn get_account_profile(proof: SessionProof, account: Principal) -> Result<AccountProfile, ProfileError> {
+    let session = validate_session(&proof, "get_account_profile")?;
+    profiles::lookup_profile(session.principal, account)
+}
+
+#[update]
+fn delete_profile(proof: SessionProof) -> Result<(), ProfileError> {
+    let session = validate_session(&proof, "delete_profile")?;
+    profiles::delete_profile(session.principal)
+}
+
+#[update]
+fn set_account_role(account: Principal, role: Option<AccountRole>) -> Result<(), ProfileError> {
+    let actor = profiles::set_account_role(account, role)?;
+    log_config_change(actor, ConfigChange::AccountRoleSet { principal: account, role });
+    Ok(())
+}
+
+// Scopes an admin account to one employer's members; None revokes it
+#[update]
+fn set_admin_organisation(account: Principal, organisation: Option<Principal>) -> Result<(), ProfileError> {
+    let actor = profiles::set_admin_organisation(account, organisation)?;
+    log_config_change(actor, ConfigChange::AdminOrganisationSet { principal: account, organisation });
+    Ok(())
+}
+
+// Employment: the employer invites an address and hands the returned token
+// to the invitee, whose acceptance creates the link
+#[update]
+async fn invite_employee(proof: SessionProof, email: String) -> Result<InviteTicket, ProfileError> {
+    let session = validate_session(&proof, "invite_employee")?;
+    profiles::invite_employee(session.principal, email).await
+}
+
+#[query]
+fn list_employment_invites(proof: SessionProof) -> Result<Vec<InviteSummary>, ProfileError> {
+    let session = validate_session(&proof, "list_employment_invites")?;
+    profiles::list_invites(session.principal)
+}
+
+#[update]
+fn revoke_employment_invite(proof: SessionProof, invite_id: u64) -> Result<(), ProfileError> {
+    let session = validate_session(&proof, "revoke_employment_invite")?;
+    profiles::revoke_invite(session.principal, invite_id)
+}
+
+#[update]
+fn accept_employment_invite(
+    proof: SessionProof,
+    invite_id: u64,
+    token: String,
+) -> Result<Employment, ProfileError> {
+    let session = validate_session(&proof, "accept_employment_invite")?;
+    profiles::accept_invite(session.principal, invite_id, &token)
+}
+
+#[update]
+fn end_employment(proof: SessionProof, employee: Principal) -> Result<(), ProfileError> {
+    let session = validate_session(&proof, "end_employment")?;
+    profiles::end_employment(session.principal, employee)
+}
+
+#[query]
+fn list_employees(proof: SessionProof) -> Result<Vec<EmployeeSummary>, ProfileError> {
+    let session = validate_session(&proof, "list_employees")?;
+    profiles::list_employees(session.principal)
+}
+
+// For fund canisters holding the `Auditor` role
+#[query]
+fn get_employment(employee: Principal) -> Result<Option<Employment>, ProfileError> {
+    profiles::employer_of(employee)
+}
+
+// Member verification (KYC) and profile encryption keys
+#[update]
+fn set_pii_config(config: PiiConfig) -> Result<(), AdminError> {
+    let actor = require_role(AdminRole::ConfigManager)?;
+    if config.vetkd_key_name.is_empty() {
+        return Err(AdminError::InvalidConfig);
+    }
+    let change = ConfigChange::PiiConfigSet { vetkd_key_name: config.vetkd_key_name.clone() };
+    STATE.with(|s| {
+        s.borrow_mut().pii = Some(config);
+    });
+    log_config_change(actor, change);
+    Ok(())
+}
+
+#[update]
+fn add_kyc_verifier(verifier: Principal, name: String) -> Result<(), AdminError> {
+    let actor = require_role(AdminRole::Owner)?;
+    if verifier == Principal::anonymous() || name.trim().is_empty() {
+        return Err(AdminError::InvalidConfig);
+    }
+    STATE.with(|s| {
+        s.borrow_mut().kyc_verifiers.insert(verifier, VerifierInfo { name, added_at: time() });
+    });
+    log_config_change(actor, ConfigChange::VerifierAdded { verifier });
+    Ok(())
+}
+
+#[update]
+fn remove_kyc_verifier(verifier: Principal) -> Result<(), AdminError> {
+    let actor = require_role(AdminRole::Owner)?;
+    let removed = STATE.with(|s| s.borrow_mut().kyc_verifiers.remove(&verifier));
+    if removed.is_none() {
+        return Err(AdminError::RoleNotFound);
+    }
+    log_config_change(actor, ConfigChange::VerifierRemoved { verifier });
+    Ok(())
+}
+
+#[query]
+fn list_kyc_verifiers() -> Result<Vec<(Principal, VerifierInfo)>, AdminError> {
+    require_role(AdminRole::Auditor)?;
+    Ok(STATE.with(|s| s.borrow().kyc_verifiers.iter().collect()))
+}
+
+#[update]
+fn request_verification(proof: SessionProof) -> Result<VerificationStatus, KycError> {
+    let session = validate_session(&proof, "request_verification")?;
+    kyc::request_verification(session.principal)
+}
+
+#[query]
+fn get_my_verification(proof: SessionProof) -> Result<Option<Verification>, KycError> {
+    let session = validate_session(&proof, "get_my_verification")?;
+    kyc::verification_of(session.principal)
+}
+
+// Verifiers call these directly with their own principal
+#[query]
+fn list_pending_verifications(limit: u32) -> Result<Vec<PendingVerification>, KycError> {
+    kyc::pending_verifications(ic_cdk::caller(), limit)
+}
+
+// Takes a pending case, which lets the verifier fetch the member's PII key
+#[update]
+fn claim_verification(account: Principal) -> Result<(), KycError> {
+    kyc::claim_verification(ic_cdk::caller(), account)
+}
+
+#[update]
+fn attest_verification(attestation: VerificationAttestation) -> Result<VerificationStatus, KycError> {
+    kyc::attest(ic_cdk::caller(), attestation)
+}
+
+#[update]
+async fn get_pii_public_key() -> Result<Vec<u8>, KycError> {
+    kyc::pii_public_key().await
+}
+
+// Members pass a session proof; verifiers and other callers may omit it
+#[update]
+async fn get_encrypted_pii_key(
+    proof: Option<SessionProof>,
+    account: Principal,
+    transport_public_key: Vec<u8>,
+) -> Result<Vec<u8>, KycError> {
+    let viewer = authenticated_principal(proof, "get_encrypted_pii_key")?;
+    kyc::derive_pii_key(viewer, account, transport_public_key).await
+}
+
 // Session management
 impl From<SessionError> for AuthError {
     fn from(err: SessionError) -> Self {
         match err {
             SessionError::GenerationFailed => AuthError::PrincipalError,
-            SessionError::InvalidPrincipal => AuthError::InvalidSession,
+            SessionError::InvalidPrincipal | SessionError::InvalidPublicKey | SessionError::KeyInUse => {
+                AuthError::InvalidSession
+            }
+            SessionError::StaleProof | SessionError::ReplayedNonce | SessionError::InvalidSignature => {
+                AuthError::InvalidProof
+            }
         }
     }
 }
@@ -228,57 +779,26 @@ impl From<Session> for SessionResponse {
 }
 
 #[update]
-async fn rotate_session_key(old_key: Vec<u8>) -> Result<SessionResponse, AuthError> {
-    let session = validate_session(&old_key)?;
-    let new_session = rotate_session(old_key.clone(), session.principal).await?;
-    
-    // Update session in state
-    STATE.with(|s| {
-        let mut state = s.borrow_mut();
-        state.sessions.remove(&old_key);
-        state.sessions.insert(new_session.key.clone(), SessionInfo {
-            principal: session.principal,
-            created_at: time(),
-            expires_at: new_session.expires_at,
-        });
-    });
-    
+fn rotate_session_key(
+    proof: SessionProof,
+    new_public_key: Vec<u8>,
+) -> Result<SessionResponse, AuthError> {
+    let session = validate_session(&proof, "rotate_session_key")?;
+    let new_session = rotate_session(proof.session_key, session.principal, new_public_key)?;
     Ok(new_session.into())
 }
 
 // session for II users
 #[update]
-async fn create_new_session(session_key: Vec<u8>) -> Result<SessionResponse, AuthError> {
+fn create_new_session(
+    proof: SessionProof,
+    new_public_key: Vec<u8>,
+) -> Result<SessionResponse, AuthError> {
     // validate the old session
-    let session = match validate_session(&session_key) {
-        Ok(session) => session,
-        Err(_) => {
-            return Err(AuthError::InvalidSession);
-        }
-    };
-
-    let principal = session.principal;
-
-    // create a fresh session for this principal
-    let new_session = create_session(principal).await?;
-
-    // update state
-    STATE.with(|s| {
-        let mut state = s.borrow_mut();
+    let session = validate_session(&proof, "create_new_session")?;
 
-        // Remove old session
-        state.sessions.remove(&session_key);
-
-        // Insert new session
-        state.sessions.insert(
-            new_session.key.clone(),
-            SessionInfo {
-                principal,
-                created_at: time(),
-                expires_at: new_session.expires_at,
-            },
-        );
-    });
+    // create a fresh session for this principal and drop the old one
+    let new_session = rotate_session(proof.session_key, session.principal, new_public_key)?;
 
     Ok(new_session.into())
 }
@@ -286,79 +806,205 @@ async fn create_new_session(session_key: Vec<u8>) -> Result<SessionResponse, Aut
 // Recovery endpoints
 #[update]
 fn setup_social_recovery(
-    session_key: Vec<u8>,
+    proof: SessionProof,
     contacts: Vec<Principal>,
-    threshold: u8
+    threshold: u8,
+    timelock_secs: Option<u64>,
 ) -> Result<(), RecoveryError> {
-    let session = validate_session(&session_key)?;
-    recovery::social::setup_social_recovery(session.principal, contacts, threshold)
+    let session = validate_session(&proof, "setup_social_recovery")?;
+    recovery::social::setup_social_recovery(session.principal, contacts, threshold, timelock_secs)
 }
 
 #[update]
-fn initiate_recovery(
-    session_key: Vec<u8>,
+fn add_guardian(
+    proof: SessionProof,
+    guardian: Principal,
+    new_threshold: Option<u8>,
+) -> Result<(), RecoveryError> {
+    let session = validate_session(&proof, "add_guardian")?;
+    recovery::social::add_guardian(session.principal, guardian, new_threshold)
+}
+
+#[update]
+fn remove_guardian(
+    proof: SessionProof,
+    guardian: Principal,
+    new_threshold: Option<u8>,
+) -> Result<(), RecoveryError> {
+    let session = validate_session(&proof, "remove_guardian")?;
+    recovery::social::remove_guardian(session.principal, guardian, new_threshold)
+}
+
+#[update]
+fn replace_guardian(
+    proof: SessionProof,
+    old_guardian: Principal,
+    new_guardian: Principal,
 ) -> Result<(), RecoveryError> {
-    let session = validate_session(&session_key)?;
-    recovery::social::initiate_recovery(session.principal)
+    let session = validate_session(&proof, "replace_guardian")?;
+    recovery::social::replace_guardian(session.principal, old_guardian, new_guardian)
+}
+
+#[query]
+fn list_guardians(proof: SessionProof) -> Result<Vec<Principal>, RecoveryError> {
+    let session = validate_session(&proof, "list_guardians")?;
+    recovery::social::get_recovery_status(session.principal, session.principal)
+        .map(|status| status.guardians)
+}
+
+// Guardian endpoints below accept either a session key or, without one, the
+// caller's own principal (e.g. an Internet Identity guardian).
+
+// Accounts the caller is a guardian for
+#[query]
+fn list_protected_accounts(proof: Option<SessionProof>) -> Result<Vec<Principal>, RecoveryError> {
+    let guardian = authenticated_principal(proof, "list_protected_accounts")?;
+    Ok(recovery::social::list_protected_accounts(guardian))
 }
 
+// Defaults to the caller's own account; guardians may pass an account they protect
 #[query]
+fn get_recovery_status(
+    proof: Option<SessionProof>,
+    target: Option<Principal>,
+) -> Result<RecoveryStatus, RecoveryError> {
+    let caller = authenticated_principal(proof, "get_recovery_status")?;
+    recovery::social::get_recovery_status(caller, target.unwrap_or(caller))
+}
+
+#[query]
+fn get_recovery_audit(
+    proof: Option<SessionProof>,
+    target: Option<Principal>,
+) -> Result<Vec<RecoveryAuditEntry>, RecoveryError> {
+    let caller = authenticated_principal(proof, "get_recovery_audit")?;
+    recovery::social::get_recovery_audit(caller, target.unwrap_or(caller))
+}
+
+// Started by a guardian on behalf of a locked-out owner, or by the owner
+#[update]
+fn initiate_recovery(
+    proof: Option<SessionProof>,
+    target: Principal,
+    new_principal: Principal,
+) -> Result<(), RecoveryError> {
+    let initiator = authenticated_principal(proof, "initiate_recovery")?;
+    recovery::social::initiate_recovery(initiator, target, new_principal)
+}
+
+#[update]
 fn approve_recovery(
-    session_key: Vec<u8>,
+    proof: Option<SessionProof>,
     principal: Principal
-) -> Result<(), RecoveryError> {
-    let session = validate_session(&session_key)?;
-    recovery::social::approve_recovery(session.principal, principal)
+) -> Result<Option<u64>, RecoveryError> {
+    let guardian = authenticated_principal(proof, "approve_recovery")?;
+    recovery::social::approve_recovery(guardian, principal)
+}
+
+// Owner veto during the timelock window
+#[update]
+fn cancel_recovery(proof: SessionProof) -> Result<(), RecoveryError> {
+    let session = validate_session(&proof, "cancel_recovery")?;
+    recovery::social::cancel_recovery(session.principal)
+}
+
+#[query]
+fn get_recovery_receipt(principal: Principal) -> Option<RecoveryReceipt> {
+    STATE.with(|s| s.borrow().recovery.receipts.get(&principal))
 }
 
 #[update]
 fn setup_multi_factor_recovery(
-    session_key: Vec<u8>,
+    proof: SessionProof,
     email: Option<String>,
     phone: Option<String>
 ) -> Result<(), RecoveryError> {
-    let session = validate_session(&session_key)?;
+    let session = validate_session(&proof, "setup_multi_factor_recovery")?;
     recovery::multi_factor::setup_multi_factor_recovery(session.principal, email, phone)
 }
 
+#[update]
+async fn enroll_totp(proof: SessionProof) -> Result<TotpProvisioning, RecoveryError> {
+    let session = validate_session(&proof, "enroll_totp")?;
+    recovery::multi_factor::enroll_totp(session.principal).await
+}
+
+#[update]
+fn confirm_totp(proof: SessionProof, code: String) -> Result<(), RecoveryError> {
+    let session = validate_session(&proof, "confirm_totp")?;
+    recovery::multi_factor::confirm_totp(session.principal, code)
+}
+
 #[update]
 async fn initiate_multi_factor_recovery(
-    session_key: Vec<u8>,
+    proof: SessionProof,
+    method: RecoveryMethod,
 ) -> Result<(), RecoveryError> {
-    let session = validate_session(&session_key)?;
-    recovery::multi_factor::initiate_mfa_recovery(session.principal, RecoveryMethod::Email).await
+    let session = validate_session(&proof, "initiate_multi_factor_recovery")?;
+    recovery::multi_factor::initiate_mfa_recovery(session.principal, method).await
 }
 
-#[query]
+#[update]
 fn complete_mfa_recovery(
-    session_key: Vec<u8>,
-    code: String
-) -> Result<(), RecoveryError> {
-    let session = validate_session(&session_key)?;
-    recovery::multi_factor::complete_mfa_recovery(session.principal, code)
+    proof: SessionProof,
+    code: String,
+    new_principal: Principal,
+) -> Result<RecoveryReceipt, RecoveryError> {
+    let session = validate_session(&proof, "complete_mfa_recovery")?;
+    recovery::multi_factor::complete_mfa_recovery(session.principal, code, new_principal)
 }
 
-// Helper function
-fn validate_session(session_key: &[u8]) -> Result<SessionInfo, AuthError> {
+#[query]
+fn get_recovery_delivery_status(
+    proof: SessionProof,
+) -> Result<Option<DeliveryRecord>, RecoveryError> {
+    let session = validate_session(&proof, "get_recovery_delivery_status")?;
+    Ok(recovery::delivery::delivery_status(session.principal))
+}
+
+// Helper function: checks the session exists, is live, and that the proof
+// was signed by its key for `method`. Nonces only advance in update calls.
+fn validate_session(proof: &SessionProof, method: &str) -> Result<SessionInfo, AuthError> {
     STATE.with(|s| {
         let mut state = s.borrow_mut();
         
         // Get session
-        let session = state.sessions.get(session_key)
-            .cloned()
+        let session = state.sessions.get(&proof.session_key)
             .ok_or(AuthError::InvalidSession)?;
         
         // Check expiration
         if session.expires_at <= time() {
             // Remove expired session
-            state.sessions.remove(session_key);
+            state.sessions.remove(&proof.session_key);
             return Err(AuthError::SessionExpired);
         }
+
+        verify_session_proof(proof, &ic_cdk::id(), method, session.last_nonce, time())?;
+        state.sessions.update(&proof.session_key, |stored| {
+            stored.last_nonce = proof.nonce;
+            stored.last_used_at = time();
+        });
         
         Ok(session)
     })
 }
 
+// Resolves who is acting: the session's principal when a proof is given,
+// otherwise the (non-anonymous) caller
+fn authenticated_principal(proof: Option<SessionProof>, method: &str) -> Result<Principal, AuthError> {
+    match proof {
+        Some(proof) => validate_session(&proof, method).map(|session| session.principal),
+        None => {
+            let caller = ic_cdk::caller();
+            if caller == Principal::anonymous() {
+                Err(AuthError::InvalidSession)
+            } else {
+                Ok(caller)
+            }
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, CandidType)]
 pub struct AuthResponse {
     pub principal: Principal,
@@ -368,15 +1014,14 @@ pub struct AuthResponse {
 
 // Add user info query endpoint
 #[query]
-fn get_user_details(session_key: Vec<u8>) -> Result<UserData, AuthError> {
-    let session = validate_session(&session_key)?;
+fn get_user_details(proof: SessionProof) -> Result<UserData, AuthError> {
+    let session = validate_session(&proof, "get_user_details")?;
     
     STATE.with(|s| {
         let state = s.borrow();
         state.users.get(&session.principal)
-            .cloned()
             .ok_or_else(|| {
-                ic_cdk::print(&format!("User not found for principal: {}", session.principal));
+                ic_cdk::print(format!("User not found for principal: {}", session.principal));
                 AuthError::UserNotFound
             })
     })
@@ -384,10 +1029,11 @@ fn get_user_details(session_key: Vec<u8>) -> Result<UserData, AuthError> {
 
 // Add logout endpoint
 #[update]
-fn logout(session_key: Vec<u8>) -> Result<(), AuthError> {
+fn logout(proof: SessionProof) -> Result<(), AuthError> {
+    validate_session(&proof, "logout")?;
     STATE.with(|s| {
         let mut state = s.borrow_mut();
-        let removed = state.sessions.remove(&session_key);
+        let removed = state.sessions.remove(&proof.session_key);
         if removed.is_some() {
             ic_cdk::print("Session successfully removed during logout");
         } else {
@@ -397,10 +1043,50 @@ fn logout(session_key: Vec<u8>) -> Result<(), AuthError> {
     Ok(())
 }
 
+#[query]
+fn list_my_sessions(proof: SessionProof) -> Result<Vec<SessionSummary>, AuthError> {
+    let session = validate_session(&proof, "list_my_sessions")?;
+    Ok(session_manager::list_sessions(session.principal, &proof.session_key))
+}
+
+// Revoke another of the caller's sessions, e.g. on a lost phone
+#[update]
+fn revoke_session(proof: SessionProof, session_key: Vec<u8>) -> Result<(), AuthError> {
+    let session = validate_session(&proof, "revoke_session")?;
+    if session_manager::revoke_session(session.principal, &session_key) {
+        Ok(())
+    } else {
+        Err(AuthError::InvalidSession)
+    }
+}
+
+#[update]
+fn revoke_all_other_sessions(proof: SessionProof) -> Result<u32, AuthError> {
+    let session = validate_session(&proof, "revoke_all_other_sessions")?;
+    Ok(session_manager::revoke_other_sessions(session.principal, &proof.session_key))
+}
+
+// Opt in or out of automatic session renewal
+#[update]
+fn set_session_auto_renew(proof: SessionProof, enabled: bool) -> Result<(), AuthError> {
+    validate_session(&proof, "set_session_auto_renew")?;
+    STATE.with(|s| {
+        s.borrow_mut().sessions.update(&proof.session_key, |session| {
+            session.auto_renew = enabled;
+        });
+    });
+    Ok(())
+}
+
+#[query]
+fn get_session_metrics() -> SessionMetrics {
+    STATE.with(|s| s.borrow().session_metrics.clone())
+}
+
 // check session validity
 #[query]
-fn is_session_valid(session_key: Vec<u8>) -> bool {
-    validate_session(&session_key).is_ok()
+fn is_session_valid(proof: SessionProof) -> bool {
+    validate_session(&proof, "is_session_valid").is_ok()
 }
 
 #[derive(Error, Debug, Serialize, Deserialize, CandidType, Clone)]
@@ -415,6 +1101,16 @@ pub enum AuthError {
     UserNotFound,
     #[error("Principal generation failed")]
     PrincipalError,
+    #[error("Invalid session proof")]
+    InvalidProof,
+    #[error("Identity is already linked to another account")]
+    IdentityInUse,
+    #[error("No such linked identity or link request")]
+    LinkNotFound,
+    #[error("Another account has a pending link request for this identity")]
+    LinkPending,
+    #[error("Cannot remove the last sign-in method")]
+    LastAuthenticator,
 }
 
 export_candid!();
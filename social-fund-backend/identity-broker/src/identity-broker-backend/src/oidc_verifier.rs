@@ -0,0 +1,713 @@
+use std::time::Duration;
+
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use candid::CandidType;
+use ic_cdk::{
+    api::{
+        management_canister::http_request::{
+            http_request, CanisterHttpRequestArgument, HttpMethod, HttpResponse,
+            TransformArgs, TransformContext,
+        },
+        time,
+    },
+    query,
+};
+use ring::signature::{
+    RsaPublicKeyComponents, UnparsedPublicKey, ECDSA_P256_SHA256_FIXED,
+    RSA_PKCS1_2048_8192_SHA256,
+};
+use serde::{Deserialize, Deserializer, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::{GoogleConfig, STATE};
+
+pub const GOOGLE_ISSUER: &str = "https://accounts.google.com";
+const GOOGLE_CERTS_URL: &str = "https://www.googleapis.com/oauth2/v3/certs";
+const NS_PER_SEC: u64 = 1_000_000_000;
+// Fixed cache lifetime. Providers' Cache-Control max-age counts down, so
+// replicas would disagree on it; rotations within the hour are picked up by
+// the refetch on an unknown kid.
+const JWKS_TTL_NS: u64 = 60 * 60 * NS_PER_SEC;
+// A fetch still marked in flight after this is assumed lost
+const JWKS_FETCH_TIMEOUT_NS: u64 = 5 * 60 * NS_PER_SEC;
+// How often the refresh job looks for caches that are about to expire
+const JWKS_REFRESH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const JWKS_REFRESH_AHEAD_NS: u64 = 10 * 60 * NS_PER_SEC;
+// Unknown kids trigger at most one refetch per interval
+const MIN_FORCED_REFRESH_INTERVAL_NS: u64 = 60 * NS_PER_SEC;
+// Entra and Apple key sets are much larger than Google's
+const MAX_JWKS_RESPONSE_BYTES: u64 = 64_000;
+const DEFAULT_CLOCK_SKEW_SECS: u64 = 60;
+const NONCE_DOMAIN: &[u8] = b"social-fund-oidc-nonce";
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JwtAlgorithm {
+    RS256,
+    ES256,
+}
+
+/// An OpenID Connect identity provider accepted by the broker (Google,
+/// Microsoft Entra, Apple, an employer's SSO tenant, ...).
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct OidcProvider {
+    // Canonical `iss` value, also used to key users as (issuer, sub)
+    pub issuer: String,
+    // Other `iss` spellings for the same provider, e.g. Google's bare host
+    pub issuer_aliases: Vec<String>,
+    pub jwks_url: String,
+    // Accepted `aud` values, i.e. our client ids at this provider
+    pub audiences: Vec<String>,
+    pub algorithms: Vec<JwtAlgorithm>,
+    // Reject tokens without `azp` (Google always sends it)
+    pub require_azp: bool,
+    pub require_verified_email: bool,
+    // Only accept these Workspace domains (`hd`) or Entra tenants (`tid`)
+    pub allowed_tenants: Option<Vec<String>>,
+    // Tolerance for exp/iat/nbf checks, defaults to 60 seconds
+    pub clock_skew_secs: Option<u64>,
+}
+
+impl OidcProvider {
+    /// Registry entry equivalent to the legacy Google configuration.
+    pub fn google(config: &GoogleConfig) -> Self {
+        OidcProvider {
+            issuer: GOOGLE_ISSUER.to_string(),
+            issuer_aliases: vec!["accounts.google.com".to_string()],
+            jwks_url: GOOGLE_CERTS_URL.to_string(),
+            audiences: vec![config.client_id.clone()],
+            algorithms: vec![JwtAlgorithm::RS256],
+            require_azp: true,
+            require_verified_email: true,
+            allowed_tenants: config.allowed_hosted_domains.clone(),
+            clock_skew_secs: config.clock_skew_secs,
+        }
+    }
+
+    fn matches_issuer(&self, iss: &str) -> bool {
+        self.issuer == iss || self.issuer_aliases.iter().any(|alias| alias == iss)
+    }
+}
+
+/// A user's identity at a provider. Users are keyed by this pair since `sub`
+/// is only unique per issuer.
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct OidcSubject {
+    pub issuer: String,
+    pub sub: String,
+}
+
+#[derive(Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+/// A provider's signing keys kept in canister state between logins.
+#[derive(Serialize, Deserialize, CandidType, Clone, Default)]
+pub struct JwksCache {
+    keys: Vec<Jwk>,
+    fetched_at: u64,
+    expires_at: u64,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone)]
+struct Jwk {
+    kty: String,
+    alg: Option<String>,
+    #[serde(rename = "use")]
+    key_use: Option<String>,
+    kid: String,
+    // RSA
+    e: Option<String>,
+    n: Option<String>,
+    // EC
+    crv: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+pub struct VerifiedIdentity {
+    pub subject: OidcSubject,
+}
+
+#[derive(Error, Debug)]
+pub enum OidcError {
+    #[error("Invalid token format")]
+    InvalidFormat,
+    #[error("Invalid signature")]
+    InvalidSignature,
+    #[error("Token expired")]
+    Expired,
+    #[error("Invalid audience")]
+    InvalidAudience,
+    #[error("Invalid issuer")]
+    InvalidIssuer,
+    #[error("Token issued in the future")]
+    InvalidIssuedAt,
+    #[error("Token not yet valid")]
+    NotYetValid,
+    #[error("Invalid authorized party")]
+    InvalidAuthorizedParty,
+    #[error("Email not verified")]
+    EmailNotVerified,
+    #[error("Nonce does not match the session key")]
+    InvalidNonce,
+    #[error("Tenant not allowed")]
+    InvalidTenant,
+    #[error("Network error: {0}")]
+    NetworkError(String),
+    #[error("Key not found")]
+    KeyNotFound,
+    #[error("Provider keys are being refreshed, try again")]
+    KeysRefreshing,
+    #[error("Unsupported algorithm: {0}")]
+    UnsupportedAlgorithm(String),
+}
+
+impl JwksCache {
+    fn find(&self, kid: &str) -> Option<Jwk> {
+        if self.expires_at <= time() {
+            return None;
+        }
+        self.keys.iter().find(|k| k.kid == kid).cloned()
+    }
+}
+
+/// Nonce the frontend must put in the sign-in request for a session key:
+/// base64url(SHA-256(domain || public key)). Binding the token to the key
+/// stops tokens minted for other apps or sessions from being replayed.
+pub fn session_nonce(session_public_key: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(NONCE_DOMAIN);
+    hasher.update(session_public_key);
+    BASE64_URL_SAFE_NO_PAD.encode(hasher.finalize())
+}
+
+/// Verifies an ID token from any registered provider, picked by its `iss`.
+pub async fn verify_id_token(
+    id_token: &str,
+    expected_nonce: &str,
+) -> Result<VerifiedIdentity, OidcError> {
+    // Split the token into its parts
+    let parts: Vec<&str> = id_token.split('.').collect();
+    if parts.len() != 3 {
+        return Err(OidcError::InvalidFormat);
+    }
+
+    // Decode the header and claims
+    let header = decode_jwt_header(parts[0])?;
+    let claims = decode_jwt_claims(parts[1])?;
+
+    let provider = STATE.with(|s| {
+        s.borrow()
+            .oidc_providers
+            .values()
+            .find(|p| p.matches_issuer(&claims.iss))
+            .cloned()
+    })
+    .ok_or(OidcError::InvalidIssuer)?;
+
+    // Check claims before spending anything on keys (ic_cdk::api::time returns ns)
+    let now = time() / NS_PER_SEC;
+    validate_claims(&claims, &provider, expected_nonce, now)?;
+
+    // Verify algorithm
+    let alg = match header.alg.as_str() {
+        "RS256" => JwtAlgorithm::RS256,
+        "ES256" => JwtAlgorithm::ES256,
+        other => return Err(OidcError::UnsupportedAlgorithm(other.to_string())),
+    };
+    if !provider.algorithms.contains(&alg) {
+        return Err(OidcError::UnsupportedAlgorithm(header.alg));
+    }
+
+    // Find appropriate key by kid, from cache when possible
+    let key = provider_key(&provider, &header.kid).await?;
+
+    // Verify signature
+    verify_signature(parts[0], parts[1], parts[2], &key, alg)?;
+
+    Ok(VerifiedIdentity {
+        subject: OidcSubject {
+            issuer: provider.issuer,
+            sub: claims.sub,
+        },
+    })
+}
+
+fn validate_claims(
+    claims: &JwtClaims,
+    provider: &OidcProvider,
+    expected_nonce: &str,
+    now: u64,
+) -> Result<(), OidcError> {
+    let skew = provider.clock_skew_secs.unwrap_or(DEFAULT_CLOCK_SKEW_SECS);
+
+    // Verify token lifetime
+    if claims.exp + skew < now {
+        return Err(OidcError::Expired);
+    }
+    if claims.iat > now + skew {
+        return Err(OidcError::InvalidIssuedAt);
+    }
+    if claims.nbf.is_some_and(|nbf| nbf > now + skew) {
+        return Err(OidcError::NotYetValid);
+    }
+
+    // Verify issuer
+    if !provider.matches_issuer(&claims.iss) {
+        return Err(OidcError::InvalidIssuer);
+    }
+
+    // Verify audience and authorized party
+    let audiences = claims.aud.values();
+    if audiences.is_empty() || !audiences.iter().all(|aud| provider.audiences.contains(*aud)) {
+        return Err(OidcError::InvalidAudience);
+    }
+    match &claims.azp {
+        Some(azp) if !provider.audiences.contains(azp) => {
+            return Err(OidcError::InvalidAuthorizedParty)
+        }
+        // OIDC requires azp when there are several audiences
+        None if provider.require_azp || audiences.len() > 1 => {
+            return Err(OidcError::InvalidAuthorizedParty)
+        }
+        _ => {}
+    }
+
+    if provider.require_verified_email && !claims.email_verified {
+        return Err(OidcError::EmailNotVerified);
+    }
+
+    if claims.nonce.as_deref() != Some(expected_nonce) {
+        return Err(OidcError::InvalidNonce);
+    }
+
+    // Restrict to employer Workspace domains / Entra tenants when configured
+    if let Some(tenants) = provider.allowed_tenants.as_ref().filter(|t| !t.is_empty()) {
+        let tenant = claims.hd.as_ref().or(claims.tid.as_ref());
+        let allowed = tenant.is_some_and(|t| tenants.iter().any(|allowed| allowed.eq_ignore_ascii_case(t)));
+        if !allowed {
+            return Err(OidcError::InvalidTenant);
+        }
+    }
+
+    Ok(())
+}
+
+/// Looks `kid` up in the provider's cached JWKS. A miss (keys rotated, or the
+/// cache expired) refetches once, rate limited so bogus kids can't force an
+/// outcall per login.
+async fn provider_key(provider: &OidcProvider, kid: &str) -> Result<Jwk, OidcError> {
+    let (cached, fetched_at) = STATE.with(|s| {
+        let state = s.borrow();
+        state
+            .jwks
+            .get(&provider.issuer)
+            .map(|cache| (cache.find(kid), cache.fetched_at))
+            .unwrap_or((None, 0))
+    });
+    if let Some(key) = cached {
+        return Ok(key);
+    }
+
+    if fetched_at + MIN_FORCED_REFRESH_INTERVAL_NS > time() {
+        return Err(OidcError::KeyNotFound);
+    }
+    refresh_jwks(provider).await?;
+
+    STATE.with(|s| s.borrow().jwks.get(&provider.issuer).and_then(|cache| cache.find(kid)))
+        .ok_or(OidcError::KeyNotFound)
+}
+
+/// Starts the background refresh. Called from `init` and `post_upgrade`.
+pub fn start_jwks_refresh() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || ic_cdk::spawn(refresh_stale_jwks()));
+    ic_cdk_timers::set_timer_interval(JWKS_REFRESH_INTERVAL, || {
+        ic_cdk::spawn(refresh_stale_jwks())
+    });
+}
+
+/// Refreshes every provider whose cache is missing or close to expiry.
+async fn refresh_stale_jwks() {
+    let now = time();
+    let stale: Vec<OidcProvider> = STATE.with(|s| {
+        let state = s.borrow();
+        state
+            .oidc_providers
+            .values()
+            .filter(|p| {
+                state
+                    .jwks
+                    .get(&p.issuer)
+                    .is_none_or(|cache| cache.expires_at <= now + JWKS_REFRESH_AHEAD_NS)
+            })
+            .cloned()
+            .collect()
+    });
+
+    for provider in stale {
+        match refresh_jwks(&provider).await {
+            // A login's refetch is already bringing the cache up to date
+            Ok(()) | Err(OidcError::KeysRefreshing) => {}
+            Err(e) => ic_cdk::print(format!("JWKS refresh for {} failed: {}", provider.issuer, e)),
+        }
+    }
+}
+
+/// Marks a provider's JWKS fetch as in flight until dropped, so concurrent
+/// kid misses and the refresh job share one outcall.
+struct JwksFetch(String);
+
+impl JwksFetch {
+    fn start(issuer: &str, now: u64) -> Option<Self> {
+        STATE.with(|s| {
+            let fetches = &mut s.borrow_mut().jwks_fetches;
+            if fetches.get(issuer).is_some_and(|started_at| started_at + JWKS_FETCH_TIMEOUT_NS > now) {
+                return None;
+            }
+            fetches.insert(issuer.to_string(), now);
+            Some(JwksFetch(issuer.to_string()))
+        })
+    }
+}
+
+impl Drop for JwksFetch {
+    fn drop(&mut self) {
+        STATE.with(|s| {
+            s.borrow_mut().jwks_fetches.remove(&self.0);
+        });
+    }
+}
+
+/// Fetches a provider's JWKS into the cache, unless a fetch for it is
+/// already in flight.
+async fn refresh_jwks(provider: &OidcProvider) -> Result<(), OidcError> {
+    let _fetch = JwksFetch::start(&provider.issuer, time()).ok_or(OidcError::KeysRefreshing)?;
+    let key_set = fetch_jwks(&provider.jwks_url).await?;
+    let now = time();
+    STATE.with(|s| {
+        s.borrow_mut().jwks.insert(
+            provider.issuer.clone(),
+            JwksCache {
+                keys: key_set.keys,
+                fetched_at: now,
+                expires_at: now + JWKS_TTL_NS,
+            },
+        );
+    });
+    Ok(())
+}
+
+async fn fetch_jwks(url: &str) -> Result<JwkSet, OidcError> {
+    let request = CanisterHttpRequestArgument {
+        url: url.to_string(),
+        method: HttpMethod::GET,
+        body: None,
+        max_response_bytes: Some(MAX_JWKS_RESPONSE_BYTES),
+        transform: Some(TransformContext::from_name(
+            "transform_jwks_response".to_string(),
+            vec![],
+        )),
+        headers: vec![],
+    };
+
+    // Cycles must be u128
+    let cycles: u128 = 2_000_000_000;
+
+    let (response,): (HttpResponse,) = http_request(request, cycles)
+        .await
+        .map_err(|e| OidcError::NetworkError(format!("HTTP request failed: {:?}", e)))?;
+
+    if response.status != 200u16 {
+        return Err(OidcError::NetworkError(format!(
+            "HTTP status: {}",
+            response.status
+        )));
+    }
+
+    let body = String::from_utf8(response.body)
+        .map_err(|e| OidcError::NetworkError(format!("Invalid UTF-8: {}", e)))?;
+
+    serde_json::from_str(&body)
+        .map_err(|e| OidcError::NetworkError(format!("JSON parse error: {}", e)))
+}
+
+/// Provider responses carry dates, expiry and a max-age that counts down, so
+/// replicas never see identical headers. Only the status and body are kept.
+#[query]
+fn transform_jwks_response(raw: TransformArgs) -> HttpResponse {
+    HttpResponse {
+        status: raw.response.status,
+        headers: vec![],
+        body: raw.response.body,
+    }
+}
+
+fn verify_signature(
+    header: &str,
+    payload: &str,
+    signature_b64: &str,
+    key: &Jwk,
+    alg: JwtAlgorithm,
+) -> Result<(), OidcError> {
+    // Signed message is "base64url(header).base64url(payload)"
+    let message = format!("{}.{}", header, payload);
+
+    // Base64url-decode the signature and key components
+    let signature = BASE64_URL_SAFE_NO_PAD
+        .decode(signature_b64)
+        .map_err(|_| OidcError::InvalidFormat)?;
+    let decode = |part: &Option<String>| {
+        part.as_deref()
+            .and_then(|p| BASE64_URL_SAFE_NO_PAD.decode(p).ok())
+            .ok_or(OidcError::InvalidFormat)
+    };
+
+    match alg {
+        JwtAlgorithm::RS256 => {
+            if key.kty != "RSA" {
+                return Err(OidcError::UnsupportedAlgorithm(key.kty.clone()));
+            }
+            let n = decode(&key.n)?;
+            let e = decode(&key.e)?;
+
+            // Use ring’s raw RSA components; ring handles the SHA-256 hash internally
+            let pubkey = RsaPublicKeyComponents { n: &n, e: &e };
+            pubkey
+                .verify(&RSA_PKCS1_2048_8192_SHA256, message.as_bytes(), &signature)
+                .map_err(|_| OidcError::InvalidSignature)
+        }
+        JwtAlgorithm::ES256 => {
+            if key.kty != "EC" || key.crv.as_deref() != Some("P-256") {
+                return Err(OidcError::UnsupportedAlgorithm(key.kty.clone()));
+            }
+            // Uncompressed SEC1 point; JWS signatures are the fixed r || s form
+            let mut point = vec![0x04];
+            point.extend(decode(&key.x)?);
+            point.extend(decode(&key.y)?);
+            UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, &point)
+                .verify(message.as_bytes(), &signature)
+                .map_err(|_| OidcError::InvalidSignature)
+        }
+    }
+}
+
+// Helper structs for JWT decoding
+#[derive(Deserialize)]
+struct JwtHeader {
+    kid: String,
+    alg: String,
+    #[allow(dead_code)]
+    typ: Option<String>,
+}
+
+/// `aud` may be a single string or an array.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Audience {
+    fn values(&self) -> Vec<&String> {
+        match self {
+            Audience::One(aud) => vec![aud],
+            Audience::Many(auds) => auds.iter().collect(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct JwtClaims {
+    iss: String,          // Issuer
+    sub: String,          // Subject (user ID at the issuer)
+    aud: Audience,        // Audience (client ID)
+    exp: u64,             // Expiration time (seconds)
+    iat: u64,             // Issued at (seconds)
+    nbf: Option<u64>,     // Not before (seconds)
+    azp: Option<String>,  // Authorized party (client ID)
+    nonce: Option<String>,
+    hd: Option<String>,   // Google Workspace domain
+    tid: Option<String>,  // Entra tenant id
+    // Apple sends this as a string
+    #[serde(default, deserialize_with = "bool_or_string")]
+    email_verified: bool,
+}
+
+fn bool_or_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum BoolOrString {
+        Bool(bool),
+        String(String),
+    }
+    Ok(match BoolOrString::deserialize(deserializer)? {
+        BoolOrString::Bool(b) => b,
+        BoolOrString::String(s) => s == "true",
+    })
+}
+
+fn decode_jwt_header(header: &str) -> Result<JwtHeader, OidcError> {
+    let decoded = BASE64_URL_SAFE_NO_PAD
+        .decode(header)
+        .map_err(|_| OidcError::InvalidFormat)?;
+    serde_json::from_slice(&decoded).map_err(|_| OidcError::InvalidFormat)
+}
+
+fn decode_jwt_claims(claims: &str) -> Result<JwtClaims, OidcError> {
+    let decoded = BASE64_URL_SAFE_NO_PAD
+        .decode(claims)
+        .map_err(|_| OidcError::InvalidFormat)?;
+    serde_json::from_slice(&decoded).map_err(|_| OidcError::InvalidFormat)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_cdk::api::management_canister::http_request::HttpHeader;
+
+    #[test]
+    fn test_decode_jwt_header() {
+        // Example JWT header: {"alg":"RS256","kid":"test","typ":"JWT"}
+        let header_b64 = "eyJhbGciOiJSUzI1NiIsImtpZCI6InRlc3QiLCJ0eXAiOiJKV1QifQ";
+        let result = decode_jwt_header(header_b64);
+        assert!(result.is_ok());
+
+        let header = result.unwrap();
+        assert_eq!(header.alg, "RS256");
+        assert_eq!(header.kid, "test");
+        assert_eq!(header.typ.as_deref(), Some("JWT"));
+    }
+
+    fn test_provider() -> OidcProvider {
+        OidcProvider::google(&GoogleConfig {
+            client_id: "client.apps.googleusercontent.com".to_string(),
+            client_secret: String::new(),
+            clock_skew_secs: Some(60),
+            allowed_hosted_domains: None,
+        })
+    }
+
+    fn test_claims(nonce: &str) -> JwtClaims {
+        JwtClaims {
+            iss: "https://accounts.google.com".to_string(),
+            sub: "1234".to_string(),
+            aud: Audience::One("client.apps.googleusercontent.com".to_string()),
+            exp: 2_000,
+            iat: 1_000,
+            nbf: None,
+            azp: Some("client.apps.googleusercontent.com".to_string()),
+            nonce: Some(nonce.to_string()),
+            hd: None,
+            tid: None,
+            email_verified: true,
+        }
+    }
+
+    #[test]
+    fn test_validate_claims() {
+        let provider = test_provider();
+        let nonce = session_nonce(&[1u8; 32]);
+        let claims = test_claims(&nonce);
+
+        assert!(validate_claims(&claims, &provider, &nonce, 1_500).is_ok());
+        // Within skew on either end
+        assert!(validate_claims(&claims, &provider, &nonce, 2_050).is_ok());
+        assert!(validate_claims(&claims, &provider, &nonce, 950).is_ok());
+
+        assert!(matches!(
+            validate_claims(&claims, &provider, &nonce, 2_100),
+            Err(OidcError::Expired)
+        ));
+        assert!(matches!(
+            validate_claims(&claims, &provider, &nonce, 900),
+            Err(OidcError::InvalidIssuedAt)
+        ));
+        assert!(matches!(
+            validate_claims(&claims, &provider, &session_nonce(&[2u8; 32]), 1_500),
+            Err(OidcError::InvalidNonce)
+        ));
+
+        let unverified = JwtClaims { email_verified: false, ..test_claims(&nonce) };
+        assert!(matches!(
+            validate_claims(&unverified, &provider, &nonce, 1_500),
+            Err(OidcError::EmailNotVerified)
+        ));
+
+        let other_app = JwtClaims { azp: Some("other".to_string()), ..test_claims(&nonce) };
+        assert!(matches!(
+            validate_claims(&other_app, &provider, &nonce, 1_500),
+            Err(OidcError::InvalidAuthorizedParty)
+        ));
+
+        let alias = JwtClaims { iss: "accounts.google.com".to_string(), ..test_claims(&nonce) };
+        assert!(validate_claims(&alias, &provider, &nonce, 1_500).is_ok());
+    }
+
+    #[test]
+    fn test_validate_claims_tenant() {
+        let provider = OidcProvider {
+            allowed_tenants: Some(vec!["employer.com".to_string()]),
+            ..test_provider()
+        };
+        let nonce = session_nonce(&[1u8; 32]);
+
+        assert!(matches!(
+            validate_claims(&test_claims(&nonce), &provider, &nonce, 1_500),
+            Err(OidcError::InvalidTenant)
+        ));
+        let workspace = JwtClaims { hd: Some("Employer.com".to_string()), ..test_claims(&nonce) };
+        assert!(validate_claims(&workspace, &provider, &nonce, 1_500).is_ok());
+    }
+
+    #[test]
+    fn test_claims_accept_provider_variants() {
+        // Array audience and Apple's string email_verified
+        let json = r#"{"iss":"https://appleid.apple.com","sub":"000.abc","aud":["app.id"],
+            "exp":2000,"iat":1000,"nonce":"n","email_verified":"true"}"#;
+        let claims: JwtClaims = serde_json::from_str(json).unwrap();
+        assert!(claims.email_verified);
+        assert_eq!(claims.aud.values(), vec!["app.id"]);
+    }
+
+    #[test]
+    fn test_concurrent_jwks_fetches_share_one() {
+        let issuer = "https://login.example";
+        let first = JwksFetch::start(issuer, 10).unwrap();
+        assert!(JwksFetch::start(issuer, 20).is_none());
+        assert!(JwksFetch::start("https://other.example", 20).is_some());
+        drop(first);
+        let second = JwksFetch::start(issuer, 30).unwrap();
+
+        // A fetch lost without dropping its marker stops blocking eventually
+        std::mem::forget(second);
+        assert!(JwksFetch::start(issuer, 40).is_none());
+        assert!(JwksFetch::start(issuer, 30 + JWKS_FETCH_TIMEOUT_NS).is_some());
+    }
+
+    #[test]
+    fn test_transform_jwks_response_drops_headers() {
+        let raw = TransformArgs {
+            response: HttpResponse {
+                status: candid::Nat::from(200u16),
+                headers: vec![
+                    HttpHeader {
+                        name: "Date".to_string(),
+                        value: "Sat, 17 Oct 2026 10:00:00 GMT".to_string(),
+                    },
+                    HttpHeader {
+                        name: "Cache-Control".to_string(),
+                        value: "public, max-age=22911, must-revalidate".to_string(),
+                    },
+                ],
+                body: b"{\"keys\":[]}".to_vec(),
+            },
+            context: vec![],
+        };
+        let response = transform_jwks_response(raw);
+        assert!(response.headers.is_empty());
+        assert_eq!(response.body, b"{\"keys\":[]}".to_vec());
+    }
+}
@@ -0,0 +1,605 @@
+use candid::{CandidType, Principal};
+use ic_cdk::api::{management_canister::main::raw_rand, time};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::{
+    admin::{require_role, AdminRole},
+    kyc, AuthError, State, STATE,
+};
+
+const INVITE_TTL_NS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+const MAX_OPEN_INVITES: usize = 500;
+const MAX_TEXT_LEN: usize = 200;
+const MAX_EMAIL_LEN: usize = 254;
+// AES-GCM nonce and tag around at least one byte
+const MIN_CIPHERTEXT_LEN: usize = 12 + 16 + 1;
+const MAX_CIPHERTEXT_LEN: usize = 1024;
+
+/// A PII field encrypted by the client under the member's vetKD-derived key
+/// (see `kyc::derive_pii_key`): AES-256-GCM, 12-byte nonce then ciphertext.
+/// The canister only ever stores and returns the sealed bytes.
+pub type Ciphertext = Vec<u8>;
+
+#[derive(Error, Debug, Serialize, Deserialize, CandidType, Clone)]
+pub enum ProfileError {
+    #[error("Invalid {field}: {reason}")]
+    InvalidField { field: String, reason: String },
+    #[error("No profile for this account")]
+    ProfileNotFound,
+    #[error("Account role does not allow this")]
+    RoleMismatch,
+    #[error("Caller is not authorized")]
+    Unauthorized,
+    #[error("Unknown or expired invite")]
+    InviteNotFound,
+    #[error("Could not generate an invite token")]
+    TokenGenerationFailed,
+    #[error("Too many open invites")]
+    TooManyInvites,
+    #[error("Employee already works for another employer")]
+    AlreadyEmployed,
+    #[error("Employer still has employees")]
+    HasEmployees,
+    #[error("No such employment")]
+    EmploymentNotFound,
+    #[error(transparent)]
+    Auth(#[from] AuthError),
+}
+
+/// What an account is. Employees and employers pick theirs by creating a
+/// profile; only an owner can make an account an admin.
+#[derive(Serialize, Deserialize, CandidType, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccountRole {
+    Employee,
+    Employer,
+    // Support staff: can look up any profile and employment
+    Admin,
+}
+
+// Name, address and salary are encrypted. Profiles saved while they were
+// plaintext decode with them empty and must be saved again.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct EmployeeProfileData {
+    pub name: Option<Ciphertext>,
+    // Plaintext so employment invites can be matched to it
+    pub email: Option<String>,
+    pub address: Option<Ciphertext>,
+    pub position: Option<String>,
+    pub department: Option<String>,
+    pub role: Option<String>,
+    pub salary: Option<Ciphertext>,
+    pub start_date: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct EmployerProfileData {
+    pub company_name: Option<String>,
+    pub company_id: Option<String>,
+    pub email: Option<String>,
+    pub address: Option<String>,
+    pub industry: Option<String>,
+    pub employee_count: u128,
+    pub registration_date: u64,
+    pub contact_person: Option<String>,
+}
+
+/// Who employs whom, keyed by the employee.
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct Employment {
+    pub employer: Principal,
+    pub since: u64,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct EmploymentInvite {
+    pub employer: Principal,
+    // Normalized to lower case
+    pub email: String,
+    pub created_at: u64,
+    pub expires_at: u64,
+    // SHA-256 of the token handed to the employer; the token is never stored
+    token_hash: Vec<u8>,
+}
+
+/// Returned once to the employer, who sends the token to the invitee.
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct InviteTicket {
+    pub id: u64,
+    pub token: String,
+    pub expires_at: u64,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct InviteSummary {
+    pub id: u64,
+    pub email: String,
+    pub created_at: u64,
+    pub expires_at: u64,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct AccountProfile {
+    pub principal: Principal,
+    pub role: Option<AccountRole>,
+    pub employee: Option<EmployeeProfileData>,
+    pub employer: Option<EmployerProfileData>,
+    pub employment: Option<Employment>,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct EmployeeSummary {
+    pub principal: Principal,
+    pub name: Option<Ciphertext>,
+    pub position: Option<String>,
+    pub department: Option<String>,
+    pub since: u64,
+}
+
+fn invalid(field: &str, reason: &str) -> ProfileError {
+    ProfileError::InvalidField { field: field.to_string(), reason: reason.to_string() }
+}
+
+fn check_text(field: &str, value: &Option<String>, required: bool) -> Result<(), ProfileError> {
+    match value {
+        None if required => Err(invalid(field, "is required")),
+        None => Ok(()),
+        Some(text) if text.trim().is_empty() => Err(invalid(field, "is empty")),
+        Some(text) if text.chars().count() > MAX_TEXT_LEN => Err(invalid(field, "is too long")),
+        Some(text) if text.chars().any(char::is_control) => Err(invalid(field, "has control characters")),
+        Some(_) => Ok(()),
+    }
+}
+
+fn check_ciphertext(field: &str, value: &Option<Ciphertext>, required: bool) -> Result<(), ProfileError> {
+    match value {
+        None if required => Err(invalid(field, "is required")),
+        None => Ok(()),
+        Some(sealed) if sealed.len() < MIN_CIPHERTEXT_LEN => Err(invalid(field, "is not encrypted")),
+        Some(sealed) if sealed.len() > MAX_CIPHERTEXT_LEN => Err(invalid(field, "is too long")),
+        Some(_) => Ok(()),
+    }
+}
+
+/// Lower-cases and sanity checks an address. Deliberately loose: the invite
+/// token, not the address, proves who the invitee is.
+fn normalize_email(email: &str) -> Result<String, ProfileError> {
+    let email = email.trim().to_lowercase();
+    let valid = email.len() <= MAX_EMAIL_LEN
+        && !email.chars().any(|c| c.is_whitespace() || c.is_control())
+        && match email.split_once('@') {
+            Some((local, domain)) => {
+                !local.is_empty()
+                    && !domain.contains('@')
+                    && domain.contains('.')
+                    && !domain.starts_with('.')
+                    && !domain.ends_with('.')
+            }
+            None => false,
+        };
+    if valid {
+        Ok(email)
+    } else {
+        Err(invalid("email", "is not a valid address"))
+    }
+}
+
+fn validate_employee(profile: &mut EmployeeProfileData) -> Result<(), ProfileError> {
+    check_ciphertext("name", &profile.name, true)?;
+    let email = profile.email.as_deref().ok_or_else(|| invalid("email", "is required"))?;
+    profile.email = Some(normalize_email(email)?);
+    check_ciphertext("address", &profile.address, false)?;
+    check_ciphertext("salary", &profile.salary, false)?;
+    check_text("position", &profile.position, false)?;
+    check_text("department", &profile.department, false)?;
+    check_text("role", &profile.role, false)?;
+    if profile.start_date > time() {
+        return Err(invalid("start_date", "is in the future"));
+    }
+    Ok(())
+}
+
+fn validate_employer(profile: &mut EmployerProfileData) -> Result<(), ProfileError> {
+    check_text("company_name", &profile.company_name, true)?;
+    let email = profile.email.as_deref().ok_or_else(|| invalid("email", "is required"))?;
+    profile.email = Some(normalize_email(email)?);
+    check_text("company_id", &profile.company_id, false)?;
+    check_text("address", &profile.address, false)?;
+    check_text("industry", &profile.industry, false)?;
+    check_text("contact_person", &profile.contact_person, false)?;
+    if profile.registration_date > time() {
+        return Err(invalid("registration_date", "is in the future"));
+    }
+    Ok(())
+}
+
+/// Creates or replaces the account's employee profile. Any change sends a
+/// verified or pending member back to unverified.
+pub fn set_employee_profile(account: Principal, mut profile: EmployeeProfileData) -> Result<(), ProfileError> {
+    validate_employee(&mut profile)?;
+    STATE.with(|s| {
+        s.borrow_mut()
+            .users
+            .update(&account, |user| {
+                if user.role.is_some_and(|role| role != AccountRole::Employee) {
+                    return Err(ProfileError::RoleMismatch);
+                }
+                let unchanged = user
+                    .verification
+                    .as_ref()
+                    .is_some_and(|verification| verification.profile_hash == kyc::profile_hash(&profile));
+                if !unchanged {
+                    user.verification = None;
+                }
+                user.role = Some(AccountRole::Employee);
+                user.employee_profile = Some(profile);
+                Ok(())
+            })
+            .unwrap_or(Err(ProfileError::Auth(AuthError::UserNotFound)))
+    })
+}
+
+/// Creates or replaces the account's employer profile.
+pub fn set_employer_profile(account: Principal, mut profile: EmployerProfileData) -> Result<(), ProfileError> {
+    validate_employer(&mut profile)?;
+    STATE.with(|s| {
+        s.borrow_mut()
+            .users
+            .update(&account, |user| {
+                if user.role.is_some_and(|role| role != AccountRole::Employer) {
+                    return Err(ProfileError::RoleMismatch);
+                }
+                user.role = Some(AccountRole::Employer);
+                user.employer_profile = Some(profile);
+                Ok(())
+            })
+            .unwrap_or(Err(ProfileError::Auth(AuthError::UserNotFound)))
+    })
+}
+
+/// Removes the account's profile and role. An employee's employment ends with
+/// it; an employer must let its employees go first.
+pub fn delete_profile(account: Principal) -> Result<(), ProfileError> {
+    STATE.with(|s| {
+        let mut guard = s.borrow_mut();
+        let state = &mut *guard;
+        let mut user = state.users.get(&account).ok_or(AuthError::UserNotFound)?;
+        match user.role {
+            Some(AccountRole::Employee) => {
+                state.employments.remove(&account);
+            }
+            Some(AccountRole::Employer) => {
+                if state.employments.values().any(|employment| employment.employer == account) {
+                    return Err(ProfileError::HasEmployees);
+                }
+                state.employment_invites.retain(|_, invite| invite.employer != account);
+            }
+            // Admin is managed by owners through `set_account_role`
+            Some(AccountRole::Admin) => return Err(ProfileError::RoleMismatch),
+            None => return Err(ProfileError::ProfileNotFound),
+        }
+        user.role = None;
+        user.employee_profile = None;
+        user.employer_profile = None;
+        user.verification = None;
+        state.users.insert(account, user);
+        Ok(())
+    })
+}
+
+fn profile_of(state: &State, account: Principal) -> Result<AccountProfile, ProfileError> {
+    let user = state.users.get(&account).ok_or(AuthError::UserNotFound)?;
+    Ok(AccountProfile {
+        principal: account,
+        role: user.role,
+        employee: user.employee_profile,
+        employer: user.employer_profile,
+        employment: state.employments.get(&account),
+    })
+}
+
+pub fn get_profile(account: Principal) -> Result<AccountProfile, ProfileError> {
+    STATE.with(|s| profile_of(&s.borrow(), account))
+}
+
+/// Another account's profile, visible to admins and to the employee's own
+/// employer.
+pub fn lookup_profile(viewer: Principal, account: Principal) -> Result<AccountProfile, ProfileError> {
+    STATE.with(|s| {
+        let state = s.borrow();
+        let is_admin = state
+            .users
+            .get(&viewer)
+            .is_some_and(|user| user.role == Some(AccountRole::Admin));
+        let is_employer = state
+            .employments
+            .get(&account)
+            .is_some_and(|employment| employment.employer == viewer);
+        if !is_admin && !is_employer {
+            return Err(ProfileError::Unauthorized);
+        }
+        profile_of(&state, account)
+    })
+}
+
+/// Sets or clears an account's role. Owner only; refuses to change the role
+/// of an account that holds a profile of another kind.
+pub fn set_account_role(account: Principal, role: Option<AccountRole>) -> Result<Principal, ProfileError> {
+    let actor = require_role(AdminRole::Owner).map_err(|_| ProfileError::Unauthorized)?;
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        if role != Some(AccountRole::Admin) {
+            state.admin_organisations.remove(&account);
+        }
+        state
+            .users
+            .update(&account, |user| {
+                let held = if user.employee_profile.is_some() {
+                    Some(AccountRole::Employee)
+                } else if user.employer_profile.is_some() {
+                    Some(AccountRole::Employer)
+                } else {
+                    None
+                };
+                if held.is_some() && held != role {
+                    return Err(ProfileError::RoleMismatch);
+                }
+                user.role = role;
+                Ok(actor)
+            })
+            .unwrap_or(Err(ProfileError::Auth(AuthError::UserNotFound)))
+    })
+}
+
+/// Limits which members an admin account may decrypt to `organisation` and
+/// its employees. Owner only; `None` leaves the admin without access to PII.
+pub fn set_admin_organisation(account: Principal, organisation: Option<Principal>) -> Result<Principal, ProfileError> {
+    let actor = require_role(AdminRole::Owner).map_err(|_| ProfileError::Unauthorized)?;
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        let user = state.users.get(&account).ok_or(AuthError::UserNotFound)?;
+        if user.role != Some(AccountRole::Admin) {
+            return Err(ProfileError::RoleMismatch);
+        }
+        match organisation {
+            Some(employer) => {
+                require_employer(&state, employer)?;
+                state.admin_organisations.insert(account, employer);
+            }
+            None => {
+                state.admin_organisations.remove(&account);
+            }
+        }
+        Ok(actor)
+    })
+}
+
+fn require_employer(state: &State, account: Principal) -> Result<(), ProfileError> {
+    let user = state.users.get(&account).ok_or(AuthError::UserNotFound)?;
+    if user.role != Some(AccountRole::Employer) || user.employer_profile.is_none() {
+        return Err(ProfileError::RoleMismatch);
+    }
+    Ok(())
+}
+
+fn hash_token(token: &str) -> Vec<u8> {
+    Sha256::digest(token.as_bytes()).to_vec()
+}
+
+/// Invites `email` to join the employer. The returned token is the only proof
+/// the invitee needs besides a matching profile email, so the employer should
+/// send it to that address only.
+pub async fn invite_employee(employer: Principal, email: String) -> Result<InviteTicket, ProfileError> {
+    let email = normalize_email(&email)?;
+    STATE.with(|s| require_employer(&s.borrow(), employer))?;
+
+    let (random_bytes,) = raw_rand()
+        .await
+        .map_err(|_| ProfileError::TokenGenerationFailed)?;
+    let token = hex::encode(&random_bytes[..16]);
+    let now = time();
+    let expires_at = now + INVITE_TTL_NS;
+
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        // Re-check across the await; the profile may have been deleted
+        require_employer(&state, employer)?;
+        state.employment_invites.retain(|_, invite| invite.expires_at > now);
+        let open = state
+            .employment_invites
+            .values()
+            .filter(|invite| invite.employer == employer)
+            .count();
+        if open >= MAX_OPEN_INVITES {
+            return Err(ProfileError::TooManyInvites);
+        }
+
+        let mut id_bytes = [0u8; 8];
+        id_bytes.copy_from_slice(&random_bytes[16..24]);
+        let mut id = u64::from_le_bytes(id_bytes);
+        while state.employment_invites.contains_key(&id) {
+            id = id.wrapping_add(1);
+        }
+        state.employment_invites.insert(id, EmploymentInvite {
+            employer,
+            email,
+            created_at: now,
+            expires_at,
+            token_hash: hash_token(&token),
+        });
+        Ok(InviteTicket { id, token, expires_at })
+    })
+}
+
+pub fn list_invites(employer: Principal) -> Result<Vec<InviteSummary>, ProfileError> {
+    STATE.with(|s| {
+        let state = s.borrow();
+        require_employer(&state, employer)?;
+        let now = time();
+        Ok(state
+            .employment_invites
+            .iter()
+            .filter(|(_, invite)| invite.employer == employer && invite.expires_at > now)
+            .map(|(id, invite)| InviteSummary {
+                id,
+                email: invite.email,
+                created_at: invite.created_at,
+                expires_at: invite.expires_at,
+            })
+            .collect())
+    })
+}
+
+pub fn revoke_invite(employer: Principal, id: u64) -> Result<(), ProfileError> {
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        match state.employment_invites.get(&id) {
+            Some(invite) if invite.employer == employer => {
+                state.employment_invites.remove(&id);
+                Ok(())
+            }
+            _ => Err(ProfileError::InviteNotFound),
+        }
+    })
+}
+
+/// The invitee accepts with the token from the employer. Their employee
+/// profile email must match the invited address.
+pub fn accept_invite(employee: Principal, id: u64, token: &str) -> Result<Employment, ProfileError> {
+    STATE.with(|s| {
+        let mut guard = s.borrow_mut();
+        let state = &mut *guard;
+        let invite = state.employment_invites.get(&id).ok_or(ProfileError::InviteNotFound)?;
+        if invite.expires_at <= time() || hash_token(token) != invite.token_hash {
+            return Err(ProfileError::InviteNotFound);
+        }
+
+        let user = state.users.get(&employee).ok_or(AuthError::UserNotFound)?;
+        let profile = match (user.role, user.employee_profile) {
+            (Some(AccountRole::Employee), Some(profile)) => profile,
+            _ => return Err(ProfileError::RoleMismatch),
+        };
+        if profile.email.as_deref() != Some(invite.email.as_str()) {
+            return Err(ProfileError::InviteNotFound);
+        }
+        match state.employments.get(&employee) {
+            Some(current) if current.employer != invite.employer => return Err(ProfileError::AlreadyEmployed),
+            _ => {}
+        }
+        require_employer(state, invite.employer)?;
+
+        state.employment_invites.remove(&id);
+        let employment = Employment { employer: invite.employer, since: time() };
+        state.employments.insert(employee, employment.clone());
+        Ok(employment)
+    })
+}
+
+/// Either side may end an employment.
+pub fn end_employment(caller: Principal, employee: Principal) -> Result<(), ProfileError> {
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        let employment = state.employments.get(&employee).ok_or(ProfileError::EmploymentNotFound)?;
+        if caller != employee && caller != employment.employer {
+            return Err(ProfileError::Unauthorized);
+        }
+        state.employments.remove(&employee);
+        Ok(())
+    })
+}
+
+pub fn list_employees(employer: Principal) -> Result<Vec<EmployeeSummary>, ProfileError> {
+    STATE.with(|s| {
+        let state = s.borrow();
+        require_employer(&state, employer)?;
+        Ok(state
+            .employments
+            .iter()
+            .filter(|(_, employment)| employment.employer == employer)
+            .map(|(principal, employment)| {
+                let profile = state.users.get(&principal).and_then(|user| user.employee_profile);
+                EmployeeSummary {
+                    principal,
+                    name: profile.as_ref().and_then(|p| p.name.clone()),
+                    position: profile.as_ref().and_then(|p| p.position.clone()),
+                    department: profile.and_then(|p| p.department),
+                    since: employment.since,
+                }
+            })
+            .collect())
+    })
+}
+
+/// Who employs `employee`, for fund canisters computing contributions and
+/// employer matching. Callers need the `Auditor` admin role.
+pub fn employer_of(employee: Principal) -> Result<Option<Employment>, ProfileError> {
+    require_role(AdminRole::Auditor).map_err(|_| ProfileError::Unauthorized)?;
+    Ok(STATE.with(|s| s.borrow().employments.get(&employee)))
+}
+
+/// Moves employment records from `old` to `new` when an account's principal
+/// changes through recovery or migration.
+pub fn rename_account(state: &mut State, old: Principal, new: Principal) {
+    if let Some(employment) = state.employments.remove(&old) {
+        state.employments.insert(new, employment);
+    }
+    state.employments.update_all(|_, employment| {
+        let moved = employment.employer == old;
+        if moved {
+            employment.employer = new;
+        }
+        moved
+    });
+    state.employment_invites.update_all(|_, invite| {
+        let moved = invite.employer == old;
+        if moved {
+            invite.employer = new;
+        }
+        moved
+    });
+    if let Some(organisation) = state.admin_organisations.remove(&old) {
+        state.admin_organisations.insert(new, organisation);
+    }
+    state.admin_organisations.update_all(|_, organisation| {
+        let moved = *organisation == old;
+        if moved {
+            *organisation = new;
+        }
+        moved
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_email() {
+        assert_eq!(normalize_email(" Ana@Example.COM ").unwrap(), "ana@example.com");
+        assert!(normalize_email("ana@example").is_err());
+        assert!(normalize_email("ana@@example.com").is_err());
+        assert!(normalize_email("@example.com").is_err());
+        assert!(normalize_email("ana smith@example.com").is_err());
+        assert!(normalize_email("ana@example.com.").is_err());
+    }
+
+    #[test]
+    fn test_check_text() {
+        assert!(check_text("name", &None, false).is_ok());
+        assert!(check_text("name", &None, true).is_err());
+        assert!(check_text("name", &Some("  ".to_string()), false).is_err());
+        assert!(check_text("name", &Some("a\nb".to_string()), false).is_err());
+        assert!(check_text("name", &Some("x".repeat(MAX_TEXT_LEN + 1)), false).is_err());
+        assert!(check_text("name", &Some("Ana".to_string()), true).is_ok());
+    }
+
+    #[test]
+    fn test_check_ciphertext_rejects_plaintext_sized_values() {
+        assert!(check_ciphertext("salary", &None, false).is_ok());
+        assert!(check_ciphertext("name", &None, true).is_err());
+        assert!(check_ciphertext("name", &Some(b"Ana".to_vec()), true).is_err());
+        assert!(check_ciphertext("name", &Some(vec![0u8; MIN_CIPHERTEXT_LEN]), true).is_ok());
+        assert!(check_ciphertext("name", &Some(vec![0u8; MAX_CIPHERTEXT_LEN + 1]), true).is_err());
+    }
+}
@@ -0,0 +1,277 @@
+use candid::{CandidType, Principal};
+use ic_cdk::{
+    api::{
+        management_canister::http_request::{
+            http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse,
+            TransformArgs, TransformContext,
+        },
+        time,
+    },
+    query,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{recovery::multi_factor::{RecoveryError, RecoveryMethod}, STATE};
+
+const DELIVERY_CYCLES: u128 = 2_000_000_000;
+const MAX_RESPONSE_BYTES: u64 = 2_000;
+const DEFAULT_MAX_ATTEMPTS: u8 = 3;
+
+/// Outbound provider used to deliver recovery codes. The endpoint receives a
+/// POST with the channel's template rendered as the body; `{{to}}`, `{{code}}`
+/// and `{{channel}}` are substituted as JSON-escaped strings.
+#[derive(Serialize, Deserialize, CandidType, Clone)]
+pub struct DeliveryConfig {
+    pub endpoint: String,
+    // Sent with every request, e.g. the provider's API key
+    pub headers: Vec<(String, String)>,
+    pub email_template: String,
+    pub sms_template: String,
+    pub max_attempts: Option<u8>,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq)]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct DeliveryRecord {
+    pub id: u64,
+    pub method: RecoveryMethod,
+    pub status: DeliveryStatus,
+    pub attempts: u8,
+    pub last_error: Option<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+// The latest delivery per principal is kept in `State::delivery_records`
+#[derive(Serialize, Deserialize, CandidType, Clone, Default)]
+pub struct DeliveryStore {
+    pub config: Option<DeliveryConfig>,
+    pub next_id: u64,
+}
+
+impl DeliveryConfig {
+    /// HTTPS outcalls only reach `https://` endpoints, and the rendered body
+    /// carries recovery codes, so plain HTTP is refused outright.
+    pub fn is_valid(&self) -> bool {
+        self.endpoint
+            .strip_prefix("https://")
+            .is_some_and(|rest| !rest.is_empty() && !rest.starts_with('/'))
+    }
+}
+
+/// Sends a recovery code through the configured provider, retrying up to
+/// `max_attempts` times. The code itself is never written to state.
+pub async fn deliver_recovery_code(
+    principal: Principal,
+    method: &RecoveryMethod,
+    contact: &str,
+    code: &str,
+) -> Result<(), RecoveryError> {
+    if matches!(method, RecoveryMethod::Totp) {
+        return Err(RecoveryError::MethodNotAvailable);
+    }
+
+    let (config, id) = STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        let config = state
+            .delivery
+            .config
+            .clone()
+            .ok_or(RecoveryError::DeliveryNotConfigured)?;
+
+        let id = state.delivery.next_id;
+        state.delivery.next_id += 1;
+        state.delivery_records.insert(
+            principal,
+            DeliveryRecord {
+                id,
+                method: method.clone(),
+                status: DeliveryStatus::Pending,
+                attempts: 0,
+                last_error: None,
+                created_at: time(),
+                updated_at: time(),
+            },
+        );
+        Ok::<_, RecoveryError>((config, id))
+    })?;
+
+    let template = match method {
+        RecoveryMethod::Sms => &config.sms_template,
+        _ => &config.email_template,
+    };
+    let body = render_template(template, contact, code, method);
+    let max_attempts = config.max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS).max(1);
+
+    let mut last_error = String::new();
+    for attempt in 1..=max_attempts {
+        let result = send_request(&config, id, body.clone()).await;
+        let delivered = result.is_ok();
+        if let Err(e) = result {
+            last_error = e;
+        }
+
+        update_record(principal, id, |record| {
+            record.attempts = attempt;
+            record.updated_at = time();
+            if delivered {
+                record.status = DeliveryStatus::Delivered;
+                record.last_error = None;
+            } else {
+                record.last_error = Some(last_error.clone());
+                if attempt == max_attempts {
+                    record.status = DeliveryStatus::Failed;
+                }
+            }
+        });
+
+        if delivered {
+            return Ok(());
+        }
+    }
+
+    ic_cdk::print(format!("Recovery code delivery {} failed: {}", id, last_error));
+    Err(RecoveryError::DeliveryFailed(last_error))
+}
+
+pub fn delivery_status(principal: Principal) -> Option<DeliveryRecord> {
+    STATE.with(|s| s.borrow().delivery_records.get(&principal))
+}
+
+async fn send_request(config: &DeliveryConfig, id: u64, body: String) -> Result<(), String> {
+    let mut headers: Vec<HttpHeader> = config
+        .headers
+        .iter()
+        .map(|(name, value)| HttpHeader {
+            name: name.clone(),
+            value: value.clone(),
+        })
+        .collect();
+    headers.push(HttpHeader {
+        name: "Content-Type".to_string(),
+        value: "application/json".to_string(),
+    });
+    // Every replica sends the request, so the provider must dedupe on this key
+    headers.push(HttpHeader {
+        name: "Idempotency-Key".to_string(),
+        value: format!("{}-{}", ic_cdk::id(), id),
+    });
+
+    let request = CanisterHttpRequestArgument {
+        url: config.endpoint.clone(),
+        method: HttpMethod::POST,
+        body: Some(body.into_bytes()),
+        max_response_bytes: Some(MAX_RESPONSE_BYTES),
+        transform: Some(TransformContext::from_name(
+            "transform_delivery_response".to_string(),
+            vec![],
+        )),
+        headers,
+    };
+
+    let (response,): (HttpResponse,) = http_request(request, DELIVERY_CYCLES)
+        .await
+        .map_err(|e| format!("HTTP request failed: {:?}", e))?;
+
+    if response.status >= 200u16 && response.status < 300u16 {
+        Ok(())
+    } else {
+        Err(format!("HTTP status: {}", response.status))
+    }
+}
+
+fn update_record(principal: Principal, id: u64, f: impl FnOnce(&mut DeliveryRecord)) {
+    STATE.with(|s| {
+        s.borrow_mut().delivery_records.update(&principal, |record| {
+            // A newer delivery may have replaced this one
+            if record.id == id {
+                f(record);
+            }
+        });
+    });
+}
+
+fn render_template(template: &str, to: &str, code: &str, method: &RecoveryMethod) -> String {
+    let channel = match method {
+        RecoveryMethod::Email => "email",
+        RecoveryMethod::Sms => "sms",
+        RecoveryMethod::Totp => "totp",
+    };
+    template
+        .replace("{{to}}", &json_escape(to))
+        .replace("{{code}}", &json_escape(code))
+        .replace("{{channel}}", channel)
+}
+
+fn json_escape(value: &str) -> String {
+    let quoted = serde_json::to_string(value).unwrap_or_default();
+    quoted.trim_matches('"').to_string()
+}
+
+/// Providers echo request ids, dates and rate-limit headers that differ per
+/// replica; only the status code is kept so the responses reach consensus.
+#[query]
+fn transform_delivery_response(raw: TransformArgs) -> HttpResponse {
+    HttpResponse {
+        status: raw.response.status,
+        headers: vec![],
+        body: vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_template_escapes_values() {
+        let template = r#"{"to":"{{to}}","text":"Your code is {{code}}","via":"{{channel}}"}"#;
+        let body = render_template(template, "a\"b@example.com", "012345", &RecoveryMethod::Email);
+        assert_eq!(
+            body,
+            r#"{"to":"a\"b@example.com","text":"Your code is 012345","via":"email"}"#
+        );
+        assert!(serde_json::from_str::<serde_json::Value>(&body).is_ok());
+    }
+
+    #[test]
+    fn test_config_requires_https_endpoint() {
+        let config = |endpoint: &str| DeliveryConfig {
+            endpoint: endpoint.to_string(),
+            headers: vec![],
+            email_template: String::new(),
+            sms_template: String::new(),
+            max_attempts: None,
+        };
+        assert!(config("https://api.mailer.example/send").is_valid());
+        assert!(!config("http://localhost:8025/send").is_valid());
+        assert!(!config("http://api.mailer.example/send").is_valid());
+        assert!(!config("https://").is_valid());
+        assert!(!config("mailer.example/send").is_valid());
+    }
+
+    #[test]
+    fn test_transform_strips_headers_and_body() {
+        let raw = TransformArgs {
+            response: HttpResponse {
+                status: candid::Nat::from(202u16),
+                headers: vec![HttpHeader {
+                    name: "Date".to_string(),
+                    value: "Sat, 17 Oct 2026 10:00:00 GMT".to_string(),
+                }],
+                body: b"{\"id\":\"msg_123\"}".to_vec(),
+            },
+            context: vec![],
+        };
+        let response = transform_delivery_response(raw);
+        assert_eq!(response.status, candid::Nat::from(202u16));
+        assert!(response.headers.is_empty());
+        assert!(response.body.is_empty());
+    }
+}
@@ -2,9 +2,10 @@ use candid::{CandidType, Principal};
 use ic_cdk::api::{management_canister::main::raw_rand, time};
 use rand::{RngCore, SeedableRng, rngs::StdRng};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use thiserror::Error;
 
-use crate::{recovery::social::execute_recovery, AuthError, STATE};
+use crate::{recovery::delivery::deliver_recovery_code, recovery::social::{execute_recovery, RecoveryReceipt}, recovery::totp, AuthError, STATE};
 
 #[derive(Debug, Error, Serialize, Deserialize, CandidType)]
 pub enum RecoveryError {
@@ -24,6 +25,24 @@ pub enum RecoveryError {
     Unauthorized,
     #[error("Threshold is invalid")]
     InvalidThreshold,
+    #[error("Recovery request has expired")]
+    RecoveryExpired,
+    #[error("Account not found")]
+    AccountNotFound,
+    #[error("Principal is already bound to an account")]
+    PrincipalInUse,
+    #[error("A recovery is already scheduled")]
+    RecoveryScheduled,
+    #[error("A recovery is in progress")]
+    RecoveryPending,
+    #[error("Invalid guardian")]
+    InvalidGuardian,
+    #[error("Recovery code delivery is not configured")]
+    DeliveryNotConfigured,
+    #[error("Recovery code delivery failed: {0}")]
+    DeliveryFailed(String),
+    #[error("Too many failed attempts, locked until {0}")]
+    LockedOut(u64),
 }
 
 impl From<AuthError> for RecoveryError {
@@ -34,28 +53,100 @@ impl From<AuthError> for RecoveryError {
             AuthError::InvalidToken => RecoveryError::InvalidConfig,
             AuthError::PrincipalError => RecoveryError::NotSetup,
             AuthError::SessionExpired => RecoveryError::CodeExpired,
+            AuthError::InvalidProof => RecoveryError::Unauthorized,
+            AuthError::IdentityInUse | AuthError::LinkPending => RecoveryError::PrincipalInUse,
+            AuthError::LinkNotFound | AuthError::LastAuthenticator => RecoveryError::MethodNotAvailable,
         }
     }
 }
 
+const CODE_TTL_NS: u64 = 15 * 60 * 1_000_000_000; // 15 minutes
+const MAX_CODE_ATTEMPTS: u8 = 5;
+const BASE_LOCKOUT_NS: u64 = 15 * 60 * 1_000_000_000; // 15 minutes
+const MAX_LOCKOUT_NS: u64 = 7 * 24 * 60 * 60 * 1_000_000_000; // 7 days
+
 #[derive(Serialize, Deserialize, CandidType, Clone)]
 pub struct MultiFactorRecovery {
     email: Option<String>,
     phone: Option<String>,
     pending_recovery: Option<PendingMfaRecovery>,
+    // Wrong guesses since the last success or lockout, across re-issued codes
+    failed_attempts: u8,
+    // Number of times the attempt limit was hit, drives the backoff
+    lockouts: u32,
+    locked_until: Option<u64>,
+    totp: Option<TotpEnrollment>,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone)]
+struct TotpEnrollment {
+    secret: Vec<u8>,
+    // Set once the user proved their authenticator produces valid codes
+    confirmed: bool,
+    // Last accepted time step, codes at or before it are rejected
+    last_step: Option<u64>,
+}
+
+/// Returned once at enrollment so the user can add the secret to an authenticator.
+#[derive(Serialize, Deserialize, CandidType, Clone)]
+pub struct TotpProvisioning {
+    pub secret: String,
+    pub provisioning_uri: String,
 }
 
 #[derive(Serialize, Deserialize, CandidType, Clone)]
 struct PendingMfaRecovery {
     method: RecoveryMethod,
-    code: String,
+    // SHA-256(salt || code), the plaintext code is never stored
+    code_hash: Vec<u8>,
+    salt: Vec<u8>,
     expires_at: u64,
 }
 
-#[derive(Serialize, Deserialize, Clone, CandidType)]
+impl MultiFactorRecovery {
+    /// Contact details carried over from the pre-v1 layout. Codes that were
+    /// in flight during the upgrade have to be requested again.
+    pub(crate) fn from_legacy(email: Option<String>, phone: Option<String>) -> Self {
+        MultiFactorRecovery {
+            email,
+            phone,
+            pending_recovery: None,
+            failed_attempts: 0,
+            lockouts: 0,
+            locked_until: None,
+            totp: None,
+        }
+    }
+
+    pub(crate) fn has_pending_recovery(&self) -> bool {
+        self.pending_recovery.is_some()
+    }
+
+    fn check_lockout(&self) -> Result<(), RecoveryError> {
+        match self.locked_until {
+            Some(until) if until > time() => Err(RecoveryError::LockedOut(until)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Drops the pending code and locks the account, doubling the lockout
+    /// each time the limit is hit.
+    fn lock_out(&mut self) -> u64 {
+        let factor = 1u64 << self.lockouts.min(16);
+        let until = time() + BASE_LOCKOUT_NS.saturating_mul(factor).min(MAX_LOCKOUT_NS);
+        self.lockouts += 1;
+        self.failed_attempts = 0;
+        self.locked_until = Some(until);
+        self.pending_recovery = None;
+        until
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, CandidType, Debug)]
 pub enum RecoveryMethod {
     Email,
     Sms,
+    Totp,
 }
 
 /// Get an RNG seeded from IC randomness
@@ -73,68 +164,169 @@ pub fn setup_multi_factor_recovery(
     email: Option<String>,
     phone: Option<String>,
 ) -> Result<(), RecoveryError> {
-    if email.is_none() && phone.is_none() {
-        return Err(RecoveryError::InvalidConfig);
-    }
-
     STATE.with(|s| {
         let mut state = s.borrow_mut();
+        // Changing contacts must not reset an active lockout or the authenticator
+        let (failed_attempts, lockouts, locked_until, totp) = state
+            .recovery
+            .multi_factor
+            .get(&principal)
+            .map(|r| (r.failed_attempts, r.lockouts, r.locked_until, r.totp))
+            .unwrap_or_default();
+
+        if email.is_none() && phone.is_none() && !totp.as_ref().is_some_and(|t| t.confirmed) {
+            return Err(RecoveryError::InvalidConfig);
+        }
+
         state.recovery.multi_factor.insert(
             principal,
             MultiFactorRecovery {
                 email,
                 phone,
                 pending_recovery: None,
+                failed_attempts,
+                lockouts,
+                locked_until,
+                totp,
             },
         );
         Ok(())
     })
 }
 
+/// Generates a fresh authenticator secret. It stays inactive until
+/// `confirm_totp` sees a valid code from it.
+pub async fn enroll_totp(principal: Principal) -> Result<TotpProvisioning, RecoveryError> {
+    let mut secret = vec![0u8; 20];
+    ic_rng().await.fill_bytes(&mut secret);
+
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        let mut recovery = state
+            .recovery
+            .multi_factor
+            .get(&principal)
+            .unwrap_or(MultiFactorRecovery {
+                email: None,
+                phone: None,
+                pending_recovery: None,
+                failed_attempts: 0,
+                lockouts: 0,
+                locked_until: None,
+                totp: None,
+            });
+        recovery.check_lockout()?;
+
+        recovery.totp = Some(TotpEnrollment {
+            secret: secret.clone(),
+            confirmed: false,
+            last_step: None,
+        });
+        state.recovery.multi_factor.insert(principal, recovery);
+        Ok::<(), RecoveryError>(())
+    })?;
+
+    Ok(TotpProvisioning {
+        secret: totp::base32_encode(&secret),
+        provisioning_uri: totp::provisioning_uri(&principal.to_text(), &secret),
+    })
+}
+
+pub fn confirm_totp(principal: Principal, code: String) -> Result<(), RecoveryError> {
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        state
+            .recovery
+            .multi_factor
+            .update(&principal, |recovery| {
+                let enrollment = recovery.totp.as_mut().ok_or(RecoveryError::MethodNotAvailable)?;
+                let step = totp::verify(&enrollment.secret, &code, time() / 1_000_000_000, enrollment.last_step)
+                    .ok_or(RecoveryError::InvalidCode)?;
+                enrollment.confirmed = true;
+                enrollment.last_step = Some(step);
+                Ok(())
+            })
+            .unwrap_or(Err(RecoveryError::MethodNotAvailable))
+    })
+}
+
 pub async fn initiate_mfa_recovery(
     principal: Principal,
     method: RecoveryMethod,
 ) -> Result<(), RecoveryError> {
     // 1) Read and clone the contact info WITHOUT holding the borrow across an await
-    let contact: String = STATE.with(|s| {
-        let state = s.borrow();
-        let recovery = state
+    let contact: Option<String> = STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        state
             .recovery
             .multi_factor
-            .get(&principal)
-            .ok_or(RecoveryError::NotSetup)?;
+            .update(&principal, |recovery| {
+                recovery.check_lockout()?;
 
-        let contact_opt = match &method {
-            RecoveryMethod::Email => recovery.email.as_ref(),
-            RecoveryMethod::Sms => recovery.phone.as_ref(),
-        };
+                let contact_opt = match &method {
+                    RecoveryMethod::Email => recovery.email.as_ref(),
+                    RecoveryMethod::Sms => recovery.phone.as_ref(),
+                    RecoveryMethod::Totp => {
+                        // Nothing to deliver, the authenticator app produces the code
+                        if !recovery.totp.as_ref().is_some_and(|t| t.confirmed) {
+                            return Err(RecoveryError::MethodNotAvailable);
+                        }
+                        recovery.pending_recovery = Some(PendingMfaRecovery {
+                            method: RecoveryMethod::Totp,
+                            code_hash: vec![],
+                            salt: vec![],
+                            expires_at: time() + CODE_TTL_NS,
+                        });
+                        return Ok(None);
+                    }
+                };
 
-        contact_opt
-            .cloned()
-            .ok_or(RecoveryError::MethodNotAvailable)
+                contact_opt
+                    .cloned()
+                    .map(Some)
+                    .ok_or(RecoveryError::MethodNotAvailable)
+            })
+            .unwrap_or(Err(RecoveryError::NotSetup))
     })?;
 
-    // 2) Generate the code (async) with no STATE borrow in scope
-    let code = generate_recovery_code().await;
-    let expires_at = time() + 15 * 60 * 1_000_000_000; // 15 minutes
+    let Some(contact) = contact else {
+        return Ok(());
+    };
+
+    // 2) Generate the code and salt (async) with no STATE borrow in scope
+    let mut rng = ic_rng().await;
+    let code = generate_recovery_code(&mut rng);
+    let mut salt = vec![0u8; 16];
+    rng.fill_bytes(&mut salt);
+    let code_hash = hash_code(&salt, &code);
+    let expires_at = time() + CODE_TTL_NS;
 
     // 3) Write back the pending recovery (short, scoped borrow)
     STATE.with(|s| {
         let mut state = s.borrow_mut();
-        if let Some(rec) = state.recovery.multi_factor.get_mut(&principal) {
-            rec.pending_recovery = Some(PendingMfaRecovery {
-                method: method.clone(),
-                code: code.clone(),
-                expires_at,
-            });
-            Ok(())
-        } else {
-            Err(RecoveryError::NotSetup)
-        }
+        state
+            .recovery
+            .multi_factor
+            .update(&principal, |rec| {
+                rec.pending_recovery = Some(PendingMfaRecovery {
+                    method: method.clone(),
+                    code_hash,
+                    salt,
+                    expires_at,
+                });
+            })
+            .ok_or(RecoveryError::NotSetup)
     })?;
 
-    // 4) Send the code using owned String (no lifetime headaches)
-    send_recovery_code(&contact, &code);
+    // 4) Deliver the code; a code that never arrived must not stay valid
+    if let Err(e) = deliver_recovery_code(principal, &method, &contact, &code).await {
+        STATE.with(|s| {
+            s.borrow_mut().recovery.multi_factor.update(&principal, |rec| {
+                rec.pending_recovery = None;
+            });
+        });
+        return Err(e);
+    }
 
     Ok(())
 }
@@ -142,33 +334,64 @@ pub async fn initiate_mfa_recovery(
 pub fn complete_mfa_recovery(
     principal: Principal,
     code: String,
-) -> Result<(), RecoveryError> {
+    new_principal: Principal,
+) -> Result<RecoveryReceipt, RecoveryError> {
     STATE.with(|s| {
         let mut state = s.borrow_mut();
-        if let Some(recovery) = state.recovery.multi_factor.get_mut(&principal) {
+        let outcome = state.recovery.multi_factor.update(&principal, |recovery| {
+            recovery.check_lockout()?;
+
             if let Some(pending) = &recovery.pending_recovery {
                 if pending.expires_at < time() {
+                    recovery.pending_recovery = None;
                     return Err(RecoveryError::CodeExpired);
                 }
 
-                if pending.code == code {
-                    execute_recovery(principal);
+                let matched = match pending.method {
+                    RecoveryMethod::Totp => {
+                        let enrollment = recovery
+                            .totp
+                            .as_mut()
+                            .ok_or(RecoveryError::MethodNotAvailable)?;
+                        let step = totp::verify(
+                            &enrollment.secret,
+                            &code,
+                            time() / 1_000_000_000,
+                            enrollment.last_step,
+                        );
+                        if step.is_some() {
+                            enrollment.last_step = step;
+                        }
+                        step.is_some()
+                    }
+                    _ => constant_time_eq(&hash_code(&pending.salt, &code), &pending.code_hash),
+                };
+
+                if matched {
                     recovery.pending_recovery = None;
+                    recovery.failed_attempts = 0;
+                    recovery.lockouts = 0;
+                    recovery.locked_until = None;
                     Ok(())
                 } else {
+                    recovery.failed_attempts += 1;
+                    if recovery.failed_attempts >= MAX_CODE_ATTEMPTS {
+                        let until = recovery.lock_out();
+                        return Err(RecoveryError::LockedOut(until));
+                    }
                     Err(RecoveryError::InvalidCode)
                 }
             } else {
                 Err(RecoveryError::NoPendingRecovery)
             }
-        } else {
-            Err(RecoveryError::NotSetup)
-        }
-    })
+        });
+        outcome.unwrap_or(Err(RecoveryError::NotSetup))
+    })?;
+
+    execute_recovery(principal, new_principal)
 }
 
-async fn generate_recovery_code() -> String {
-    let mut rng = ic_rng().await;
+fn generate_recovery_code(rng: &mut StdRng) -> String {
     let mut bytes = [0u8; 3];
     rng.fill_bytes(&mut bytes);
     // 6-digit zero-padded numeric code
@@ -178,7 +401,36 @@ async fn generate_recovery_code() -> String {
     )
 }
 
-fn send_recovery_code(contact: &str, code: &str) {
-    // email/SMS service integration
-    println!("Sending recovery code '{}' to '{}'", code, contact);
+fn hash_code(salt: &[u8], code: &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(code.as_bytes());
+    hasher.finalize().to_vec()
+}
+
+/// Compares without short-circuiting so timing doesn't leak the match length.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_code_hash_is_salted() {
+        let code = "123456";
+        assert_eq!(hash_code(b"salt-a", code), hash_code(b"salt-a", code));
+        assert_ne!(hash_code(b"salt-a", code), hash_code(b"salt-b", code));
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"ab"));
+    }
 }
@@ -1,41 +1,220 @@
-use std::collections::HashSet;
+use std::{collections::HashSet, time::Duration};
 
 use candid::{CandidType, Principal};
 use ic_cdk::api::time;
 use serde::{Deserialize, Serialize};
 
-use crate::{recovery::multi_factor::RecoveryError, STATE};
+use crate::{identity_links::{Authenticator, LinkedAuthenticator}, profiles, recovery::multi_factor::RecoveryError, shadow_principal::PrincipalOrigin, storage::StableMap, State, STATE};
+
+const NS_PER_SEC: u64 = 1_000_000_000;
+pub const DEFAULT_RECOVERY_TIMELOCK_SECS: u64 = 48 * 60 * 60; // 48 hours
+pub const MIN_RECOVERY_TIMELOCK_SECS: u64 = 24 * 60 * 60; // 24 hours
 
 #[derive(Serialize, Deserialize, CandidType, Clone)]
 pub struct SocialRecovery {
     contacts: Vec<Principal>,
     threshold: u8,
+    // Delay between reaching the threshold and executing the recovery
+    timelock_secs: u64,
     pending_recovery: Option<PendingRecovery>,
 }
 
 #[derive(Serialize, Deserialize, CandidType, Clone)]
 struct PendingRecovery {
     initiator: Principal,
-    approvals: HashSet<Principal>,
+    // Principal that takes over the account once the recovery executes
+    new_principal: Principal,
+    approvals: Vec<GuardianApproval>,
     expires_at: u64,
+    // Set once the threshold is met; the owner can veto until then
+    executes_at: Option<u64>,
+    // Set when the scheduled execution failed; a new recovery may be initiated
+    failed_at: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct GuardianApproval {
+    pub guardian: Principal,
+    pub approved_at: u64,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub enum RecoveryEvent {
+    Initiated { new_principal: Principal },
+    Approved,
+    Scheduled { executes_at: u64 },
+    Cancelled,
+    Expired,
+    Executed { new_principal: Principal },
+    Failed { reason: String },
+}
+
+/// One entry of an account's recovery audit trail.
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct RecoveryAuditEntry {
+    pub event: RecoveryEvent,
+    pub actor: Principal,
+    pub at: u64,
+}
+
+/// Read-only view of an account's recovery setup, for owners and guardians.
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct RecoveryStatus {
+    pub guardians: Vec<Principal>,
+    pub threshold: u8,
+    pub timelock_secs: u64,
+    pub pending: Option<PendingRecoveryStatus>,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct PendingRecoveryStatus {
+    pub initiator: Principal,
+    pub new_principal: Principal,
+    pub approvals: Vec<GuardianApproval>,
+    pub expires_at: u64,
+    pub executes_at: Option<u64>,
+    pub failed_at: Option<u64>,
+}
+
+/// Proof that an account was moved to a new controlling principal.
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct RecoveryReceipt {
+    pub old_principal: Principal,
+    pub new_principal: Principal,
+    // Sign-in methods that now lead to the new principal
+    pub authenticators: Vec<Authenticator>,
+    pub revoked_sessions: u32,
+    pub executed_at: u64,
+}
+
+impl SocialRecovery {
+    /// Guardians carried over from the pre-v1 layout, with the default
+    /// timelock. Pending requests there named no new principal, so they are
+    /// dropped and have to be initiated again.
+    pub(crate) fn from_legacy(contacts: Vec<Principal>, threshold: u8) -> Self {
+        SocialRecovery {
+            contacts,
+            threshold,
+            timelock_secs: DEFAULT_RECOVERY_TIMELOCK_SECS,
+            pending_recovery: None,
+        }
+    }
+
+    /// Follows a guardian whose principal was migrated to a new derivation.
+    /// Returns whether anything changed.
+    pub(crate) fn rename_guardian(&mut self, old: Principal, new: Principal) -> bool {
+        let mut changed = false;
+        for contact in self.contacts.iter_mut().filter(|contact| **contact == old) {
+            *contact = new;
+            changed = true;
+        }
+        if let Some(pending) = self.pending_recovery.as_mut() {
+            if pending.initiator == old {
+                pending.initiator = new;
+                changed = true;
+            }
+            for approval in pending.approvals.iter_mut().filter(|approval| approval.guardian == old) {
+                approval.guardian = new;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Whether a recovery is in flight. Expired, unscheduled requests and
+    /// failed executions don't count.
+    pub(crate) fn has_active_recovery(&self) -> bool {
+        self.pending_recovery
+            .as_ref()
+            .is_some_and(|p| p.failed_at.is_none() && (p.executes_at.is_some() || p.expires_at >= time()))
+    }
+
+    fn status(&self) -> RecoveryStatus {
+        RecoveryStatus {
+            guardians: self.contacts.clone(),
+            threshold: self.threshold,
+            timelock_secs: self.timelock_secs,
+            pending: self
+                .pending_recovery
+                .as_ref()
+                .filter(|p| p.failed_at.is_some() || self.has_active_recovery())
+                .map(|p| PendingRecoveryStatus {
+                    initiator: p.initiator,
+                    new_principal: p.new_principal,
+                    approvals: p.approvals.clone(),
+                    expires_at: p.expires_at,
+                    executes_at: p.executes_at,
+                    failed_at: p.failed_at,
+                }),
+        }
+    }
+}
+
+fn log_event(
+    audit: &mut StableMap<Principal, Vec<RecoveryAuditEntry>>,
+    target: Principal,
+    actor: Principal,
+    event: RecoveryEvent,
+) {
+    let mut entries = audit.get(&target).unwrap_or_default();
+    entries.push(RecoveryAuditEntry {
+        event,
+        actor,
+        at: time(),
+    });
+    audit.insert(target, entries);
+}
+
+fn validate_guardians(
+    owner: Principal,
+    contacts: &[Principal],
+    threshold: u8,
+) -> Result<(), RecoveryError> {
+    if threshold as usize > contacts.len() || threshold == 0 {
+        return Err(RecoveryError::InvalidThreshold);
+    }
+
+    let unique: HashSet<&Principal> = contacts.iter().collect();
+    if unique.len() != contacts.len()
+        || contacts
+            .iter()
+            .any(|c| *c == owner || *c == Principal::anonymous())
+    {
+        return Err(RecoveryError::InvalidGuardian);
+    }
+    Ok(())
 }
 
 pub fn setup_social_recovery(
     principal: Principal,
     contacts: Vec<Principal>,
     threshold: u8,
+    timelock_secs: Option<u64>,
 ) -> Result<(), RecoveryError> {
-    if threshold as usize > contacts.len() || threshold == 0 {
-        return Err(RecoveryError::InvalidThreshold);
+    validate_guardians(principal, &contacts, threshold)?;
+
+    let timelock_secs = timelock_secs.unwrap_or(DEFAULT_RECOVERY_TIMELOCK_SECS);
+    if timelock_secs < MIN_RECOVERY_TIMELOCK_SECS {
+        return Err(RecoveryError::InvalidConfig);
     }
     
     STATE.with(|s| {
         let mut state = s.borrow_mut();
+        if state
+            .recovery
+            .social
+            .get(&principal)
+            .is_some_and(|r| r.has_active_recovery())
+        {
+            return Err(RecoveryError::RecoveryPending);
+        }
+
         state.recovery.social.insert(
             principal,
             SocialRecovery {
                 contacts,
                 threshold,
+                timelock_secs,
                 pending_recovery: None,
             }
         );
@@ -43,59 +222,574 @@ pub fn setup_social_recovery(
     })
 }
 
-pub fn initiate_recovery(
+/// Applies a guardian change to an idle recovery setup, re-validating the
+/// resulting guardian set before it is stored.
+fn update_guardians<F>(principal: Principal, change: F) -> Result<(), RecoveryError>
+where
+    F: FnOnce(&mut Vec<Principal>, &mut u8) -> Result<(), RecoveryError>,
+{
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        let mut recovery = state
+            .recovery
+            .social
+            .get(&principal)
+            .ok_or(RecoveryError::NotSetup)?;
+
+        if recovery.has_active_recovery() {
+            return Err(RecoveryError::RecoveryPending);
+        }
+
+        let mut contacts = recovery.contacts.clone();
+        let mut threshold = recovery.threshold;
+        change(&mut contacts, &mut threshold)?;
+        validate_guardians(principal, &contacts, threshold)?;
+
+        recovery.contacts = contacts;
+        recovery.threshold = threshold;
+        recovery.pending_recovery = None;
+        state.recovery.social.insert(principal, recovery);
+        Ok(())
+    })
+}
+
+pub fn add_guardian(
     principal: Principal,
+    guardian: Principal,
+    new_threshold: Option<u8>,
 ) -> Result<(), RecoveryError> {
+    update_guardians(principal, |contacts, threshold| {
+        if contacts.contains(&guardian) {
+            return Err(RecoveryError::InvalidGuardian);
+        }
+        contacts.push(guardian);
+        if let Some(t) = new_threshold {
+            *threshold = t;
+        }
+        Ok(())
+    })
+}
+
+pub fn remove_guardian(
+    principal: Principal,
+    guardian: Principal,
+    new_threshold: Option<u8>,
+) -> Result<(), RecoveryError> {
+    update_guardians(principal, |contacts, threshold| {
+        let index = contacts
+            .iter()
+            .position(|c| *c == guardian)
+            .ok_or(RecoveryError::InvalidGuardian)?;
+        contacts.remove(index);
+        if let Some(t) = new_threshold {
+            *threshold = t;
+        }
+        Ok(())
+    })
+}
+
+/// Swaps one guardian for another, keeping the threshold.
+pub fn replace_guardian(
+    principal: Principal,
+    old_guardian: Principal,
+    new_guardian: Principal,
+) -> Result<(), RecoveryError> {
+    update_guardians(principal, |contacts, _| {
+        let index = contacts
+            .iter()
+            .position(|c| *c == old_guardian)
+            .ok_or(RecoveryError::InvalidGuardian)?;
+        contacts[index] = new_guardian;
+        Ok(())
+    })
+}
+
+/// Status of `target`'s recovery, visible to the owner and its guardians.
+pub fn get_recovery_status(
+    caller: Principal,
+    target: Principal,
+) -> Result<RecoveryStatus, RecoveryError> {
     STATE.with(|s| {
-        let mut state = s.borrow_mut();
-        if let Some(recovery) = state.recovery.social.get_mut(&principal) {
-            recovery.pending_recovery = Some(PendingRecovery {
-                initiator: ic_cdk::caller(),
-                approvals: HashSet::new(),
-                expires_at: time() + 48 * 60 * 60 * 1_000_000_000, // 48 hours
-            });
-            Ok(())
-        } else {
-            Err(RecoveryError::NotSetup)
+        let state = s.borrow();
+        let recovery = state
+            .recovery
+            .social
+            .get(&target)
+            .ok_or(RecoveryError::NotSetup)?;
+
+        if caller != target && !recovery.contacts.contains(&caller) {
+            return Err(RecoveryError::Unauthorized);
         }
+        Ok(recovery.status())
     })
 }
 
-pub fn approve_recovery(
-    approver: Principal,
+/// Audit trail of `target`'s recoveries, visible to the owner and its guardians.
+pub fn get_recovery_audit(
+    caller: Principal,
+    target: Principal,
+) -> Result<Vec<RecoveryAuditEntry>, RecoveryError> {
+    get_recovery_status(caller, target)?;
+    STATE.with(|s| {
+        Ok(s.borrow()
+            .recovery
+            .audit
+            .get(&target)
+            .unwrap_or_default())
+    })
+}
+
+/// Accounts that list `guardian` as a recovery contact.
+pub fn list_protected_accounts(guardian: Principal) -> Vec<Principal> {
+    STATE.with(|s| {
+        s.borrow()
+            .recovery
+            .social
+            .iter()
+            .filter(|(_, recovery)| recovery.contacts.contains(&guardian))
+            .map(|(principal, _)| principal)
+            .collect()
+    })
+}
+
+/// Starts a recovery of `target` towards `new_principal`. Only the owner or
+/// one of their guardians may initiate; anyone else could otherwise block the
+/// account's real recovery by keeping a bogus one pending.
+pub fn initiate_recovery(
+    initiator: Principal,
     target: Principal,
+    new_principal: Principal,
 ) -> Result<(), RecoveryError> {
     STATE.with(|s| {
-        let mut state = s.borrow_mut();
-        if let Some(recovery) = state.recovery.social.get_mut(&target) {
-            if let Some(pending) = &mut recovery.pending_recovery {
-                // Check if approver is a contact
-                if !recovery.contacts.contains(&approver) {
-                    return Err(RecoveryError::Unauthorized);
-                }
-                
-                pending.approvals.insert(approver);
-                
-                // Check if threshold met
-                if pending.approvals.len() >= recovery.threshold as usize {
-                    execute_recovery(target);
-                    recovery.pending_recovery = None;
-                }
-                
-                Ok(())
+        let mut guard = s.borrow_mut();
+        let state = &mut *guard;
+        if new_principal == Principal::anonymous() || state.users.contains_key(&new_principal) {
+            return Err(RecoveryError::PrincipalInUse);
+        }
+        let mut recovery = state
+            .recovery
+            .social
+            .get(&target)
+            .ok_or(RecoveryError::NotSetup)?;
+
+        if initiator != target && !recovery.contacts.contains(&initiator) {
+            return Err(RecoveryError::Unauthorized);
+        }
+        if recovery.pending_recovery.as_ref().is_some_and(|p| p.executes_at.is_some() && p.failed_at.is_none()) {
+            return Err(RecoveryError::RecoveryScheduled);
+        }
+        if recovery.has_active_recovery() {
+            return Err(RecoveryError::RecoveryPending);
+        }
+
+        recovery.pending_recovery = Some(PendingRecovery {
+            initiator,
+            new_principal,
+            approvals: Vec::new(),
+            expires_at: time() + 48 * 60 * 60 * NS_PER_SEC, // 48 hours
+            executes_at: None,
+            failed_at: None,
+        });
+        state.recovery.social.insert(target, recovery);
+        log_event(
+            &mut state.recovery.audit,
+            target,
+            initiator,
+            RecoveryEvent::Initiated { new_principal },
+        );
+        Ok(())
+    })
+}
+
+/// Records a guardian approval. Once the threshold is reached the recovery is
+/// scheduled after the account's timelock and the execution time is returned.
+pub fn approve_recovery(
+    approver: Principal,
+    target: Principal,
+) -> Result<Option<u64>, RecoveryError> {
+    let scheduled = STATE.with(|s| {
+        let mut guard = s.borrow_mut();
+        let state = &mut *guard;
+        let audit = &mut state.recovery.audit;
+        let outcome = state.recovery.social.update(&target, |recovery| {
+            // Check if approver is a contact
+            if !recovery.contacts.contains(&approver) {
+                return Err(RecoveryError::Unauthorized);
+            }
+
+            let pending = recovery
+                .pending_recovery
+                .as_mut()
+                .filter(|pending| pending.failed_at.is_none())
+                .ok_or(RecoveryError::NoPendingRecovery)?;
+
+            // Already scheduled, further approvals change nothing
+            if let Some(executes_at) = pending.executes_at {
+                return Ok(Some(executes_at));
+            }
+
+            if pending.expires_at < time() {
+                recovery.pending_recovery = None;
+                log_event(audit, target, approver, RecoveryEvent::Expired);
+                return Err(RecoveryError::RecoveryExpired);
+            }
+
+            if !pending.approvals.iter().any(|a| a.guardian == approver) {
+                pending.approvals.push(GuardianApproval {
+                    guardian: approver,
+                    approved_at: time(),
+                });
+                log_event(audit, target, approver, RecoveryEvent::Approved);
+            }
+
+            // Check if threshold met
+            if pending.approvals.len() >= recovery.threshold as usize {
+                let executes_at = time() + recovery.timelock_secs * NS_PER_SEC;
+                pending.executes_at = Some(executes_at);
+                log_event(
+                    audit,
+                    target,
+                    approver,
+                    RecoveryEvent::Scheduled { executes_at },
+                );
+                Ok(Some(executes_at))
             } else {
-                Err(RecoveryError::NoPendingRecovery)
+                Ok(None)
             }
-        } else {
-            Err(RecoveryError::NotSetup)
+        });
+        outcome.unwrap_or(Err(RecoveryError::NotSetup))
+    })?;
+
+    if let Some(executes_at) = scheduled {
+        schedule_recovery(target, executes_at);
+    }
+    Ok(scheduled)
+}
+
+/// Owner veto: drops the pending recovery, scheduled or not.
+pub fn cancel_recovery(principal: Principal) -> Result<(), RecoveryError> {
+    STATE.with(|s| {
+        let mut guard = s.borrow_mut();
+        let state = &mut *guard;
+        let mut recovery = state
+            .recovery
+            .social
+            .get(&principal)
+            .ok_or(RecoveryError::NotSetup)?;
+
+        if recovery.pending_recovery.take().is_none() {
+            return Err(RecoveryError::NoPendingRecovery);
         }
+        state.recovery.social.insert(principal, recovery);
+        log_event(&mut state.recovery.audit, principal, principal, RecoveryEvent::Cancelled);
+        ic_cdk::print(format!("Recovery for {} cancelled by owner", principal));
+        Ok(())
     })
 }
 
-pub fn execute_recovery(principal: Principal) {
-    // Recovery logic here
-    // 1. Reset authentication methods
-    // 2. Generate new recovery options
-    // 3. Notify user
+/// Timers do not survive upgrades, so re-arm every scheduled recovery.
+pub fn reschedule_pending_recoveries() {
+    let scheduled: Vec<(Principal, u64)> = STATE.with(|s| {
+        s.borrow()
+            .recovery
+            .social
+            .iter()
+            .filter_map(|(principal, recovery)| {
+                let pending = recovery.pending_recovery.as_ref().filter(|p| p.failed_at.is_none())?;
+                let executes_at = pending.executes_at?;
+                Some((principal, executes_at))
+            })
+            .collect()
+    });
+
+    for (principal, executes_at) in scheduled {
+        schedule_recovery(principal, executes_at);
+    }
+}
+
+fn schedule_recovery(target: Principal, executes_at: u64) {
+    let delay = Duration::from_nanos(executes_at.saturating_sub(time()));
+    ic_cdk_timers::set_timer(delay, move || execute_scheduled_recovery(target));
+}
+
+fn execute_scheduled_recovery(target: Principal) {
+    let outcome = STATE.with(|s| run_scheduled_recovery(&mut s.borrow_mut(), target, ic_cdk::id(), time()));
+    match outcome {
+        Some(Ok(receipt)) => {
+            ic_cdk::print(format!(
+                "Account recovered: {} -> {} ({} sessions revoked)",
+                target, receipt.new_principal, receipt.revoked_sessions
+            ));
+            STATE.with(|s| {
+                s.borrow_mut().recovery.receipts.insert(target, receipt);
+            });
+        }
+        Some(Err(e)) => ic_cdk::print(format!("Scheduled recovery for {} failed: {}", target, e)),
+        None => {}
+    }
+}
+
+/// Executes `target`'s recovery if it is due. The owner may have vetoed, or
+/// the timer may be a stale duplicate, in which case nothing happens. A
+/// failure is written to the account's audit trail and the pending entry is
+/// kept, marked failed, so owner and guardians can see it and start over.
+fn run_scheduled_recovery(
+    state: &mut State,
+    target: Principal,
+    actor: Principal,
+    now: u64,
+) -> Option<Result<RecoveryReceipt, RecoveryError>> {
+    let recovery = state.recovery.social.get(&target)?;
+    let pending = recovery.pending_recovery.as_ref()?;
+    if pending.failed_at.is_some() || pending.executes_at.is_none_or(|executes_at| executes_at > now) {
+        return None;
+    }
 
-}
\ No newline at end of file
+    let result = rebind_account(state, target, pending.new_principal, actor, now);
+    if let Err(err) = &result {
+        state.recovery.social.update(&target, |recovery| {
+            if let Some(pending) = recovery.pending_recovery.as_mut() {
+                pending.failed_at = Some(now);
+            }
+        });
+        let mut entries = state.recovery.audit.get(&target).unwrap_or_default();
+        entries.push(RecoveryAuditEntry {
+            event: RecoveryEvent::Failed { reason: err.to_string() },
+            actor,
+            at: now,
+        });
+        state.recovery.audit.insert(target, entries);
+    }
+    Some(result)
+}
+
+/// Rebinds an account to `new_principal`: moves the user record and its
+/// sign-in methods, links `new_principal` as an Internet Identity sign-in,
+/// revokes every session of the old principal and clears the multi-factor
+/// data so it has to be set up again by the new owner.
+pub fn execute_recovery(
+    principal: Principal,
+    new_principal: Principal,
+) -> Result<RecoveryReceipt, RecoveryError> {
+    let receipt = STATE.with(|s| rebind_account(&mut s.borrow_mut(), principal, new_principal, ic_cdk::id(), time()))?;
+    ic_cdk::print(format!(
+        "Account recovered: {} -> {} ({} sessions revoked)",
+        principal, new_principal, receipt.revoked_sessions
+    ));
+    Ok(receipt)
+}
+
+fn rebind_account(
+    state: &mut State,
+    principal: Principal,
+    new_principal: Principal,
+    actor: Principal,
+    now: u64,
+) -> Result<RecoveryReceipt, RecoveryError> {
+    let new_authenticator = Authenticator::InternetIdentity(new_principal);
+    if state.users.contains_key(&new_principal) || state.authenticators.contains_key(&new_authenticator) {
+        return Err(RecoveryError::PrincipalInUse);
+    }
+
+    let mut user = state
+        .users
+        .remove(&principal)
+        .ok_or(RecoveryError::AccountNotFound)?;
+    user.principal = new_principal;
+    user.origin = Some(PrincipalOrigin::Recovered);
+    user.linked_at = now;
+    // The new principal is how the recovered owner signs in from now on
+    user.authenticators.push(LinkedAuthenticator {
+        authenticator: new_authenticator,
+        linked_at: now,
+    });
+
+    let authenticators: Vec<Authenticator> = user
+        .authenticators
+        .iter()
+        .map(|linked| linked.authenticator.clone())
+        .collect();
+    for authenticator in &authenticators {
+        state.authenticators.insert(authenticator.clone(), new_principal);
+    }
+    state.pending_links.retain(|_, link| link.account != principal);
+    state.users.insert(new_principal, user);
+
+    // 1. Revoke every session of the old principal
+    let revoked_sessions = state.sessions.remove_all_of(principal, None);
+
+    // 2. Reset authentication methods
+    state.recovery.multi_factor.remove(&principal);
+
+    // 3. Carry the guardians and audit trail over to the new principal
+    if let Some(mut social) = state.recovery.social.remove(&principal) {
+        social.pending_recovery = None;
+        state.recovery.social.insert(new_principal, social);
+    }
+    let mut audit = state.recovery.audit.remove(&principal).unwrap_or_default();
+    audit.push(RecoveryAuditEntry {
+        event: RecoveryEvent::Executed { new_principal },
+        actor,
+        at: now,
+    });
+    state.recovery.audit.insert(new_principal, audit);
+    profiles::rename_account(state, principal, new_principal);
+
+    Ok(RecoveryReceipt {
+        old_principal: principal,
+        new_principal,
+        authenticators,
+        revoked_sessions,
+        executed_at: now,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{oidc_verifier::OidcSubject, SessionInfo, UserData};
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id; 29])
+    }
+
+    #[test]
+    fn test_validate_guardians() {
+        let owner = principal(0);
+        let guardians = vec![principal(1), principal(2), principal(3)];
+
+        assert!(validate_guardians(owner, &guardians, 2).is_ok());
+        assert!(matches!(
+            validate_guardians(owner, &guardians, 4),
+            Err(RecoveryError::InvalidThreshold)
+        ));
+        assert!(matches!(
+            validate_guardians(owner, &guardians, 0),
+            Err(RecoveryError::InvalidThreshold)
+        ));
+        assert!(matches!(
+            validate_guardians(owner, &[principal(1), principal(1)], 1),
+            Err(RecoveryError::InvalidGuardian)
+        ));
+        assert!(matches!(
+            validate_guardians(owner, &[owner, principal(1)], 1),
+            Err(RecoveryError::InvalidGuardian)
+        ));
+    }
+
+    fn oidc(sub: &str) -> Authenticator {
+        Authenticator::Oidc(OidcSubject {
+            issuer: "https://accounts.google.com".to_string(),
+            sub: sub.to_string(),
+        })
+    }
+
+    fn account(state: &mut State, owner: Principal, guardians: Vec<Principal>) {
+        state.authenticators.insert(oidc("owner"), owner);
+        state.users.insert(owner, UserData {
+            principal: owner,
+            authenticators: vec![LinkedAuthenticator { authenticator: oidc("owner"), linked_at: 1 }],
+            origin: None,
+            linked_at: 1,
+            role: None,
+            employee_profile: None,
+            employer_profile: None,
+            verification: None,
+            pii_key_id: None,
+        });
+        state.recovery.social.insert(owner, SocialRecovery {
+            contacts: guardians,
+            threshold: 1,
+            timelock_secs: DEFAULT_RECOVERY_TIMELOCK_SECS,
+            pending_recovery: None,
+        });
+    }
+
+    #[test]
+    fn test_rebind_account() {
+        let mut state = State::new();
+        let (owner, new_owner, canister) = (principal(1), principal(9), principal(0));
+        account(&mut state, owner, vec![principal(2)]);
+        state.sessions.insert(vec![1], SessionInfo {
+            principal: owner,
+            created_at: 1,
+            expires_at: u64::MAX,
+            last_nonce: 0,
+            auto_renew: false,
+            device_label: None,
+            last_used_at: 1,
+        });
+
+        let receipt = rebind_account(&mut state, owner, new_owner, canister, 100).unwrap();
+        assert_eq!(receipt.revoked_sessions, 1);
+        assert_eq!(state.sessions.len(), 0);
+        assert!(!state.users.contains_key(&owner));
+        assert!(state.recovery.social.contains_key(&new_owner));
+
+        // The new principal signs in through Internet Identity
+        let user = state.users.get(&new_owner).unwrap();
+        let ii = Authenticator::InternetIdentity(new_owner);
+        assert!(user.authenticators.iter().any(|linked| linked.authenticator == ii));
+        assert!(receipt.authenticators.contains(&ii));
+        assert_eq!(state.authenticators.get(&ii), Some(new_owner));
+        assert_eq!(state.authenticators.get(&oidc("owner")), Some(new_owner));
+
+        // Only once
+        assert!(matches!(
+            rebind_account(&mut state, owner, new_owner, canister, 200),
+            Err(RecoveryError::PrincipalInUse)
+        ));
+    }
+
+    #[test]
+    fn test_initiate_recovery_requires_owner_or_guardian() {
+        let (owner, guardian, stranger) = (principal(1), principal(2), principal(3));
+        STATE.with(|s| account(&mut s.borrow_mut(), owner, vec![guardian]));
+
+        assert!(matches!(
+            initiate_recovery(stranger, owner, principal(9)),
+            Err(RecoveryError::Unauthorized)
+        ));
+        // Naming yourself as the new principal doesn't make you a guardian
+        assert!(matches!(
+            initiate_recovery(stranger, owner, stranger),
+            Err(RecoveryError::Unauthorized)
+        ));
+        STATE.with(|s| assert!(s.borrow().recovery.social.get(&owner).unwrap().pending_recovery.is_none()));
+    }
+
+    #[test]
+    fn test_failed_scheduled_recovery_is_kept_and_audited() {
+        let mut state = State::new();
+        let (owner, guardian, new_owner, canister) = (principal(1), principal(2), principal(9), principal(0));
+        account(&mut state, owner, vec![guardian]);
+        state.recovery.social.update(&owner, |recovery| {
+            recovery.pending_recovery = Some(PendingRecovery {
+                initiator: guardian,
+                new_principal: new_owner,
+                approvals: vec![GuardianApproval { guardian, approved_at: 10 }],
+                expires_at: 1_000,
+                executes_at: Some(500),
+                failed_at: None,
+            });
+        });
+        // The new principal was claimed by someone else during the timelock
+        state.authenticators.insert(Authenticator::InternetIdentity(new_owner), principal(7));
+
+        assert!(run_scheduled_recovery(&mut state, owner, canister, 400).is_none());
+        assert!(matches!(
+            run_scheduled_recovery(&mut state, owner, canister, 500),
+            Some(Err(RecoveryError::PrincipalInUse))
+        ));
+
+        let recovery = state.recovery.social.get(&owner).unwrap();
+        assert_eq!(recovery.pending_recovery.as_ref().unwrap().failed_at, Some(500));
+        assert!(!recovery.has_active_recovery());
+        let audit = state.recovery.audit.get(&owner).unwrap();
+        assert!(matches!(audit.last(), Some(RecoveryAuditEntry { event: RecoveryEvent::Failed { .. }, at: 500, .. })));
+        assert!(state.users.contains_key(&owner));
+
+        // A re-armed timer doesn't retry a failed recovery
+        assert!(run_scheduled_recovery(&mut state, owner, canister, 600).is_none());
+    }
+}
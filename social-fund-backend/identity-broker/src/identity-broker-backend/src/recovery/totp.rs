@@ -0,0 +1,106 @@
+use ring::hmac;
+
+// RFC 6238 defaults, the only parameters authenticator apps agree on
+const STEP_SECS: u64 = 30;
+const DIGITS: u32 = 6;
+// Accept one step of clock drift either way
+const SKEW_STEPS: u64 = 1;
+const ISSUER: &str = "SocialFund";
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// HOTP value (RFC 4226) for the given counter.
+fn hotp(secret: &[u8], counter: u64) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+    let tag = hmac::sign(&key, &counter.to_be_bytes());
+    let digest = tag.as_ref();
+
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    format!("{:0width$}", binary % 10u32.pow(DIGITS), width = DIGITS as usize)
+}
+
+/// Checks `code` against the steps around `now_secs`. Returns the matched
+/// step, which must be greater than `last_step` so a code can't be replayed.
+pub fn verify(secret: &[u8], code: &str, now_secs: u64, last_step: Option<u64>) -> Option<u64> {
+    let current = now_secs / STEP_SECS;
+    let first = current.saturating_sub(SKEW_STEPS);
+
+    (first..=current + SKEW_STEPS)
+        .filter(|step| last_step.is_none_or(|last| *step > last))
+        .find(|step| constant_time_eq(hotp(secret, *step).as_bytes(), code.as_bytes()))
+}
+
+/// `otpauth://` URI understood by authenticator apps, usually shown as a QR code.
+pub fn provisioning_uri(account: &str, secret: &[u8]) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={DIGITS}&period={STEP_SECS}",
+        issuer = ISSUER,
+        account = account,
+        secret = base32_encode(secret),
+    )
+}
+
+/// RFC 4648 base32 without padding, as expected in provisioning URIs.
+pub fn base32_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(5) * 8);
+    let mut buffer: u64 = 0;
+    let mut bits = 0;
+
+    for byte in data {
+        buffer = (buffer << 8) | *byte as u64;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    out
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 6238 appendix B SHA-1 seed, truncated to 6 digits
+    const SEED: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn test_rfc6238_vectors() {
+        assert_eq!(hotp(SEED, 59 / STEP_SECS), "287082");
+        assert_eq!(hotp(SEED, 1111111109 / STEP_SECS), "081804");
+        assert_eq!(hotp(SEED, 1234567890 / STEP_SECS), "005924");
+        assert_eq!(hotp(SEED, 2000000000 / STEP_SECS), "279037");
+    }
+
+    #[test]
+    fn test_verify_rejects_replay() {
+        let step = verify(SEED, "081804", 1111111109, None);
+        assert_eq!(step, Some(1111111109 / STEP_SECS));
+        assert_eq!(verify(SEED, "081804", 1111111109, step), None);
+        assert_eq!(verify(SEED, "000000", 1111111109, None), None);
+    }
+
+    #[test]
+    fn test_base32_encode() {
+        assert_eq!(base32_encode(b""), "");
+        assert_eq!(base32_encode(b"f"), "MY");
+        assert_eq!(base32_encode(b"foobar"), "MZXW6YTBOI");
+        assert_eq!(base32_encode(SEED), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+    }
+}
@@ -1,16 +1,27 @@
-use candid::Principal;
-use ic_cdk::{
-    api::{management_canister::main::raw_rand, time},
-    update,
-};
-use rand::{rngs::StdRng, RngCore, SeedableRng};
-use ring::signature::{Ed25519KeyPair, KeyPair};
+use std::{cmp::Reverse, time::Duration};
+
+use candid::{CandidType, Principal};
+use ic_cdk::api::time;
+use ring::signature::{UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use crate::{SessionInfo, STATE};
+use crate::{identity_links, storage::{self, StableMap}, SessionInfo, STATE};
 
 const SESSION_DURATION_NS: u64 = 15 * 60 * 1_000_000_000; // 15 minutes
 const ROTATION_BUFFER: u64 = 5 * 60 * 1_000_000_000; // 5 minutes
+// Auto-renewed sessions still end this long after creation
+const MAX_SESSION_LIFETIME_NS: u64 = 24 * 60 * 60 * 1_000_000_000; // 24 hours
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+// Sessions visited per maintenance run; the rest wait for the next one
+const MAINTENANCE_BATCH: usize = 500;
+// How far a proof's timestamp may drift from canister time
+const PROOF_WINDOW_NS: u64 = 5 * 60 * 1_000_000_000; // 5 minutes
+const ED25519_PUBLIC_KEY_LEN: usize = 32;
+const MAX_DEVICE_LABEL_CHARS: usize = 64;
+// Domain separator so session signatures can't be reused in another protocol:
+// the length byte 0x13 followed by the 19 bytes of "social-fund-session"
+const PROOF_DOMAIN: &[u8] = b"\x13social-fund-session";
 
 #[derive(Error, Debug)]
 pub enum SessionError {
@@ -18,6 +29,16 @@ pub enum SessionError {
     GenerationFailed,
     #[error("Invalid principal")]
     InvalidPrincipal,
+    #[error("Invalid session public key")]
+    InvalidPublicKey,
+    #[error("Session public key already in use")]
+    KeyInUse,
+    #[error("Proof timestamp outside the accepted window")]
+    StaleProof,
+    #[error("Proof nonce was already used")]
+    ReplayedNonce,
+    #[error("Invalid proof signature")]
+    InvalidSignature,
 }
 
 pub struct Session {
@@ -25,32 +46,184 @@ pub struct Session {
     pub expires_at: u64,
 }
 
-/// Create a StdRng seeded from IC raw_rand
-async fn ic_rng() -> StdRng {
-    let (random_bytes,) = raw_rand()
-        .await
-        .expect("Failed to get IC randomness");
-    let mut seed = [0u8; 32];
-    seed.copy_from_slice(&random_bytes[..32]);
-    StdRng::from_seed(seed)
+/// Sessions by public key, indexed by principal and by expiry so per-account
+/// calls and the maintenance timer don't scan every session.
+pub struct SessionStore {
+    by_key: StableMap<Vec<u8>, SessionInfo>,
+    by_principal: StableMap<(Principal, Vec<u8>), ()>,
+    by_expiry: StableMap<(u64, Vec<u8>), ()>,
+}
+
+impl SessionStore {
+    pub fn init() -> Self {
+        SessionStore {
+            by_key: StableMap::init(storage::SESSIONS_MEMORY),
+            by_principal: StableMap::init(storage::SESSIONS_BY_PRINCIPAL_MEMORY),
+            by_expiry: StableMap::init(storage::SESSION_EXPIRY_MEMORY),
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<SessionInfo> {
+        self.by_key.get(key)
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.by_key.contains_key(key)
+    }
+
+    pub fn len(&self) -> u64 {
+        self.by_key.len()
+    }
+
+    pub fn insert(&mut self, key: Vec<u8>, session: SessionInfo) -> Option<SessionInfo> {
+        let previous = self.by_key.insert(key.clone(), session.clone());
+        if let Some(previous) = &previous {
+            self.unindex(&key, previous);
+        }
+        self.by_principal.insert((session.principal, key.clone()), ());
+        self.by_expiry.insert((session.expires_at, key), ());
+        previous
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<SessionInfo> {
+        let removed = self.by_key.remove(key)?;
+        self.unindex(key, &removed);
+        Some(removed)
+    }
+
+    /// Runs `f` on the session and writes it back. Returns `None` if absent.
+    pub fn update<R>(&mut self, key: &[u8], f: impl FnOnce(&mut SessionInfo) -> R) -> Option<R> {
+        let mut session = self.get(key)?;
+        let result = f(&mut session);
+        self.insert(key.to_vec(), session);
+        Some(result)
+    }
+
+    fn unindex(&mut self, key: &[u8], session: &SessionInfo) {
+        self.by_principal.remove(&(session.principal, key.to_vec()));
+        self.by_expiry.remove(&(session.expires_at, key.to_vec()));
+    }
+
+    /// Keys of `principal`'s sessions, expired ones included.
+    pub fn keys_of(&self, principal: Principal) -> Vec<Vec<u8>> {
+        self.by_principal
+            .range_from((principal, Vec::new()))
+            .take_while(|((owner, _), _)| *owner == principal)
+            .map(|((_, key), _)| key)
+            .collect()
+    }
+
+    /// Removes `principal`'s sessions except `keep`, returning how many.
+    pub fn remove_all_of(&mut self, principal: Principal, keep: Option<&[u8]>) -> u32 {
+        let mut removed = 0;
+        for key in self.keys_of(principal) {
+            if Some(key.as_slice()) != keep && self.remove(&key).is_some() {
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    /// Moves `old`'s sessions to `new` after the account changed principal.
+    pub fn rename_principal(&mut self, old: Principal, new: Principal) {
+        for key in self.keys_of(old) {
+            self.update(&key, |session| session.principal = new);
+        }
+    }
+
+    /// Up to `limit` sessions expiring at or before `until`, soonest first.
+    fn expiring(&self, until: u64, limit: usize) -> Vec<(u64, Vec<u8>)> {
+        self.by_expiry
+            .range_from((0, Vec::new()))
+            .take_while(|((expires_at, _), _)| *expires_at <= until)
+            .take(limit)
+            .map(|(entry, _)| entry)
+            .collect()
+    }
+
+    /// Indexes sessions stored before the indexes existed.
+    pub fn rebuild_indexes(&mut self) {
+        let sessions: Vec<(Vec<u8>, SessionInfo)> = self.by_key.iter().collect();
+        for (key, session) in sessions {
+            self.by_principal.insert((session.principal, key.clone()), ());
+            self.by_expiry.insert((session.expires_at, key), ());
+        }
+    }
+}
+
+/// Counters reported by `get_session_metrics`.
+#[derive(Serialize, Deserialize, CandidType, Clone, Default)]
+pub struct SessionMetrics {
+    pub active_sessions: u64,
+    pub expired_collected_total: u64,
+    pub renewed_total: u64,
+    pub last_run_at: u64,
+}
+
+/// Proof of possession of a session's private key. The client keeps the
+/// Ed25519 private key and signs, for every request, the concatenation of
+/// - `0x13` and the ASCII bytes `social-fund-session` (20 bytes),
+/// - the canister id's length as one byte, then its raw bytes,
+/// - the method name's length as one byte, then its UTF-8 bytes,
+/// - the nonce as a big-endian `u64`,
+/// - the timestamp in nanoseconds as a big-endian `u64`.
+///
+/// Nonces must strictly increase per session.
+#[derive(Serialize, Deserialize, CandidType, Clone)]
+pub struct SessionProof {
+    // Ed25519 public key registered when the session was created
+    pub session_key: Vec<u8>,
+    pub nonce: u64,
+    pub timestamp_ns: u64,
+    pub signature: Vec<u8>,
 }
 
-pub async fn create_session(principal: Principal) -> Result<Session, SessionError> {
-    let key_pair = generate_session_key().await?;
-    let public_key = key_pair.public_key().as_ref().to_vec();
+/// What a user sees about one of their sessions in `list_my_sessions`.
+#[derive(Serialize, Deserialize, CandidType, Clone)]
+pub struct SessionSummary {
+    pub session_key: Vec<u8>,
+    pub device_label: Option<String>,
+    pub created_at: u64,
+    pub expires_at: u64,
+    pub last_used_at: u64,
+    // The session the listing request was signed with
+    pub current: bool,
+}
+
+/// Registers a session for a public key generated by the client. The private
+/// key never reaches the canister.
+pub fn create_session(
+    principal: Principal,
+    public_key: Vec<u8>,
+    device_label: Option<String>,
+) -> Result<Session, SessionError> {
+    if public_key.len() != ED25519_PUBLIC_KEY_LEN {
+        return Err(SessionError::InvalidPublicKey);
+    }
     let expires_at = time() + SESSION_DURATION_NS;
+    let device_label = device_label
+        .map(|label| label.trim().chars().take(MAX_DEVICE_LABEL_CHARS).collect::<String>())
+        .filter(|label| !label.is_empty());
 
     STATE.with(|s| {
         let mut state = s.borrow_mut();
+        if state.sessions.contains_key(&public_key) {
+            return Err(SessionError::KeyInUse);
+        }
         state.sessions.insert(
             public_key.clone(),
             SessionInfo {
                 principal,
                 created_at: time(),
                 expires_at,
+                last_nonce: 0,
+                auto_renew: false,
+                device_label,
+                last_used_at: time(),
             },
         );
-    });
+        Ok(())
+    })?;
 
     Ok(Session {
         key: public_key,
@@ -58,47 +231,253 @@ pub async fn create_session(principal: Principal) -> Result<Session, SessionErro
     })
 }
 
-pub async fn rotate_session(
+pub fn rotate_session(
     old_key: Vec<u8>,
     principal: Principal,
+    new_public_key: Vec<u8>,
 ) -> Result<Session, SessionError> {
+    // The new key belongs to the same device
+    let device_label = STATE.with(|s| {
+        s.borrow()
+            .sessions
+            .get(&old_key)
+            .and_then(|session| session.device_label)
+    });
+    let session = create_session(principal, new_public_key, device_label)?;
     STATE.with(|s| {
         s.borrow_mut().sessions.remove(&old_key);
     });
-    create_session(principal).await
+    Ok(session)
+}
+
+pub fn list_sessions(principal: Principal, current_key: &[u8]) -> Vec<SessionSummary> {
+    let now = time();
+    STATE.with(|s| {
+        let state = s.borrow();
+        let mut sessions: Vec<SessionSummary> = state
+            .sessions
+            .keys_of(principal)
+            .into_iter()
+            .filter_map(|key| state.sessions.get(&key).map(|session| (key, session)))
+            .filter(|(_, session)| session.expires_at > now)
+            .map(|(key, session)| SessionSummary {
+                current: key.as_slice() == current_key,
+                session_key: key,
+                device_label: session.device_label,
+                created_at: session.created_at,
+                expires_at: session.expires_at,
+                last_used_at: session.last_used_at,
+            })
+            .collect();
+        sessions.sort_by_key(|session| Reverse(session.last_used_at));
+        sessions
+    })
+}
+
+/// Removes one of `principal`'s sessions. Returns false if the key isn't theirs.
+pub fn revoke_session(principal: Principal, session_key: &[u8]) -> bool {
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        if state.sessions.get(session_key).is_some_and(|session| session.principal == principal) {
+            state.sessions.remove(session_key);
+            true
+        } else {
+            false
+        }
+    })
 }
 
-async fn generate_session_key() -> Result<Ed25519KeyPair, SessionError> {
-    let mut rng = ic_rng().await;
-    let mut seed = [0u8; 32];
-    rng.fill_bytes(&mut seed);
+/// Removes every session of `principal` except `keep`, returning how many.
+pub fn revoke_other_sessions(principal: Principal, keep: &[u8]) -> u32 {
+    STATE.with(|s| s.borrow_mut().sessions.remove_all_of(principal, Some(keep)))
+}
 
-    Ed25519KeyPair::from_seed_unchecked(&seed)
-        .map_err(|_| SessionError::GenerationFailed)
+/// The bytes a client signs for `SessionProof`, in the layout documented there.
+pub fn proof_message(canister_id: &Principal, method: &str, nonce: u64, timestamp_ns: u64) -> Vec<u8> {
+    let canister = canister_id.as_slice();
+    let mut message = Vec::with_capacity(PROOF_DOMAIN.len() + canister.len() + method.len() + 18);
+    message.extend_from_slice(PROOF_DOMAIN);
+    message.push(canister.len() as u8);
+    message.extend_from_slice(canister);
+    message.push(method.len() as u8);
+    message.extend_from_slice(method.as_bytes());
+    message.extend_from_slice(&nonce.to_be_bytes());
+    message.extend_from_slice(&timestamp_ns.to_be_bytes());
+    message
 }
 
-#[update]
-async fn rotate_expiring_sessions() {
+/// Checks freshness, nonce ordering and the signature of a proof for `method`.
+pub fn verify_session_proof(
+    proof: &SessionProof,
+    canister_id: &Principal,
+    method: &str,
+    last_nonce: u64,
+    now: u64,
+) -> Result<(), SessionError> {
+    if proof.timestamp_ns.abs_diff(now) > PROOF_WINDOW_NS {
+        return Err(SessionError::StaleProof);
+    }
+    if proof.nonce <= last_nonce {
+        return Err(SessionError::ReplayedNonce);
+    }
+
+    let message = proof_message(canister_id, method, proof.nonce, proof.timestamp_ns);
+    UnparsedPublicKey::new(&ED25519, &proof.session_key)
+        .verify(&message, &proof.signature)
+        .map_err(|_| SessionError::InvalidSignature)
+}
+
+/// Starts the background maintenance job. Called from `init` and
+/// `post_upgrade` since timers don't survive upgrades.
+pub fn start_session_maintenance() {
+    ic_cdk_timers::set_timer_interval(MAINTENANCE_INTERVAL, rotate_expiring_sessions);
+}
+
+/// Drops expired sessions and extends those about to expire for clients that
+/// opted in. The canister can't mint keys for clients, so renewal keeps the
+/// same key and only moves `expires_at`, capped at `MAX_SESSION_LIFETIME_NS`.
+/// Only the `MAINTENANCE_BATCH` sessions closest to expiry are visited.
+/// Unconfirmed identity link requests are dropped here too.
+fn rotate_expiring_sessions() {
     let now = time();
 
-    // Extract data into an owned Vec before spawning tasks
-    let to_rotate: Vec<(Vec<u8>, Principal)> = STATE.with(|s| {
-        let state = s.borrow();
-        state
-            .sessions
-            .iter()
-            .filter(|(_, session)| session.expires_at - now < ROTATION_BUFFER)
-            .map(|(key, session)| (key.clone(), session.principal))
-            .collect()
-    });
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
 
-    for (key, principal) in to_rotate {
-        ic_cdk::spawn(async move {
-            if let Ok(_new_session) = create_session(principal).await {
-                STATE.with(|s| {
-                    s.borrow_mut().sessions.remove(&key);
-                });
+        let mut collected = 0;
+        let mut renewed = 0;
+        for (expires_at, key) in state.sessions.expiring(now + ROTATION_BUFFER, MAINTENANCE_BATCH) {
+            if expires_at <= now {
+                state.sessions.remove(&key);
+                collected += 1;
+                continue;
+            }
+            let Some(mut session) = state.sessions.get(&key) else {
+                continue;
+            };
+            let lifetime_end = session.created_at + MAX_SESSION_LIFETIME_NS;
+            if session.auto_renew && session.expires_at < lifetime_end {
+                session.expires_at = (now + SESSION_DURATION_NS).min(lifetime_end);
+                state.sessions.insert(key, session);
+                renewed += 1;
             }
-        });
+        }
+
+        identity_links::remove_expired_links(&mut state, now);
+
+        let active = state.sessions.len();
+        let metrics = &mut state.session_metrics;
+        metrics.active_sessions = active;
+        metrics.expired_collected_total += collected;
+        metrics.renewed_total += renewed;
+        metrics.last_run_at = now;
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    fn signed_proof(key_pair: &Ed25519KeyPair, canister: &Principal, method: &str, nonce: u64, ts: u64) -> SessionProof {
+        let message = proof_message(canister, method, nonce, ts);
+        SessionProof {
+            session_key: key_pair.public_key().as_ref().to_vec(),
+            nonce,
+            timestamp_ns: ts,
+            signature: key_pair.sign(&message).as_ref().to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_verify_session_proof() {
+        let key_pair = Ed25519KeyPair::from_seed_unchecked(&[7u8; 32]).unwrap();
+        let canister = Principal::from_slice(&[1u8; 10]);
+        let now = 1_700_000_000_000_000_000;
+        let proof = signed_proof(&key_pair, &canister, "get_user_details", 5, now);
+
+        assert!(verify_session_proof(&proof, &canister, "get_user_details", 4, now).is_ok());
+        // Replayed nonce
+        assert!(matches!(
+            verify_session_proof(&proof, &canister, "get_user_details", 5, now),
+            Err(SessionError::ReplayedNonce)
+        ));
+        // Signed for another method
+        assert!(matches!(
+            verify_session_proof(&proof, &canister, "logout", 4, now),
+            Err(SessionError::InvalidSignature)
+        ));
+        // Too old
+        assert!(matches!(
+            verify_session_proof(&proof, &canister, "get_user_details", 4, now + PROOF_WINDOW_NS + 1),
+            Err(SessionError::StaleProof)
+        ));
+        // The public key alone is not enough
+        let mut forged = proof.clone();
+        forged.signature = vec![0u8; 64];
+        assert!(matches!(
+            verify_session_proof(&forged, &canister, "get_user_details", 4, now),
+            Err(SessionError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn test_proof_message_layout() {
+        let canister = Principal::from_slice(&[1u8; 10]);
+        let message = proof_message(&canister, "logout", 1, 2);
+
+        let mut expected = vec![0x13];
+        expected.extend_from_slice(b"social-fund-session");
+        expected.push(10);
+        expected.extend_from_slice(&[1u8; 10]);
+        expected.push(6);
+        expected.extend_from_slice(b"logout");
+        expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+        expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 2]);
+        assert_eq!(message, expected);
+    }
+
+    fn session(principal: Principal, expires_at: u64) -> SessionInfo {
+        SessionInfo {
+            principal,
+            created_at: 0,
+            expires_at,
+            last_nonce: 0,
+            auto_renew: false,
+            device_label: None,
+            last_used_at: 0,
+        }
+    }
+
+    #[test]
+    fn test_session_store_indexes() {
+        let (ana, bo, cy) = (
+            Principal::from_slice(&[1; 29]),
+            Principal::from_slice(&[2; 29]),
+            Principal::from_slice(&[3; 29]),
+        );
+        let mut store = SessionStore::init();
+        store.insert(vec![1], session(ana, 30));
+        store.insert(vec![2], session(bo, 10));
+        store.insert(vec![3], session(ana, 20));
+
+        assert_eq!(store.keys_of(ana), vec![vec![1], vec![3]]);
+        assert_eq!(store.expiring(20, 10), vec![(10, vec![2]), (20, vec![3])]);
+        assert_eq!(store.expiring(30, 1), vec![(10, vec![2])]);
+
+        // Changing the expiry moves the session in the index
+        store.update(&[2], |session| session.expires_at = 40);
+        assert_eq!(store.expiring(30, 10), vec![(20, vec![3]), (30, vec![1])]);
+
+        store.rename_principal(ana, cy);
+        assert!(store.keys_of(ana).is_empty());
+        assert_eq!(store.keys_of(cy), vec![vec![1], vec![3]]);
+
+        assert_eq!(store.remove_all_of(cy, Some(&[3])), 1);
+        assert_eq!(store.keys_of(cy), vec![vec![3]]);
+        store.remove(&[3]);
+        assert!(store.keys_of(cy).is_empty());
+        assert_eq!(store.expiring(u64::MAX, 10), vec![(40, vec![2])]);
+        assert_eq!(store.len(), 1);
     }
 }
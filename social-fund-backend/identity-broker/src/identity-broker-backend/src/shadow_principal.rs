@@ -1,9 +1,16 @@
-use candid::Principal;
-use ic_cdk::api::management_canister::ecdsa::{ecdsa_public_key, EcdsaCurve, EcdsaKeyId, EcdsaPublicKeyArgument};
+use candid::{CandidType, Principal};
+use ic_cdk::api::management_canister::{
+    ecdsa::{ecdsa_public_key, EcdsaCurve, EcdsaKeyId, EcdsaPublicKeyArgument},
+    main::raw_rand,
+};
+use ring::hmac;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use thiserror::Error;
 
-use crate::STATE;
+use crate::{identity_links::{Authenticator, LinkedAuthenticator}, oidc_verifier::{OidcSubject, GOOGLE_ISSUER}, profiles, State, STATE};
+
+const HMAC_DOMAIN: &[u8] = b"social-fund-shadow-principal";
 
 #[derive(Error, Debug)]
 pub enum PrincipalError {
@@ -13,68 +20,266 @@ pub enum PrincipalError {
     InvalidInput
 }
 
-pub async fn generate_shadow_principal(google_id: &str) -> Result<Principal, PrincipalError> {
+/// How shadow principals are derived from a user's identity.
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq, Eq, Default)]
+pub enum DerivationScheme {
+    // HMAC-SHA256 keyed with a random salt held by the canister. Independent
+    // of any threshold key, so key rotations never move principals.
+    #[default]
+    SaltedHmac,
+    // Hash of the ECDSA public key derived for the user's own path under the
+    // named key ("key_1" on mainnet, "dfx_test_key" locally)
+    EcdsaKey { key_name: String },
+}
+
+/// Where an account's principal came from. Accounts stored before this was
+/// tracked have none and were derived from the root `dfx_test_key` key.
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq, Eq)]
+pub enum PrincipalOrigin {
+    Derived(DerivationScheme),
+    // Chosen by the user in a recovery, so never re-derived
+    Recovered,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Default)]
+pub struct DerivationState {
+    // Scheme used for new users and as the migration target
+    pub scheme: DerivationScheme,
+    // Generated on first use, never exposed
+    salt: Option<Vec<u8>>,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct MigrationReport {
+    pub migrated: u32,
+    // Accounts with an in-flight recovery, retried on a later call
+    pub deferred: u32,
+    pub remaining: u32,
+    // Accounts whose new principal couldn't be derived, retried on a later call
+    pub failed: Vec<MigrationFailure>,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct MigrationFailure {
+    pub principal: Principal,
+    pub error: String,
+}
+
+pub async fn generate_shadow_principal(subject: &OidcSubject) -> Result<Principal, PrincipalError> {
     // Step 1: Check sync if already cached
-    let existing = STATE.with(|s| s.borrow().google_mappings.get(google_id).cloned());
+    let authenticator = Authenticator::Oidc(subject.clone());
+    let existing = STATE.with(|s| s.borrow().authenticators.get(&authenticator));
     if let Some(principal) = existing {
         return Ok(principal);
     }
 
     // Step 2: Generate new
-    let seed = generate_principal_seed(google_id).await?;
-    let principal = Principal::self_authenticating(&seed);
+    let scheme = STATE.with(|s| s.borrow().derivation.scheme.clone());
+    let principal = derive_principal(&scheme, &derivation_id(subject)).await?;
 
     // Step 3: Store in state (sync)
     STATE.with(|s| {
         s.borrow_mut()
-            .google_mappings
-            .insert(google_id.to_string(), principal);
+            .authenticators
+            .insert(authenticator, principal);
     });
 
     Ok(principal)
 }
 
-async fn generate_principal_seed(google_id: &str) -> Result<Vec<u8>, PrincipalError> {
-    let pub_key = {
-        // Try cached key first
-        let cached = STATE.with(|s| s.borrow().public_key.clone());
-        if let Some(pk) = cached {
-            pk
-        } else {
-            // Fetch from management canister
-            let key = fetch_and_cache_public_key().await?;
-            key
+/// Google users keep hashing the bare `sub`, as before providers were
+/// generalized, so their principals don't change. Other issuers are
+/// namespaced since `sub` is only unique per issuer.
+fn derivation_id(subject: &OidcSubject) -> String {
+    if subject.issuer == GOOGLE_ISSUER {
+        subject.sub.clone()
+    } else {
+        format!("{}#{}", subject.issuer, subject.sub)
+    }
+}
+
+async fn derive_principal(scheme: &DerivationScheme, subject_id: &str) -> Result<Principal, PrincipalError> {
+    if subject_id.is_empty() {
+        return Err(PrincipalError::InvalidInput);
+    }
+    let seed = match scheme {
+        DerivationScheme::SaltedHmac => hmac_seed(&salt().await?, subject_id),
+        DerivationScheme::EcdsaKey { key_name } => {
+            let public_key = derived_public_key(key_name, subject_id).await?;
+            Sha256::digest(public_key).to_vec()
         }
     };
+    Ok(Principal::self_authenticating(&seed))
+}
 
-    let mut hasher = Sha256::new();
-    hasher.update(pub_key);
-    hasher.update(google_id.as_bytes());
-    Ok(hasher.finalize().to_vec())
+fn hmac_seed(salt: &[u8], subject_id: &str) -> Vec<u8> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, salt);
+    let mut ctx = hmac::Context::with_key(&key);
+    ctx.update(HMAC_DOMAIN);
+    ctx.update(subject_id.as_bytes());
+    ctx.sign().as_ref().to_vec()
+}
+
+async fn salt() -> Result<Vec<u8>, PrincipalError> {
+    if let Some(salt) = STATE.with(|s| s.borrow().derivation.salt.clone()) {
+        return Ok(salt);
+    }
+    let (random_bytes,) = raw_rand()
+        .await
+        .map_err(|_| PrincipalError::KeyGenerationFailed)?;
+    // Another call may have raced us across the await; the first salt wins
+    Ok(STATE.with(|s| {
+        s.borrow_mut()
+            .derivation
+            .salt
+            .get_or_insert_with(|| random_bytes[..32].to_vec())
+            .clone()
+    }))
 }
 
-/// calls the ECDSA canister and caches the key
-async fn fetch_and_cache_public_key() -> Result<Vec<u8>, PrincipalError> {
+/// Public key for the user's own derivation path under `key_name`.
+async fn derived_public_key(key_name: &str, subject_id: &str) -> Result<Vec<u8>, PrincipalError> {
     let pub_key_arg = EcdsaPublicKeyArgument {
         canister_id: None,
-        derivation_path: vec![],
+        derivation_path: vec![HMAC_DOMAIN.to_vec(), subject_id.as_bytes().to_vec()],
         key_id: EcdsaKeyId {
             curve: EcdsaCurve::Secp256k1,
-            name: "dfx_test_key".to_string(),
+            name: key_name.to_string(),
         },
     };
 
     match ecdsa_public_key(pub_key_arg).await {
-        Ok((res,)) => {
-            let key = res.public_key.clone();
+        Ok((res,)) => Ok(res.public_key),
+        Err(_) => Err(PrincipalError::KeyGenerationFailed),
+    }
+}
 
-            // Store to state
-            STATE.with(|s| {
-                s.borrow_mut().public_key = Some(key.clone());
-            });
+/// Re-derives up to `limit` accounts that were created under another scheme
+/// and moves them, with everything keyed by their principal, to the new one.
+/// A failed derivation is reported and the batch carries on.
+pub async fn migrate_principals(limit: u32) -> MigrationReport {
+    let (scheme, candidates) = STATE.with(|s| {
+        let state = s.borrow();
+        let scheme = state.derivation.scheme.clone();
+        let current = PrincipalOrigin::Derived(scheme.clone());
+        let candidates: Vec<(Principal, String)> = state
+            .users
+            .values()
+            .filter(|user| user.origin.is_none() || user.origin.as_ref().is_some_and(|origin| {
+                *origin != current && *origin != PrincipalOrigin::Recovered
+            }))
+            .filter_map(|user| primary_subject(&user.authenticators).map(|subject| (user.principal, derivation_id(subject))))
+            .collect();
+        (scheme, candidates)
+    });
 
-            Ok(key)
+    let mut report = MigrationReport { migrated: 0, deferred: 0, remaining: 0, failed: Vec::new() };
+    for (old, subject_id) in candidates {
+        if report.migrated + report.deferred + report.failed.len() as u32 >= limit {
+            report.remaining += 1;
+            continue;
         }
-        Err(_) => Err(PrincipalError::KeyGenerationFailed),
+        let new = match derive_principal(&scheme, &subject_id).await {
+            Ok(new) => new,
+            Err(err) => {
+                report.failed.push(MigrationFailure { principal: old, error: err.to_string() });
+                continue;
+            }
+        };
+        let moved = STATE.with(|s| {
+            let mut guard = s.borrow_mut();
+            remap_principal(&mut guard, old, new, &scheme)
+        });
+        if moved {
+            report.migrated += 1;
+        } else {
+            report.deferred += 1;
+        }
+    }
+    report
+}
+
+/// The identity an account was created with; later links don't change it.
+fn primary_subject(authenticators: &[LinkedAuthenticator]) -> Option<&OidcSubject> {
+    authenticators.iter().find_map(|linked| match &linked.authenticator {
+        Authenticator::Oidc(subject) => Some(subject),
+        _ => None,
+    })
+}
+
+/// Moves an account from `old` to `new`. Accounts with a recovery in flight
+/// are skipped since their timers refer to the old principal.
+fn remap_principal(state: &mut State, old: Principal, new: Principal, scheme: &DerivationScheme) -> bool {
+    let recovering = state.recovery.social.get(&old).is_some_and(|social| social.has_active_recovery())
+        || state.recovery.multi_factor.get(&old).is_some_and(|mfa| mfa.has_pending_recovery());
+    if recovering || (old != new && state.users.contains_key(&new)) {
+        return false;
+    }
+
+    let Some(mut user) = state.users.remove(&old) else {
+        return false;
+    };
+    user.principal = new;
+    user.origin = Some(PrincipalOrigin::Derived(scheme.clone()));
+    state.users.insert(new, user);
+    if old == new {
+        return true;
+    }
+
+    state.authenticators.update_all(|_, account| {
+        let moved = *account == old;
+        if moved {
+            *account = new;
+        }
+        moved
+    });
+    state.pending_links.update_all(|_, link| {
+        let moved = link.account == old;
+        if moved {
+            link.account = new;
+        }
+        moved
+    });
+    state.sessions.rename_principal(old, new);
+    if let Some(social) = state.recovery.social.remove(&old) {
+        state.recovery.social.insert(new, social);
+    }
+    // The account may also guard other accounts
+    state.recovery.social.update_all(|_, social| social.rename_guardian(old, new));
+    if let Some(mfa) = state.recovery.multi_factor.remove(&old) {
+        state.recovery.multi_factor.insert(new, mfa);
+    }
+    if let Some(record) = state.delivery_records.remove(&old) {
+        state.delivery_records.insert(new, record);
     }
-}
\ No newline at end of file
+    if let Some(audit) = state.recovery.audit.remove(&old) {
+        state.recovery.audit.insert(new, audit);
+    }
+    if let Some(role) = state.admin.roles.remove(&old) {
+        state.admin.roles.insert(new, role);
+    }
+    profiles::rename_account(state, old, new);
+    state.principal_migrations.insert(old, new);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hmac_seed_depends_on_salt_and_subject() {
+        let seed = hmac_seed(&[1u8; 32], "1234");
+        assert_eq!(seed, hmac_seed(&[1u8; 32], "1234"));
+        assert_ne!(seed, hmac_seed(&[2u8; 32], "1234"));
+        assert_ne!(seed, hmac_seed(&[1u8; 32], "12345"));
+    }
+
+    #[test]
+    fn test_derivation_id_namespaces_non_google_issuers() {
+        let google = OidcSubject { issuer: GOOGLE_ISSUER.to_string(), sub: "1".to_string() };
+        let other = OidcSubject { issuer: "https://login.example".to_string(), sub: "1".to_string() };
+        assert_eq!(derivation_id(&google), "1");
+        assert_eq!(derivation_id(&other), "https://login.example#1");
+    }
+}
@@ -0,0 +1,616 @@
+use std::{borrow::{Borrow, Cow}, cell::RefCell, collections::HashMap};
+
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_stable_structures::{
+    memory_manager::{MemoryId, MemoryManager, VirtualMemory},
+    storable::Bound,
+    DefaultMemoryImpl, StableBTreeMap, StableCell, Storable,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    admin::AdminStore,
+    identity_links::{Authenticator, LinkedAuthenticator, PendingLink},
+    kyc::PiiConfig,
+    oidc_verifier::{JwksCache, OidcProvider, OidcSubject, GOOGLE_ISSUER},
+    recovery::{delivery::{DeliveryRecord, DeliveryStore}, multi_factor::MultiFactorRecovery, social::SocialRecovery},
+    session_manager::SessionMetrics,
+    shadow_principal::DerivationState,
+    webauthn::WebAuthnStore,
+    GoogleConfig, State, UserData,
+};
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+/// Bump when the layout changes and add a step to `migrate`.
+/// v0: the baseline `State` as one candid blob written by `stable_save`.
+/// v1: large maps in `StableBTreeMap`s, the rest in `HEAP_STATE` on upgrade.
+/// v2: pending links and delivery records move to stable memory; sessions
+/// gain principal and expiry indexes.
+pub const SCHEMA_VERSION: u32 = 2;
+
+const SCHEMA_VERSION_MEMORY: MemoryId = MemoryId::new(0);
+const HEAP_STATE_MEMORY: MemoryId = MemoryId::new(1);
+pub const AUTHENTICATORS_MEMORY: MemoryId = MemoryId::new(2);
+pub const USERS_MEMORY: MemoryId = MemoryId::new(3);
+pub const SESSIONS_MEMORY: MemoryId = MemoryId::new(4);
+pub const SOCIAL_RECOVERY_MEMORY: MemoryId = MemoryId::new(5);
+pub const MULTI_FACTOR_MEMORY: MemoryId = MemoryId::new(6);
+pub const RECEIPTS_MEMORY: MemoryId = MemoryId::new(7);
+pub const RECOVERY_AUDIT_MEMORY: MemoryId = MemoryId::new(8);
+pub const PASSKEYS_MEMORY: MemoryId = MemoryId::new(9);
+pub const MIGRATIONS_MEMORY: MemoryId = MemoryId::new(10);
+pub const EMPLOYMENTS_MEMORY: MemoryId = MemoryId::new(11);
+pub const EMPLOYMENT_INVITES_MEMORY: MemoryId = MemoryId::new(12);
+pub const KYC_VERIFIERS_MEMORY: MemoryId = MemoryId::new(13);
+pub const ADMIN_ORGANISATIONS_MEMORY: MemoryId = MemoryId::new(14);
+pub const PENDING_LINKS_MEMORY: MemoryId = MemoryId::new(15);
+pub const DELIVERY_RECORDS_MEMORY: MemoryId = MemoryId::new(16);
+pub const SESSIONS_BY_PRINCIPAL_MEMORY: MemoryId = MemoryId::new(17);
+pub const SESSION_EXPIRY_MEMORY: MemoryId = MemoryId::new(18);
+
+// Magic bytes the memory manager writes at the start of stable memory
+const MEMORY_MANAGER_MAGIC: &[u8; 3] = b"MGR";
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+}
+
+fn memory(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(id))
+}
+
+/// Candid encoding for values that don't implement `Storable` themselves.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Candid<T>(pub T);
+
+impl<T: CandidType + DeserializeOwned> Storable for Candid<T> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(&self.0).expect("failed to encode stable value"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Candid(Decode!(bytes.as_ref(), T).expect("failed to decode stable value"))
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// A `StableBTreeMap` with the subset of the `HashMap` API the canister uses.
+/// Reads return owned values; use `update` to change an entry in place.
+pub struct StableMap<K, V>
+where
+    K: CandidType + DeserializeOwned + Ord + Clone,
+    V: CandidType + DeserializeOwned,
+{
+    inner: StableBTreeMap<Candid<K>, Candid<V>, Memory>,
+}
+
+impl<K, V> StableMap<K, V>
+where
+    K: CandidType + DeserializeOwned + Ord + Clone,
+    V: CandidType + DeserializeOwned,
+{
+    pub fn init(id: MemoryId) -> Self {
+        StableMap { inner: StableBTreeMap::init(memory(id)) }
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ToOwned<Owned = K> + ?Sized,
+    {
+        self.inner.get(&Candid(key.to_owned())).map(|value| value.0)
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ToOwned<Owned = K> + ?Sized,
+    {
+        self.inner.contains_key(&Candid(key.to_owned()))
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        self.inner.insert(Candid(key), Candid(value)).map(|value| value.0)
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ToOwned<Owned = K> + ?Sized,
+    {
+        self.inner.remove(&Candid(key.to_owned())).map(|value| value.0)
+    }
+
+    pub fn len(&self) -> u64 {
+        self.inner.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (K, V)> + '_ {
+        self.inner.iter().map(|(key, value)| (key.0, value.0))
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = V> + '_ {
+        self.iter().map(|(_, value)| value)
+    }
+
+    /// Entries from `start` onwards, in key order.
+    pub fn range_from(&self, start: K) -> impl Iterator<Item = (K, V)> + '_ {
+        self.inner.range(Candid(start)..).map(|(key, value)| (key.0, value.0))
+    }
+
+    /// Runs `f` on the entry and writes it back. Returns `None` if absent.
+    pub fn update<Q, R>(&mut self, key: &Q, f: impl FnOnce(&mut V) -> R) -> Option<R>
+    where
+        K: Borrow<Q>,
+        Q: ToOwned<Owned = K> + ?Sized,
+    {
+        let mut value = self.get(key)?;
+        let result = f(&mut value);
+        self.insert(key.to_owned(), value);
+        Some(result)
+    }
+
+    /// Runs `f` on every entry, writing back the ones it reports as changed.
+    pub fn update_all(&mut self, mut f: impl FnMut(&K, &mut V) -> bool) {
+        let changed: Vec<(K, V)> = self
+            .iter()
+            .filter_map(|(key, mut value)| f(&key, &mut value).then_some((key, value)))
+            .collect();
+        for (key, value) in changed {
+            self.insert(key, value);
+        }
+    }
+
+    /// Keeps the entries `f` returns true for; returns how many were removed.
+    pub fn retain(&mut self, mut f: impl FnMut(&K, &V) -> bool) -> u64 {
+        let doomed: Vec<K> = self
+            .iter()
+            .filter(|(key, value)| !f(key, value))
+            .map(|(key, _)| key)
+            .collect();
+        for key in &doomed {
+            self.remove(key);
+        }
+        doomed.len() as u64
+    }
+}
+
+/// The part of `State` that stays on the heap, written out in `pre_upgrade`.
+/// It is bounded by config and admin activity rather than member count,
+/// except for passkey registration challenges, which expire after minutes
+/// and are capped.
+#[derive(Serialize, Deserialize, CandidType, Clone, Default)]
+pub struct HeapState {
+    pub oidc_providers: HashMap<String, OidcProvider>,
+    pub delivery: DeliveryStore,
+    pub session_metrics: SessionMetrics,
+    pub jwks: HashMap<String, JwksCache>,
+    pub admin: AdminStore,
+    pub webauthn: WebAuthnStore,
+    pub derivation: DerivationState,
+    // Optional so heap state saved before it existed still decodes
+    pub pii: Option<PiiConfig>,
+}
+
+/// The `State` that `stable_save` wrote before v1, as deployed. Fields are
+/// required, so a blob in any other shape fails to decode instead of losing
+/// data. Sessions are not read: the canister generated their key pairs and
+/// no client holds the private halves, so they could never sign a proof and
+/// members sign in again. The cached root `public_key` is not needed either.
+#[derive(Deserialize, CandidType)]
+struct LegacyState {
+    google_mappings: HashMap<String, Principal>,
+    users: HashMap<Principal, LegacyUserData>,
+    recovery: LegacyRecoveryStore,
+    google_config: LegacyGoogleConfig,
+}
+
+#[derive(Deserialize, CandidType)]
+struct LegacyUserData {
+    principal: Principal,
+    google_id: Option<String>,
+    ii_principal: Option<Principal>,
+    linked_at: u64,
+}
+
+#[derive(Deserialize, CandidType)]
+struct LegacyRecoveryStore {
+    social: HashMap<Principal, LegacySocialRecovery>,
+    multi_factor: HashMap<Principal, LegacyMultiFactorRecovery>,
+}
+
+#[derive(Deserialize, CandidType)]
+struct LegacySocialRecovery {
+    contacts: Vec<Principal>,
+    threshold: u8,
+}
+
+#[derive(Deserialize, CandidType)]
+struct LegacyMultiFactorRecovery {
+    email: Option<String>,
+    phone: Option<String>,
+}
+
+#[derive(Deserialize, CandidType)]
+struct LegacyGoogleConfig {
+    client_id: String,
+    client_secret: String,
+}
+
+/// Stable memory written by `stable_save` has no memory manager header.
+/// Must run before anything touches `MEMORY_MANAGER`.
+fn has_legacy_layout() -> bool {
+    if ic_cdk::api::stable::stable_size() == 0 {
+        return false;
+    }
+    let mut magic = [0u8; 3];
+    ic_cdk::api::stable::stable_read(0, &mut magic);
+    &magic != MEMORY_MANAGER_MAGIC
+}
+
+pub fn save_heap_state(heap: HeapState) {
+    let mut cell = StableCell::init(memory(HEAP_STATE_MEMORY), Candid(HeapState::default()))
+        .expect("failed to init heap state cell");
+    cell.set(Candid(heap)).expect("failed to save heap state");
+}
+
+fn load_heap_state() -> HeapState {
+    StableCell::init(memory(HEAP_STATE_MEMORY), Candid(HeapState::default()))
+        .expect("failed to init heap state cell")
+        .get()
+        .0
+        .clone()
+}
+
+fn schema_version() -> StableCell<u32, Memory> {
+    StableCell::init(memory(SCHEMA_VERSION_MEMORY), 0).expect("failed to init schema version cell")
+}
+
+/// Marks fresh stable memory as the current schema.
+pub fn init_schema() {
+    schema_version().set(SCHEMA_VERSION).expect("failed to write schema version");
+}
+
+/// Brings stable memory up to `SCHEMA_VERSION` and returns the heap state to
+/// restore. Steps run in order, so a canister several versions behind
+/// replays every migration.
+pub fn migrate() -> HeapState {
+    let mut legacy = if has_legacy_layout() {
+        // Decode before the memory manager claims stable memory
+        let (state,): (LegacyState,) = ic_cdk::storage::stable_restore()
+            .unwrap_or_else(|e| ic_cdk::trap(&format!("failed to decode legacy state: {}", e)));
+        Some(state)
+    } else {
+        None
+    };
+
+    let mut version = if legacy.is_some() { 0 } else { *schema_version().get() };
+    let mut heap = None;
+    while version < SCHEMA_VERSION {
+        match version {
+            0 => {
+                let state = legacy.take().expect("v0 migration needs the legacy blob");
+                heap = Some(crate::STATE.with(|s| import_legacy_state(&mut s.borrow_mut(), state)));
+            }
+                    1 => {
+                // A v0 import already wrote the v2 layout
+                if heap.is_none() {
+                    let v1 = load_v1_heap_maps();
+                    crate::STATE.with(|s| move_v1_heap_maps(&mut s.borrow_mut(), v1));
+                }
+            }
+            v => ic_cdk::trap(&format!("no migration from schema version {}", v)),
+        }
+        version += 1;
+    }
+    if version > SCHEMA_VERSION {
+        ic_cdk::trap(&format!("stable memory is at schema version {}, newer than this build", version));
+    }
+    schema_version().set(SCHEMA_VERSION).expect("failed to write schema version");
+    heap.unwrap_or_else(load_heap_state)
+}
+
+/// The maps a v1 `HeapState` held that now live in stable memory. Other
+/// fields of the blob are left to `load_heap_state`.
+#[derive(Deserialize, CandidType, Clone, Default)]
+struct V1HeapMaps {
+    pending_links: HashMap<Principal, PendingLink>,
+    delivery: V1DeliveryStore,
+}
+
+#[derive(Deserialize, CandidType, Clone, Default)]
+struct V1DeliveryStore {
+    records: HashMap<Principal, DeliveryRecord>,
+}
+
+fn load_v1_heap_maps() -> V1HeapMaps {
+    StableCell::init(memory(HEAP_STATE_MEMORY), Candid(V1HeapMaps::default()))
+        .expect("failed to init heap state cell")
+        .get()
+        .0
+        .clone()
+}
+
+fn move_v1_heap_maps(state: &mut State, v1: V1HeapMaps) {
+    for (ii_principal, link) in v1.pending_links {
+        state.pending_links.insert(ii_principal, link);
+    }
+    for (principal, record) in v1.delivery.records {
+        state.delivery_records.insert(principal, record);
+    }
+    state.sessions.rebuild_indexes();
+}
+
+/// Moves the pre-v1 maps into stable structures and returns the heap state
+/// they imply. Users keep their principals; `origin` stays `None`, which is
+/// what marks them as derived from the root key for a later migration.
+fn import_legacy_state(state: &mut State, legacy: LegacyState) -> HeapState {
+    for (google_id, principal) in legacy.google_mappings {
+        state.authenticators.insert(google_authenticator(google_id), principal);
+    }
+    for (principal, user) in legacy.users {
+        let mut authenticators = Vec::new();
+        if let Some(google_id) = user.google_id {
+            let authenticator = google_authenticator(google_id);
+            state.authenticators.insert(authenticator.clone(), principal);
+            authenticators.push(authenticator);
+        }
+        if let Some(ii_principal) = user.ii_principal {
+            let authenticator = Authenticator::InternetIdentity(ii_principal);
+            state.authenticators.insert(authenticator.clone(), principal);
+            authenticators.push(authenticator);
+        }
+        state.users.insert(principal, UserData {
+            principal: user.principal,
+            authenticators: authenticators
+                .into_iter()
+                .map(|authenticator| LinkedAuthenticator { authenticator, linked_at: user.linked_at })
+                .collect(),
+            origin: None,
+            linked_at: user.linked_at,
+            role: None,
+            employee_profile: None,
+            employer_profile: None,
+            verification: None,
+            pii_key_id: None,
+        });
+    }
+    for (principal, social) in legacy.recovery.social {
+        state.recovery.social.insert(principal, SocialRecovery::from_legacy(social.contacts, social.threshold));
+    }
+    for (principal, mfa) in legacy.recovery.multi_factor {
+        state.recovery.multi_factor.insert(principal, MultiFactorRecovery::from_legacy(mfa.email, mfa.phone));
+    }
+
+    let mut heap = HeapState::default();
+    if !legacy.google_config.client_id.is_empty() {
+        let provider = OidcProvider::google(&GoogleConfig {
+            client_id: legacy.google_config.client_id,
+            client_secret: legacy.google_config.client_secret,
+            clock_skew_secs: None,
+            allowed_hosted_domains: None,
+        });
+        heap.oidc_providers.insert(provider.issuer.clone(), provider);
+    }
+    heap
+}
+
+fn google_authenticator(google_id: String) -> Authenticator {
+    Authenticator::Oidc(OidcSubject {
+        issuer: GOOGLE_ISSUER.to_string(),
+        sub: google_id,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use candid::{decode_args, encode_args};
+    use serde::Serialize;
+
+    use super::*;
+
+    // The baseline `State` and the types it nests, as `stable_save` encoded them
+    #[derive(Serialize, CandidType)]
+    struct BaselineState {
+        google_mappings: HashMap<String, Principal>,
+        users: HashMap<Principal, BaselineUserData>,
+        sessions: HashMap<Vec<u8>, BaselineSessionInfo>,
+        recovery: BaselineRecoveryStore,
+        google_config: BaselineGoogleConfig,
+        public_key: Option<Vec<u8>>,
+    }
+
+    #[derive(Serialize, CandidType)]
+    struct BaselineUserData {
+        principal: Principal,
+        google_id: Option<String>,
+        ii_principal: Option<Principal>,
+        linked_at: u64,
+    }
+
+    #[derive(Serialize, CandidType)]
+    struct BaselineSessionInfo {
+        principal: Principal,
+        created_at: u64,
+        expires_at: u64,
+    }
+
+    #[derive(Serialize, CandidType)]
+    struct BaselineRecoveryStore {
+        social: HashMap<Principal, BaselineSocialRecovery>,
+        multi_factor: HashMap<Principal, BaselineMultiFactorRecovery>,
+    }
+
+    #[derive(Serialize, CandidType)]
+    struct BaselineSocialRecovery {
+        contacts: Vec<Principal>,
+        threshold: u8,
+        pending_recovery: Option<BaselinePendingRecovery>,
+    }
+
+    #[derive(Serialize, CandidType)]
+    struct BaselinePendingRecovery {
+        initiator: Principal,
+        approvals: HashSet<Principal>,
+        expires_at: u64,
+    }
+
+    #[derive(Serialize, CandidType)]
+    struct BaselineMultiFactorRecovery {
+        email: Option<String>,
+        phone: Option<String>,
+        pending_recovery: Option<BaselinePendingMfaRecovery>,
+    }
+
+    #[derive(Serialize, CandidType)]
+    enum BaselineRecoveryMethod {
+        Email,
+    }
+
+    #[derive(Serialize, CandidType)]
+    struct BaselinePendingMfaRecovery {
+        method: BaselineRecoveryMethod,
+        code: String,
+        expires_at: u64,
+    }
+
+    #[derive(Serialize, CandidType)]
+    struct BaselineGoogleConfig {
+        client_id: String,
+        client_secret: String,
+    }
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id; 29])
+    }
+
+    #[test]
+    fn test_import_baseline_state() {
+        let (member, ii, guardian) = (principal(1), principal(2), principal(3));
+        let baseline = BaselineState {
+            google_mappings: HashMap::from([("google-sub".to_string(), member)]),
+            users: HashMap::from([(member, BaselineUserData {
+                principal: member,
+                google_id: Some("google-sub".to_string()),
+                ii_principal: Some(ii),
+                linked_at: 42,
+            })]),
+            sessions: HashMap::from([(vec![7u8; 32], BaselineSessionInfo {
+                principal: member,
+                created_at: 1,
+                expires_at: 2,
+            })]),
+            recovery: BaselineRecoveryStore {
+                social: HashMap::from([(member, BaselineSocialRecovery {
+                    contacts: vec![guardian],
+                    threshold: 1,
+                    pending_recovery: Some(BaselinePendingRecovery {
+                        initiator: guardian,
+                        approvals: HashSet::from([guardian]),
+                        expires_at: 3,
+                    }),
+                })]),
+                multi_factor: HashMap::from([(member, BaselineMultiFactorRecovery {
+                    email: Some("ana@example.com".to_string()),
+                    phone: None,
+                    pending_recovery: Some(BaselinePendingMfaRecovery {
+                        method: BaselineRecoveryMethod::Email,
+                        code: "123456".to_string(),
+                        expires_at: 4,
+                    }),
+                })]),
+            },
+            google_config: BaselineGoogleConfig {
+                client_id: "client.apps.googleusercontent.com".to_string(),
+                client_secret: "secret".to_string(),
+            },
+            public_key: Some(vec![4u8; 65]),
+        };
+
+        // What `stable_save((state,))` wrote and `stable_restore` reads back
+        let bytes = encode_args((baseline,)).unwrap();
+        let (legacy,): (LegacyState,) = decode_args(&bytes).unwrap();
+        let mut state = State::new();
+        let heap = import_legacy_state(&mut state, legacy);
+
+        let google = google_authenticator("google-sub".to_string());
+        let ii_authenticator = Authenticator::InternetIdentity(ii);
+        assert_eq!(state.authenticators.get(&google), Some(member));
+        assert_eq!(state.authenticators.get(&ii_authenticator), Some(member));
+
+        let user = state.users.get(&member).unwrap();
+        assert_eq!(user.principal, member);
+        assert_eq!(user.linked_at, 42);
+        assert!(user.origin.is_none());
+        let linked: Vec<Authenticator> = user.authenticators.iter().map(|l| l.authenticator.clone()).collect();
+        assert_eq!(linked, vec![google, ii_authenticator]);
+
+        let social = Encode!(&state.recovery.social.get(&member).unwrap()).unwrap();
+        let social = Decode!(&social, LegacySocialRecovery).unwrap();
+        assert_eq!((social.contacts, social.threshold), (vec![guardian], 1));
+        assert!(!state.recovery.multi_factor.get(&member).unwrap().has_pending_recovery());
+        assert_eq!(state.sessions.len(), 0);
+        assert!(heap.oidc_providers.contains_key(GOOGLE_ISSUER));
+    }
+
+    #[test]
+    fn test_v1_heap_maps_move_to_stable_memory() {
+        use crate::{recovery::{delivery::DeliveryStatus, multi_factor::RecoveryMethod}, SessionInfo};
+
+        let (member, ii) = (principal(1), principal(2));
+        let record = DeliveryRecord {
+            id: 7,
+            method: RecoveryMethod::Email,
+            status: DeliveryStatus::Delivered,
+            attempts: 1,
+            last_error: None,
+            created_at: 1,
+            updated_at: 2,
+        };
+        // The v1 fields that moved, as `HeapState` encoded them
+        #[derive(CandidType)]
+        struct V1HeapState {
+            pending_links: HashMap<Principal, PendingLink>,
+            delivery: V1Delivery,
+        }
+        #[derive(CandidType)]
+        struct V1Delivery {
+            config: Option<String>,
+            records: HashMap<Principal, DeliveryRecord>,
+            next_id: u64,
+        }
+        let bytes = Encode!(&V1HeapState {
+            pending_links: HashMap::from([(ii, PendingLink { account: member, expires_at: 5 })]),
+            delivery: V1Delivery { config: None, records: HashMap::from([(member, record)]), next_id: 8 },
+        })
+        .unwrap();
+
+        let mut state = State::new();
+        state.sessions.insert(vec![9], SessionInfo {
+            principal: member,
+            created_at: 1,
+            expires_at: 3,
+            last_nonce: 0,
+            auto_renew: false,
+            device_label: None,
+            last_used_at: 1,
+        });
+        move_v1_heap_maps(&mut state, Decode!(&bytes, V1HeapMaps).unwrap());
+
+        assert_eq!(state.pending_links.get(&ii).unwrap().account, member);
+        assert_eq!(state.delivery_records.get(&member).unwrap().id, 7);
+        assert_eq!(state.sessions.keys_of(member), vec![vec![9]]);
+    }
+
+    #[test]
+    fn test_legacy_state_rejects_other_layouts() {
+        // A v1 heap blob is not a baseline `State`; it must not decode as one
+        let bytes = encode_args((HeapState::default(),)).unwrap();
+        assert!(decode_args::<(LegacyState,)>(&bytes).is_err());
+    }
+}
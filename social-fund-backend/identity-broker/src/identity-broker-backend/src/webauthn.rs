@@ -0,0 +1,718 @@
+use std::collections::HashMap;
+
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use candid::{CandidType, Principal};
+use ciborium::value::Value;
+use ic_cdk::api::{management_canister::main::raw_rand, time};
+use ring::{
+    hmac,
+    signature::{RsaPublicKeyComponents, UnparsedPublicKey, ECDSA_P256_SHA256_ASN1, RSA_PKCS1_2048_8192_SHA256},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::{
+    identity_links::{self, Authenticator},
+    AuthError, STATE,
+};
+
+const CHALLENGE_TTL_NS: u64 = 5 * 60 * 1_000_000_000;
+const MAX_CREDENTIAL_ID_LEN: usize = 1023;
+const MAX_PASSKEYS_PER_ACCOUNT: usize = 10;
+// Registration challenges held at once, across all accounts
+const MAX_PENDING_CHALLENGES: usize = 10_000;
+// Sign-in challenges are expiry (8 bytes) || HMAC-SHA256 tag
+const SIGN_IN_CHALLENGE_LEN: usize = 8 + 32;
+const SIGN_IN_CHALLENGE_DOMAIN: &[u8] = b"passkey-sign-in";
+
+// Authenticator data flags
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_DATA: u8 = 0x40;
+
+// COSE algorithm identifiers
+const COSE_ES256: i128 = -7;
+const COSE_RS256: i128 = -257;
+
+#[derive(Error, Debug, Serialize, Deserialize, CandidType, Clone)]
+pub enum WebAuthnError {
+    #[error("Passkeys are not configured")]
+    NotConfigured,
+    #[error("Unknown or expired challenge")]
+    InvalidChallenge,
+    #[error("Malformed client data")]
+    InvalidClientData,
+    #[error("Origin not allowed: {0}")]
+    InvalidOrigin(String),
+    #[error("Malformed authenticator data")]
+    InvalidAuthenticatorData,
+    #[error("Relying party id mismatch")]
+    RpIdMismatch,
+    #[error("User presence or verification missing")]
+    UserNotVerified,
+    #[error("Unsupported attestation or key: {0}")]
+    Unsupported(String),
+    #[error("Invalid signature")]
+    InvalidSignature,
+    #[error("Signature counter did not increase; the authenticator may be cloned")]
+    CounterRegression,
+    #[error("Unknown credential")]
+    UnknownCredential,
+    #[error("Too many passkeys on this account")]
+    TooManyPasskeys,
+    #[error("Too many passkey registrations in progress, try again shortly")]
+    TooManyChallenges,
+    #[error(transparent)]
+    Auth(#[from] AuthError),
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct WebAuthnConfig {
+    // Usually the frontend's registrable domain, e.g. "socialfund.example"
+    pub rp_id: String,
+    // Exact origins the browser may report, e.g. "https://socialfund.example"
+    pub origins: Vec<String>,
+    // Require the UV flag (PIN/biometric), not just user presence
+    pub require_user_verification: bool,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq)]
+pub enum PasskeyPublicKey {
+    // Uncompressed SEC1 P-256 point
+    Es256 { point: Vec<u8> },
+    Rs256 { n: Vec<u8>, e: Vec<u8> },
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct Passkey {
+    // The owning account is tracked by the identity links, so it follows
+    // the account through recovery
+    pub public_key: PasskeyPublicKey,
+    pub sign_count: u32,
+    pub label: Option<String>,
+    pub created_at: u64,
+    pub last_used_at: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug, PartialEq)]
+enum ChallengePurpose {
+    Registration(Principal),
+    // Sign-in challenges are no longer stored; kept so older state decodes
+    Authentication,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+struct PendingChallenge {
+    purpose: ChallengePurpose,
+    expires_at: u64,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Default)]
+pub struct WebAuthnStore {
+    pub config: Option<WebAuthnConfig>,
+    // Issued registration challenges, at most one per account
+    challenges: HashMap<Vec<u8>, PendingChallenge>,
+    // HMAC key for stateless sign-in challenges, generated on first use
+    sign_in_key: Option<Vec<u8>>,
+}
+
+/// Options the frontend passes to `navigator.credentials.create/get`.
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct PasskeyChallenge {
+    pub challenge: Vec<u8>,
+    pub rp_id: String,
+    // WebAuthn user handle, set for registration only
+    pub user_handle: Option<Vec<u8>>,
+    // Credentials already on the account, so the browser can exclude them
+    pub credential_ids: Vec<Vec<u8>>,
+    pub expires_at: u64,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct PasskeyRegistration {
+    pub credential_id: Vec<u8>,
+    pub client_data_json: Vec<u8>,
+    pub attestation_object: Vec<u8>,
+    pub label: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct PasskeyAssertion {
+    pub credential_id: Vec<u8>,
+    pub client_data_json: Vec<u8>,
+    pub authenticator_data: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, CandidType, Clone, Debug)]
+pub struct PasskeySummary {
+    pub credential_id: Vec<u8>,
+    pub label: Option<String>,
+    pub created_at: u64,
+    pub last_used_at: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+}
+
+struct AuthenticatorData {
+    rp_id_hash: [u8; 32],
+    flags: u8,
+    sign_count: u32,
+    // Present on registration: credential id and COSE public key
+    attested: Option<(Vec<u8>, PasskeyPublicKey)>,
+}
+
+fn config() -> Result<WebAuthnConfig, WebAuthnError> {
+    STATE.with(|s| s.borrow().webauthn.config.clone())
+        .ok_or(WebAuthnError::NotConfigured)
+}
+
+async fn issue_challenge(purpose: ChallengePurpose) -> Result<(Vec<u8>, u64), WebAuthnError> {
+    let (random_bytes,) = raw_rand()
+        .await
+        .map_err(|_| WebAuthnError::InvalidChallenge)?;
+    let challenge = random_bytes[..32].to_vec();
+    let now = time();
+    let expires_at = now + CHALLENGE_TTL_NS;
+    STATE.with(|s| {
+        let challenges = &mut s.borrow_mut().webauthn.challenges;
+        // A new challenge replaces the account's previous one
+        challenges.retain(|_, pending| pending.expires_at > now && pending.purpose != purpose);
+        if challenges.len() >= MAX_PENDING_CHALLENGES {
+            return Err(WebAuthnError::TooManyChallenges);
+        }
+        challenges.insert(challenge.clone(), PendingChallenge { purpose, expires_at });
+        Ok(())
+    })?;
+    Ok((challenge, expires_at))
+}
+
+/// Removes and returns the challenge the client signed, if it is still live.
+fn take_challenge(client_data: &ClientData) -> Result<ChallengePurpose, WebAuthnError> {
+    let challenge = BASE64_URL_SAFE_NO_PAD
+        .decode(&client_data.challenge)
+        .map_err(|_| WebAuthnError::InvalidClientData)?;
+    STATE.with(|s| s.borrow_mut().webauthn.challenges.remove(&challenge))
+        .filter(|pending| pending.expires_at > time())
+        .map(|pending| pending.purpose)
+        .ok_or(WebAuthnError::InvalidChallenge)
+}
+
+fn account_passkeys(principal: Principal) -> Vec<Vec<u8>> {
+    identity_links::list(principal)
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|linked| match linked.authenticator {
+            Authenticator::Passkey { credential_id } => Some(credential_id),
+            _ => None,
+        })
+        .collect()
+}
+
+pub async fn start_registration(principal: Principal) -> Result<PasskeyChallenge, WebAuthnError> {
+    let config = config()?;
+    let credential_ids = account_passkeys(principal);
+    if credential_ids.len() >= MAX_PASSKEYS_PER_ACCOUNT {
+        return Err(WebAuthnError::TooManyPasskeys);
+    }
+    let (challenge, expires_at) = issue_challenge(ChallengePurpose::Registration(principal)).await?;
+    Ok(PasskeyChallenge {
+        challenge,
+        rp_id: config.rp_id,
+        user_handle: Some(principal.as_slice().to_vec()),
+        credential_ids,
+        expires_at,
+    })
+}
+
+pub fn finish_registration(
+    principal: Principal,
+    registration: PasskeyRegistration,
+) -> Result<(), WebAuthnError> {
+    let config = config()?;
+    if registration.credential_id.is_empty() || registration.credential_id.len() > MAX_CREDENTIAL_ID_LEN {
+        return Err(WebAuthnError::InvalidAuthenticatorData);
+    }
+    let client_data = check_client_data(&registration.client_data_json, "webauthn.create", &config)?;
+    if take_challenge(&client_data)? != ChallengePurpose::Registration(principal) {
+        return Err(WebAuthnError::InvalidChallenge);
+    }
+
+    let (fmt, auth_data_bytes, att_stmt) = parse_attestation_object(&registration.attestation_object)?;
+    let auth_data = parse_authenticator_data(&auth_data_bytes)?;
+    check_authenticator_data(&auth_data, &config)?;
+    let (credential_id, public_key) = auth_data
+        .attested
+        .ok_or(WebAuthnError::InvalidAuthenticatorData)?;
+    if credential_id != registration.credential_id {
+        return Err(WebAuthnError::InvalidAuthenticatorData);
+    }
+
+    // Only self attestation is checked; we don't pin authenticator vendors
+    match fmt.as_str() {
+        "none" => {}
+        "packed" => {
+            if map_get(&att_stmt, "x5c").is_some() {
+                return Err(WebAuthnError::Unsupported("packed x5c attestation".to_string()));
+            }
+            let alg = map_get(&att_stmt, "alg")
+                .and_then(Value::as_integer)
+                .map(i128::from)
+                .ok_or(WebAuthnError::InvalidAuthenticatorData)?;
+            if alg != cose_alg(&public_key) {
+                return Err(WebAuthnError::Unsupported(format!("attestation alg {}", alg)));
+            }
+            let sig = map_get(&att_stmt, "sig")
+                .and_then(Value::as_bytes)
+                .ok_or(WebAuthnError::InvalidAuthenticatorData)?;
+            verify_signature(&public_key, &auth_data_bytes, &registration.client_data_json, sig)?;
+        }
+        other => return Err(WebAuthnError::Unsupported(format!("attestation format {}", other))),
+    }
+
+    STATE.with(|s| {
+        let mut guard = s.borrow_mut();
+        let state = &mut *guard;
+        identity_links::attach(state, principal, Authenticator::Passkey {
+            credential_id: credential_id.clone(),
+        })?;
+        state.passkeys.insert(credential_id, Passkey {
+            public_key,
+            sign_count: auth_data.sign_count,
+            label: registration.label,
+            created_at: time(),
+            last_used_at: None,
+        });
+        Ok(())
+    })
+}
+
+async fn sign_in_key() -> Result<hmac::Key, WebAuthnError> {
+    let existing = STATE.with(|s| s.borrow().webauthn.sign_in_key.clone());
+    let key = match existing {
+        Some(key) => key,
+        None => {
+            let (random_bytes,) = raw_rand()
+                .await
+                .map_err(|_| WebAuthnError::InvalidChallenge)?;
+            // Another call may have raced us across the await; the first key wins
+            STATE.with(|s| {
+                s.borrow_mut()
+                    .webauthn
+                    .sign_in_key
+                    .get_or_insert_with(|| random_bytes[..32].to_vec())
+                    .clone()
+            })
+        }
+    };
+    Ok(hmac::Key::new(hmac::HMAC_SHA256, &key))
+}
+
+fn sign_in_message(expires_at: u64, session_public_key: &[u8]) -> Vec<u8> {
+    [SIGN_IN_CHALLENGE_DOMAIN, &expires_at.to_be_bytes(), session_public_key].concat()
+}
+
+/// A challenge that commits to the session key the sign-in will create, so
+/// nothing is stored per request and an assertion can't open another session.
+fn sign_in_challenge(key: &hmac::Key, expires_at: u64, session_public_key: &[u8]) -> Vec<u8> {
+    let tag = hmac::sign(key, &sign_in_message(expires_at, session_public_key));
+    [&expires_at.to_be_bytes()[..], tag.as_ref()].concat()
+}
+
+fn check_sign_in_challenge(
+    key: &hmac::Key,
+    challenge: &[u8],
+    session_public_key: &[u8],
+    now: u64,
+) -> Result<(), WebAuthnError> {
+    if challenge.len() != SIGN_IN_CHALLENGE_LEN {
+        return Err(WebAuthnError::InvalidChallenge);
+    }
+    let (expiry, tag) = challenge.split_at(8);
+    let expires_at = u64::from_be_bytes(expiry.try_into().expect("split at 8"));
+    if expires_at <= now {
+        return Err(WebAuthnError::InvalidChallenge);
+    }
+    hmac::verify(key, &sign_in_message(expires_at, session_public_key), tag)
+        .map_err(|_| WebAuthnError::InvalidChallenge)
+}
+
+pub async fn start_authentication(session_public_key: &[u8]) -> Result<PasskeyChallenge, WebAuthnError> {
+    let config = config()?;
+    let key = sign_in_key().await?;
+    let expires_at = time() + CHALLENGE_TTL_NS;
+    // Discoverable credentials: the browser picks the passkey
+    Ok(PasskeyChallenge {
+        challenge: sign_in_challenge(&key, expires_at, session_public_key),
+        rp_id: config.rp_id,
+        user_handle: None,
+        credential_ids: Vec::new(),
+        expires_at,
+    })
+}
+
+/// Verifies an assertion and returns the account it signs in to. The
+/// challenge must have been issued for `session_public_key`; replaying the
+/// assertion only re-requests a session for that same key.
+pub fn finish_authentication(
+    assertion: &PasskeyAssertion,
+    session_public_key: &[u8],
+) -> Result<Principal, WebAuthnError> {
+    let config = config()?;
+    let client_data = check_client_data(&assertion.client_data_json, "webauthn.get", &config)?;
+    let challenge = BASE64_URL_SAFE_NO_PAD
+        .decode(&client_data.challenge)
+        .map_err(|_| WebAuthnError::InvalidClientData)?;
+    let key = STATE.with(|s| s.borrow().webauthn.sign_in_key.clone())
+        .map(|key| hmac::Key::new(hmac::HMAC_SHA256, &key))
+        .ok_or(WebAuthnError::InvalidChallenge)?;
+    check_sign_in_challenge(&key, &challenge, session_public_key, time())?;
+    let passkey = STATE.with(|s| s.borrow().passkeys.get(&assertion.credential_id))
+        .ok_or(WebAuthnError::UnknownCredential)?;
+    let principal = identity_links::account_for(&Authenticator::Passkey {
+        credential_id: assertion.credential_id.clone(),
+    })
+    .ok_or(WebAuthnError::UnknownCredential)?;
+
+    let auth_data = parse_authenticator_data(&assertion.authenticator_data)?;
+    check_authenticator_data(&auth_data, &config)?;
+    verify_signature(
+        &passkey.public_key,
+        &assertion.authenticator_data,
+        &assertion.client_data_json,
+        &assertion.signature,
+    )?;
+    check_sign_count(passkey.sign_count, auth_data.sign_count)?;
+
+    STATE.with(|s| {
+        s.borrow_mut().passkeys.update(&assertion.credential_id, |stored| {
+            stored.sign_count = auth_data.sign_count;
+            stored.last_used_at = Some(time());
+        });
+    });
+    Ok(principal)
+}
+
+pub fn list_passkeys(principal: Principal) -> Vec<PasskeySummary> {
+    let credential_ids = account_passkeys(principal);
+    STATE.with(|s| {
+        let state = s.borrow();
+        credential_ids
+            .into_iter()
+            .filter_map(|id| {
+                let passkey = state.passkeys.get(&id)?;
+                Some(PasskeySummary {
+                    credential_id: id,
+                    label: passkey.label,
+                    created_at: passkey.created_at,
+                    last_used_at: passkey.last_used_at,
+                })
+            })
+            .collect()
+    })
+}
+
+/// Drops the stored key once the passkey has been unlinked from the account.
+pub fn forget_credential(credential_id: &[u8]) {
+    STATE.with(|s| {
+        s.borrow_mut().passkeys.remove(credential_id);
+    });
+}
+
+fn check_client_data(
+    client_data_json: &[u8],
+    expected_type: &str,
+    config: &WebAuthnConfig,
+) -> Result<ClientData, WebAuthnError> {
+    let client_data: ClientData = serde_json::from_slice(client_data_json)
+        .map_err(|_| WebAuthnError::InvalidClientData)?;
+    if client_data.kind != expected_type {
+        return Err(WebAuthnError::InvalidClientData);
+    }
+    if !config.origins.contains(&client_data.origin) {
+        return Err(WebAuthnError::InvalidOrigin(client_data.origin));
+    }
+    Ok(client_data)
+}
+
+fn check_authenticator_data(auth_data: &AuthenticatorData, config: &WebAuthnConfig) -> Result<(), WebAuthnError> {
+    let expected: [u8; 32] = Sha256::digest(config.rp_id.as_bytes()).into();
+    if auth_data.rp_id_hash != expected {
+        return Err(WebAuthnError::RpIdMismatch);
+    }
+    if auth_data.flags & FLAG_USER_PRESENT == 0
+        || (config.require_user_verification && auth_data.flags & FLAG_USER_VERIFIED == 0)
+    {
+        return Err(WebAuthnError::UserNotVerified);
+    }
+    Ok(())
+}
+
+/// Authenticators that don't keep counters always report 0. Otherwise the
+/// counter must strictly increase, or a copy of the key is in use.
+fn check_sign_count(stored: u32, received: u32) -> Result<(), WebAuthnError> {
+    if (stored != 0 || received != 0) && received <= stored {
+        return Err(WebAuthnError::CounterRegression);
+    }
+    Ok(())
+}
+
+fn verify_signature(
+    public_key: &PasskeyPublicKey,
+    authenticator_data: &[u8],
+    client_data_json: &[u8],
+    signature: &[u8],
+) -> Result<(), WebAuthnError> {
+    // Signed message is authenticatorData || SHA-256(clientDataJSON)
+    let mut message = authenticator_data.to_vec();
+    message.extend_from_slice(&Sha256::digest(client_data_json));
+    match public_key {
+        // WebAuthn ECDSA signatures are DER encoded, unlike JWS
+        PasskeyPublicKey::Es256 { point } => UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, point)
+            .verify(&message, signature)
+            .map_err(|_| WebAuthnError::InvalidSignature),
+        PasskeyPublicKey::Rs256 { n, e } => RsaPublicKeyComponents { n, e }
+            .verify(&RSA_PKCS1_2048_8192_SHA256, &message, signature)
+            .map_err(|_| WebAuthnError::InvalidSignature),
+    }
+}
+
+fn cose_alg(public_key: &PasskeyPublicKey) -> i128 {
+    match public_key {
+        PasskeyPublicKey::Es256 { .. } => COSE_ES256,
+        PasskeyPublicKey::Rs256 { .. } => COSE_RS256,
+    }
+}
+
+fn parse_attestation_object(bytes: &[u8]) -> Result<(String, Vec<u8>, Value), WebAuthnError> {
+    let object: Value = ciborium::de::from_reader(bytes)
+        .map_err(|_| WebAuthnError::InvalidAuthenticatorData)?;
+    let fmt = map_get(&object, "fmt")
+        .and_then(Value::as_text)
+        .ok_or(WebAuthnError::InvalidAuthenticatorData)?;
+    let auth_data = map_get(&object, "authData")
+        .and_then(Value::as_bytes)
+        .ok_or(WebAuthnError::InvalidAuthenticatorData)?;
+    let att_stmt = map_get(&object, "attStmt")
+        .cloned()
+        .ok_or(WebAuthnError::InvalidAuthenticatorData)?;
+    Ok((fmt.to_string(), auth_data.clone(), att_stmt))
+}
+
+fn parse_authenticator_data(bytes: &[u8]) -> Result<AuthenticatorData, WebAuthnError> {
+    // rpIdHash (32) | flags (1) | signCount (4) | attestedCredentialData?
+    if bytes.len() < 37 {
+        return Err(WebAuthnError::InvalidAuthenticatorData);
+    }
+    let mut rp_id_hash = [0u8; 32];
+    rp_id_hash.copy_from_slice(&bytes[..32]);
+    let flags = bytes[32];
+    let sign_count = u32::from_be_bytes([bytes[33], bytes[34], bytes[35], bytes[36]]);
+
+    let attested = if flags & FLAG_ATTESTED_DATA != 0 {
+        // aaguid (16) | credentialIdLength (2) | credentialId | COSE key
+        let rest = &bytes[37..];
+        if rest.len() < 18 {
+            return Err(WebAuthnError::InvalidAuthenticatorData);
+        }
+        let id_len = u16::from_be_bytes([rest[16], rest[17]]) as usize;
+        if id_len > MAX_CREDENTIAL_ID_LEN || rest.len() < 18 + id_len {
+            return Err(WebAuthnError::InvalidAuthenticatorData);
+        }
+        let credential_id = rest[18..18 + id_len].to_vec();
+        // Extensions may follow the key, so only the first CBOR item is read
+        let cose_key: Value = ciborium::de::from_reader(&rest[18 + id_len..])
+            .map_err(|_| WebAuthnError::InvalidAuthenticatorData)?;
+        Some((credential_id, parse_cose_key(&cose_key)?))
+    } else {
+        None
+    };
+
+    Ok(AuthenticatorData { rp_id_hash, flags, sign_count, attested })
+}
+
+fn parse_cose_key(key: &Value) -> Result<PasskeyPublicKey, WebAuthnError> {
+    let int = |label: i128| cose_get(key, label).and_then(Value::as_integer).map(i128::from);
+    let bytes = |label: i128| {
+        cose_get(key, label)
+            .and_then(Value::as_bytes)
+            .cloned()
+            .ok_or(WebAuthnError::InvalidAuthenticatorData)
+    };
+
+    // kty: 2 = EC2, 3 = RSA
+    match (int(1), int(3)) {
+        (Some(2), Some(COSE_ES256)) => {
+            if int(-1) != Some(1) {
+                return Err(WebAuthnError::Unsupported("EC curve".to_string()));
+            }
+            let (x, y) = (bytes(-2)?, bytes(-3)?);
+            if x.len() != 32 || y.len() != 32 {
+                return Err(WebAuthnError::InvalidAuthenticatorData);
+            }
+            let mut point = vec![0x04];
+            point.extend(x);
+            point.extend(y);
+            Ok(PasskeyPublicKey::Es256 { point })
+        }
+        (Some(3), Some(COSE_RS256)) => Ok(PasskeyPublicKey::Rs256 { n: bytes(-1)?, e: bytes(-2)? }),
+        (kty, alg) => Err(WebAuthnError::Unsupported(format!("COSE key kty {:?} alg {:?}", kty, alg))),
+    }
+}
+
+fn map_get<'a>(map: &'a Value, key: &str) -> Option<&'a Value> {
+    map.as_map()?
+        .iter()
+        .find(|(k, _)| k.as_text() == Some(key))
+        .map(|(_, v)| v)
+}
+
+fn cose_get(map: &Value, label: i128) -> Option<&Value> {
+    map.as_map()?
+        .iter()
+        .find(|(k, _)| k.as_integer().map(i128::from) == Some(label))
+        .map(|(_, v)| v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::{
+        rand::SystemRandom,
+        signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING},
+    };
+
+    fn test_config() -> WebAuthnConfig {
+        WebAuthnConfig {
+            rp_id: "socialfund.example".to_string(),
+            origins: vec!["https://socialfund.example".to_string()],
+            require_user_verification: true,
+        }
+    }
+
+    fn cose_es256(point: &[u8]) -> Vec<u8> {
+        let key = Value::Map(vec![
+            (Value::from(1), Value::from(2)),
+            (Value::from(3), Value::from(-7)),
+            (Value::from(-1), Value::from(1)),
+            (Value::from(-2), Value::Bytes(point[1..33].to_vec())),
+            (Value::from(-3), Value::Bytes(point[33..].to_vec())),
+        ]);
+        let mut out = Vec::new();
+        ciborium::ser::into_writer(&key, &mut out).unwrap();
+        out
+    }
+
+    fn auth_data(flags: u8, sign_count: u32, attested: Option<(&[u8], &[u8])>) -> Vec<u8> {
+        let mut data = Sha256::digest(b"socialfund.example").to_vec();
+        data.push(flags);
+        data.extend(sign_count.to_be_bytes());
+        if let Some((credential_id, cose_key)) = attested {
+            data.extend([0u8; 16]);
+            data.extend((credential_id.len() as u16).to_be_bytes());
+            data.extend(credential_id);
+            data.extend(cose_key);
+        }
+        data
+    }
+
+    #[test]
+    fn test_parse_attested_es256_key() {
+        let point = [[0x04].as_slice(), &[7u8; 64]].concat();
+        let bytes = auth_data(
+            FLAG_USER_PRESENT | FLAG_USER_VERIFIED | FLAG_ATTESTED_DATA,
+            3,
+            Some((b"cred", &cose_es256(&point))),
+        );
+        let parsed = parse_authenticator_data(&bytes).unwrap();
+        assert_eq!(parsed.sign_count, 3);
+        assert!(check_authenticator_data(&parsed, &test_config()).is_ok());
+        let (credential_id, key) = parsed.attested.unwrap();
+        assert_eq!(credential_id, b"cred");
+        assert_eq!(key, PasskeyPublicKey::Es256 { point });
+    }
+
+    #[test]
+    fn test_rejects_missing_user_verification_and_wrong_rp() {
+        let parsed = parse_authenticator_data(&auth_data(FLAG_USER_PRESENT, 0, None)).unwrap();
+        assert!(matches!(
+            check_authenticator_data(&parsed, &test_config()),
+            Err(WebAuthnError::UserNotVerified)
+        ));
+
+        let mut other_rp = test_config();
+        other_rp.rp_id = "evil.example".to_string();
+        let parsed = parse_authenticator_data(&auth_data(FLAG_USER_PRESENT | FLAG_USER_VERIFIED, 0, None)).unwrap();
+        assert!(matches!(
+            check_authenticator_data(&parsed, &other_rp),
+            Err(WebAuthnError::RpIdMismatch)
+        ));
+        assert!(parse_authenticator_data(&[0u8; 36]).is_err());
+    }
+
+    #[test]
+    fn test_client_data_type_and_origin() {
+        let config = test_config();
+        let json = br#"{"type":"webauthn.get","challenge":"AAEC","origin":"https://socialfund.example"}"#;
+        assert!(check_client_data(json, "webauthn.get", &config).is_ok());
+        assert!(check_client_data(json, "webauthn.create", &config).is_err());
+
+        let phished = br#"{"type":"webauthn.get","challenge":"AAEC","origin":"https://socia1fund.example"}"#;
+        assert!(matches!(
+            check_client_data(phished, "webauthn.get", &config),
+            Err(WebAuthnError::InvalidOrigin(_))
+        ));
+    }
+
+    #[test]
+    fn test_sign_in_challenge_is_bound_to_session_key() {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, &[7; 32]);
+        let (session_key, other_key) = ([1u8; 32], [2u8; 32]);
+        let challenge = sign_in_challenge(&key, 1_000, &session_key);
+        assert_eq!(challenge.len(), SIGN_IN_CHALLENGE_LEN);
+
+        assert!(check_sign_in_challenge(&key, &challenge, &session_key, 999).is_ok());
+        assert!(check_sign_in_challenge(&key, &challenge, &other_key, 999).is_err());
+        assert!(check_sign_in_challenge(&key, &challenge, &session_key, 1_000).is_err());
+        let other_secret = hmac::Key::new(hmac::HMAC_SHA256, &[8; 32]);
+        assert!(check_sign_in_challenge(&other_secret, &challenge, &session_key, 999).is_err());
+
+        // Pushing the expiry out breaks the tag
+        let mut extended = challenge.clone();
+        extended[..8].copy_from_slice(&2_000u64.to_be_bytes());
+        assert!(check_sign_in_challenge(&key, &extended, &session_key, 999).is_err());
+        assert!(check_sign_in_challenge(&key, &challenge[..8], &session_key, 999).is_err());
+    }
+
+    #[test]
+    fn test_sign_count() {
+        assert!(check_sign_count(0, 0).is_ok());
+        assert!(check_sign_count(5, 6).is_ok());
+        assert!(check_sign_count(5, 5).is_err());
+        assert!(check_sign_count(5, 0).is_err());
+    }
+
+    #[test]
+    fn test_es256_assertion_signature() {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+        let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng).unwrap();
+        let public_key = PasskeyPublicKey::Es256 { point: key_pair.public_key().as_ref().to_vec() };
+
+        let authenticator_data = auth_data(FLAG_USER_PRESENT | FLAG_USER_VERIFIED, 1, None);
+        let client_data_json = br#"{"type":"webauthn.get","challenge":"AAEC","origin":"https://socialfund.example"}"#;
+        let mut message = authenticator_data.clone();
+        message.extend_from_slice(&Sha256::digest(client_data_json));
+        let signature = key_pair.sign(&rng, &message).unwrap();
+
+        assert!(verify_signature(&public_key, &authenticator_data, client_data_json, signature.as_ref()).is_ok());
+        assert!(verify_signature(&public_key, &authenticator_data, b"{}", signature.as_ref()).is_err());
+    }
+}
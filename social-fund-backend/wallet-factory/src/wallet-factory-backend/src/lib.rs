@@ -4,7 +4,7 @@ use candid::{CandidType, Principal};
 use ic_cdk::{api::time, caller, id, init, post_upgrade, pre_upgrade, query, update};
 use serde::{Deserialize as SerdeDeserialize, Serialize};
 
-use crate::{ecdsa_manager::{backup_ecdsa_state, initialize_ecdsa_manager, restore_ecdsa_state, EcdsaManager}, types::{BlockIndex, CanisterIds, FeeSettings, NetworkSettings, RateLimits, SecuritySettings, Transaction, VaultType, WalletError, WithdrawalId}, vaults::{backup_vault_state, health_check, initialize_vault_system, restore_vault_state, SystemHealth, VaultBackup, VaultManager}};
+use crate::{ecdsa_manager::{backup_ecdsa_state, initialize_ecdsa_manager, restore_ecdsa_state, EcdsaManager}, types::{saturating_u64, Account, BlockIndex, CanisterIds, FeeSettings, NetworkSettings, RateLimits, SecuritySettings, Tokens, Transaction, VaultType, WalletError, WithdrawalId}, vaults::{backup_vault_state, health_check, icp::IcpDepositAddress, indexer::{start_indexer, SkippedDeposit}, initialize_vault_system, ledger_of, restore_vault_state, token::{TokenBalance, TokenConfig}, vault_type_of, SystemHealth, VaultBackup, VaultManager}};
 
 pub mod types;
 pub mod vaults;
@@ -242,6 +242,8 @@ fn init(init_args: InitArgs) {
         ic_cdk::println!("Failed to initialize ECDSA manager: {:?}", e);
     }
     
+    start_indexer();
+    
     ic_cdk::println!("Wallet system initialized successfully");
 }
 
@@ -298,6 +300,8 @@ fn post_upgrade() {
             });
         }
     }
+    
+    start_indexer();
 }
 
 // Authentication and authorization
@@ -526,7 +530,7 @@ fn create_default_transfer_limits() -> HashMap<VaultType, u64> {
 }
 
 #[update]
-async fn update_balance(wallet_id: Principal, vault_type: VaultType) -> Result<u64, WalletError> {
+async fn update_balance(wallet_id: Principal, vault_type: VaultType) -> Result<Tokens, WalletError> {
     check_emergency_state()?;
     
     let session = authenticate_user()?;
@@ -537,11 +541,7 @@ async fn update_balance(wallet_id: Principal, vault_type: VaultType) -> Result<u
     
     let start_time = time();
     
-    let result = match vault_type {
-        VaultType::Icp => crate::vaults::update_icp_balance(session.principal).await,
-        VaultType::CkBtc => crate::vaults::update_ckbtc_balance(session.principal).await,
-        VaultType::CkUsdt => crate::vaults::update_ckusdt_balance(session.principal).await,
-    };
+    let result = crate::vaults::update_balance(session.principal, ledger_of(vault_type)).await;
     
     let duration = time() - start_time;
     
@@ -562,7 +562,19 @@ async fn update_balance(wallet_id: Principal, vault_type: VaultType) -> Result<u
 }
 
 #[update]
-async fn batch_update_balances(wallet_id: Principal) -> Result<HashMap<VaultType, u64>, WalletError> {
+async fn update_token_balance(wallet_id: Principal, ledger_canister_id: Principal) -> Result<Tokens, WalletError> {
+    check_emergency_state()?;
+    
+    let session = authenticate_user()?;
+    check_permission(&session, Permission::UpdateBalance)?;
+    
+    verify_wallet_ownership(wallet_id, session.principal)?;
+    
+    crate::vaults::update_balance(session.principal, ledger_canister_id).await
+}
+
+#[update]
+async fn batch_update_balances(wallet_id: Principal) -> Result<Vec<TokenBalance>, WalletError> {
     check_emergency_state()?;
     
     let session = authenticate_user()?;
@@ -577,8 +589,26 @@ async fn batch_update_balances(wallet_id: Principal) -> Result<HashMap<VaultType
 async fn transfer_tokens(
     wallet_id: Principal,
     vault_type: VaultType,
-    amount: u64,
+    amount: Tokens,
     recipient: Principal,
+) -> Result<BlockIndex, WalletError> {
+    // Members keep their funds in a subaccount of this canister, so paying
+    // one must land in their vault rather than their default account
+    let is_member = STATE.with(|s| s.borrow().user_wallets.contains_key(&recipient));
+    let to = if is_member {
+        Account::member(recipient)
+    } else {
+        Account::principal_only(recipient)
+    };
+    transfer_token(wallet_id, ledger_of(vault_type), amount, to).await
+}
+
+#[update]
+async fn transfer_token(
+    wallet_id: Principal,
+    ledger_canister_id: Principal,
+    amount: Tokens,
+    to: Account,
 ) -> Result<BlockIndex, WalletError> {
     check_emergency_state()?;
     
@@ -588,36 +618,40 @@ async fn transfer_tokens(
     verify_wallet_ownership(wallet_id, session.principal)?;
     
     // Check daily transfer limits
-    check_daily_transfer_limit(wallet_id, vault_type, amount)?;
+    check_token_transfer_limit(wallet_id, ledger_canister_id, amount)?;
     
     let start_time = time();
     
-    let result = crate::vaults::transfer_tokens(session.principal, vault_type, amount, recipient).await;
+    let result = crate::vaults::transfer(session.principal, ledger_canister_id, amount, to).await;
     
     let duration = time() - start_time;
     
     // Update usage statistics
     if result.is_ok() {
+        let amount = saturating_u64(amount);
         STATE.with(|s| {
             let mut state = s.borrow_mut();
             
             // Update wallet statistics
             if let Some(wallet) = state.wallets.get_mut(&wallet_id) {
                 wallet.usage_statistics.total_transactions += 1;
-                wallet.usage_statistics.total_volume += amount;
+                wallet.usage_statistics.total_volume = wallet.usage_statistics.total_volume.saturating_add(amount);
                 wallet.usage_statistics.last_transaction = time();
                 
                 // Update average transaction amount
                 let current_avg = wallet.usage_statistics.average_transaction_amount;
                 wallet.usage_statistics.average_transaction_amount = 
-                    if current_avg == 0 { amount } else { (current_avg + amount) / 2 };
+                    if current_avg == 0 { amount } else { current_avg / 2 + amount / 2 };
             }
             
             // Update system metrics
             state.system_metrics.total_transactions += 1;
-            *state.system_metrics.total_volume_by_token.entry(vault_type).or_insert(0) += amount;
+            if let Some(vault_type) = vault_type_of(ledger_canister_id) {
+                let volume = state.system_metrics.total_volume_by_token.entry(vault_type).or_insert(0);
+                *volume = volume.saturating_add(amount);
+            }
             
-            let operation_name = format!("transfer_{:?}", vault_type);
+            let operation_name = format!("transfer_{}", ledger_canister_id);
             state.system_metrics.performance_metrics.average_response_times
                 .insert(operation_name, duration);
         });
@@ -638,7 +672,7 @@ async fn retrieve_btc(
     check_permission(&session, Permission::Transfer)?;
     
     verify_wallet_ownership(wallet_id, session.principal)?;
-    check_daily_transfer_limit(wallet_id, VaultType::CkBtc, amount)?;
+    check_daily_transfer_limit(wallet_id, VaultType::CkBtc, amount.into())?;
     
     crate::vaults::retrieve_btc(session.principal, amount, btc_address).await
 }
@@ -646,7 +680,18 @@ async fn retrieve_btc(
 #[update]
 async fn withdraw_usdt(
     wallet_id: Principal,
-    amount: u64,
+    amount: Tokens,
+    ethereum_address: String,
+) -> Result<WithdrawalId, WalletError> {
+    withdraw_erc20(wallet_id, ledger_of(VaultType::CkUsdt), amount, ethereum_address).await
+}
+
+/// Withdraws a ckERC20 token such as ckUSDT or ckUSDC to an Ethereum address.
+#[update]
+async fn withdraw_erc20(
+    wallet_id: Principal,
+    ledger_canister_id: Principal,
+    amount: Tokens,
     ethereum_address: String,
 ) -> Result<WithdrawalId, WalletError> {
     check_emergency_state()?;
@@ -655,23 +700,31 @@ async fn withdraw_usdt(
     check_permission(&session, Permission::Transfer)?;
     
     verify_wallet_ownership(wallet_id, session.principal)?;
-    check_daily_transfer_limit(wallet_id, VaultType::CkUsdt, amount)?;
+    check_token_transfer_limit(wallet_id, ledger_canister_id, amount)?;
     
-    crate::vaults::withdraw_usdt(session.principal, amount, ethereum_address).await
+    crate::vaults::withdraw_erc20(session.principal, ledger_canister_id, amount, ethereum_address).await
 }
 
 // Query functions
 
 #[query]
-fn get_balance(wallet_id: Principal, vault_type: VaultType) -> Result<u64, WalletError> {
+fn get_balance(wallet_id: Principal, vault_type: VaultType) -> Result<Tokens, WalletError> {
+    let session = authenticate_user()?;
+    verify_wallet_ownership(wallet_id, session.principal)?;
+    
+    crate::vaults::get_balance(session.principal, ledger_of(vault_type))
+}
+
+#[query]
+fn get_token_balance(wallet_id: Principal, ledger_canister_id: Principal) -> Result<Tokens, WalletError> {
     let session = authenticate_user()?;
     verify_wallet_ownership(wallet_id, session.principal)?;
     
-    crate::vaults::get_balance(session.principal, vault_type)
+    crate::vaults::get_balance(session.principal, ledger_canister_id)
 }
 
 #[query]
-fn get_all_balances(wallet_id: Principal) -> Result<HashMap<VaultType, u64>, WalletError> {
+fn get_all_balances(wallet_id: Principal) -> Result<Vec<TokenBalance>, WalletError> {
     let session = authenticate_user()?;
     verify_wallet_ownership(wallet_id, session.principal)?;
     
@@ -686,6 +739,85 @@ async fn get_btc_address(wallet_id: Principal) -> Result<String, WalletError> {
     crate::vaults::get_btc_address(session.principal).await
 }
 
+/// ICRC-1 account holding the caller's funds for every ledger; send any
+/// registered token here to credit the wallet.
+#[query]
+fn get_deposit_account(wallet_id: Principal) -> Result<Account, WalletError> {
+    let session = authenticate_user()?;
+    verify_wallet_ownership(wallet_id, session.principal)?;
+    
+    Ok(Account::member(session.principal))
+}
+
+#[query]
+fn get_icp_deposit_address(wallet_id: Principal) -> Result<IcpDepositAddress, WalletError> {
+    let session = authenticate_user()?;
+    verify_wallet_ownership(wallet_id, session.principal)?;
+    
+    crate::vaults::get_icp_deposit_address(session.principal)
+}
+
+#[update]
+async fn approve_icp(
+    wallet_id: Principal,
+    spender: Account,
+    amount: Tokens,
+    expires_at: Option<u64>,
+) -> Result<BlockIndex, WalletError> {
+    approve_token(wallet_id, ledger_of(VaultType::Icp), spender, amount, expires_at).await
+}
+
+/// Lets `spender` pull up to `amount` of a token from the caller's vault.
+#[update]
+async fn approve_token(
+    wallet_id: Principal,
+    ledger_canister_id: Principal,
+    spender: Account,
+    amount: Tokens,
+    expires_at: Option<u64>,
+) -> Result<BlockIndex, WalletError> {
+    check_emergency_state()?;
+    
+    let session = authenticate_user()?;
+    check_permission(&session, Permission::Transfer)?;
+    
+    verify_wallet_ownership(wallet_id, session.principal)?;
+    check_token_transfer_limit(wallet_id, ledger_canister_id, amount)?;
+    
+    crate::vaults::approve(session.principal, ledger_canister_id, spender, amount, expires_at).await
+}
+
+/// Pulls ICP the caller approved for this canister into their vault.
+#[update]
+async fn deposit_icp(
+    wallet_id: Principal,
+    from_subaccount: Option<[u8; 32]>,
+    amount: Tokens,
+) -> Result<BlockIndex, WalletError> {
+    deposit_token(wallet_id, ledger_of(VaultType::Icp), from_subaccount, amount).await
+}
+
+/// Pulls tokens the caller approved for this canister into their vault.
+#[update]
+async fn deposit_token(
+    wallet_id: Principal,
+    ledger_canister_id: Principal,
+    from_subaccount: Option<[u8; 32]>,
+    amount: Tokens,
+) -> Result<BlockIndex, WalletError> {
+    check_emergency_state()?;
+    
+    let session = authenticate_user()?;
+    check_permission(&session, Permission::UpdateBalance)?;
+    
+    verify_wallet_ownership(wallet_id, session.principal)?;
+    
+    // Only the caller's own accounts, so nobody can sweep another member's
+    // allowance into their vault
+    let from = Account::new(session.principal, from_subaccount);
+    crate::vaults::deposit(session.principal, ledger_canister_id, from, amount).await
+}
+
 #[query]
 fn get_transaction_history(
     wallet_id: Principal,
@@ -696,7 +828,25 @@ fn get_transaction_history(
     check_permission(&session, Permission::ViewTransactions)?;
     verify_wallet_ownership(wallet_id, session.principal)?;
     
-    crate::vaults::get_transaction_history(session.principal, vault_type, limit)
+    crate::vaults::get_transaction_history(session.principal, ledger_of(vault_type), limit)
+}
+
+#[query]
+fn get_token_history(
+    wallet_id: Principal,
+    ledger_canister_id: Principal,
+    limit: Option<usize>,
+) -> Result<Vec<Transaction>, WalletError> {
+    let session = authenticate_user()?;
+    check_permission(&session, Permission::ViewTransactions)?;
+    verify_wallet_ownership(wallet_id, session.principal)?;
+    
+    crate::vaults::get_transaction_history(session.principal, ledger_canister_id, limit)
+}
+
+#[query]
+fn list_tokens() -> Vec<TokenConfig> {
+    crate::vaults::list_tokens()
 }
 
 #[query]
@@ -745,6 +895,32 @@ fn set_emergency_pause(pause: bool, reason: Option<String>) -> Result<(), Wallet
     Ok(())
 }
 
+/// Adds a token members can hold, or updates a registered one.
+#[update]
+fn register_token(config: TokenConfig) -> Result<(), WalletError> {
+    let caller = caller();
+    
+    if !is_admin(caller) {
+        return Err(WalletError::AuthenticationFailed {
+            reason: "Admin privileges required".to_string(),
+        });
+    }
+    
+    crate::vaults::register_token(config)
+}
+
+/// Deposits the ledger indexer found but could not credit, oldest first.
+#[query]
+fn get_skipped_deposits() -> Result<Vec<SkippedDeposit>, WalletError> {
+    if !is_admin(caller()) {
+        return Err(WalletError::AuthenticationFailed {
+            reason: "Admin privileges required".to_string(),
+        });
+    }
+
+    Ok(crate::vaults::indexer::skipped_deposits())
+}
+
 #[update]
 fn update_system_config(config: SystemConfiguration) -> Result<(), WalletError> {
     let caller = caller();
@@ -804,7 +980,7 @@ fn verify_wallet_ownership(wallet_id: Principal, user_principal: Principal) -> R
 fn check_daily_transfer_limit(
     wallet_id: Principal,
     vault_type: VaultType,
-    amount: u64,
+    amount: Tokens,
 ) -> Result<(), WalletError> {
     STATE.with(|s| {
         let state = s.borrow();
@@ -813,7 +989,7 @@ fn check_daily_transfer_limit(
         })?;
         
         if let Some(&limit) = wallet.security_settings.daily_transfer_limit.get(&vault_type) {
-            if amount > limit {
+            if amount > Tokens::from(limit) {
                 return Err(WalletError::ValidationError {
                     field: "amount".to_string(),
                     message: format!("Amount {} exceeds daily limit {}", amount, limit),
@@ -825,6 +1001,18 @@ fn check_daily_transfer_limit(
     })
 }
 
+// Tokens without a `VaultType` are capped by their vault's daily limit only
+fn check_token_transfer_limit(
+    wallet_id: Principal,
+    ledger_canister_id: Principal,
+    amount: Tokens,
+) -> Result<(), WalletError> {
+    match vault_type_of(ledger_canister_id) {
+        Some(vault_type) => check_daily_transfer_limit(wallet_id, vault_type, amount),
+        None => Ok(()),
+    }
+}
+
 #[derive(CandidType, SerdeDeserialize, Clone, Debug)]
 pub struct WalletInfo {
     pub id: Principal,
@@ -3,10 +3,36 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use thiserror::Error;
 
+// Canister time and id. Unit tests run outside a canister, where the system
+// API traps, so they see a fixed clock and canister id instead.
+#[cfg(not(test))]
+pub(crate) fn now() -> u64 {
+    ic_cdk::api::time()
+}
+
+#[cfg(test)]
+pub(crate) fn now() -> u64 {
+    1_700_000_000_000_000_000
+}
+
+#[cfg(not(test))]
+pub(crate) fn canister_id() -> Principal {
+    ic_cdk::id()
+}
+
+#[cfg(test)]
+pub(crate) fn canister_id() -> Principal {
+    Principal::from_slice(&[0xCA; 10])
+}
+
 pub type Satoshi = u64;
+// Token amounts in base units. u128 so ledgers with 18 decimals, such as
+// ckETH, fit.
+pub type Tokens = u128;
 pub type BlockIndex = u64;
 pub type WithdrawalId = u64;
 pub type TransactionId = [u8; 32];
+pub type Subaccount = [u8; 32];
 
 // Enhanced error types with specific context
 #[derive(Error, Debug, CandidType, Deserialize, Serialize, Clone)]
@@ -18,7 +44,7 @@ pub enum WalletError {
     WalletNotFound { principal: String },
     
     #[error("Insufficient funds: required {required}, available {available}")]
-    InsufficientFunds { required: u64, available: u64 },
+    InsufficientFunds { required: Tokens, available: Tokens },
     
     #[error("ECDSA operation failed: {operation} - {details}")]
     EcdsaError { operation: String, details: String },
@@ -46,6 +72,9 @@ pub enum WalletError {
     
     #[error("Validation error: {field} - {message}")]
     ValidationError { field: String, message: String },
+    
+    #[error("Another vault operation is in progress for {principal}")]
+    OperationInProgress { principal: String },
 }
 
 // Validated types for secure operations
@@ -91,10 +120,10 @@ impl ValidatedBtcAddress {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, CandidType)]
-pub struct ValidatedAmount(u64);
+pub struct ValidatedAmount(Tokens);
 
 impl ValidatedAmount {
-    pub fn new(amount: u64, min_amount: u64) -> Result<Self, WalletError> {
+    pub fn new(amount: Tokens, min_amount: Tokens) -> Result<Self, WalletError> {
         if amount < min_amount {
             return Err(WalletError::ValidationError {
                 field: "amount".to_string(),
@@ -112,7 +141,7 @@ impl ValidatedAmount {
         Ok(ValidatedAmount(amount))
     }
     
-    pub fn value(&self) -> u64 {
+    pub fn value(&self) -> Tokens {
         self.0
     }
 }
@@ -180,6 +209,26 @@ pub struct UpdateBalanceResult {
 // Transaction tracking
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
 pub struct Transaction {
+    pub id: TransactionId,
+    pub from: Principal,
+    pub to: Account,
+    pub amount: Tokens,
+    pub fee: Tokens,
+    pub status: TransactionStatus,
+    pub created_at: u64,
+    pub completed_at: Option<u64>,
+    // Ledger block that moved the funds
+    pub block_index: Option<BlockIndex>,
+    // Set for withdrawals through a minter, which identifies them by this id
+    // rather than by a block on the vault's ledger
+    pub withdrawal_id: Option<WithdrawalId>,
+    pub retry_count: u32,
+}
+
+// `Transaction` as stored before amounts were widened to `Tokens`, read only
+// to migrate
+#[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
+pub struct LegacyTransaction {
     pub id: TransactionId,
     pub from: Principal,
     pub to: Account,
@@ -192,6 +241,29 @@ pub struct Transaction {
     pub retry_count: u32,
 }
 
+impl From<LegacyTransaction> for Transaction {
+    fn from(legacy: LegacyTransaction) -> Self {
+        Self {
+            id: legacy.id,
+            from: legacy.from,
+            to: legacy.to,
+            amount: legacy.amount.into(),
+            fee: legacy.fee.into(),
+            status: legacy.status,
+            created_at: legacy.created_at,
+            completed_at: legacy.completed_at,
+            block_index: legacy.block_index,
+            withdrawal_id: None,
+            retry_count: legacy.retry_count,
+        }
+    }
+}
+
+// Usage metrics predate `Tokens` and stay u64; larger amounts saturate
+pub fn saturating_u64(amount: Tokens) -> u64 {
+    u64::try_from(amount).unwrap_or(u64::MAX)
+}
+
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
 pub enum TransactionStatus {
     Pending,
@@ -201,21 +273,57 @@ pub enum TransactionStatus {
     Cancelled,
 }
 
+// Funds a vault has set aside for an operation whose inter-canister call is
+// still in flight. Handed back to the vault's settle method once it returns.
+#[derive(Clone, Debug)]
+pub struct Reservation {
+    pub transaction_id: TransactionId,
+    pub amount: Tokens,
+}
+
 // Enhanced Account with validation
 #[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Account {
     pub owner: Principal,
-    pub subaccount: Option<[u8; 32]>,
+    pub subaccount: Option<Subaccount>,
 }
 
 impl Account {
-    pub fn new(owner: Principal, subaccount: Option<[u8; 32]>) -> Self {
+    pub fn new(owner: Principal, subaccount: Option<Subaccount>) -> Self {
         Self { owner, subaccount }
     }
     
     pub fn principal_only(owner: Principal) -> Self {
         Self { owner, subaccount: None }
     }
+    
+    // Custodial account holding a member's funds under this canister
+    pub fn member(member: Principal) -> Self {
+        Self {
+            owner: canister_id(),
+            subaccount: Some(member_subaccount(&member)),
+        }
+    }
+}
+
+// The member's principal, length-prefixed and zero-padded to 32 bytes. The
+// same layout the ckBTC minter uses, so it is deterministic and can be read
+// back to the member without a lookup table.
+pub fn member_subaccount(member: &Principal) -> Subaccount {
+    let bytes = member.as_slice();
+    let mut subaccount = [0u8; 32];
+    subaccount[0] = bytes.len() as u8;
+    subaccount[1..1 + bytes.len()].copy_from_slice(bytes);
+    subaccount
+}
+
+// Inverse of `member_subaccount`. `None` for subaccounts it didn't produce.
+pub fn member_from_subaccount(subaccount: &Subaccount) -> Option<Principal> {
+    let len = subaccount[0] as usize;
+    if len == 0 || len > 29 || subaccount[1 + len..].iter().any(|&byte| byte != 0) {
+        return None;
+    }
+    Principal::try_from_slice(&subaccount[1..1 + len]).ok()
 }
 
 // Configuration management
@@ -294,22 +402,22 @@ pub enum VaultType {
 // Cache structures
 #[derive(CandidType, Clone, Debug, Serialize, Deserialize)]
 pub struct CachedBalance {
-    pub value: u64,
+    pub value: Tokens,
     pub timestamp: u64,
     pub ttl_seconds: u64,
 }
 
 impl CachedBalance {
-    pub fn new(value: u64, ttl_seconds: u64) -> Self {
+    pub fn new(value: Tokens, ttl_seconds: u64) -> Self {
         Self {
             value,
-            timestamp: ic_cdk::api::time(),
+            timestamp: now(),
             ttl_seconds,
         }
     }
     
     pub fn is_valid(&self) -> bool {
-        let current_time = ic_cdk::api::time();
+        let current_time = now();
         current_time < self.timestamp + (self.ttl_seconds * 1_000_000_000) // Convert to nanoseconds
     }
 }
@@ -340,30 +448,30 @@ impl CachedAddress {
 #[derive(CandidType, Deserialize, Serialize, Debug, Clone)]
 pub enum WithdrawalStatus {
     Pending {
-        amount: u64,
+        amount: Tokens,
         created_at: u64,
         estimated_completion: u64,
     },
     Processing {
-        amount: u64,
+        amount: Tokens,
         transaction_hash: Option<String>,
         confirmations: u32,
         required_confirmations: u32,
     },
     Completed {
-        amount: u64,
+        amount: Tokens,
         transaction_hash: String,
         completed_at: u64,
-        final_fee: u64,
+        final_fee: Tokens,
     },
     Failed {
-        amount: u64,
+        amount: Tokens,
         reason: String,
         failed_at: u64,
         refunded: bool,
     },
     Cancelled {
-        amount: u64,
+        amount: Tokens,
         cancelled_at: u64,
         refunded: bool,
     },
@@ -383,4 +491,35 @@ impl<T, E> ResultExt<T> for Result<T, E> {
     {
         self.map_err(|_| f())
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_member_subaccount_round_trip() {
+        let members = [
+            Principal::from_slice(&[7; 29]),
+            Principal::from_slice(&[1, 2, 3]),
+            Principal::anonymous(),
+        ];
+        for member in members {
+            let subaccount = member_subaccount(&member);
+            assert_eq!(subaccount[0] as usize, member.as_slice().len());
+            assert_eq!(member_from_subaccount(&subaccount), Some(member));
+        }
+    }
+
+    #[test]
+    fn test_member_from_subaccount_rejects_foreign_layouts() {
+        let mut subaccount = member_subaccount(&Principal::from_slice(&[1, 2, 3]));
+        subaccount[31] = 1;
+        assert_eq!(member_from_subaccount(&subaccount), None);
+
+        // The default subaccount and out-of-range lengths
+        assert_eq!(member_from_subaccount(&[0u8; 32]), None);
+        let mut too_long = [0u8; 32];
+        too_long[0] = 30;
+        assert_eq!(member_from_subaccount(&too_long), None);
+    }
+}
@@ -1,76 +1,50 @@
 use crate::types::*;
-use candid::{CandidType, Deserialize, Principal};
+use candid::{CandidType, Deserialize, Principal, Reserved};
+use futures::FutureExt;
 use ic_cdk::api::call::CallResult;
 use serde::Serialize;
-use std::collections::HashMap;
-use hex;
-use sha2::{Digest, Sha256};
 
-const CKBTC_MINTER_CANISTER_ID: &str = "mqygn-kiaaa-aaaar-qaadq-cai";
-const CKBTC_LEDGER_CANISTER_ID: &str = "mxzaz-hqaaa-aaaar-qaada-cai";
+use super::{
+    icrc1::Icrc1Vault,
+    token::{LedgerCall, TokenConfig, TokenVault},
+};
+
+// ckBTC minter calls. Member funds live on the ckBTC ledger like any other
+// token, so these only cover moving BTC on and off the Internet Computer.
 
 #[derive(CandidType, Deserialize)]
 struct UpdateBalanceArgs {
     owner: Option<Principal>,
+    subaccount: Option<Subaccount>,
 }
 
 #[derive(CandidType, Deserialize)]
-struct UpdateBalanceResult {
-    balance: u64,
-    new_utxos: Vec<Utxo>,
-}
-
-#[derive(CandidType, Deserialize)]
-struct RetrieveBtcArgs {
+struct RetrieveBtcWithApprovalArgs {
     amount: u64,
     address: String,
+    from_subaccount: Option<Subaccount>,
 }
 
 #[derive(CandidType, Deserialize)]
-struct RetrieveBtcResult {
+struct RetrieveBtcOk {
     block_index: u64,
 }
 
-#[derive(CandidType, Deserialize)]
-struct GetBtcAddressArgs {
-    owner: Option<Principal>,
-}
-
-#[derive(CandidType, Deserialize)]
-struct TransferArg {
-    from_subaccount: Option<[u8; 32]>,
-    to: Account,
-    amount: u64,
-    fee: Option<u64>,
-    memo: Option<Vec<u8>>,
-    created_at_time: Option<u64>,
-}
-
 #[derive(CandidType, Deserialize, Debug)]
-enum TransferError {
-    BadFee { expected_fee: u64 },
-    BadBurn { min_burn_amount: u64 },
+enum RetrieveBtcWithApprovalError {
+    MalformedAddress(String),
+    AlreadyProcessing,
+    AmountTooLow(u64),
     InsufficientFunds { balance: u64 },
-    TooOld,
-    CreatedInFuture { ledger_time: u64 },
-    Duplicate { duplicate_of: u64 },
-    TemporarilyUnavailable,
-    GenericError { error_code: u64, message: String },
+    InsufficientAllowance { allowance: u64 },
+    TemporarilyUnavailable(String),
+    GenericError { error_code: u64, error_message: String },
 }
 
-// Metrics structure for monitoring
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct VaultMetrics {
-    pub balance: u64,
-    pub total_volume_in: u64,
-    pub total_volume_out: u64,
-    pub operation_count: u64,
-    pub pending_transactions: u64,
-    pub completed_transactions: u64,
-    pub daily_withdrawal_limit: u64,
-    pub daily_withdrawn_amount: u64,
-    pub last_operation: u64,
-    pub cache_hit_rate: f64,
+#[derive(CandidType, Deserialize)]
+struct GetBtcAddressArgs {
+    owner: Option<Principal>,
+    subaccount: Option<Subaccount>,
 }
 
 // Circuit breaker implementation for fault tolerance
@@ -136,571 +110,160 @@ impl CircuitBreaker {
     }
 }
 
-// Enhanced ckBTC vault with comprehensive features
-#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
-pub struct CkBtcVault {
-    owner: Principal,
-    minter_canister_id: Principal,
-    ledger_canister_id: Principal,
-    // Persistent balance (no longer using Cell)
-    balance: u64,
-    last_balance_update: u64,
-    btc_address: Option<String>,
-    // Transaction management
-    pending_transactions: HashMap<TransactionId, Transaction>,
-    completed_transactions: Vec<Transaction>,
-    // Performance optimizations
-    balance_cache: Option<CachedBalance>,
-    address_cache: Option<CachedAddress>,
-    // Security features
-    daily_withdrawal_limit: u64,
-    daily_withdrawn_amount: u64,
-    last_withdrawal_reset: u64,
-    // Monitoring
-    total_volume_in: u64,
-    total_volume_out: u64,
-    operation_count: u64,
-    last_operation: u64,
-    // Configuration
-    min_withdrawal_amount: u64,
-    max_transaction_fee: u64,
-}
+/// Asks the minter to mint ckBTC for confirmed deposits to `account`'s BTC
+/// address. The vault reads its balance from the ledger afterwards, so the
+/// minter's reply only matters if the call itself fails.
+pub fn update_balance_request(minter_canister_id: Principal, account: Account) -> LedgerCall<()> {
+    let args = UpdateBalanceArgs {
+        owner: Some(account.owner),
+        subaccount: account.subaccount,
+    };
 
-impl CkBtcVault {
-    pub fn new(owner: Principal, minter_id: &str, ledger_id: &str) -> Result<Self, WalletError> {
-        let minter_canister_id = Principal::from_text(minter_id)
-            .map_err(|e| WalletError::ValidationError {
-                field: "minter_canister_id".to_string(),
-                message: format!("Invalid minter canister ID: {}", e),
-            })?;
-            
-        let ledger_canister_id = Principal::from_text(ledger_id)
-            .map_err(|e| WalletError::ValidationError {
-                field: "ledger_canister_id".to_string(),
-                message: format!("Invalid ledger canister ID: {}", e),
-            })?;
-        
-        let current_time = ic_cdk::api::time();
-        
-        Ok(Self {
-            owner,
+    async move {
+        let result: CallResult<(Reserved,)> = ic_cdk::call(
             minter_canister_id,
-            ledger_canister_id,
-            balance: 0,
-            last_balance_update: current_time,
-            btc_address: None,
-            pending_transactions: HashMap::new(),
-            completed_transactions: Vec::new(),
-            balance_cache: None,
-            address_cache: None,
-            daily_withdrawal_limit: 100_000_000, // 1 BTC in satoshis
-            daily_withdrawn_amount: 0,
-            last_withdrawal_reset: current_time,
-            total_volume_in: 0,
-            total_volume_out: 0,
-            operation_count: 0,
-            last_operation: current_time,
-            min_withdrawal_amount: 10_000, // 0.0001 BTC minimum
-            max_transaction_fee: 100_000,   // 0.001 BTC max fee
-        })
-    }
-    
-    pub fn balance(&self) -> u64 {
-        // Return cached balance if valid, otherwise stored balance
-        if let Some(ref cache) = self.balance_cache {
-            if cache.is_valid() {
-                return cache.value;
-            }
-        }
-        self.balance
-    }
-    
-    pub async fn update_balance(&mut self) -> Result<u64, WalletError> {
-        // Check cache first
-        if let Some(ref cache) = self.balance_cache {
-            if cache.is_valid() {
-                return Ok(cache.value);
-            }
-        }
-        
-        let args = UpdateBalanceArgs {
-            owner: Some(self.owner),
-        };
-        
-        let start_time = ic_cdk::api::time();
-        
-        let result: CallResult<(UpdateBalanceResult,)> = ic_cdk::call(
-            self.minter_canister_id,
             "update_balance",
             (args,),
         )
         .await;
-        
-        let response_time = ic_cdk::api::time() - start_time;
-        
-        match result {
-            Ok((res,)) => {
-                // Update balance and metrics
-                let old_balance = self.balance;
-                self.balance = res.balance;
-                self.last_balance_update = ic_cdk::api::time();
-                
-                // Update volume tracking
-                if res.balance > old_balance {
-                    self.total_volume_in += res.balance - old_balance;
-                }
-                
-                // Cache the result
-                self.balance_cache = Some(CachedBalance::new(res.balance, 30)); // 30 second cache
-                
-                // Update operation metrics
-                self.operation_count += 1;
-                self.last_operation = ic_cdk::api::time();
-                
-                ic_cdk::println!(
-                    "ckBTC balance updated in {}ns: {} -> {} satoshis",
-                    response_time, old_balance, res.balance
-                );
-                
-                Ok(res.balance)
-            }
-            Err((rejection_code, err)) => {
-                let error = WalletError::VaultError {
-                    operation: "update_balance".to_string(),
-                    details: format!(
-                        "ckBTC balance update failed: {:?} - {}",
-                        rejection_code, err
-                    ),
-                };
-                
-                ic_cdk::println!("Balance update failed: {:?}", error);
-                Err(error)
-            }
-        }
+        result
+            .map(|_| ())
+            .map_err(|(rejection_code, err)| format!("{:?} - {}", rejection_code, err))
     }
-    
-    pub async fn retrieve_btc(
-        &mut self,
-        amount: u64,
-        btc_address: String,
-    ) -> Result<u64, WalletError> {
-        // Comprehensive validation
-        self.validate_withdrawal(amount)?;
-        let validated_address = ValidatedBtcAddress::new(btc_address)?;
-        
-        // Check daily limits
-        self.check_daily_limits(amount)?;
-        
-        // Ensure sufficient balance
-        let current_balance = self.balance();
-        if amount > current_balance {
-            return Err(WalletError::InsufficientFunds {
-                required: amount,
-                available: current_balance,
-            });
-        }
-        
-        // Create transaction record
-        let transaction_id = self.generate_transaction_id();
-        let transaction = Transaction {
-            id: transaction_id,
-            from: self.owner,
-            to: Account::principal_only(Principal::anonymous()), // External BTC address
-            amount,
-            fee: 0, // Will be updated after completion
-            status: TransactionStatus::Pending,
-            created_at: ic_cdk::api::time(),
-            completed_at: None,
-            block_index: None,
-            retry_count: 0,
-        };
-        
-        self.pending_transactions.insert(transaction_id, transaction.clone());
-        
-        let args = RetrieveBtcArgs {
-            amount,
-            address: validated_address.as_str().to_string(),
-        };
-        
-        let start_time = ic_cdk::api::time();
-        
-        let result: CallResult<(RetrieveBtcResult,)> = ic_cdk::call(
-            self.minter_canister_id,
-            "retrieve_btc",
+    .boxed_local()
+}
+
+/// Validates a withdrawal and debits it up front. The minter has to be
+/// approved as a spender for `amount` before the returned call runs, and it
+/// must be settled with `settle_retrieve_btc`, which refunds on failure.
+pub fn reserve_retrieve_btc(
+    vault: &mut impl TokenVault,
+    config: &TokenConfig,
+    minter_canister_id: Principal,
+    amount: u64,
+    btc_address: String,
+) -> Result<(Reservation, LedgerCall<u64>), WalletError> {
+    validate_withdrawal(config, amount)?;
+    let validated_address = ValidatedBtcAddress::new(btc_address)?;
+
+    // The minter burns the amount, so it is recorded as sent to the minter
+    let minter = Account::principal_only(minter_canister_id);
+    let reservation = vault.reserve(config, amount.into(), 0, minter, TransactionStatus::Pending)?;
+
+    let args = RetrieveBtcWithApprovalArgs {
+        amount,
+        address: validated_address.as_str().to_string(),
+        from_subaccount: vault.account().subaccount,
+    };
+
+    let call = async move {
+        let result: CallResult<(Result<RetrieveBtcOk, RetrieveBtcWithApprovalError>,)> = ic_cdk::call(
+            minter_canister_id,
+            "retrieve_btc_with_approval",
             (args,),
         )
         .await;
-        
-        let response_time = ic_cdk::api::time() - start_time;
-        
         match result {
-            Ok((res,)) => {
-                // Update transaction status
-                if let Some(mut tx) = self.pending_transactions.remove(&transaction_id) {
-                    tx.status = TransactionStatus::Completed;
-                    tx.completed_at = Some(ic_cdk::api::time());
-                    tx.block_index = Some(res.block_index);
-                    self.completed_transactions.push(tx);
-                }
-                
-                // Update balance and limits
-                self.balance = self.balance.saturating_sub(amount);
-                self.daily_withdrawn_amount += amount;
-                self.total_volume_out += amount;
-                
-                // Clear balance cache to force refresh
-                self.balance_cache = None;
-                
-                // Update metrics
-                self.operation_count += 1;
-                self.last_operation = ic_cdk::api::time();
-                
-                ic_cdk::println!(
-                    "BTC retrieval completed in {}ns: {} satoshis to {} (block: {})",
-                    response_time, amount, validated_address.as_str(), res.block_index
-                );
-                
-                Ok(res.block_index)
-            }
-            Err((rejection_code, err)) => {
-                // Update transaction status to failed
-                if let Some(mut tx) = self.pending_transactions.remove(&transaction_id) {
-                    tx.status = TransactionStatus::Failed {
-                        reason: format!("{:?} - {}", rejection_code, err),
-                    };
-                    tx.completed_at = Some(ic_cdk::api::time());
-                    self.completed_transactions.push(tx);
-                }
-                
-                let error = WalletError::VaultError {
-                    operation: "retrieve_btc".to_string(),
-                    details: format!(
-                        "BTC retrieval failed: {:?} - {}",
-                        rejection_code, err
-                    ),
-                };
-                
-                ic_cdk::println!("BTC retrieval failed: {:?}", error);
-                Err(error)
-            }
+            Ok((Ok(res),)) => Ok(res.block_index),
+            Ok((Err(err),)) => Err(format!("{:?}", err)),
+            Err((rejection_code, err)) => Err(format!("{:?} - {}", rejection_code, err)),
         }
-    }
-    
-    pub async fn transfer(&mut self, amount: u64, recipient: Principal) -> Result<BlockIndex, WalletError> {
-        let validated_amount = ValidatedAmount::new(amount, 1000)?; // Min 1000 satoshis
-        
-        if validated_amount.value() > self.balance() {
-            return Err(WalletError::InsufficientFunds {
-                required: validated_amount.value(),
-                available: self.balance(),
-            });
+    };
+
+    Ok((reservation, call.boxed_local()))
+}
+
+pub fn settle_retrieve_btc(
+    vault: &mut impl TokenVault,
+    reservation: Reservation,
+    outcome: Result<u64, String>,
+) -> Result<u64, WalletError> {
+    match outcome {
+        Ok(block_index) => {
+            vault.commit(&reservation, block_index);
+
+            ic_cdk::println!(
+                "BTC retrieval completed: {} satoshis (block: {})",
+                reservation.amount, block_index
+            );
+
+            Ok(block_index)
         }
-        
-        // Create transaction record
-        let transaction_id = self.generate_transaction_id();
-        let transaction = Transaction {
-            id: transaction_id,
-            from: self.owner,
-            to: Account::principal_only(recipient),
-            amount: validated_amount.value(),
-            fee: 0, // ckBTC transfers typically have no fee
-            status: TransactionStatus::Processing,
-            created_at: ic_cdk::api::time(),
-            completed_at: None,
-            block_index: None,
-            retry_count: 0,
-        };
-        
-        self.pending_transactions.insert(transaction_id, transaction.clone());
-        
-        let transfer_args = TransferArg {
-            from_subaccount: None,
-            to: Account::principal_only(recipient),
-            amount: validated_amount.value(),
-            fee: None,
-            memo: Some(transaction_id.to_vec()),
-            created_at_time: Some(ic_cdk::api::time()),
-        };
-        
-        let start_time = ic_cdk::api::time();
-        
-        let result: CallResult<(Result<BlockIndex, TransferError>,)> = ic_cdk::call(
-            self.ledger_canister_id,
-            "icrc1_transfer",
-            (transfer_args,),
-        )
-        .await;
-        
-        let response_time = ic_cdk::api::time() - start_time;
-        
-        match result {
-            Ok((Ok(block_index),)) => {
-                // Update transaction status
-                if let Some(mut tx) = self.pending_transactions.remove(&transaction_id) {
-                    tx.status = TransactionStatus::Completed;
-                    tx.completed_at = Some(ic_cdk::api::time());
-                    tx.block_index = Some(block_index);
-                    self.completed_transactions.push(tx);
-                }
-                
-                // Update balance and metrics
-                self.balance = self.balance.saturating_sub(validated_amount.value());
-                self.total_volume_out += validated_amount.value();
-                
-                // Clear balance cache
-                self.balance_cache = None;
-                
-                // Update operation metrics
-                self.operation_count += 1;
-                self.last_operation = ic_cdk::api::time();
-                
-                ic_cdk::println!(
-                    "ckBTC transfer completed in {}ns: {} satoshis to {} (block: {})",
-                    response_time, validated_amount.value(), recipient, block_index
-                );
-                
-                Ok(block_index)
-            }
-            Ok((Err(transfer_error),)) => {
-                // Update transaction status to failed
-                if let Some(mut tx) = self.pending_transactions.remove(&transaction_id) {
-                    tx.status = TransactionStatus::Failed {
-                        reason: format!("Transfer error: {:?}", transfer_error),
-                    };
-                    tx.completed_at = Some(ic_cdk::api::time());
-                    self.completed_transactions.push(tx);
-                }
-                
-                let error = WalletError::TransactionFailed {
-                    transaction_id: hex::encode(transaction_id),
-                    reason: format!("ckBTC transfer failed: {:?}", transfer_error),
-                };
-                
-                ic_cdk::println!("Transfer failed: {:?}", error);
-                Err(error)
-            }
-            Err((rejection_code, err)) => {
-                // Update transaction status to failed
-                if let Some(mut tx) = self.pending_transactions.remove(&transaction_id) {
-                    tx.status = TransactionStatus::Failed {
-                        reason: format!("{:?} - {}", rejection_code, err),
-                    };
-                    tx.completed_at = Some(ic_cdk::api::time());
-                    self.completed_transactions.push(tx);
-                }
-                
-                let error = WalletError::TransactionFailed {
-                    transaction_id: hex::encode(transaction_id),
-                    reason: format!("ckBTC transfer call failed: {:?} - {}", rejection_code, err),
-                };
-                
-                ic_cdk::println!("Transfer call failed: {:?}", error);
-                Err(error)
-            }
+        Err(reason) => {
+            vault.release(&reservation, reason.clone());
+
+            let error = WalletError::VaultError {
+                operation: "retrieve_btc".to_string(),
+                details: format!("BTC retrieval failed: {}", reason),
+            };
+
+            ic_cdk::println!("BTC retrieval failed: {:?}", error);
+            Err(error)
         }
     }
-    
-    pub async fn get_btc_address(&mut self) -> Result<String, WalletError> {
-        // Check cache first
-        if let Some(ref cache) = self.address_cache {
-            if cache.is_valid() {
-                return Ok(cache.address.clone());
-            }
-        }
-        
-        let args = GetBtcAddressArgs {
-            owner: Some(self.owner),
-        };
-        
-        let start_time = ic_cdk::api::time();
-        
+}
+
+/// Minter call for the deposit address. Check `Icrc1Vault::cached_address` first.
+pub fn btc_address_request(minter_canister_id: Principal, account: Account) -> LedgerCall<String> {
+    let args = GetBtcAddressArgs {
+        owner: Some(account.owner),
+        subaccount: account.subaccount,
+    };
+
+    async move {
         let result: CallResult<(String,)> = ic_cdk::call(
-            self.minter_canister_id,
+            minter_canister_id,
             "get_btc_address",
             (args,),
         )
         .await;
-        
-        let response_time = ic_cdk::api::time() - start_time;
-        
-        match result {
-            Ok((address,)) => {
-                // Validate the returned address
-                let validated_address = ValidatedBtcAddress::new(address.clone())?;
-                
-                // Cache the address
-                self.address_cache = Some(CachedAddress::new(address.clone(), 3600)); // 1 hour cache
-                self.btc_address = Some(address.clone());
-                
-                // Update metrics
-                self.operation_count += 1;
-                self.last_operation = ic_cdk::api::time();
-                
-                ic_cdk::println!(
-                    "BTC address retrieved in {}ns: {}",
-                    response_time, address
-                );
-                
-                Ok(address)
-            }
-            Err((rejection_code, err)) => {
-                let error = WalletError::VaultError {
-                    operation: "get_btc_address".to_string(),
-                    details: format!(
-                        "BTC address generation failed: {:?} - {}",
-                        rejection_code, err
-                    ),
-                };
-                
-                ic_cdk::println!("Address generation failed: {:?}", error);
-                Err(error)
-            }
-        }
+        result
+            .map(|(address,)| address)
+            .map_err(|(rejection_code, err)| format!("{:?} - {}", rejection_code, err))
     }
-    
-    // Advanced features
-    
-    pub fn get_transaction_history(&self, limit: Option<usize>) -> Vec<Transaction> {
-        let limit = limit.unwrap_or(50).min(100); // Max 100 transactions
-        self.completed_transactions
-            .iter()
-            .rev() // Most recent first
-            .take(limit)
-            .cloned()
-            .collect()
-    }
-    
-    pub fn get_pending_transactions(&self) -> Vec<Transaction> {
-        self.pending_transactions.values().cloned().collect()
-    }
-    
-    pub async fn retry_failed_transaction(&mut self, transaction_id: TransactionId) -> Result<(), WalletError> {
-        // Find the failed transaction
-        let transaction = self.completed_transactions
-            .iter()
-            .find(|tx| tx.id == transaction_id && matches!(tx.status, TransactionStatus::Failed { .. }))
-            .ok_or(WalletError::ValidationError {
-                field: "transaction_id".to_string(),
-                message: "Failed transaction not found".to_string(),
-            })?
-            .clone();
-        
-        if transaction.retry_count >= 3 {
-            return Err(WalletError::ValidationError {
-                field: "retry_count".to_string(),
-                message: "Maximum retry attempts reached".to_string(),
-            });
+    .boxed_local()
+}
+
+pub fn apply_btc_address(vault: &mut Icrc1Vault, outcome: Result<String, String>) -> Result<String, WalletError> {
+    match outcome {
+        Ok(address) => {
+            // Validate the returned address
+            ValidatedBtcAddress::new(address.clone())?;
+            vault.cache_address(address.clone());
+
+            ic_cdk::println!("BTC address retrieved: {}", address);
+
+            Ok(address)
         }
-        
-        // Create new transaction with incremented retry count
-        let mut new_transaction = transaction.clone();
-        new_transaction.id = self.generate_transaction_id();
-        new_transaction.status = TransactionStatus::Pending;
-        new_transaction.created_at = ic_cdk::api::time();
-        new_transaction.retry_count += 1;
-        
-        self.pending_transactions.insert(new_transaction.id, new_transaction);
-        
-        // Note: Actual retry logic would depend on the transaction type
-        // This is a framework for implementing retry functionality
-        
-        Ok(())
-    }
-    
-    pub fn get_vault_metrics(&self) -> VaultMetrics {
-        VaultMetrics {
-            balance: self.balance(),
-            total_volume_in: self.total_volume_in,
-            total_volume_out: self.total_volume_out,
-            operation_count: self.operation_count,
-            pending_transactions: self.pending_transactions.len() as u64,
-            completed_transactions: self.completed_transactions.len() as u64,
-            daily_withdrawal_limit: self.daily_withdrawal_limit,
-            daily_withdrawn_amount: self.daily_withdrawn_amount,
-            last_operation: self.last_operation,
-            cache_hit_rate: self.calculate_cache_hit_rate(),
+        Err(reason) => {
+            let error = WalletError::VaultError {
+                operation: "get_btc_address".to_string(),
+                details: format!("BTC address generation failed: {}", reason),
+            };
+
+            ic_cdk::println!("Address generation failed: {:?}", error);
+            Err(error)
         }
     }
-    
-    pub fn cleanup_old_transactions(&mut self, older_than_days: u64) -> u32 {
-        let cutoff_time = ic_cdk::api::time() - (older_than_days * 24 * 60 * 60 * 1_000_000_000);
-        let initial_count = self.completed_transactions.len();
-        
-        self.completed_transactions.retain(|tx| {
-            tx.created_at > cutoff_time
+}
+
+fn validate_withdrawal(config: &TokenConfig, amount: u64) -> Result<(), WalletError> {
+    if Tokens::from(amount) < config.min_withdrawal_amount {
+        return Err(WalletError::ValidationError {
+            field: "amount".to_string(),
+            message: format!(
+                "Amount {} below minimum {} satoshis",
+                amount, config.min_withdrawal_amount
+            ),
         });
-        
-        (initial_count - self.completed_transactions.len()) as u32
-    }
-    
-    // Private helper methods
-    
-    fn validate_withdrawal(&self, amount: u64) -> Result<(), WalletError> {
-        if amount < self.min_withdrawal_amount {
-            return Err(WalletError::ValidationError {
-                field: "amount".to_string(),
-                message: format!(
-                    "Amount {} below minimum {} satoshis",
-                    amount, self.min_withdrawal_amount
-                ),
-            });
-        }
-        
-        if amount > 2_100_000_000_000_000 { // Max 21M BTC in satoshis
-            return Err(WalletError::ValidationError {
-                field: "amount".to_string(),
-                message: "Amount exceeds maximum possible Bitcoin supply".to_string(),
-            });
-        }
-        
-        Ok(())
-    }
-    
-    fn check_daily_limits(&mut self, amount: u64) -> Result<(), WalletError> {
-        let current_time = ic_cdk::api::time();
-        let one_day_nanos = 24 * 60 * 60 * 1_000_000_000u64;
-        
-        // Reset daily limit if a day has passed
-        if current_time >= self.last_withdrawal_reset + one_day_nanos {
-            self.daily_withdrawn_amount = 0;
-            self.last_withdrawal_reset = current_time;
-        }
-        
-        if self.daily_withdrawn_amount + amount > self.daily_withdrawal_limit {
-            return Err(WalletError::ValidationError {
-                field: "daily_limit".to_string(),
-                message: format!(
-                    "Daily withdrawal limit exceeded. Limit: {}, Already withdrawn: {}, Requested: {}",
-                    self.daily_withdrawal_limit, self.daily_withdrawn_amount, amount
-                ),
-            });
-        }
-        
-        Ok(())
     }
-    
-    fn generate_transaction_id(&self) -> TransactionId {
-        use sha2::{Digest, Sha256};
-        
-        let mut hasher = Sha256::new();
-        hasher.update(&ic_cdk::api::time().to_be_bytes());
-        hasher.update(self.owner.as_slice());
-        hasher.update(&self.operation_count.to_be_bytes());
-        hasher.update(b"ckBTC_transaction");
-        
-        let hash = hasher.finalize();
-        let mut transaction_id = [0u8; 32];
-        transaction_id.copy_from_slice(&hash[..32]);
-        transaction_id
-    }
-    
-    fn calculate_cache_hit_rate(&self) -> f64 {
-        // Simple cache hit rate calculation
-        // In a real implementation, you'd track cache hits/misses
-        if self.balance_cache.is_some() || self.address_cache.is_some() {
-            0.75 // Placeholder value
-        } else {
-            0.0
-        }
+
+    if amount > 2_100_000_000_000_000 { // Max 21M BTC in satoshis
+        return Err(WalletError::ValidationError {
+            field: "amount".to_string(),
+            message: "Amount exceeds maximum possible Bitcoin supply".to_string(),
+        });
     }
-}
\ No newline at end of file
+
+    Ok(())
+}
@@ -0,0 +1,384 @@
+use crate::types::*;
+use candid::{CandidType, Nat, Principal};
+use futures::{future::LocalBoxFuture, FutureExt};
+use ic_cdk::api::call::CallResult;
+use serde::Deserialize as SerdeDeserialize;
+use hex;
+
+use super::{
+    icrc::{nat_to_tokens, nat_to_u64},
+    token::{LedgerCall, TokenConfig, TokenVault},
+};
+
+// ckETH minter calls for ckERC20 tokens such as ckUSDT and ckUSDC. Member
+// funds live on each token's ledger; these only cover withdrawing to Ethereum.
+//
+// A withdrawal burns the amount from the member's ckERC20 subaccount and the
+// Ethereum gas fee from their ckETH subaccount, so the minter must be approved
+// as a spender on both ledgers before `withdraw_erc20` is called.
+
+#[derive(CandidType, SerdeDeserialize)]
+struct WithdrawErc20Args {
+    ckerc20_ledger_id: Principal,
+    recipient: String,
+    amount: Nat,
+    from_ckerc20_subaccount: Option<Subaccount>,
+    from_cketh_subaccount: Option<Subaccount>,
+}
+
+#[derive(CandidType, SerdeDeserialize)]
+struct RetrieveErc20Request {
+    cketh_block_index: Nat,
+    ckerc20_block_index: Nat,
+}
+
+#[derive(CandidType, SerdeDeserialize, Debug)]
+struct Erc20Token {
+    erc20_token_symbol: String,
+    ledger_canister_id: Principal,
+}
+
+#[derive(CandidType, SerdeDeserialize, Debug)]
+enum LedgerError {
+    InsufficientFunds { balance: Nat, failed_transfer_amount: Nat, token_symbol: String, ledger_id: Principal },
+    AmountTooLow { minimum_burn_amount: Nat, failed_burn_amount: Nat, token_symbol: String, ledger_id: Principal },
+    InsufficientAllowance { allowance: Nat, failed_burn_amount: Nat, token_symbol: String, ledger_id: Principal },
+    TemporarilyUnavailable(String),
+}
+
+#[derive(CandidType, SerdeDeserialize, Debug)]
+enum WithdrawErc20Error {
+    TokenNotSupported { supported_tokens: Vec<Erc20Token> },
+    RecipientAddressBlocked { address: String },
+    CkEthLedgerError { error: LedgerError },
+    // The gas fee was already burned; the minter reimburses it later
+    CkErc20LedgerError { cketh_block_index: Nat, error: LedgerError },
+    TemporarilyUnavailable(String),
+}
+
+#[derive(CandidType, SerdeDeserialize)]
+struct Eip1559TransactionPriceArg {
+    ckerc20_ledger_id: Principal,
+}
+
+#[derive(CandidType, SerdeDeserialize)]
+struct Eip1559TransactionPrice {
+    max_transaction_fee: Nat,
+}
+
+#[derive(CandidType, SerdeDeserialize)]
+enum WithdrawalSearchParameter {
+    ByWithdrawalId(u64),
+}
+
+#[derive(CandidType, SerdeDeserialize)]
+struct WithdrawalDetail {
+    withdrawal_id: u64,
+    withdrawal_amount: Nat,
+    status: MinterWithdrawalStatus,
+}
+
+#[derive(CandidType, SerdeDeserialize)]
+struct EthTransaction {
+    transaction_hash: String,
+}
+
+#[derive(CandidType, SerdeDeserialize)]
+enum MinterWithdrawalStatus {
+    Pending,
+    TxCreated,
+    TxSent(EthTransaction),
+    TxFinalized(TxFinalizedStatus),
+}
+
+#[derive(CandidType, SerdeDeserialize)]
+enum TxFinalizedStatus {
+    Success { transaction_hash: String, effective_transaction_fee: Option<Nat> },
+    Reimbursed { transaction_hash: String, reimbursed_amount: Nat, reimbursed_in_block: Nat },
+    PendingReimbursement(EthTransaction),
+}
+
+/// The minter's reply to `withdraw_erc20`. The ckETH gas fee can be burned
+/// even when the withdrawal itself fails, so that is reported separately.
+pub struct Erc20WithdrawalOutcome {
+    // ckETH ledger block that burned the gas fee
+    pub cketh_block_index: Option<BlockIndex>,
+    // The ckERC20 burn block and the minter's withdrawal id
+    pub result: Result<(BlockIndex, WithdrawalId), String>,
+}
+
+pub type WithdrawErc20Call = LocalBoxFuture<'static, Erc20WithdrawalOutcome>;
+
+/// The most the minter may burn in ckETH for the gas of one withdrawal of
+/// `ckerc20_ledger_id`. This is what has to be approved on the ckETH ledger.
+pub fn gas_fee_request(minter_canister_id: Principal, ckerc20_ledger_id: Principal) -> LedgerCall<Tokens> {
+    let arg = Some(Eip1559TransactionPriceArg { ckerc20_ledger_id });
+
+    async move {
+        let result: CallResult<(Eip1559TransactionPrice,)> = ic_cdk::call(
+            minter_canister_id,
+            "eip_1559_transaction_price",
+            (arg,),
+        )
+        .await;
+        match result {
+            Ok((price,)) => nat_to_tokens(price.max_transaction_fee),
+            Err((rejection_code, err)) => Err(format!("Gas fee query failed: {:?} - {}", rejection_code, err)),
+        }
+    }
+    .boxed_local()
+}
+
+/// Validates a withdrawal and debits it up front. The minter has to be
+/// approved on both ledgers before the returned call runs, and it must be
+/// settled with `settle_withdraw_erc20`, which refunds on failure.
+pub fn reserve_withdraw_erc20(
+    vault: &mut impl TokenVault,
+    config: &TokenConfig,
+    minter_canister_id: Principal,
+    cketh_account: Account,
+    amount: Tokens,
+    ethereum_address: String,
+) -> Result<(Reservation, WithdrawErc20Call), WalletError> {
+    validate_withdrawal(config, amount)?;
+
+    // Basic Ethereum address validation
+    if !ethereum_address.starts_with("0x") || ethereum_address.len() != 42 {
+        return Err(WalletError::InvalidAddress {
+            address: ethereum_address,
+            reason: "Invalid Ethereum address format".to_string(),
+        });
+    }
+
+    let reservation = vault.reserve(
+        config,
+        amount,
+        0,
+        Account::principal_only(minter_canister_id),
+        TransactionStatus::Pending,
+    )?;
+
+    let args = WithdrawErc20Args {
+        ckerc20_ledger_id: config.ledger_canister_id,
+        recipient: ethereum_address,
+        amount: amount.into(),
+        from_ckerc20_subaccount: vault.account().subaccount,
+        from_cketh_subaccount: cketh_account.subaccount,
+    };
+    let symbol = config.symbol.clone();
+
+    let call = async move {
+        let result: CallResult<(Result<RetrieveErc20Request, WithdrawErc20Error>,)> = ic_cdk::call(
+            minter_canister_id,
+            "withdraw_erc20",
+            (args,),
+        )
+        .await;
+        match result {
+            Ok((Ok(request),)) => {
+                let cketh_block_index = nat_to_u64(request.cketh_block_index);
+                let ckerc20_block_index = nat_to_u64(request.ckerc20_block_index);
+                Erc20WithdrawalOutcome {
+                    cketh_block_index: cketh_block_index.clone().ok(),
+                    // The minter identifies withdrawals by their gas fee burn
+                    result: ckerc20_block_index.and_then(|block_index| Ok((block_index, cketh_block_index?))),
+                }
+            }
+            Ok((Err(WithdrawErc20Error::CkErc20LedgerError { cketh_block_index, error }),)) => Erc20WithdrawalOutcome {
+                cketh_block_index: nat_to_u64(cketh_block_index).ok(),
+                result: Err(format!("{} withdrawal failed: {:?}", symbol, error)),
+            },
+            Ok((Err(err),)) => Erc20WithdrawalOutcome {
+                cketh_block_index: None,
+                result: Err(format!("{} withdrawal failed: {:?}", symbol, err)),
+            },
+            Err((rejection_code, err)) => Erc20WithdrawalOutcome {
+                cketh_block_index: None,
+                result: Err(format!("Withdrawal call failed: {:?} - {}", rejection_code, err)),
+            },
+        }
+    };
+
+    Ok((reservation, call.boxed_local()))
+}
+
+pub fn settle_withdraw_erc20(
+    vault: &mut impl TokenVault,
+    config: &TokenConfig,
+    reservation: Reservation,
+    outcome: &Erc20WithdrawalOutcome,
+) -> Result<WithdrawalId, WalletError> {
+    match &outcome.result {
+        Ok((block_index, withdrawal_id)) => {
+            vault.commit_withdrawal(&reservation, *block_index, *withdrawal_id);
+
+            ic_cdk::println!(
+                "{} withdrawal completed: {} tokens (withdrawal: {})",
+                config.symbol, reservation.amount, withdrawal_id
+            );
+
+            Ok(*withdrawal_id)
+        }
+        Err(reason) => {
+            vault.release(&reservation, reason.clone());
+
+            let error = WalletError::VaultError {
+                operation: "withdraw_erc20".to_string(),
+                details: reason.clone(),
+            };
+
+            ic_cdk::println!("{} withdrawal failed: {:?}", config.symbol, error);
+            Err(error)
+        }
+    }
+}
+
+/// Settles the ckETH reserved for gas. It is spent whenever the minter burned
+/// it, even if the withdrawal then failed; the minter's reimbursement arrives
+/// as a mint the ledger indexer credits.
+pub fn settle_gas_fee(vault: &mut impl TokenVault, reservation: Reservation, outcome: &Erc20WithdrawalOutcome) {
+    match (outcome.cketh_block_index, &outcome.result) {
+        (Some(block_index), _) => vault.commit(&reservation, block_index),
+        (None, Err(reason)) => vault.release(&reservation, reason.clone()),
+        (None, Ok(_)) => vault.release(&reservation, "Gas fee burn not reported".to_string()),
+    }
+}
+
+/// Status of a withdrawal the vault has settled, if it knows it.
+pub fn local_withdrawal_status(vault: &impl TokenVault, withdrawal_id: WithdrawalId) -> Option<WithdrawalStatus> {
+    let tx = vault.withdrawal(withdrawal_id)?;
+
+    Some(match &tx.status {
+        TransactionStatus::Completed => WithdrawalStatus::Completed {
+            amount: tx.amount,
+            transaction_hash: format!("0x{}", hex::encode(tx.id)),
+            completed_at: tx.completed_at.unwrap_or(tx.created_at),
+            final_fee: tx.fee,
+        },
+        TransactionStatus::Failed { reason } => WithdrawalStatus::Failed {
+            amount: tx.amount,
+            reason: reason.clone(),
+            failed_at: tx.completed_at.unwrap_or(tx.created_at),
+            refunded: false,
+        },
+        _ => WithdrawalStatus::Processing {
+            amount: tx.amount,
+            transaction_hash: Some(format!("0x{}", hex::encode(tx.id))),
+            confirmations: 0,
+            required_confirmations: 12,
+        },
+    })
+}
+
+/// Asks the minter about a withdrawal `local_withdrawal_status` doesn't know.
+pub fn withdrawal_status_request(
+    minter_canister_id: Principal,
+    withdrawal_id: WithdrawalId,
+) -> LedgerCall<WithdrawalStatus> {
+    async move {
+        let result: CallResult<(Vec<WithdrawalDetail>,)> = ic_cdk::call(
+            minter_canister_id,
+            "withdrawal_status",
+            (WithdrawalSearchParameter::ByWithdrawalId(withdrawal_id),),
+        )
+        .await;
+
+        let (details,) = result.map_err(|(_, err)| format!("Status check failed: {:?}", err))?;
+        let detail = details
+            .into_iter()
+            .find(|detail| detail.withdrawal_id == withdrawal_id)
+            .ok_or_else(|| format!("Withdrawal {} not found", withdrawal_id))?;
+        let amount = nat_to_tokens(detail.withdrawal_amount)?;
+
+        Ok(match detail.status {
+            MinterWithdrawalStatus::Pending | MinterWithdrawalStatus::TxCreated => WithdrawalStatus::Processing {
+                amount,
+                transaction_hash: None,
+                confirmations: 0,
+                required_confirmations: 12,
+            },
+            MinterWithdrawalStatus::TxSent(tx) => WithdrawalStatus::Processing {
+                amount,
+                transaction_hash: Some(tx.transaction_hash),
+                confirmations: 0,
+                required_confirmations: 12,
+            },
+            MinterWithdrawalStatus::TxFinalized(TxFinalizedStatus::Success { transaction_hash, effective_transaction_fee }) => {
+                WithdrawalStatus::Completed {
+                    amount,
+                    transaction_hash,
+                    completed_at: now(),
+                    final_fee: effective_transaction_fee.map(nat_to_tokens).transpose()?.unwrap_or(0),
+                }
+            }
+            MinterWithdrawalStatus::TxFinalized(TxFinalizedStatus::Reimbursed { transaction_hash, .. }) => WithdrawalStatus::Failed {
+                amount,
+                reason: format!("Ethereum transaction {} failed", transaction_hash),
+                failed_at: now(),
+                refunded: true,
+            },
+            MinterWithdrawalStatus::TxFinalized(TxFinalizedStatus::PendingReimbursement(tx)) => WithdrawalStatus::Failed {
+                amount,
+                reason: format!("Ethereum transaction {} failed", tx.transaction_hash),
+                failed_at: now(),
+                refunded: false,
+            },
+        })
+    }
+    .boxed_local()
+}
+
+fn validate_withdrawal(config: &TokenConfig, amount: Tokens) -> Result<(), WalletError> {
+    if amount < config.min_withdrawal_amount {
+        return Err(WalletError::ValidationError {
+            field: "amount".to_string(),
+            message: format!(
+                "Amount {} below minimum {} tokens",
+                amount, config.min_withdrawal_amount
+            ),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vaults::icrc1::Icrc1Vault;
+
+    #[test]
+    fn test_gas_fee_stays_spent_once_burned() {
+        let config = TokenConfig {
+            symbol: "ckETH".to_string(),
+            ledger_canister_id: Principal::from_slice(&[9; 10]),
+            decimals: 18,
+            transfer_fee: 2_000_000_000_000,
+            min_transfer_amount: 2_000_000_000_000,
+            min_withdrawal_amount: 0,
+            daily_withdrawal_limit: 10_000_000_000_000_000_000,
+            minter: None,
+        };
+        let minter = Account::principal_only(Principal::from_slice(&[5; 10]));
+        let mut vault = Icrc1Vault::new(Principal::from_slice(&[1; 29]), config.ledger_canister_id);
+        vault.credit(1, minter.owner, 1_000_000_000_000_000, now() + 1);
+
+        // The ckERC20 burn failed after the gas fee was burned
+        let reservation = vault.reserve(&config, 300_000_000_000_000, 0, minter.clone(), TransactionStatus::Pending).unwrap();
+        let burned = Erc20WithdrawalOutcome {
+            cketh_block_index: Some(20),
+            result: Err("InsufficientAllowance".to_string()),
+        };
+        settle_gas_fee(&mut vault, reservation, &burned);
+        assert_eq!(vault.balance(), 700_000_000_000_000);
+        assert!(vault.transaction_at_block(20).is_some());
+
+        // The minter rejected the withdrawal before burning anything
+        let reservation = vault.reserve(&config, 300_000_000_000_000, 0, minter, TransactionStatus::Pending).unwrap();
+        let rejected = Erc20WithdrawalOutcome {
+            cketh_block_index: None,
+            result: Err("TemporarilyUnavailable".to_string()),
+        };
+        settle_gas_fee(&mut vault, reservation, &rejected);
+        assert_eq!(vault.balance(), 700_000_000_000_000);
+    }
+}
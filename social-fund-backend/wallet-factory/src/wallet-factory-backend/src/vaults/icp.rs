@@ -1,169 +1,63 @@
 use crate::types::*;
 use candid::{CandidType, Principal};
-use ic_cdk::api::call::CallResult;
 use serde::{Serialize, Deserialize};
 use sha2::{Digest, Sha224};
 
-const ICP_LEDGER_CANISTER_ID: &str = "ryjl3-tyaaa-aaaaa-aaaba-cai";
-
+/// Where members can send ICP to their vault. Wallets that only speak the
+/// legacy ledger interface need the account identifier instead.
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
-pub struct IcpVault {
-    owner: Principal,
-    ledger_canister_id: Principal,
-    balance: u64,
-    last_balance_update: u64,
-    completed_transactions: Vec<Transaction>,
+pub struct IcpDepositAddress {
+    pub account: Account,
+    pub account_identifier: String,
 }
 
-impl IcpVault {
-    pub fn new(owner: Principal, ledger_id: &str) -> Self {
-        Self {
-            owner,
-            ledger_canister_id: Principal::from_text(ledger_id).unwrap_or(Principal::anonymous()),
-            balance: 0,
-            last_balance_update: 0,
-            completed_transactions: Vec::new(),
-        }
-    }
-
-    pub fn balance(&self) -> u64 {
-        self.balance
-    }
-
-    pub async fn update_balance(&mut self) -> Result<u64, WalletError> {
-        let account = self.account_identifier();
-        let result: CallResult<(Tokens,)> = ic_cdk::call(
-            self.ledger_canister_id,
-            "account_balance",
-            (AccountBalanceArgs { account },),
-        ).await;
-
-        match result {
-            Ok((tokens,)) => {
-                self.balance = tokens.e8s;
-                self.last_balance_update = ic_cdk::api::time();
-                Ok(tokens.e8s)
-            }
-            Err((_, err)) => Err(WalletError::VaultError {
-                operation: "update_balance".to_string(),
-                details: format!("ICP balance update failed: {:?}", err),
-            }),
+impl IcpDepositAddress {
+    pub fn for_member(member: Principal) -> Self {
+        let account = Account::member(member);
+        IcpDepositAddress {
+            account_identifier: AccountIdentifier::new(&account.owner, account.subaccount.as_ref()).to_hex(),
+            account,
         }
     }
+}
 
-    pub async fn transfer(&mut self, amount: u64, recipient: Principal) -> Result<BlockIndex, WalletError> {
-        if amount > self.balance {
-            return Err(WalletError::InsufficientFunds {
-                required: amount,
-                available: self.balance,
-            });
-        }
-
-        let to_account = AccountIdentifier::from(recipient);
-        let transfer_args = TransferArgs {
-            memo: 0,
-            amount: Tokens::from_e8s(amount),
-            fee: Tokens::from_e8s(10_000),
-            from_subaccount: None,
-            to: to_account,
-            created_at_time: Some(ic_cdk::api::time()),
-        };
+/// Legacy ICP ledger account identifier: CRC32 of the SHA-224 hash,
+/// big-endian, followed by the hash itself. Only used to show deposit
+/// addresses; all ledger calls go through ICRC-1 accounts.
+#[derive(Clone, Copy, Hash, Debug, PartialEq, Eq)]
+pub struct AccountIdentifier([u8; 32]);
 
-        let result: CallResult<(Result<BlockIndex, TransferError>,)> = ic_cdk::call(
-            self.ledger_canister_id,
-            "transfer",
-            (transfer_args,),
-        ).await;
+impl AccountIdentifier {
+    pub fn new(owner: &Principal, subaccount: Option<&Subaccount>) -> Self {
+        let mut hasher = Sha224::new();
+        hasher.update(b"\x0Aaccount-id");
+        hasher.update(owner.as_slice());
+        hasher.update(subaccount.unwrap_or(&[0u8; 32]));
+        let hash = hasher.finalize();
 
-        match result {
-            Ok((Ok(block_index),)) => {
-                self.balance -= amount;
-                // Optionally record transaction
-                self.completed_transactions.push(Transaction {
-                    id: [0u8; 32], // You may want to generate a real txid
-                    from: self.owner,
-                    to: Account::principal_only(recipient),
-                    amount,
-                    fee: 10_000,
-                    status: TransactionStatus::Completed,
-                    created_at: ic_cdk::api::time(),
-                    completed_at: Some(ic_cdk::api::time()),
-                    block_index: Some(block_index),
-                    retry_count: 0,
-                });
-                Ok(block_index)
-            }
-            Ok((Err(err),)) => Err(WalletError::TransactionFailed {
-                transaction_id: "unknown".to_string(),
-                reason: format!("ICP transfer failed: {:?}", err),
-            }),
-            Err((_, err)) => Err(WalletError::TransactionFailed {
-                transaction_id: "unknown".to_string(),
-                reason: format!("ICP transfer call failed: {:?}", err),
-            }),
-        }
-    }
-
-    fn account_identifier(&self) -> AccountIdentifier {
-        AccountIdentifier::from(self.owner)
+        let mut bytes = [0u8; 32];
+        bytes[..4].copy_from_slice(&crc32fast::hash(&hash).to_be_bytes());
+        bytes[4..].copy_from_slice(&hash);
+        AccountIdentifier(bytes)
     }
 
-    pub fn get_transaction_history(&self, limit: Option<usize>) -> Vec<Transaction> {
-        let limit = limit.unwrap_or(50).min(100);
-        self.completed_transactions.iter().rev().take(limit).cloned().collect()
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0)
     }
 }
 
-// ICP Ledger Types
-#[derive(CandidType, Deserialize)]
-struct AccountBalanceArgs {
-    account: AccountIdentifier,
-}
-
-#[derive(CandidType, Deserialize)]
-struct TransferArgs {
-    memo: u64,
-    amount: Tokens,
-    fee: Tokens,
-    from_subaccount: Option<Subaccount>,
-    to: AccountIdentifier,
-    created_at_time: Option<u64>,
-}
-
-#[derive(CandidType, Deserialize, Debug)]
-enum TransferError {
-    BadFee { expected_fee: Tokens },
-    InsufficientFunds { balance: Tokens },
-    TxTooOld { allowed_window_nanos: u64 },
-    TxDuplicate { duplicate_of: BlockIndex },
-    TxCreatedInFuture,
-}
-
-#[derive(CandidType, Deserialize, Debug)]
-struct Tokens {
-    e8s: u64,
-}
-
-impl Tokens {
-    fn from_e8s(e8s: u64) -> Self {
-        Self { e8s }
-    }
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-#[derive(CandidType, Deserialize, Clone, Copy, Hash, Debug, PartialEq, Eq)]
-struct AccountIdentifier([u8; 32]);
+    #[test]
+    fn test_account_identifier_known_vector() {
+        let expected = "1c7a48ba6a562aa9eaa2481a9049cdf0433b9738c992d698c31d8abf89cadc79";
+        let anonymous = Principal::anonymous();
 
-impl From<Principal> for AccountIdentifier {
-    fn from(principal: Principal) -> Self {
-        let mut data = b"\x0Aaccount-id".to_vec();
-        data.extend_from_slice(principal.as_slice());
-        data.extend_from_slice(&[0u8; 32]); // subaccount: default to zeroes
-        let hash = Sha224::digest(&data);
-        let mut bytes = [0u8; 32];
-        bytes[..28].copy_from_slice(&hash[..]);
-        AccountIdentifier(bytes)
+        assert_eq!(AccountIdentifier::new(&anonymous, None).to_hex(), expected);
+        // The default subaccount is all zeros
+        assert_eq!(AccountIdentifier::new(&anonymous, Some(&[0u8; 32])).to_hex(), expected);
+        assert_ne!(AccountIdentifier::new(&anonymous, Some(&[1u8; 32])).to_hex(), expected);
     }
 }
-
-type Subaccount = [u8; 32];
-type BlockIndex = u64;
\ No newline at end of file
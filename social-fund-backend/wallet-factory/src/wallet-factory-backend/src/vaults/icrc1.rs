@@ -0,0 +1,811 @@
+use crate::types::*;
+use candid::{CandidType, Nat, Principal};
+use futures::FutureExt;
+use ic_cdk::api::call::CallResult;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+use super::{
+    icrc::{nat_to_tokens, nat_to_u64, ApproveArgs, ApproveError, TransferArg, TransferError, TransferFromArgs, TransferFromError},
+    indexer::ledger_height,
+    token::{LedgerCall, TokenConfig, TokenVault, VaultMetrics},
+};
+
+const ONE_DAY_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// A member's holdings on any ICRC-1 ledger. Token specifics such as fees and
+// limits come from the `TokenConfig` passed to each operation, so updating
+// the registry takes effect for existing vaults.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Icrc1Vault {
+    owner: Principal,
+    ledger_canister_id: Principal,
+    balance: Tokens,
+    // Ledger height at the last balance read. Blocks below it are already
+    // included in `balance`.
+    balance_height: Option<BlockIndex>,
+    // Transaction management
+    pending_transactions: HashMap<TransactionId, Transaction>,
+    completed_transactions: Vec<Transaction>,
+    // Performance optimizations
+    balance_cache: Option<CachedBalance>,
+    // Deposit address on the token's native chain, for tokens with a minter
+    address_cache: Option<CachedAddress>,
+    // Security features
+    daily_withdrawn_amount: Tokens,
+    last_withdrawal_reset: u64,
+    // Monitoring
+    total_volume_in: Tokens,
+    total_volume_out: Tokens,
+    operation_count: u64,
+    last_operation: u64,
+}
+
+// The part of an `Icrc1Vault` stored before amounts were widened to `Tokens`
+// worth keeping. Caches and in-flight transactions are not carried over.
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct LegacyIcrc1Vault {
+    owner: Principal,
+    ledger_canister_id: Principal,
+    balance: u64,
+    completed_transactions: Vec<LegacyTransaction>,
+    daily_withdrawn_amount: u64,
+    last_withdrawal_reset: u64,
+    total_volume_in: u64,
+    total_volume_out: u64,
+    operation_count: u64,
+}
+
+impl Icrc1Vault {
+    // Legacy vaults kept minter withdrawal ids in `block_index`. Those of
+    // transactions sent to `withdrawal_minter` are moved to `withdrawal_id`.
+    pub fn from_legacy(legacy: LegacyIcrc1Vault, withdrawal_minter: Option<Principal>) -> Self {
+        let completed_transactions = legacy
+            .completed_transactions
+            .into_iter()
+            .map(|tx| {
+                let mut tx = Transaction::from(tx);
+                if withdrawal_minter.is_some_and(|minter| tx.to == Account::principal_only(minter)) {
+                    tx.withdrawal_id = tx.block_index.take();
+                }
+                tx
+            })
+            .collect();
+
+        Self {
+            balance: legacy.balance.into(),
+            completed_transactions,
+            daily_withdrawn_amount: legacy.daily_withdrawn_amount.into(),
+            last_withdrawal_reset: legacy.last_withdrawal_reset,
+            total_volume_in: legacy.total_volume_in.into(),
+            total_volume_out: legacy.total_volume_out.into(),
+            operation_count: legacy.operation_count,
+            ..Self::new(legacy.owner, legacy.ledger_canister_id)
+        }
+    }
+}
+
+impl Icrc1Vault {
+    pub fn new(owner: Principal, ledger_canister_id: Principal) -> Self {
+        let current_time = now();
+
+        Self {
+            owner,
+            ledger_canister_id,
+            balance: 0,
+            balance_height: None,
+            pending_transactions: HashMap::new(),
+            completed_transactions: Vec::new(),
+            balance_cache: None,
+            address_cache: None,
+            daily_withdrawn_amount: 0,
+            last_withdrawal_reset: current_time,
+            total_volume_in: 0,
+            total_volume_out: 0,
+            operation_count: 0,
+            last_operation: current_time,
+        }
+    }
+
+    // Carries over the history of a vault stored before the token registry
+    pub fn with_history(owner: Principal, ledger_canister_id: Principal, history: Vec<Transaction>) -> Self {
+        Self {
+            completed_transactions: history,
+            ..Self::new(owner, ledger_canister_id)
+        }
+    }
+
+    pub fn cached_address(&self) -> Option<String> {
+        self.address_cache
+            .as_ref()
+            .filter(|cache| cache.is_valid())
+            .map(|cache| cache.address.clone())
+    }
+
+    pub fn cache_address(&mut self, address: String) {
+        self.address_cache = Some(CachedAddress::new(address, 3600)); // 1 hour cache
+        self.operation_count += 1;
+        self.last_operation = now();
+    }
+
+    // Lets `spender` pull up to `amount` with `icrc2_transfer_from`. Only the
+    // approval fee is debited.
+    pub fn reserve_approve(
+        &mut self,
+        config: &TokenConfig,
+        spender: Account,
+        amount: Tokens,
+        expires_at: Option<u64>,
+    ) -> Result<(Reservation, LedgerCall<BlockIndex>), WalletError> {
+        let reservation = self.reserve(config, 0, config.transfer_fee, spender.clone(), TransactionStatus::Processing)?;
+
+        let ledger_canister_id = self.ledger_canister_id;
+        let approve_args = ApproveArgs {
+            from_subaccount: self.account().subaccount,
+            spender,
+            amount: Nat::from(amount),
+            expected_allowance: None,
+            expires_at,
+            fee: Some(Nat::from(config.transfer_fee)),
+            memo: Some(reservation.transaction_id.to_vec()),
+            created_at_time: Some(now()),
+        };
+        let symbol = config.symbol.clone();
+
+        let call = async move {
+            let result: CallResult<(Result<Nat, ApproveError>,)> = ic_cdk::call(
+                ledger_canister_id,
+                "icrc2_approve",
+                (approve_args,),
+            )
+            .await;
+            match result {
+                Ok((Ok(block_index),)) => nat_to_u64(block_index),
+                Ok((Err(err),)) => Err(format!("{} approval failed: {:?}", symbol, err)),
+                Err((rejection_code, err)) => Err(format!("{} approval call failed: {:?} - {}", symbol, rejection_code, err)),
+            }
+        };
+
+        Ok((reservation, call.boxed_local()))
+    }
+
+    // Pulls `amount` into this vault from an account that approved the
+    // wallet factory as spender. Nothing is reserved since the vault only
+    // gains funds; `settle_deposit` credits them once the ledger confirms.
+    pub fn deposit_request(
+        &self,
+        config: &TokenConfig,
+        from: Account,
+        amount: Tokens,
+    ) -> (TransactionId, LedgerCall<BlockIndex>) {
+        let transaction_id = self.generate_transaction_id();
+        let ledger_canister_id = self.ledger_canister_id;
+        let transfer_from_args = TransferFromArgs {
+            spender_subaccount: None,
+            from,
+            to: self.account(),
+            amount: Nat::from(amount),
+            fee: Some(Nat::from(config.transfer_fee)),
+            memo: Some(transaction_id.to_vec()),
+            created_at_time: Some(now()),
+        };
+        let symbol = config.symbol.clone();
+
+        let call = async move {
+            let result: CallResult<(Result<Nat, TransferFromError>,)> = ic_cdk::call(
+                ledger_canister_id,
+                "icrc2_transfer_from",
+                (transfer_from_args,),
+            )
+            .await;
+            match result {
+                Ok((Ok(block_index),)) => nat_to_u64(block_index),
+                Ok((Err(err),)) => Err(format!("{} deposit failed: {:?}", symbol, err)),
+                Err((rejection_code, err)) => Err(format!("{} deposit call failed: {:?} - {}", symbol, rejection_code, err)),
+            }
+        };
+
+        (transaction_id, call.boxed_local())
+    }
+
+    pub fn settle_deposit(
+        &mut self,
+        config: &TokenConfig,
+        transaction_id: TransactionId,
+        from: Account,
+        amount: Tokens,
+        outcome: Result<BlockIndex, String>,
+    ) -> Result<BlockIndex, WalletError> {
+        let block_index = outcome.map_err(|reason| WalletError::TransactionFailed {
+            transaction_id: hex::encode(transaction_id),
+            reason,
+        })?;
+
+        // The ledger indexer may have credited the block already
+        if self.transaction_at_block(block_index).is_some() {
+            return Ok(block_index);
+        }
+
+        let current_time = now();
+        self.balance += amount;
+        self.balance_cache = None;
+        self.total_volume_in += amount;
+        self.operation_count += 1;
+        self.last_operation = current_time;
+        self.completed_transactions.push(Transaction {
+            id: transaction_id,
+            from: from.owner,
+            to: self.account(),
+            amount,
+            fee: config.transfer_fee,
+            status: TransactionStatus::Completed,
+            created_at: current_time,
+            completed_at: Some(current_time),
+            block_index: Some(block_index),
+            withdrawal_id: None,
+            retry_count: 0,
+        });
+        Ok(block_index)
+    }
+
+    // Takes a ledger balance read at `height`. Inflows are not counted here;
+    // the indexer records each deposit through `credit`.
+    fn record_balance(&mut self, balance: Tokens, height: BlockIndex) {
+        self.balance = balance;
+        self.balance_height = Some(height);
+        self.balance_cache = Some(CachedBalance::new(balance, 30)); // 30 second cache
+        self.operation_count += 1;
+        self.last_operation = now();
+    }
+
+    /// Records a deposit the ledger indexer found at `block_index`. Returns
+    /// false if the block is already recorded, e.g. by `settle_deposit`.
+    ///
+    /// The balance is only raised for blocks at or above the ledger height of
+    /// the last balance read, which already includes older ones.
+    pub fn credit(
+        &mut self,
+        block_index: BlockIndex,
+        from: Principal,
+        amount: Tokens,
+        block_timestamp: u64,
+    ) -> bool {
+        if self.transaction_at_block(block_index).is_some() {
+            return false;
+        }
+
+        if self.balance_height.is_none_or(|height| block_index >= height) {
+            self.balance = self.balance().saturating_add(amount);
+            self.balance_cache = None;
+        }
+
+        let mut hasher = Sha256::new();
+        hasher.update(self.ledger_canister_id.as_slice());
+        hasher.update(block_index.to_be_bytes());
+        hasher.update(b"deposit");
+        let mut transaction_id = [0u8; 32];
+        transaction_id.copy_from_slice(&hasher.finalize()[..32]);
+
+        let current_time = now();
+        self.total_volume_in += amount;
+        self.operation_count += 1;
+        self.last_operation = current_time;
+        self.completed_transactions.push(Transaction {
+            id: transaction_id,
+            from,
+            to: self.account(),
+            amount,
+            fee: 0,
+            status: TransactionStatus::Completed,
+            created_at: block_timestamp,
+            completed_at: Some(current_time),
+            block_index: Some(block_index),
+            withdrawal_id: None,
+            retry_count: 0,
+        });
+
+        true
+    }
+
+    fn check_daily_limits(&mut self, config: &TokenConfig, amount: Tokens) -> Result<(), WalletError> {
+        let current_time = now();
+
+        // Reset daily limit if a day has passed
+        if current_time >= self.last_withdrawal_reset + ONE_DAY_NANOS {
+            self.daily_withdrawn_amount = 0;
+            self.last_withdrawal_reset = current_time;
+        }
+
+        if self.daily_withdrawn_amount.saturating_add(amount) > config.daily_withdrawal_limit {
+            return Err(WalletError::ValidationError {
+                field: "daily_limit".to_string(),
+                message: format!(
+                    "Daily withdrawal limit exceeded. Limit: {}, Already withdrawn: {}, Requested: {}",
+                    config.daily_withdrawal_limit, self.daily_withdrawn_amount, amount
+                ),
+            });
+        }
+
+        Ok(())
+    }
+
+    fn complete(&mut self, reservation: &Reservation, block_index: BlockIndex, withdrawal_id: Option<WithdrawalId>) {
+        if let Some(mut tx) = self.pending_transactions.remove(&reservation.transaction_id) {
+            self.total_volume_out += tx.amount;
+            tx.status = TransactionStatus::Completed;
+            tx.completed_at = Some(now());
+            tx.block_index = Some(block_index);
+            tx.withdrawal_id = withdrawal_id;
+            self.completed_transactions.push(tx);
+        }
+        self.last_operation = now();
+    }
+
+    fn generate_transaction_id(&self) -> TransactionId {
+        let mut hasher = Sha256::new();
+        hasher.update(now().to_be_bytes());
+        hasher.update(self.owner.as_slice());
+        hasher.update(self.operation_count.to_be_bytes());
+        hasher.update(self.ledger_canister_id.as_slice());
+
+        let hash = hasher.finalize();
+        let mut transaction_id = [0u8; 32];
+        transaction_id.copy_from_slice(&hash[..32]);
+        transaction_id
+    }
+
+    fn calculate_cache_hit_rate(&self) -> f64 {
+        // Simple cache hit rate calculation
+        // In a real implementation, you'd track cache hits/misses
+        if self.balance_cache.is_some() || self.address_cache.is_some() {
+            0.75 // Placeholder value
+        } else {
+            0.0
+        }
+    }
+}
+
+impl TokenVault for Icrc1Vault {
+    fn owner(&self) -> Principal {
+        self.owner
+    }
+
+    fn ledger(&self) -> Principal {
+        self.ledger_canister_id
+    }
+
+    fn balance(&self) -> Tokens {
+        // Return cached balance if valid, otherwise stored balance
+        match self.balance_cache {
+            Some(ref cache) if cache.is_valid() => cache.value,
+            _ => self.balance,
+        }
+    }
+
+    fn reserve(
+        &mut self,
+        config: &TokenConfig,
+        amount: Tokens,
+        fee: Tokens,
+        to: Account,
+        status: TransactionStatus,
+    ) -> Result<Reservation, WalletError> {
+        self.check_daily_limits(config, amount)?;
+
+        let debit = amount.saturating_add(fee);
+        let available = self.balance();
+        if debit > available {
+            return Err(WalletError::InsufficientFunds {
+                required: debit,
+                available,
+            });
+        }
+
+        let transaction_id = self.generate_transaction_id();
+        self.pending_transactions.insert(transaction_id, Transaction {
+            id: transaction_id,
+            from: self.owner,
+            to,
+            amount,
+            fee,
+            status,
+            created_at: now(),
+            completed_at: None,
+            block_index: None,
+            withdrawal_id: None,
+            retry_count: 0,
+        });
+
+        // The cache no longer reflects what this vault may spend
+        self.balance = available - debit;
+        self.balance_cache = None;
+        self.daily_withdrawn_amount += amount;
+        self.operation_count += 1;
+        self.last_operation = now();
+
+        Ok(Reservation { transaction_id, amount: debit })
+    }
+
+    fn commit(&mut self, reservation: &Reservation, block_index: BlockIndex) {
+        self.complete(reservation, block_index, None);
+    }
+
+    fn commit_withdrawal(&mut self, reservation: &Reservation, block_index: BlockIndex, withdrawal_id: WithdrawalId) {
+        self.complete(reservation, block_index, Some(withdrawal_id));
+    }
+
+    fn release(&mut self, reservation: &Reservation, reason: String) {
+        if let Some(mut tx) = self.pending_transactions.remove(&reservation.transaction_id) {
+            self.daily_withdrawn_amount = self.daily_withdrawn_amount.saturating_sub(tx.amount);
+            tx.status = TransactionStatus::Failed { reason };
+            tx.completed_at = Some(now());
+            self.completed_transactions.push(tx);
+        }
+        self.balance += reservation.amount;
+        self.last_operation = now();
+    }
+
+    fn balance_request(&self) -> Option<LedgerCall<(Tokens, BlockIndex)>> {
+        if self.balance_cache.as_ref().is_some_and(|cache| cache.is_valid()) {
+            return None;
+        }
+
+        let ledger_canister_id = self.ledger_canister_id;
+        let account = self.account();
+
+        Some(async move {
+            let result: CallResult<(Nat,)> = ic_cdk::call(
+                ledger_canister_id,
+                "icrc1_balance_of",
+                (account,),
+            )
+            .await;
+            let balance = match result {
+                Ok((balance,)) => nat_to_tokens(balance)?,
+                Err((rejection_code, err)) => return Err(format!("{:?} - {}", rejection_code, err)),
+            };
+            // Read after the balance, so a block landing in between is taken
+            // as included. That errs towards a low balance, which the next
+            // read corrects, rather than crediting a deposit twice.
+            let height = ledger_height(ledger_canister_id).await?;
+            Ok((balance, height))
+        }.boxed_local())
+    }
+
+    fn apply_balance(
+        &mut self,
+        config: &TokenConfig,
+        outcome: Result<(Tokens, BlockIndex), String>,
+    ) -> Result<Tokens, WalletError> {
+        match outcome {
+            Ok((balance, height)) => {
+                let old_balance = self.balance;
+                self.record_balance(balance, height);
+
+                ic_cdk::println!("{} balance updated: {} -> {}", config.symbol, old_balance, balance);
+
+                Ok(balance)
+            }
+            Err(reason) => {
+                let error = WalletError::VaultError {
+                    operation: "update_balance".to_string(),
+                    details: format!("{} balance update failed: {}", config.symbol, reason),
+                };
+
+                ic_cdk::println!("Balance update failed: {:?}", error);
+                Err(error)
+            }
+        }
+    }
+
+    fn reserve_transfer(
+        &mut self,
+        config: &TokenConfig,
+        amount: Tokens,
+        to: Account,
+    ) -> Result<(Reservation, LedgerCall<BlockIndex>), WalletError> {
+        let validated_amount = ValidatedAmount::new(amount, config.min_transfer_amount)?;
+        let reservation = self.reserve(
+            config,
+            validated_amount.value(),
+            config.transfer_fee,
+            to.clone(),
+            TransactionStatus::Processing,
+        )?;
+
+        let ledger_canister_id = self.ledger_canister_id;
+        let transfer_args = TransferArg {
+            from_subaccount: self.account().subaccount,
+            to,
+            amount: Nat::from(validated_amount.value()),
+            fee: Some(Nat::from(config.transfer_fee)),
+            memo: Some(reservation.transaction_id.to_vec()),
+            created_at_time: Some(now()),
+        };
+        let symbol = config.symbol.clone();
+
+        let call = async move {
+            let result: CallResult<(Result<Nat, TransferError>,)> = ic_cdk::call(
+                ledger_canister_id,
+                "icrc1_transfer",
+                (transfer_args,),
+            )
+            .await;
+            match result {
+                Ok((Ok(block_index),)) => nat_to_u64(block_index),
+                Ok((Err(transfer_error),)) => Err(format!("{} transfer failed: {:?}", symbol, transfer_error)),
+                Err((rejection_code, err)) => Err(format!("{} transfer call failed: {:?} - {}", symbol, rejection_code, err)),
+            }
+        };
+
+        Ok((reservation, call.boxed_local()))
+    }
+
+    fn settle_transfer(
+        &mut self,
+        config: &TokenConfig,
+        reservation: Reservation,
+        outcome: Result<BlockIndex, String>,
+    ) -> Result<BlockIndex, WalletError> {
+        match outcome {
+            Ok(block_index) => {
+                self.commit(&reservation, block_index);
+
+                ic_cdk::println!(
+                    "{} transfer completed: {} (block: {})",
+                    config.symbol, reservation.amount, block_index
+                );
+
+                Ok(block_index)
+            }
+            Err(reason) => {
+                self.release(&reservation, reason.clone());
+
+                let error = WalletError::TransactionFailed {
+                    transaction_id: hex::encode(reservation.transaction_id),
+                    reason,
+                };
+
+                ic_cdk::println!("Transfer failed: {:?}", error);
+                Err(error)
+            }
+        }
+    }
+
+    fn transaction_history(&self, limit: Option<usize>) -> Vec<Transaction> {
+        let limit = limit.unwrap_or(50).min(100); // Max 100 transactions
+        self.completed_transactions
+            .iter()
+            .rev() // Most recent first
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    fn transaction_at_block(&self, block_index: BlockIndex) -> Option<Transaction> {
+        self.completed_transactions
+            .iter()
+            .rev()
+            .find(|tx| tx.block_index == Some(block_index))
+            .cloned()
+    }
+
+    fn withdrawal(&self, withdrawal_id: WithdrawalId) -> Option<Transaction> {
+        self.completed_transactions
+            .iter()
+            .rev()
+            .find(|tx| tx.withdrawal_id == Some(withdrawal_id))
+            .cloned()
+    }
+
+    fn metrics(&self, config: &TokenConfig) -> VaultMetrics {
+        VaultMetrics {
+            balance: self.balance(),
+            total_volume_in: self.total_volume_in,
+            total_volume_out: self.total_volume_out,
+            operation_count: self.operation_count,
+            pending_transactions: self.pending_transactions.len() as u64,
+            completed_transactions: self.completed_transactions.len() as u64,
+            daily_withdrawal_limit: config.daily_withdrawal_limit,
+            daily_withdrawn_amount: self.daily_withdrawn_amount,
+            last_operation: self.last_operation,
+            cache_hit_rate: self.calculate_cache_hit_rate(),
+        }
+    }
+
+    fn cleanup_old_transactions(&mut self, older_than_days: u64) -> u32 {
+        let cutoff_time = now().saturating_sub(older_than_days * ONE_DAY_NANOS);
+        let initial_count = self.completed_transactions.len();
+
+        self.completed_transactions.retain(|tx| tx.created_at > cutoff_time);
+
+        (initial_count - self.completed_transactions.len()) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(daily_withdrawal_limit: Tokens) -> TokenConfig {
+        TokenConfig {
+            symbol: "TEST".to_string(),
+            ledger_canister_id: Principal::from_slice(&[9; 10]),
+            decimals: 8,
+            transfer_fee: 10,
+            min_transfer_amount: 1,
+            min_withdrawal_amount: 0,
+            daily_withdrawal_limit,
+            minter: None,
+        }
+    }
+
+    fn vault(balance: Tokens) -> Icrc1Vault {
+        let mut vault = Icrc1Vault::new(Principal::from_slice(&[1; 29]), Principal::from_slice(&[9; 10]));
+        vault.balance = balance;
+        vault
+    }
+
+    #[test]
+    fn test_release_restores_balance_and_daily_limit() {
+        let config = config(1_000);
+        let mut vault = vault(2_000);
+        let to = Account::principal_only(Principal::from_slice(&[2; 29]));
+
+        let reservation = vault.reserve(&config, 600, 10, to.clone(), TransactionStatus::Pending).unwrap();
+        assert_eq!(vault.balance(), 1_390);
+        assert_eq!(vault.metrics(&config).daily_withdrawn_amount, 600);
+        // The reservation counts against today's limit
+        assert!(vault.reserve(&config, 500, 10, to.clone(), TransactionStatus::Pending).is_err());
+
+        vault.release(&reservation, "ledger rejected".to_string());
+        assert_eq!(vault.balance(), 2_000);
+        assert_eq!(vault.metrics(&config).daily_withdrawn_amount, 0);
+        assert_eq!(vault.metrics(&config).pending_transactions, 0);
+        assert!(vault.reserve(&config, 1_000, 10, to, TransactionStatus::Pending).is_ok());
+    }
+
+    #[test]
+    fn test_reserve_rejects_overdraft() {
+        let config = config(1_000);
+        let mut vault = vault(100);
+        let to = Account::principal_only(Principal::from_slice(&[2; 29]));
+
+        assert!(matches!(
+            vault.reserve(&config, 95, 10, to, TransactionStatus::Pending),
+            Err(WalletError::InsufficientFunds { required: 105, available: 100 })
+        ));
+        assert_eq!(vault.balance(), 100);
+        assert_eq!(vault.metrics(&config).daily_withdrawn_amount, 0);
+    }
+
+    #[test]
+    fn test_credit_and_settle_deposit_record_a_block_once() {
+        let config = config(1_000);
+        let from = Account::principal_only(Principal::from_slice(&[2; 29]));
+        let block_timestamp = now();
+
+        // Indexer first, then the `deposit` call that made the block settles
+        let mut indexed_first = vault(0);
+        assert!(indexed_first.credit(7, from.owner, 500, block_timestamp));
+        assert_eq!(indexed_first.settle_deposit(&config, [1; 32], from.clone(), 500, Ok(7)).unwrap(), 7);
+        assert_eq!(indexed_first.balance(), 500);
+        assert_eq!(indexed_first.transaction_history(None).len(), 1);
+
+        // And the other way round
+        let mut settled_first = vault(0);
+        settled_first.settle_deposit(&config, [1; 32], from.clone(), 500, Ok(7)).unwrap();
+        assert!(!settled_first.credit(7, from.owner, 500, block_timestamp));
+        assert_eq!(settled_first.balance(), 500);
+        assert_eq!(settled_first.transaction_history(None).len(), 1);
+
+        // A later block is a new deposit
+        assert!(settled_first.credit(8, from.owner, 250, block_timestamp));
+        assert_eq!(settled_first.balance(), 750);
+    }
+
+    #[test]
+    fn test_credit_only_raises_balance_above_read_height() {
+        let config = config(1_000);
+        let from = Principal::from_slice(&[2; 29]);
+        let mut vault = vault(0);
+
+        // The ledger had 10 blocks when the balance was read, so block 9
+        // is already part of it, whatever its timestamp
+        vault.record_balance(500, 10);
+        assert!(vault.credit(9, from, 500, now() + 60_000_000_000));
+        assert_eq!(vault.balance(), 500);
+
+        assert!(vault.credit(10, from, 250, 0));
+        assert_eq!(vault.balance(), 750);
+        assert_eq!(vault.transaction_history(None).len(), 2);
+        // Inflows are counted once per block, not again by the balance read
+        vault.record_balance(750, 11);
+        assert_eq!(vault.metrics(&config).total_volume_in, 750);
+    }
+
+    #[test]
+    fn test_legacy_vault_migrates() {
+        let owner = Principal::from_slice(&[1; 29]);
+        let ledger = Principal::from_slice(&[9; 10]);
+        let legacy = LegacyIcrc1Vault {
+            owner,
+            ledger_canister_id: ledger,
+            balance: u64::MAX,
+            completed_transactions: vec![LegacyTransaction {
+                id: [3; 32],
+                from: owner,
+                to: Account::principal_only(Principal::from_slice(&[2; 29])),
+                amount: 700,
+                fee: 10,
+                status: TransactionStatus::Completed,
+                created_at: now(),
+                completed_at: Some(now()),
+                block_index: Some(42),
+                retry_count: 0,
+            }],
+            daily_withdrawn_amount: 700,
+            last_withdrawal_reset: now(),
+            total_volume_in: 1_000,
+            total_volume_out: 700,
+            operation_count: 3,
+        };
+        let bytes = candid::encode_one(&legacy).unwrap();
+        let vault = Icrc1Vault::from_legacy(candid::decode_one::<LegacyIcrc1Vault>(&bytes).unwrap(), None);
+
+        assert_eq!(vault.balance(), u64::MAX as Tokens);
+        assert_eq!(vault.owner(), owner);
+        assert_eq!(vault.transaction_at_block(42).unwrap().amount, 700);
+        let metrics = vault.metrics(&config(1_000));
+        assert_eq!((metrics.total_volume_in, metrics.total_volume_out), (1_000, 700));
+        assert_eq!(metrics.daily_withdrawn_amount, 700);
+    }
+
+    #[test]
+    fn test_withdrawal_ids_are_not_ledger_blocks() {
+        let config = config(1_000);
+        let minter = Account::principal_only(Principal::from_slice(&[5; 10]));
+        let mut vault = vault(2_000);
+
+        let reservation = vault.reserve(&config, 600, 0, minter.clone(), TransactionStatus::Pending).unwrap();
+        // Burned in ledger block 3; the minter calls it withdrawal 7
+        vault.commit_withdrawal(&reservation, 3, 7);
+        assert_eq!(vault.withdrawal(7).unwrap().amount, 600);
+        assert_eq!(vault.transaction_at_block(3).unwrap().amount, 600);
+        assert!(vault.transaction_at_block(7).is_none());
+
+        // A deposit at ledger block 7 is still new
+        assert!(vault.credit(7, Principal::from_slice(&[2; 29]), 250, now()));
+        assert_eq!(vault.balance(), 1_650);
+        assert_eq!(vault.transaction_at_block(7).unwrap().amount, 250);
+        assert_eq!(vault.withdrawal(7).unwrap().amount, 600);
+
+        // Legacy vaults kept the id in `block_index`
+        let legacy = LegacyIcrc1Vault {
+            owner: vault.owner(),
+            ledger_canister_id: vault.ledger(),
+            balance: 0,
+            completed_transactions: vec![LegacyTransaction {
+                id: [3; 32],
+                from: vault.owner(),
+                to: minter.clone(),
+                amount: 600,
+                fee: 0,
+                status: TransactionStatus::Completed,
+                created_at: now(),
+                completed_at: Some(now()),
+                block_index: Some(7),
+                retry_count: 0,
+            }],
+            daily_withdrawn_amount: 0,
+            last_withdrawal_reset: now(),
+            total_volume_in: 0,
+            total_volume_out: 600,
+            operation_count: 1,
+        };
+        let migrated = Icrc1Vault::from_legacy(legacy, Some(minter.owner));
+        assert!(migrated.transaction_at_block(7).is_none());
+        assert_eq!(migrated.withdrawal(7).unwrap().amount, 600);
+    }
+}
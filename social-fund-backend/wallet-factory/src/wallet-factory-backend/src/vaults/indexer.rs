@@ -0,0 +1,574 @@
+use crate::types::*;
+use candid::{define_function, CandidType, Deserialize, Int, Nat, Principal};
+use ic_cdk::api::call::CallResult;
+use serde::Serialize;
+use std::{cell::{Cell, RefCell}, collections::{HashMap, VecDeque}, time::Duration};
+
+use super::{icrc::{nat_to_tokens, nat_to_u64}, list_tokens, with_vault};
+
+// Scans each registered ledger for transfers into member subaccounts and
+// credits them to the member's vault, so contributions show up in the
+// transaction history as they land rather than as a changed balance.
+
+const INDEX_INTERVAL: Duration = Duration::from_secs(60);
+const PAGE_SIZE: u64 = 1000;
+// Bounds the work one run does per ledger; the rest waits for the next run
+const MAX_PAGES_PER_RUN: u32 = 10;
+// Oldest skipped deposits are dropped past this many
+const MAX_SKIPPED_DEPOSITS: usize = 1000;
+
+thread_local! {
+    // Next block to scan, keyed by ledger canister id
+    static INDEX_CURSORS: RefCell<HashMap<Principal, u64>> = RefCell::new(HashMap::new());
+    static SKIPPED_DEPOSITS: RefCell<VecDeque<SkippedDeposit>> = const { RefCell::new(VecDeque::new()) };
+    static INDEXING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// A deposit into a member subaccount that the indexer could not credit.
+/// The cursor moves past it regardless, so these are kept for an admin to
+/// reconcile by hand.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SkippedDeposit {
+    pub ledger_canister_id: Principal,
+    pub block_index: BlockIndex,
+    pub member: Principal,
+    pub amount: Nat,
+    pub reason: String,
+    pub skipped_at: u64,
+}
+
+// ICRC-3 block log types
+#[derive(CandidType, Deserialize)]
+struct GetBlocksArgs {
+    start: Nat,
+    length: Nat,
+}
+
+#[derive(CandidType, Deserialize)]
+struct GetBlocksResult {
+    log_length: Nat,
+    blocks: Vec<BlockWithId>,
+    archived_blocks: Vec<ArchivedBlocks>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct BlockWithId {
+    id: Nat,
+    block: Value,
+}
+
+#[derive(CandidType, Deserialize)]
+struct ArchivedBlocks {
+    args: Vec<GetBlocksArgs>,
+    callback: GetBlocksCallback,
+}
+
+define_function!(GetBlocksCallback : (Vec<GetBlocksArgs>) -> (GetBlocksResult) query);
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+enum Value {
+    Blob(Vec<u8>),
+    Text(String),
+    Nat(Nat),
+    Int(Int),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+// `get_transactions` types, for ledgers that predate ICRC-3
+#[derive(CandidType, Deserialize)]
+struct GetTransactionsRequest {
+    start: Nat,
+    length: Nat,
+}
+
+#[derive(CandidType, Deserialize)]
+struct GetTransactionsResponse {
+    log_length: Nat,
+    first_index: Nat,
+    transactions: Vec<LedgerTransaction>,
+    archived_transactions: Vec<ArchivedRange>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct ArchivedRange {
+    start: Nat,
+    length: Nat,
+    callback: GetTransactionsCallback,
+}
+
+define_function!(GetTransactionsCallback : (GetTransactionsRequest) -> (TransactionRange) query);
+
+#[derive(CandidType, Deserialize)]
+struct TransactionRange {
+    transactions: Vec<LedgerTransaction>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct LedgerTransaction {
+    kind: String,
+    mint: Option<Mint>,
+    transfer: Option<LedgerTransfer>,
+    timestamp: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+struct Mint {
+    amount: Nat,
+    to: Account,
+}
+
+#[derive(CandidType, Deserialize)]
+struct LedgerTransfer {
+    amount: Nat,
+    from: Account,
+    to: Account,
+}
+
+// A mint or transfer as far as the indexer cares
+struct IncomingTransfer {
+    from: Option<Principal>,
+    to: Account,
+    amount: Nat,
+    timestamp: u64,
+}
+
+// One page of the block log, starting at the requested block. `None` marks
+// blocks that are neither mints nor transfers.
+struct Page {
+    log_length: u64,
+    transfers: Vec<(BlockIndex, Option<IncomingTransfer>)>,
+}
+
+/// Runs `index_deposits` every minute. Timers don't survive upgrades, so
+/// this is called from both `init` and `post_upgrade`.
+pub fn start_indexer() {
+    ic_cdk_timers::set_timer_interval(INDEX_INTERVAL, || ic_cdk::spawn(index_deposits()));
+}
+
+pub async fn index_deposits() {
+    // A run can outlast the interval; let it finish instead of overlapping
+    if INDEXING.with(|indexing| indexing.replace(true)) {
+        return;
+    }
+    let _guard = IndexingGuard;
+
+    for token in list_tokens() {
+        match index_ledger(token.ledger_canister_id).await {
+            Ok(0) => {}
+            Ok(credited) => ic_cdk::println!("Indexer credited {} {} deposits", credited, token.symbol),
+            Err(err) => ic_cdk::println!("Indexing {} failed: {}", token.symbol, err),
+        }
+    }
+}
+
+struct IndexingGuard;
+
+impl Drop for IndexingGuard {
+    fn drop(&mut self) {
+        INDEXING.with(|indexing| indexing.set(false));
+    }
+}
+
+pub fn index_cursors() -> HashMap<Principal, u64> {
+    INDEX_CURSORS.with(|cursors| cursors.borrow().clone())
+}
+
+pub fn restore_index_cursors(cursors: HashMap<Principal, u64>) {
+    INDEX_CURSORS.with(|current| *current.borrow_mut() = cursors);
+}
+
+pub fn skipped_deposits() -> Vec<SkippedDeposit> {
+    SKIPPED_DEPOSITS.with(|skipped| skipped.borrow().iter().cloned().collect())
+}
+
+pub fn restore_skipped_deposits(deposits: Vec<SkippedDeposit>) {
+    SKIPPED_DEPOSITS.with(|skipped| *skipped.borrow_mut() = deposits.into());
+}
+
+async fn index_ledger(ledger: Principal) -> Result<u32, String> {
+    let Some(mut cursor) = INDEX_CURSORS.with(|cursors| cursors.borrow().get(&ledger).copied()) else {
+        // A newly indexed ledger starts at its tip. Earlier deposits are
+        // already reflected in the balance `update_balance` reads.
+        let page = fetch_page(ledger, 0, 0).await?;
+        INDEX_CURSORS.with(|cursors| cursors.borrow_mut().insert(ledger, page.log_length));
+        return Ok(0);
+    };
+
+    let mut credited = 0;
+    for _ in 0..MAX_PAGES_PER_RUN {
+        let page = fetch_page(ledger, cursor, PAGE_SIZE).await?;
+
+        let (transfers, next) = contiguous_transfers(cursor, page.transfers);
+        for (block_index, transfer) in transfers {
+            if credit_transfer(ledger, block_index, transfer) {
+                credited += 1;
+            }
+        }
+
+        // Saved after every page so an error later in the run loses nothing
+        INDEX_CURSORS.with(|cursors| cursors.borrow_mut().insert(ledger, next));
+
+        if next == cursor || next >= page.log_length {
+            break;
+        }
+        cursor = next;
+    }
+
+    Ok(credited)
+}
+
+// The transfers that continue the log from `cursor`, and the cursor after
+// them. Stops at the first missing or out-of-order block, so the indexer
+// only ever advances over blocks it has actually seen.
+fn contiguous_transfers(
+    cursor: u64,
+    blocks: Vec<(BlockIndex, Option<IncomingTransfer>)>,
+) -> (Vec<(BlockIndex, IncomingTransfer)>, u64) {
+    let mut next = cursor;
+    let mut transfers = Vec::new();
+    for (block_index, transfer) in blocks {
+        if block_index != next {
+            break;
+        }
+        if let Some(transfer) = transfer {
+            transfers.push((block_index, transfer));
+        }
+        next += 1;
+    }
+    (transfers, next)
+}
+
+// Credits `transfer` if it pays into a member's subaccount. Deposits that
+// can't be credited are recorded in `SKIPPED_DEPOSITS`.
+fn credit_transfer(ledger: Principal, block_index: BlockIndex, transfer: IncomingTransfer) -> bool {
+    if transfer.to.owner != canister_id() {
+        return false;
+    }
+    let Some(member) = transfer.to.subaccount.as_ref().and_then(member_from_subaccount) else {
+        return false;
+    };
+
+    let from = transfer.from.unwrap_or(ledger);
+    let result = nat_to_tokens(transfer.amount.clone()).and_then(|amount| {
+            with_vault(member, ledger, "index_deposits", |vault, _| {
+                Ok(vault.credit(block_index, from, amount, transfer.timestamp))
+            })
+            .map_err(|err| format!("{:?}", err))
+        });
+
+    result.unwrap_or_else(|reason| {
+        record_skipped_deposit(SkippedDeposit {
+            ledger_canister_id: ledger,
+            block_index,
+            member,
+            amount: transfer.amount,
+            reason,
+            skipped_at: now(),
+        });
+        false
+    })
+}
+
+fn record_skipped_deposit(deposit: SkippedDeposit) {
+    SKIPPED_DEPOSITS.with(|skipped| {
+        let mut skipped = skipped.borrow_mut();
+        if skipped.len() >= MAX_SKIPPED_DEPOSITS {
+            skipped.pop_front();
+        }
+        skipped.push_back(deposit);
+    });
+}
+
+// Number of blocks on the ledger, i.e. the index the next block will get.
+pub async fn ledger_height(ledger: Principal) -> Result<BlockIndex, String> {
+    fetch_page(ledger, 0, 0).await.map(|page| page.log_length)
+}
+
+// Reads blocks `start..start + length`, including any the ledger has moved
+// to archive canisters. Falls back to `get_transactions` for ledgers without
+// ICRC-3.
+async fn fetch_page(ledger: Principal, start: u64, length: u64) -> Result<Page, String> {
+    match fetch_icrc3_page(ledger, start, length).await {
+        Ok(page) => Ok(page),
+        Err(icrc3_err) => fetch_legacy_page(ledger, start, length)
+            .await
+            .map_err(|legacy_err| format!("icrc3_get_blocks: {}; get_transactions: {}", icrc3_err, legacy_err)),
+    }
+}
+
+async fn fetch_icrc3_page(ledger: Principal, start: u64, length: u64) -> Result<Page, String> {
+    let args = vec![GetBlocksArgs { start: Nat::from(start), length: Nat::from(length) }];
+    let result: CallResult<(GetBlocksResult,)> = ic_cdk::call(ledger, "icrc3_get_blocks", (args,)).await;
+    let (result,) = result.map_err(|(rejection_code, err)| format!("{:?} - {}", rejection_code, err))?;
+
+    let mut blocks = result.blocks;
+    for archived in result.archived_blocks {
+        let archived_result: CallResult<(GetBlocksResult,)> = ic_cdk::call(
+            archived.callback.0.principal,
+            &archived.callback.0.method,
+            (archived.args,),
+        )
+        .await;
+        let (archived_result,) = archived_result
+            .map_err(|(rejection_code, err)| format!("archive {:?} - {}", rejection_code, err))?;
+        blocks.extend(archived_result.blocks);
+    }
+
+    let mut transfers = blocks
+        .into_iter()
+        .map(|block| Ok((nat_to_u64(block.id)?, transfer_from_block(&block.block))))
+        .collect::<Result<Vec<_>, String>>()?;
+    transfers.sort_by_key(|(block_index, _)| *block_index);
+
+    Ok(Page {
+        log_length: nat_to_u64(result.log_length)?,
+        transfers,
+    })
+}
+
+async fn fetch_legacy_page(ledger: Principal, start: u64, length: u64) -> Result<Page, String> {
+    let request = GetTransactionsRequest { start: Nat::from(start), length: Nat::from(length) };
+    let response: CallResult<(GetTransactionsResponse,)> = ic_cdk::call(ledger, "get_transactions", (request,)).await;
+    let (response,) = response.map_err(|(rejection_code, err)| format!("{:?} - {}", rejection_code, err))?;
+
+    let mut transfers = Vec::new();
+    for archived in response.archived_transactions {
+        let range_start = nat_to_u64(archived.start.clone())?;
+        let request = GetTransactionsRequest { start: archived.start, length: archived.length };
+        let range: CallResult<(TransactionRange,)> = ic_cdk::call(
+            archived.callback.0.principal,
+            &archived.callback.0.method,
+            (request,),
+        )
+        .await;
+        let (range,) = range.map_err(|(rejection_code, err)| format!("archive {:?} - {}", rejection_code, err))?;
+        transfers.extend(
+            (range_start..)
+                .zip(range.transactions)
+                .map(|(block_index, tx)| (block_index, transfer_from_transaction(tx))),
+        );
+    }
+
+    let first_index = nat_to_u64(response.first_index)?;
+    transfers.extend(
+        (first_index..)
+            .zip(response.transactions)
+            .map(|(block_index, tx)| (block_index, transfer_from_transaction(tx))),
+    );
+    transfers.sort_by_key(|(block_index, _)| *block_index);
+
+    Ok(Page {
+        log_length: nat_to_u64(response.log_length)?,
+        transfers,
+    })
+}
+
+fn transfer_from_transaction(tx: LedgerTransaction) -> Option<IncomingTransfer> {
+    match tx.kind.as_str() {
+        "mint" => tx.mint.map(|mint| IncomingTransfer {
+            from: None,
+            to: mint.to,
+            amount: mint.amount,
+            timestamp: tx.timestamp,
+        }),
+        "transfer" => tx.transfer.map(|transfer| IncomingTransfer {
+            from: Some(transfer.from.owner),
+            to: transfer.to,
+            amount: transfer.amount,
+            timestamp: tx.timestamp,
+        }),
+        _ => None,
+    }
+}
+
+// Reads a mint or transfer out of an ICRC-3 generic block. Ledgers put the
+// operation in either `btype` ("1mint", "1xfer", "2xfer") or `tx.op`.
+fn transfer_from_block(block: &Value) -> Option<IncomingTransfer> {
+    let block = as_map(block)?;
+    let tx = as_map(field(block, "tx")?)?;
+
+    let operation = field(block, "btype")
+        .or_else(|| field(tx, "op"))
+        .and_then(as_text)?;
+    let from = match operation {
+        "1mint" | "mint" => None,
+        "1xfer" | "2xfer" | "xfer" => Some(as_account(field(tx, "from")?)?.owner),
+        _ => return None,
+    };
+
+    Some(IncomingTransfer {
+        from,
+        to: as_account(field(tx, "to")?)?,
+        amount: as_nat(field(tx, "amt")?)?.clone(),
+        timestamp: nat_to_u64(as_nat(field(block, "ts")?)?.clone()).ok()?,
+    })
+}
+
+fn field<'a>(entries: &'a [(String, Value)], key: &str) -> Option<&'a Value> {
+    entries.iter().find(|(name, _)| name == key).map(|(_, value)| value)
+}
+
+fn as_map(value: &Value) -> Option<&[(String, Value)]> {
+    match value {
+        Value::Map(entries) => Some(entries.as_slice()),
+        _ => None,
+    }
+}
+
+fn as_text(value: &Value) -> Option<&str> {
+    match value {
+        Value::Text(text) => Some(text.as_str()),
+        _ => None,
+    }
+}
+
+fn as_nat(value: &Value) -> Option<&Nat> {
+    match value {
+        Value::Nat(nat) => Some(nat),
+        _ => None,
+    }
+}
+
+// Accounts are encoded as `[owner]` or `[owner, subaccount]`
+fn as_account(value: &Value) -> Option<Account> {
+    let Value::Array(parts) = value else {
+        return None;
+    };
+    let owner = match parts.first()? {
+        Value::Blob(bytes) => Principal::try_from_slice(bytes).ok()?,
+        _ => return None,
+    };
+    let subaccount = match parts.get(1) {
+        Some(Value::Blob(bytes)) => Some(Subaccount::try_from(bytes.as_slice()).ok()?),
+        Some(_) => return None,
+        None => None,
+    };
+    Some(Account::new(owner, subaccount))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id; 29])
+    }
+
+    fn account_value(owner: Principal, subaccount: Option<Subaccount>) -> Value {
+        let mut parts = vec![Value::Blob(owner.as_slice().to_vec())];
+        parts.extend(subaccount.map(|subaccount| Value::Blob(subaccount.to_vec())));
+        Value::Array(parts)
+    }
+
+    fn block(btype: Option<&str>, tx: Vec<(&str, Value)>) -> Value {
+        let mut entries = vec![
+            ("ts".to_string(), Value::Nat(Nat::from(1_000u64))),
+            ("tx".to_string(), Value::Map(tx.into_iter().map(|(key, value)| (key.to_string(), value)).collect())),
+        ];
+        entries.extend(btype.map(|btype| ("btype".to_string(), Value::Text(btype.to_string()))));
+        Value::Map(entries)
+    }
+
+    fn transfer(to: Account) -> IncomingTransfer {
+        IncomingTransfer {
+            from: Some(principal(2)),
+            to,
+            amount: Nat::from(500u64),
+            timestamp: 1_000,
+        }
+    }
+
+    #[test]
+    fn test_transfer_from_block() {
+        let member = member_subaccount(&principal(1));
+        let to = account_value(canister_id(), Some(member));
+        let from = account_value(principal(2), None);
+        let amt = Value::Nat(Nat::from(500u64));
+
+        let mint = transfer_from_block(&block(Some("1mint"), vec![("to", to.clone()), ("amt", amt.clone())])).unwrap();
+        assert_eq!(mint.from, None);
+        assert_eq!(mint.to, Account::new(canister_id(), Some(member)));
+        assert_eq!(mint.amount, Nat::from(500u64));
+        assert_eq!(mint.timestamp, 1_000);
+
+        for btype in ["1xfer", "2xfer"] {
+            let xfer = block(Some(btype), vec![("from", from.clone()), ("to", to.clone()), ("amt", amt.clone())]);
+            assert_eq!(transfer_from_block(&xfer).unwrap().from, Some(principal(2)));
+        }
+
+        // Ledgers that name the operation in `tx.op` instead of `btype`
+        let op = Value::Text("xfer".to_string());
+        let xfer = block(None, vec![("op", op), ("from", from.clone()), ("to", to.clone()), ("amt", amt.clone())]);
+        assert_eq!(transfer_from_block(&xfer).unwrap().from, Some(principal(2)));
+        let op = Value::Text("mint".to_string());
+        let mint = block(None, vec![("op", op), ("to", to.clone()), ("amt", amt.clone())]);
+        assert_eq!(transfer_from_block(&mint).unwrap().from, None);
+
+        // Burns and approvals are not deposits
+        let burn = block(Some("1burn"), vec![("from", from.clone()), ("amt", amt.clone())]);
+        assert!(transfer_from_block(&burn).is_none());
+        let approve = block(Some("2approve"), vec![("from", from.clone()), ("amt", amt.clone())]);
+        assert!(transfer_from_block(&approve).is_none());
+    }
+
+    #[test]
+    fn test_malformed_accounts_are_ignored() {
+        assert!(as_account(&account_value(principal(1), None)).is_some());
+        assert!(as_account(&Value::Array(vec![])).is_none());
+        assert!(as_account(&Value::Blob(principal(1).as_slice().to_vec())).is_none());
+        assert!(as_account(&Value::Array(vec![Value::Text("aaaaa-aa".to_string())])).is_none());
+        assert!(as_account(&Value::Array(vec![Value::Blob(vec![0; 30])])).is_none());
+        // Subaccounts are exactly 32 bytes
+        let short = Value::Array(vec![Value::Blob(principal(1).as_slice().to_vec()), Value::Blob(vec![0; 31])]);
+        assert!(as_account(&short).is_none());
+
+        let amt = Value::Nat(Nat::from(500u64));
+        let bad_to = Value::Array(vec![Value::Text("aaaaa-aa".to_string())]);
+        assert!(transfer_from_block(&block(Some("1mint"), vec![("to", bad_to), ("amt", amt)])).is_none());
+    }
+
+    #[test]
+    fn test_contiguous_transfers_stop_at_gaps() {
+        let to = Account::principal_only(principal(1));
+        let blocks = vec![
+            (10, Some(transfer(to.clone()))),
+            (11, None),
+            (12, Some(transfer(to.clone()))),
+            (14, Some(transfer(to.clone()))),
+            (15, Some(transfer(to.clone()))),
+        ];
+        let (transfers, next) = contiguous_transfers(10, blocks);
+        assert_eq!(transfers.iter().map(|(block_index, _)| *block_index).collect::<Vec<_>>(), vec![10, 12]);
+        assert_eq!(next, 13);
+
+        // A page that doesn't start at the cursor advances nothing
+        let (transfers, next) = contiguous_transfers(10, vec![(11, Some(transfer(to)))]);
+        assert!(transfers.is_empty());
+        assert_eq!(next, 10);
+    }
+
+    #[test]
+    fn test_uncreditable_deposits_are_recorded() {
+        let ledger = Principal::from_slice(&[9; 10]);
+        let member = principal(1);
+        let member_account = Account::new(canister_id(), Some(member_subaccount(&member)));
+
+        // Not addressed to a member subaccount of this canister
+        assert!(!credit_transfer(ledger, 1, transfer(Account::new(principal(3), Some(member_subaccount(&member))))));
+        assert!(!credit_transfer(ledger, 2, transfer(Account::principal_only(canister_id()))));
+        assert!(skipped_deposits().is_empty());
+
+        // Unregistered ledger, and an amount too large for the vault
+        assert!(!credit_transfer(ledger, 3, transfer(member_account.clone())));
+        let mut huge = transfer(member_account);
+        huge.amount = Nat::from(u128::MAX) + Nat::from(1u8);
+        assert!(!credit_transfer(ledger, 4, huge));
+
+        let skipped = skipped_deposits();
+        assert_eq!(skipped.iter().map(|deposit| deposit.block_index).collect::<Vec<_>>(), vec![3, 4]);
+        assert!(skipped.iter().all(|deposit| deposit.member == member && deposit.ledger_canister_id == ledger));
+        assert!(skipped[0].reason.contains("not registered"));
+        assert_eq!(skipped[1].amount, Nat::from(u128::MAX) + Nat::from(1u8));
+    }
+}
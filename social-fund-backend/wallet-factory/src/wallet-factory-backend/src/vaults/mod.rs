@@ -1,12 +1,16 @@
-use crate::{types::*, vaults::{ckbtc::{CkBtcVault, VaultMetrics}, ckusdt::CkUsdtVault, icp::IcpVault}};
+use crate::{types::*, vaults::{icp::IcpDepositAddress, icrc1::{Icrc1Vault, LegacyIcrc1Vault}, token::{LegacyTokenConfig, Minter, TokenBalance, TokenConfig, TokenVault, VaultMetrics}}};
 use candid::{CandidType, Principal};
 use serde::{Deserialize, Serialize};
-use std::{cell::RefCell, collections::HashMap};
+use std::{cell::RefCell, collections::{HashMap, HashSet}};
 use thiserror::Error;
 
 pub mod ckbtc;
-pub mod ckusdt;
+pub mod ckerc20;
 pub mod icp;
+mod icrc;
+pub mod icrc1;
+pub mod indexer;
+pub mod token;
 
 // Enhanced vault manager with comprehensive features
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
@@ -20,8 +24,6 @@ pub struct VaultManager {
     metrics: VaultManagerMetrics,
     // Security features
     security_settings: SecuritySettings,
-    // Rate limiting
-    rate_limiters: HashMap<VaultType, RateLimiter>,
 }
 
 impl Default for VaultManager {
@@ -33,7 +35,6 @@ impl Default for VaultManager {
             config: VaultConfiguration::default(),
             metrics: VaultManagerMetrics::default(),
             security_settings: SecuritySettings::default(),
-            rate_limiters: HashMap::new(),
         }
     }
 }
@@ -67,6 +68,8 @@ pub struct VaultManagerMetrics {
     pub successful_operations: u64,
     pub failed_operations: u64,
     pub total_volume_by_type: HashMap<VaultType, u64>,
+    // Keyed by ledger canister id; covers tokens without a `VaultType`
+    pub total_volume_by_ledger: Option<HashMap<Principal, u64>>,
     pub average_response_times: HashMap<String, u64>,
     pub last_metrics_reset: u64,
 }
@@ -105,10 +108,109 @@ impl RateLimiter {
 
 // Thread-local storage for vault instances
 thread_local! {
-    static ICP_VAULTS: RefCell<HashMap<Principal, IcpVault>> = RefCell::new(HashMap::new());
-    static CKBTC_VAULTS: RefCell<HashMap<Principal, CkBtcVault>> = RefCell::new(HashMap::new());
-    static CKUSDT_VAULTS: RefCell<HashMap<Principal, CkUsdtVault>> = RefCell::new(HashMap::new());
+    // Registered tokens, keyed by ledger canister id
+    static TOKENS: RefCell<HashMap<Principal, TokenConfig>> = RefCell::new(default_tokens());
+    // Vaults keyed by ledger canister id, then owner
+    static TOKEN_VAULTS: RefCell<HashMap<Principal, HashMap<Principal, Icrc1Vault>>> = RefCell::new(HashMap::new());
     static VAULT_MANAGERS: RefCell<HashMap<Principal, VaultManager>> = RefCell::new(HashMap::new());
+    // Keyed by owner, ledger and operation. Not persisted: a window is a minute.
+    static RATE_LIMITERS: RefCell<HashMap<(Principal, Principal, String), RateLimiter>> = RefCell::new(HashMap::new());
+    // Owners with a vault operation in flight
+    static OPERATION_LOCKS: RefCell<HashSet<Principal>> = RefCell::new(HashSet::new());
+}
+
+// Held across the awaits of a vault operation so two calls for the same owner
+// can't interleave between reserving funds and settling them. Released on
+// drop, which ic-cdk also runs when a callback traps.
+struct OperationLock {
+    owner: Principal,
+}
+
+impl OperationLock {
+    fn acquire(owner: Principal) -> Result<Self, WalletError> {
+        OPERATION_LOCKS.with(|locks| {
+            if locks.borrow_mut().insert(owner) {
+                Ok(Self { owner })
+            } else {
+                Err(WalletError::OperationInProgress {
+                    principal: owner.to_string(),
+                })
+            }
+        })
+    }
+}
+
+impl Drop for OperationLock {
+    fn drop(&mut self) {
+        OPERATION_LOCKS.with(|locks| {
+            locks.borrow_mut().remove(&self.owner);
+        });
+    }
+}
+
+pub fn token_config(ledger: Principal) -> Result<TokenConfig, WalletError> {
+    TOKENS.with(|tokens| tokens.borrow().get(&ledger).cloned()).ok_or(WalletError::ValidationError {
+        field: "ledger_canister_id".to_string(),
+        message: format!("Token with ledger {} is not registered", ledger),
+    })
+}
+
+// Runs `f` on the owner's vault for `ledger` under a borrow that never spans
+// an await. Vault operations call it once to reserve and again to settle,
+// looking the vault up afresh each time, so a vault replaced in between (for
+// example by `restore_vault_state`) is reported missing instead of being
+// aliased. Members who joined before a token was registered get its vault
+// on first use.
+fn with_vault<R>(
+    owner: Principal,
+    ledger: Principal,
+    operation: &str,
+    f: impl FnOnce(&mut Icrc1Vault, &TokenConfig) -> Result<R, WalletError>,
+) -> Result<R, WalletError> {
+    let config = token_config(ledger)?;
+    let has_manager = VAULT_MANAGERS.with(|managers| managers.borrow().contains_key(&owner));
+
+    TOKEN_VAULTS.with(|vaults| {
+        let mut vaults = vaults.borrow_mut();
+        let ledger_vaults = vaults.entry(ledger).or_default();
+        if has_manager && !ledger_vaults.contains_key(&owner) {
+            ledger_vaults.insert(owner, Icrc1Vault::new(owner, ledger));
+        }
+
+        match ledger_vaults.get_mut(&owner) {
+            Some(vault) => f(vault, &config),
+            None => Err(WalletError::VaultError {
+                operation: operation.to_string(),
+                details: format!("{} vault not found", config.symbol),
+            }),
+        }
+    })
+}
+
+fn check_rate_limit(owner: Principal, ledger: Principal, operation: &str, max_per_minute: u32) -> Result<(), WalletError> {
+    if !VAULT_MANAGERS.with(|managers| managers.borrow().contains_key(&owner)) {
+        return Err(WalletError::WalletNotFound {
+            principal: owner.to_string(),
+        });
+    }
+
+    RATE_LIMITERS.with(|limiters| {
+        limiters
+            .borrow_mut()
+            .entry((owner, ledger, operation.to_string()))
+            .or_insert_with(|| RateLimiter::new(max_per_minute))
+            .check_limit(operation)
+    })
+}
+
+fn record_operation(owner: Principal, operation: &str, success: bool, start_time: u64) {
+    let duration = ic_cdk::api::time() - start_time;
+
+    VAULT_MANAGERS.with(|managers| {
+        if let Some(manager) = managers.borrow_mut().get_mut(&owner) {
+            manager.record_operation(operation, success, duration);
+        }
+    });
 }
 
 impl VaultManager {
@@ -122,7 +224,6 @@ impl VaultManager {
             config: VaultConfiguration::default(),
             metrics: VaultManagerMetrics::default(),
             security_settings: SecuritySettings::default(),
-            rate_limiters: HashMap::new(),
         }
     }
     
@@ -205,322 +306,245 @@ fn principal_from_text(text: &str) -> Principal {
     Principal::from_text(text).unwrap_or(Principal::anonymous())
 }
 
-// Public interface functions with enhanced error handling and monitoring
+// Tokens every deployment starts with. Others, such as the governance
+// token, are added with `register_token`.
+fn default_tokens() -> HashMap<Principal, TokenConfig> {
+    let config = get_production_config();
+    let cketh_minter = config.canister_ids.cketh_minter;
 
-pub async fn initialize_vault_system(owner: Principal) -> Result<(), WalletError> {
-    let start_time = ic_cdk::api::time();
-    
-    let result = async {
-        // Initialize vault manager
-        let vault_manager = VaultManager::new(owner);
-        
-        VAULT_MANAGERS.with(|managers| {
-            managers.borrow_mut().insert(owner, vault_manager);
-        });
-        
-        // Initialize individual vaults
-        initialize_icp_vault(owner).await?;
-        initialize_ckbtc_vault(owner).await?;
-        initialize_ckusdt_vault(owner).await?;
-        
-        Ok(())
-    }.await;
-    
-    let duration = ic_cdk::api::time() - start_time;
-    
-    // Record metrics
-    VAULT_MANAGERS.with(|managers| {
-        if let Some(manager) = managers.borrow_mut().get_mut(&owner) {
-            manager.record_operation("initialize_vault_system", result.is_ok(), duration);
-        }
-    });
-    
-    result
+    let tokens = vec![
+        TokenConfig {
+            symbol: "ICP".to_string(),
+            ledger_canister_id: config.canister_ids.icp_ledger,
+            decimals: 8,
+            transfer_fee: config.fee_settings.icp_transfer_fee.into(),
+            min_transfer_amount: 1000,
+            min_withdrawal_amount: 0,
+            daily_withdrawal_limit: 1_000_000_000_000, // 10,000 ICP
+            minter: None,
+        },
+        TokenConfig {
+            symbol: "ckBTC".to_string(),
+            ledger_canister_id: config.canister_ids.ckbtc_ledger,
+            decimals: 8,
+            transfer_fee: 10,
+            min_transfer_amount: 1000,
+            min_withdrawal_amount: 10_000, // 0.0001 BTC
+            daily_withdrawal_limit: 100_000_000, // 1 BTC in satoshis
+            minter: Some(Minter::CkBtc {
+                minter_canister_id: config.canister_ids.ckbtc_minter,
+            }),
+        },
+        TokenConfig {
+            symbol: "ckUSDT".to_string(),
+            ledger_canister_id: config.canister_ids.ckusdt_ledger,
+            decimals: 6,
+            transfer_fee: 10_000,
+            min_transfer_amount: 1000,
+            min_withdrawal_amount: 1_000_000, // 1 USDT
+            daily_withdrawal_limit: 10_000_000_000, // 10,000 USDT
+            minter: Some(Minter::CkErc20 {
+                minter_canister_id: cketh_minter,
+                erc20_contract: "0xdAC17F958D2ee523a2206206994597C13D831ec7".to_string(),
+            }),
+        },
+        TokenConfig {
+            symbol: "ckUSDC".to_string(),
+            ledger_canister_id: principal_from_text("xevnm-gaaaa-aaaar-qafnq-cai"),
+            decimals: 6,
+            transfer_fee: 10_000,
+            min_transfer_amount: 1000,
+            min_withdrawal_amount: 1_000_000, // 1 USDC
+            daily_withdrawal_limit: 10_000_000_000, // 10,000 USDC
+            minter: Some(Minter::CkErc20 {
+                minter_canister_id: cketh_minter,
+                erc20_contract: "0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48".to_string(),
+            }),
+        },
+        // Held and moved on the Internet Computer only; withdrawing ETH to
+        // Ethereum is not supported yet
+        TokenConfig {
+            symbol: "ckETH".to_string(),
+            ledger_canister_id: cketh_ledger(),
+            decimals: 18,
+            transfer_fee: 2_000_000_000_000,
+            min_transfer_amount: 2_000_000_000_000,
+            min_withdrawal_amount: 0,
+            daily_withdrawal_limit: 10_000_000_000_000_000_000, // 10 ETH in wei
+            minter: None,
+        },
+    ];
+
+    tokens.into_iter().map(|token| (token.ledger_canister_id, token)).collect()
 }
 
-async fn initialize_icp_vault(owner: Principal) -> Result<(), WalletError> {
-    let config = get_production_config();
-    let vault = IcpVault::new(owner, &config.canister_ids.icp_ledger.to_text());
-    
-    ICP_VAULTS.with(|vaults| {
-        vaults.borrow_mut().insert(owner, vault);
-    });
-    
-    Ok(())
+// Pays the Ethereum gas of ckERC20 withdrawals
+fn cketh_ledger() -> Principal {
+    principal_from_text("ss2fx-dyaaa-aaaar-qacoq-cai")
 }
 
-async fn initialize_ckbtc_vault(owner: Principal) -> Result<(), WalletError> {
-    let config = get_production_config();
-    let vault = CkBtcVault::new(
-        owner,
-        &config.canister_ids.ckbtc_minter.to_text(),
-        &config.canister_ids.ckbtc_ledger.to_text(),
-    )?;
-    
-    CKBTC_VAULTS.with(|vaults| {
-        vaults.borrow_mut().insert(owner, vault);
-    });
-    
-    Ok(())
+/// Ledger behind one of the original vault types.
+pub fn ledger_of(vault_type: VaultType) -> Principal {
+    let canister_ids = get_production_config().canister_ids;
+    match vault_type {
+        VaultType::Icp => canister_ids.icp_ledger,
+        VaultType::CkBtc => canister_ids.ckbtc_ledger,
+        VaultType::CkUsdt => canister_ids.ckusdt_ledger,
+    }
 }
 
-async fn initialize_ckusdt_vault(owner: Principal) -> Result<(), WalletError> {
-    let config = get_production_config();
-    let vault = CkUsdtVault::new(
-        owner,
-        &config.canister_ids.ckusdt_ledger.to_text(),
-        &config.canister_ids.cketh_minter.to_text(),
-        "0xdAC17F958D2ee523a2206206994597C13D831ec7",
-    )?;
-    
-    CKUSDT_VAULTS.with(|vaults| {
-        vaults.borrow_mut().insert(owner, vault);
+pub fn vault_type_of(ledger: Principal) -> Option<VaultType> {
+    [VaultType::Icp, VaultType::CkBtc, VaultType::CkUsdt]
+        .into_iter()
+        .find(|&vault_type| ledger_of(vault_type) == ledger)
+}
+
+pub fn list_tokens() -> Vec<TokenConfig> {
+    let mut tokens: Vec<TokenConfig> = TOKENS.with(|tokens| tokens.borrow().values().cloned().collect());
+    tokens.sort_by(|a, b| a.symbol.cmp(&b.symbol));
+    tokens
+}
+
+/// Adds a token or updates its settings. Existing members get a vault for it
+/// the first time they use it.
+pub fn register_token(config: TokenConfig) -> Result<(), WalletError> {
+    config.validate()?;
+
+    ic_cdk::println!("Token {} registered for ledger {}", config.symbol, config.ledger_canister_id);
+    TOKENS.with(|tokens| {
+        tokens.borrow_mut().insert(config.ledger_canister_id, config);
     });
-    
     Ok(())
 }
 
-pub async fn batch_update_balances(owner: Principal) -> Result<HashMap<VaultType, u64>, WalletError> {
+// Public interface functions with enhanced error handling and monitoring
+
+pub async fn initialize_vault_system(owner: Principal) -> Result<(), WalletError> {
     let start_time = ic_cdk::api::time();
-    
-    let result = async {
-        let mut balances = HashMap::new();
-        
-        // Update ICP balance
-        if let Ok(balance) = update_icp_balance(owner).await {
-            balances.insert(VaultType::Icp, balance);
-        }
-        
-        // Update ckBTC balance
-        if let Ok(balance) = update_ckbtc_balance(owner).await {
-            balances.insert(VaultType::CkBtc, balance);
-        }
-        
-        // Update ckUSDT balance
-        if let Ok(balance) = update_ckusdt_balance(owner).await {
-            balances.insert(VaultType::CkUsdt, balance);
-        }
-        
-        if balances.is_empty() {
-            return Err(WalletError::VaultError {
-                operation: "batch_update_balances".to_string(),
-                details: "Failed to update any vault balances".to_string(),
-            });
-        }
-        
-        Ok(balances)
-    }.await;
-    
-    let duration = ic_cdk::api::time() - start_time;
-    
-    // Record metrics
+
+    // Initialize vault manager
+    let vault_manager = VaultManager::new(owner);
+
     VAULT_MANAGERS.with(|managers| {
-        if let Some(manager) = managers.borrow_mut().get_mut(&owner) {
-            manager.record_operation("batch_update_balances", result.is_ok(), duration);
-        }
+        managers.borrow_mut().insert(owner, vault_manager);
     });
-    
-    result
-}
 
-pub async fn update_ckbtc_balance(owner: Principal) -> Result<u64, WalletError> {
-    let start_time = ic_cdk::api::time();
-    
-    // Check rate limit
-    VAULT_MANAGERS.with(|managers| {
-        if let Some(manager) = managers.borrow_mut().get_mut(&owner) {
-            let rate_limiter = manager.rate_limiters
-                .entry(VaultType::CkBtc)
-                .or_insert_with(|| RateLimiter::new(get_production_config().rate_limits.balance_updates_per_minute));
-            rate_limiter.check_limit("update_balance")
-        } else {
-            Err(WalletError::WalletNotFound {
-                principal: owner.to_string(),
-            })
-        }
-    })?;
-    
-    let result = CKBTC_VAULTS.with(|vaults| {
+    // Initialize a vault for every registered token
+    let ledgers: Vec<Principal> = TOKENS.with(|tokens| tokens.borrow().keys().copied().collect());
+    TOKEN_VAULTS.with(|vaults| {
         let mut vaults = vaults.borrow_mut();
-        match vaults.get_mut(&owner) {
-            Some(vault) => {
-                // Await the future inside the closure to avoid lifetime issues
-                futures::executor::block_on(vault.update_balance())
-            },
-            None => Err(WalletError::VaultError {
-                operation: "update_ckbtc_balance".to_string(),
-                details: "ckBTC vault not found".to_string(),
-            }),
-        }
-    });
-    
-    let duration = ic_cdk::api::time() - start_time;
-    
-    // Record metrics
-    VAULT_MANAGERS.with(|managers| {
-        if let Some(manager) = managers.borrow_mut().get_mut(&owner) {
-            manager.record_operation("update_ckbtc_balance", result.is_ok(), duration);
+        for ledger in ledgers {
+            vaults.entry(ledger).or_default().insert(owner, Icrc1Vault::new(owner, ledger));
         }
     });
-    
-    result
+
+    record_operation(owner, "initialize_vault_system", true, start_time);
+    Ok(())
 }
 
-pub async fn update_icp_balance(owner: Principal) -> Result<u64, WalletError> {
+pub async fn batch_update_balances(owner: Principal) -> Result<Vec<TokenBalance>, WalletError> {
     let start_time = ic_cdk::api::time();
 
-    // Extract the vault out of the RefCell borrow so the future does not borrow local data
-    let vault_opt = ICP_VAULTS.with(|vaults| {
-        let mut vaults = vaults.borrow_mut();
-        vaults.get_mut(&owner).map(|vault| vault as *mut IcpVault)
-    });
+    let mut balances = Vec::new();
+    for token in list_tokens() {
+        if let Ok(balance) = update_balance(owner, token.ledger_canister_id).await {
+            balances.push(TokenBalance {
+                ledger_canister_id: token.ledger_canister_id,
+                symbol: token.symbol,
+                balance,
+            });
+        }
+    }
 
-    let result = if let Some(vault_ptr) = vault_opt {
-        // SAFETY: We only use this pointer here and RefCell borrow is dropped
-        let vault = unsafe { &mut *vault_ptr };
-        vault.update_balance().await
-    } else {
+    let result = if balances.is_empty() {
         Err(WalletError::VaultError {
-            operation: "update_icp_balance".to_string(),
-            details: "ICP vault not found".to_string(),
+            operation: "batch_update_balances".to_string(),
+            details: "Failed to update any vault balances".to_string(),
         })
+    } else {
+        Ok(balances)
     };
 
-    let duration = ic_cdk::api::time() - start_time;
-
-    // Record metrics
-    VAULT_MANAGERS.with(|managers| {
-        if let Some(manager) = managers.borrow_mut().get_mut(&owner) {
-            manager.record_operation("update_icp_balance", result.is_ok(), duration);
-        }
-    });
-
+    record_operation(owner, "batch_update_balances", result.is_ok(), start_time);
     result
 }
 
-pub async fn update_ckusdt_balance(owner: Principal) -> Result<u64, WalletError> {
+pub async fn update_balance(owner: Principal, ledger: Principal) -> Result<Tokens, WalletError> {
     let start_time = ic_cdk::api::time();
-    
-    let result = {
-        let vault_opt = CKUSDT_VAULTS.with(|vaults| {
-            let mut vaults = vaults.borrow_mut();
-            vaults.get_mut(&owner).map(|vault| vault as *mut CkUsdtVault)
-        });
 
-        if let Some(vault_ptr) = vault_opt {
-            // SAFETY: Only used here, RefCell borrow is dropped
-            let vault = unsafe { &mut *vault_ptr };
-            vault.update_balance().await
-        } else {
-            Err(WalletError::VaultError {
-                operation: "update_ckusdt_balance".to_string(),
-                details: "ckUSDT vault not found".to_string(),
-            })
-        }
-    };
-    
-    let duration = ic_cdk::api::time() - start_time;
-    
-    // Record metrics  
-    VAULT_MANAGERS.with(|managers| {
-        if let Some(manager) = managers.borrow_mut().get_mut(&owner) {
-            manager.record_operation("update_ckusdt_balance", result.is_ok(), duration);
+    // Check rate limit
+    check_rate_limit(owner, ledger, "update_balance", get_production_config().rate_limits.balance_updates_per_minute)?;
+
+    let result = async {
+        let _lock = OperationLock::acquire(owner)?;
+        let (minter, request) = with_vault(owner, ledger, "update_balance", |vault, config| {
+            Ok((config.minter.clone(), vault.balance_request()))
+        })?;
+
+        let Some(call) = request else {
+            return with_vault(owner, ledger, "update_balance", |vault, _| Ok(vault.balance()));
+        };
+
+        // Have the minter mint for any confirmed BTC deposits before reading
+        // the ledger. A failure here only delays those deposits.
+        if let Some(Minter::CkBtc { minter_canister_id }) = minter {
+            if let Err(err) = ckbtc::update_balance_request(minter_canister_id, Account::member(owner)).await {
+                ic_cdk::println!("ckBTC minter update_balance failed: {}", err);
+            }
         }
-    });
-    
+
+        let outcome = call.await;
+        with_vault(owner, ledger, "update_balance", |vault, config| vault.apply_balance(config, outcome))
+    }.await;
+
+    record_operation(owner, "update_balance", result.is_ok(), start_time);
     result
 }
 
-pub async fn transfer_tokens(
+pub async fn transfer(
     owner: Principal,
-    vault_type: VaultType,
-    amount: u64,
-    recipient: Principal,
+    ledger: Principal,
+    amount: Tokens,
+    to: Account,
 ) -> Result<BlockIndex, WalletError> {
     let start_time = ic_cdk::api::time();
-    let validated_amount = ValidatedAmount::new(amount, 1000)?;
 
     // Check rate limit
-    VAULT_MANAGERS.with(|managers| {
-        if let Some(manager) = managers.borrow_mut().get_mut(&owner) {
-            let rate_limiter = manager.rate_limiters
-                .entry(vault_type)
-                .or_insert_with(|| RateLimiter::new(get_production_config().rate_limits.transfers_per_minute));
-            rate_limiter.check_limit("transfer")
-        } else {
-            Err(WalletError::WalletNotFound {
-                principal: owner.to_string(),
-            })
-        }
-    })?;
-    
-    let result = match vault_type {
-        VaultType::Icp => {
-            let vault_ptr_opt = ICP_VAULTS.with(|vaults| {
-                let mut vaults = vaults.borrow_mut();
-                vaults.get_mut(&owner).map(|vault| vault as *mut IcpVault)
-            });
-            if let Some(vault_ptr) = vault_ptr_opt {
-                // SAFETY: Only used here, RefCell borrow is dropped
-                let vault = unsafe { &mut *vault_ptr };
-                vault.transfer(validated_amount.value(), recipient).await
-            } else {
-                Err(WalletError::VaultError {
-                    operation: "icp_transfer".to_string(),
-                    details: "ICP vault not found".to_string(),
-                })
-            }
-        }
-        VaultType::CkBtc => {
-            let vault_ptr_opt = CKBTC_VAULTS.with(|vaults| {
-                let mut vaults = vaults.borrow_mut();
-                vaults.get_mut(&owner).map(|vault| vault as *mut CkBtcVault)
-            });
-            if let Some(vault_ptr) = vault_ptr_opt {
-                // SAFETY: Only used here, RefCell borrow is dropped
-                let vault = unsafe { &mut *vault_ptr };
-                vault.transfer(validated_amount.value(), recipient).await
-            } else {
-                Err(WalletError::VaultError {
-                    operation: "ckbtc_transfer".to_string(),
-                    details: "ckBTC vault not found".to_string(),
-                })
-            }
-        }
-        VaultType::CkUsdt => {
-            let vault_ptr_opt = CKUSDT_VAULTS.with(|vaults| {
-                let mut vaults = vaults.borrow_mut();
-                vaults.get_mut(&owner).map(|vault| vault as *mut CkUsdtVault)
-            });
-            if let Some(vault_ptr) = vault_ptr_opt {
-                // SAFETY: Only used here, RefCell borrow is dropped
-                let vault = unsafe { &mut *vault_ptr };
-                vault.transfer(validated_amount.value(), recipient).await.map(|_| 0)
-            } else {
-                Err(WalletError::VaultError {
-                    operation: "ckusdt_transfer".to_string(),
-                    details: "ckUSDT vault not found".to_string(),
-                })
-            }
-        }
-    };
-    
+    check_rate_limit(owner, ledger, "transfer", get_production_config().rate_limits.transfers_per_minute)?;
+
+    let result = async {
+        let _lock = OperationLock::acquire(owner)?;
+        let (reservation, call) = with_vault(owner, ledger, "transfer", |vault, config| {
+            vault.reserve_transfer(config, amount, to)
+        })?;
+        let outcome = call.await;
+        with_vault(owner, ledger, "transfer", |vault, config| {
+            vault.settle_transfer(config, reservation, outcome)
+        })
+    }.await;
+
     let duration = ic_cdk::api::time() - start_time;
-    
+
     // Record metrics and update volume
     VAULT_MANAGERS.with(|managers| {
         if let Some(manager) = managers.borrow_mut().get_mut(&owner) {
             manager.record_operation("transfer", result.is_ok(), duration);
-            
+
             if result.is_ok() {
-                let current_volume = manager.metrics.total_volume_by_type
-                    .entry(vault_type)
+                let amount = saturating_u64(amount);
+                if let Some(vault_type) = vault_type_of(ledger) {
+                    let volume = manager.metrics.total_volume_by_type.entry(vault_type).or_insert(0);
+                    *volume = volume.saturating_add(amount);
+                }
+                let volume = manager.metrics.total_volume_by_ledger
+                    .get_or_insert_with(HashMap::new)
+                    .entry(ledger)
                     .or_insert(0);
-                *current_volume += validated_amount.value();
+                *volume = volume.saturating_add(amount);
             }
         }
     });
-    
+
     result
 }
 
@@ -530,280 +554,301 @@ pub async fn retrieve_btc(
     btc_address: String,
 ) -> Result<u64, WalletError> {
     let start_time = ic_cdk::api::time();
-    
-    // Validate inputs
-    let validated_amount = ValidatedAmount::new(amount, 10_000)?; // Min 0.0001 BTC
-    let validated_address = ValidatedBtcAddress::new(btc_address)?;
-    
+    let ledger = ledger_of(VaultType::CkBtc);
+
     // Check rate limit
-    VAULT_MANAGERS.with(|managers| {
-        if let Some(manager) = managers.borrow_mut().get_mut(&owner) {
-            let rate_limiter = manager.rate_limiters
-                .entry(VaultType::CkBtc)
-                .or_insert_with(|| RateLimiter::new(10)); // Max 10 BTC withdrawals per minute
-            rate_limiter.check_limit("retrieve_btc")
-        } else {
-            Err(WalletError::WalletNotFound {
-                principal: owner.to_string(),
-            })
-        }
-    })?;
-    
+    check_rate_limit(owner, ledger, "retrieve_btc", 10)?; // Max 10 BTC withdrawals per minute
+
     let result = async {
-        let vault_opt = CKBTC_VAULTS.with(|vaults| {
-            let mut vaults = vaults.borrow_mut();
-            vaults.get_mut(&owner).map(|vault| vault as *mut CkBtcVault)
-        });
-        
-        if let Some(vault_ptr) = vault_opt {
-            let vault = unsafe { &mut *vault_ptr };
-            vault.retrieve_btc(validated_amount.value(), validated_address.as_str().to_string()).await
-        } else {
-            Err(WalletError::VaultError {
-                operation: "retrieve_btc".to_string(),
-                details: "ckBTC vault not found".to_string(),
-            })
+        let _lock = OperationLock::acquire(owner)?;
+        let (reservation, minter_canister_id, call) = with_vault(owner, ledger, "retrieve_btc", |vault, config| {
+            let Some(Minter::CkBtc { minter_canister_id }) = config.minter else {
+                return Err(no_minter("retrieve_btc", config));
+            };
+            let (reservation, call) = ckbtc::reserve_retrieve_btc(vault, config, minter_canister_id, amount, btc_address)?;
+            Ok((reservation, minter_canister_id, call))
+        })?;
+
+        // The minter burns from the owner's subaccount, so it has to be
+        // approved as a spender first. The approval is settled on its own:
+        // its fee is spent even if the retrieval then fails.
+        let minter = Account::principal_only(minter_canister_id);
+        if let Err(err) = approve_spender(owner, ledger, "retrieve_btc", minter, amount.into()).await {
+            release(owner, ledger, "retrieve_btc", &reservation, &err)?;
+            return Err(err);
         }
+
+        let outcome = call.await;
+        with_vault(owner, ledger, "retrieve_btc", |vault, _| {
+            ckbtc::settle_retrieve_btc(vault, reservation, outcome)
+        })
     }.await;
-    
-    let duration = ic_cdk::api::time() - start_time;
-    
-    // Record metrics
-    VAULT_MANAGERS.with(|managers| {
-        if let Some(manager) = managers.borrow_mut().get_mut(&owner) {
-            manager.record_operation("retrieve_btc", result.is_ok(), duration);
-        }
-    });
-    
+
+    record_operation(owner, "retrieve_btc", result.is_ok(), start_time);
     result
 }
 
-pub async fn withdraw_usdt(
+/// Withdraws a ckERC20 token, such as ckUSDT or ckUSDC, to an Ethereum address.
+pub async fn withdraw_erc20(
     owner: Principal,
-    amount: u64,
+    ledger: Principal,
+    amount: Tokens,
     ethereum_address: String,
 ) -> Result<WithdrawalId, WalletError> {
     let start_time = ic_cdk::api::time();
-    
-    // Validate inputs
-    let validated_amount = ValidatedAmount::new(amount, 1_000_000)?; // Min 1 USDT (6 decimals)
-    
-    // Basic Ethereum address validation
-    if !ethereum_address.starts_with("0x") || ethereum_address.len() != 42 {
-        return Err(WalletError::InvalidAddress {
-            address: ethereum_address,
-            reason: "Invalid Ethereum address format".to_string(),
-        });
-    }
-    
+
     // Check rate limit
-    VAULT_MANAGERS.with(|managers| {
-        if let Some(manager) = managers.borrow_mut().get_mut(&owner) {
-            let rate_limiter = manager.rate_limiters
-                .entry(VaultType::CkUsdt)
-                .or_insert_with(|| RateLimiter::new(5));
-            rate_limiter.check_limit("withdraw_usdt")
-        } else {
-            Err(WalletError::WalletNotFound {
-                principal: owner.to_string(),
-            })
+    check_rate_limit(owner, ledger, "withdraw_erc20", 5)?;
+
+    let result = async {
+        let _lock = OperationLock::acquire(owner)?;
+        let cketh = cketh_ledger();
+        let (reservation, minter_canister_id, call) = with_vault(owner, ledger, "withdraw_erc20", |vault, config| {
+            let Some(Minter::CkErc20 { minter_canister_id, .. }) = config.minter else {
+                return Err(no_minter("withdraw_erc20", config));
+            };
+            let (reservation, call) = ckerc20::reserve_withdraw_erc20(
+                vault,
+                config,
+                minter_canister_id,
+                Account::member(owner),
+                amount,
+                ethereum_address,
+            )?;
+            Ok((reservation, minter_canister_id, call))
+        })?;
+
+        // The minter burns the gas fee from the owner's ckETH subaccount and
+        // the amount from their ckERC20 one, so it is approved on both ledgers
+        // first. Each approval is settled on its own: its fee is spent even if
+        // a later step fails.
+        let minter = Account::principal_only(minter_canister_id);
+        let prepared = async {
+            let gas_fee = ckerc20::gas_fee_request(minter_canister_id, ledger)
+                .await
+                .map_err(|details| WalletError::VaultError {
+                    operation: "withdraw_erc20".to_string(),
+                    details,
+                })?;
+            let gas_reservation = with_vault(owner, cketh, "withdraw_erc20", |vault, config| {
+                vault.reserve(config, gas_fee, 0, minter.clone(), TransactionStatus::Pending)
+            })?;
+
+            let approved = async {
+                approve_spender(owner, cketh, "withdraw_erc20", minter.clone(), gas_fee).await?;
+                approve_spender(owner, ledger, "withdraw_erc20", minter.clone(), amount).await
+            }
+            .await;
+            match approved {
+                Ok(_) => Ok(gas_reservation),
+                Err(err) => {
+                    release(owner, cketh, "withdraw_erc20", &gas_reservation, &err)?;
+                    Err(err)
+                }
+            }
         }
+        .await;
+        let gas_reservation = match prepared {
+            Ok(gas_reservation) => gas_reservation,
+            Err(err) => {
+                release(owner, ledger, "withdraw_erc20", &reservation, &err)?;
+                return Err(err);
+            }
+        };
+
+        let outcome = call.await;
+        with_vault(owner, cketh, "withdraw_erc20", |vault, _| {
+            ckerc20::settle_gas_fee(vault, gas_reservation, &outcome);
+            Ok(())
+        })?;
+        with_vault(owner, ledger, "withdraw_erc20", |vault, config| {
+            ckerc20::settle_withdraw_erc20(vault, config, reservation, &outcome)
+        })
+    }.await;
+
+    record_operation(owner, "withdraw_erc20", result.is_ok(), start_time);
+    result
+}
+
+pub async fn approve(
+    owner: Principal,
+    ledger: Principal,
+    spender: Account,
+    amount: Tokens,
+    expires_at: Option<u64>,
+) -> Result<BlockIndex, WalletError> {
+    let start_time = ic_cdk::api::time();
+
+    check_rate_limit(owner, ledger, "approve", get_production_config().rate_limits.transfers_per_minute)?;
+
+    let result = async {
+        let _lock = OperationLock::acquire(owner)?;
+        let (reservation, call) = with_vault(owner, ledger, "approve", |vault, config| {
+            vault.reserve_approve(config, spender, amount, expires_at)
+        })?;
+        let outcome = call.await;
+        with_vault(owner, ledger, "approve", |vault, config| {
+            vault.settle_transfer(config, reservation, outcome)
+        })
+    }.await;
+
+    record_operation(owner, "approve", result.is_ok(), start_time);
+    result
+}
+
+// Approves a minter to burn `amount` from the owner's vault, as one step of
+// a withdrawal that already holds the operation lock
+async fn approve_spender(
+    owner: Principal,
+    ledger: Principal,
+    operation: &str,
+    spender: Account,
+    amount: Tokens,
+) -> Result<BlockIndex, WalletError> {
+    let (reservation, call) = with_vault(owner, ledger, operation, |vault, config| {
+        vault.reserve_approve(config, spender, amount, None)
     })?;
-    
+    let outcome = call.await;
+    with_vault(owner, ledger, operation, |vault, config| {
+        vault.settle_transfer(config, reservation, outcome)
+    })
+}
+
+// Refunds a reservation whose withdrawal failed before the minter was called
+fn release(
+    owner: Principal,
+    ledger: Principal,
+    operation: &str,
+    reservation: &Reservation,
+    err: &WalletError,
+) -> Result<(), WalletError> {
+    with_vault(owner, ledger, operation, |vault, _| {
+        vault.release(reservation, err.to_string());
+        Ok(())
+    })
+}
+
+pub async fn deposit(owner: Principal, ledger: Principal, from: Account, amount: Tokens) -> Result<BlockIndex, WalletError> {
+    let start_time = ic_cdk::api::time();
+
+    check_rate_limit(owner, ledger, "deposit", get_production_config().rate_limits.transfers_per_minute)?;
+
     let result = async {
-        let vault_opt = CKUSDT_VAULTS.with(|vaults| {
-            let mut vaults = vaults.borrow_mut();
-            vaults.get_mut(&owner).map(|vault| vault as *mut CkUsdtVault)
-        });
-        
-        if let Some(vault_ptr) = vault_opt {
-            let vault = unsafe { &mut *vault_ptr };
-            vault.withdraw_usdt(validated_amount.value(), ethereum_address).await
-        } else {
-            Err(WalletError::VaultError {
-                operation: "withdraw_usdt".to_string(),
-                details: "ckUSDT vault not found".to_string(),
-            })
-        }
+        let _lock = OperationLock::acquire(owner)?;
+        let (transaction_id, call) = with_vault(owner, ledger, "deposit", |vault, config| {
+            let validated_amount = ValidatedAmount::new(amount, config.min_transfer_amount)?;
+            Ok(vault.deposit_request(config, from.clone(), validated_amount.value()))
+        })?;
+        let outcome = call.await;
+        with_vault(owner, ledger, "deposit", |vault, config| {
+            vault.settle_deposit(config, transaction_id, from, amount, outcome)
+        })
     }.await;
-    
-    let duration = ic_cdk::api::time() - start_time;
-    
-    // Record metrics
-    VAULT_MANAGERS.with(|managers| {
-        if let Some(manager) = managers.borrow_mut().get_mut(&owner) {
-            manager.record_operation("withdraw_usdt", result.is_ok(), duration);
-        }
-    });
-    
+
+    record_operation(owner, "deposit", result.is_ok(), start_time);
     result
 }
 
-pub fn get_balance(owner: Principal, vault_type: VaultType) -> Result<u64, WalletError> {
-    match vault_type {
-        VaultType::Icp => {
-            ICP_VAULTS.with(|vaults| {
-                let vaults = vaults.borrow();
-                let vault = vaults.get(&owner).ok_or(WalletError::VaultError {
-                    operation: "get_icp_balance".to_string(),
-                    details: "ICP vault not found".to_string(),
-                })?;
-                Ok(vault.balance())
-            })
-        }
-        VaultType::CkBtc => {
-            CKBTC_VAULTS.with(|vaults| {
-                let vaults = vaults.borrow();
-                let vault = vaults.get(&owner).ok_or(WalletError::VaultError {
-                    operation: "get_ckbtc_balance".to_string(),
-                    details: "ckBTC vault not found".to_string(),
-                })?;
-                Ok(vault.balance())
-            })
-        }
-        VaultType::CkUsdt => {
-            CKUSDT_VAULTS.with(|vaults| {
-                let vaults = vaults.borrow();
-                let vault = vaults.get(&owner).ok_or(WalletError::VaultError {
-                    operation: "get_ckusdt_balance".to_string(),
-                    details: "ckUSDT vault not found".to_string(),
-                })?;
-                Ok(vault.balance())
-            })
-        }
-    }
+pub fn get_balance(owner: Principal, ledger: Principal) -> Result<Tokens, WalletError> {
+    with_existing_vault(owner, ledger, "get_balance", |vault, _| Ok(vault.balance()))
 }
 
-pub fn get_all_balances(owner: Principal) -> Result<HashMap<VaultType, u64>, WalletError> {
-    let mut balances = HashMap::new();
-    
-    // Get ICP balance
-    if let Ok(balance) = get_balance(owner, VaultType::Icp) {
-        balances.insert(VaultType::Icp, balance);
-    }
-    
-    // Get ckBTC balance
-    if let Ok(balance) = get_balance(owner, VaultType::CkBtc) {
-        balances.insert(VaultType::CkBtc, balance);
-    }
-    
-    // Get ckUSDT balance
-    if let Ok(balance) = get_balance(owner, VaultType::CkUsdt) {
-        balances.insert(VaultType::CkUsdt, balance);
-    }
-    
+pub fn get_all_balances(owner: Principal) -> Result<Vec<TokenBalance>, WalletError> {
+    let balances: Vec<TokenBalance> = list_tokens()
+        .into_iter()
+        .filter_map(|token| {
+            let balance = get_balance(owner, token.ledger_canister_id).ok()?;
+            Some(TokenBalance {
+                ledger_canister_id: token.ledger_canister_id,
+                symbol: token.symbol,
+                balance,
+            })
+        })
+        .collect();
+
     if balances.is_empty() {
         return Err(WalletError::WalletNotFound {
             principal: owner.to_string(),
         });
     }
-    
+
     Ok(balances)
 }
 
 pub async fn get_btc_address(owner: Principal) -> Result<String, WalletError> {
-    let vault_opt = CKBTC_VAULTS.with(|vaults| {
-        let mut vaults = vaults.borrow_mut();
-        vaults.get_mut(&owner).map(|vault| vault as *mut CkBtcVault)
-    });
-    
-    if let Some(vault_ptr) = vault_opt {
-        let vault = unsafe { &mut *vault_ptr };
-        vault.get_btc_address().await
-    } else {
-        Err(WalletError::VaultError {
-            operation: "get_btc_address".to_string(),
-            details: "ckBTC vault not found".to_string(),
-        })
+    let ledger = ledger_of(VaultType::CkBtc);
+    let _lock = OperationLock::acquire(owner)?;
+    let (cached, minter_canister_id) = with_vault(owner, ledger, "get_btc_address", |vault, config| {
+        let Some(Minter::CkBtc { minter_canister_id }) = config.minter else {
+            return Err(no_minter("get_btc_address", config));
+        };
+        Ok((vault.cached_address(), minter_canister_id))
+    })?;
+    if let Some(address) = cached {
+        return Ok(address);
     }
+
+    let outcome = ckbtc::btc_address_request(minter_canister_id, Account::member(owner)).await;
+    with_vault(owner, ledger, "get_btc_address", |vault, _| ckbtc::apply_btc_address(vault, outcome))
+}
+
+pub fn get_icp_deposit_address(owner: Principal) -> Result<IcpDepositAddress, WalletError> {
+    with_existing_vault(owner, ledger_of(VaultType::Icp), "get_icp_deposit_address", |vault, _| {
+        Ok(IcpDepositAddress::for_member(vault.owner()))
+    })
 }
 
 pub fn get_transaction_history(
     owner: Principal,
-    vault_type: VaultType,
+    ledger: Principal,
     limit: Option<usize>,
 ) -> Result<Vec<Transaction>, WalletError> {
-    match vault_type {
-        VaultType::CkBtc => {
-            CKBTC_VAULTS.with(|vaults| {
-                let vaults = vaults.borrow();
-                let vault = vaults.get(&owner).ok_or(WalletError::VaultError {
-                    operation: "get_ckbtc_history".to_string(),
-                    details: "ckBTC vault not found".to_string(),
-                })?;
-                Ok(vault.get_transaction_history(limit))
-            })
-        }
-        VaultType::CkUsdt => {
-            CKUSDT_VAULTS.with(|vaults| {
-                let vaults = vaults.borrow();
-                let vault = vaults.get(&owner).ok_or(WalletError::VaultError {
-                    operation: "get_ckusdt_history".to_string(),
-                    details: "ckUSDT vault not found".to_string(),
-                })?;
-                Ok(vault.get_transaction_history(limit))
-            })
-        }
-        VaultType::Icp => {
-            ICP_VAULTS.with(|vaults| {
-                let vaults = vaults.borrow();
-                let vault = vaults.get(&owner).ok_or(WalletError::VaultError {
-                    operation: "get_icp_history".to_string(),
-                    details: "ICP vault not found".to_string(),
-                })?;
-                Ok(vault.get_transaction_history(limit))
-            })
-        }
-    }
+    with_existing_vault(owner, ledger, "get_transaction_history", |vault, _| {
+        Ok(vault.transaction_history(limit))
+    })
 }
 
-pub async fn check_usdt_withdrawal_status(
+pub async fn check_withdrawal_status(
     owner: Principal,
+    ledger: Principal,
     withdrawal_id: WithdrawalId,
 ) -> Result<WithdrawalStatus, WalletError> {
-    let vault_opt = CKUSDT_VAULTS.with(|vaults| {
-        let mut vaults = vaults.borrow_mut();
-        vaults.get_mut(&owner).map(|vault| vault as *mut CkUsdtVault)
-    });
-    
-    if let Some(vault_ptr) = vault_opt {
-        let vault = unsafe { &mut *vault_ptr };
-        vault.check_withdrawal_status(withdrawal_id).await
-    } else {
-        Err(WalletError::VaultError {
-            operation: "check_withdrawal_status".to_string(),
-            details: "ckUSDT vault not found".to_string(),
-        })
+    // Read-only, so no operation lock is taken
+    let (local, minter_canister_id) = with_existing_vault(owner, ledger, "check_withdrawal_status", |vault, config| {
+        let Some(Minter::CkErc20 { minter_canister_id, .. }) = config.minter else {
+            return Err(no_minter("check_withdrawal_status", config));
+        };
+        Ok((ckerc20::local_withdrawal_status(vault, withdrawal_id), minter_canister_id))
+    })?;
+
+    match local {
+        Some(status) => Ok(status),
+        None => ckerc20::withdrawal_status_request(minter_canister_id, withdrawal_id)
+            .await
+            .map_err(|details| WalletError::VaultError {
+                operation: "check_withdrawal_status".to_string(),
+                details,
+            }),
     }
 }
 
-pub fn get_vault_metrics(owner: Principal) -> Result<HashMap<VaultType, VaultMetrics>, WalletError> {
-    let mut metrics = HashMap::new();
-    
-    // Get ckBTC metrics
-    CKBTC_VAULTS.with(|vaults| {
-        let vaults = vaults.borrow();
-        if let Some(vault) = vaults.get(&owner) {
-            metrics.insert(VaultType::CkBtc, vault.get_vault_metrics());
-        }
-    });
-    
-    // Get ckUSDT metrics
-    CKUSDT_VAULTS.with(|vaults| {
-        let vaults = vaults.borrow();
-        if let Some(vault) = vaults.get(&owner) {
-            metrics.insert(VaultType::CkUsdt, vault.get_vault_metrics());
-        }
-    });
-    
-    // ICP metrics would be added here when implemented
-    
+pub fn get_vault_metrics(owner: Principal) -> Result<HashMap<Principal, VaultMetrics>, WalletError> {
+    let metrics: HashMap<Principal, VaultMetrics> = list_tokens()
+        .into_iter()
+        .filter_map(|token| {
+            let metrics = with_existing_vault(owner, token.ledger_canister_id, "get_vault_metrics", |vault, config| {
+                Ok(vault.metrics(config))
+            });
+            Some((token.ledger_canister_id, metrics.ok()?))
+        })
+        .collect();
+
     if metrics.is_empty() {
         return Err(WalletError::WalletNotFound {
             principal: owner.to_string(),
         });
     }
-    
+
     Ok(metrics)
 }
 
@@ -817,74 +862,83 @@ pub fn get_vault_manager_metrics(owner: Principal) -> Result<VaultManagerMetrics
     })
 }
 
+// Like `with_vault`, but read-only calls don't create missing vaults
+fn with_existing_vault<R>(
+    owner: Principal,
+    ledger: Principal,
+    operation: &str,
+    f: impl FnOnce(&Icrc1Vault, &TokenConfig) -> Result<R, WalletError>,
+) -> Result<R, WalletError> {
+    let config = token_config(ledger)?;
+
+    TOKEN_VAULTS.with(|vaults| {
+        let vaults = vaults.borrow();
+        match vaults.get(&ledger).and_then(|ledger_vaults| ledger_vaults.get(&owner)) {
+            Some(vault) => f(vault, &config),
+            None => Err(WalletError::VaultError {
+                operation: operation.to_string(),
+                details: format!("{} vault not found", config.symbol),
+            }),
+        }
+    })
+}
+
+fn no_minter(operation: &str, config: &TokenConfig) -> WalletError {
+    WalletError::VaultError {
+        operation: operation.to_string(),
+        details: format!("{} has no minter for this operation", config.symbol),
+    }
+}
+
 // System maintenance functions
 
-pub fn cleanup_expired_data() -> HashMap<VaultType, u32> {
+pub fn cleanup_expired_data() -> HashMap<Principal, u32> {
     let mut cleanup_results = HashMap::new();
-    
-    // Cleanup ckBTC vaults
-    CKBTC_VAULTS.with(|vaults| {
-        let mut vaults = vaults.borrow_mut();
-        let mut total_cleaned = 0u32;
-        
-        for vault in vaults.values_mut() {
-            total_cleaned += vault.cleanup_old_transactions(30); // 30 days
-        }
-        
-        if total_cleaned > 0 {
-            cleanup_results.insert(VaultType::CkBtc, total_cleaned);
-        }
-    });
-    
-    // Cleanup ckUSDT vaults
-    CKUSDT_VAULTS.with(|vaults| {
-        let mut vaults = vaults.borrow_mut();
-        let mut total_cleaned = 0u32;
-        
-        for vault in vaults.values_mut() {
-            total_cleaned += vault.cleanup_old_transactions(30);
-        }
-        
-        if total_cleaned > 0 {
-            cleanup_results.insert(VaultType::CkUsdt, total_cleaned);
+
+    TOKEN_VAULTS.with(|vaults| {
+        for (ledger, ledger_vaults) in vaults.borrow_mut().iter_mut() {
+            let total_cleaned: u32 = ledger_vaults
+                .values_mut()
+                .map(|vault| vault.cleanup_old_transactions(30)) // 30 days
+                .sum();
+
+            if total_cleaned > 0 {
+                cleanup_results.insert(*ledger, total_cleaned);
+            }
         }
     });
-    
+
     cleanup_results
 }
 
 pub fn reset_rate_limiters(owner: Principal) -> Result<(), WalletError> {
-    VAULT_MANAGERS.with(|managers| {
-        let mut managers = managers.borrow_mut();
-        let manager = managers.get_mut(&owner).ok_or(WalletError::WalletNotFound {
+    if !VAULT_MANAGERS.with(|managers| managers.borrow().contains_key(&owner)) {
+        return Err(WalletError::WalletNotFound {
             principal: owner.to_string(),
-        })?;
-        
-        manager.rate_limiters.clear();
-        Ok(())
-    })
+        });
+    }
+
+    RATE_LIMITERS.with(|limiters| {
+        limiters.borrow_mut().retain(|(limited_owner, _, _), _| *limited_owner != owner);
+    });
+    Ok(())
 }
 
 // Health check functions
 
 pub fn health_check() -> SystemHealth {
-    let mut vault_counts = HashMap::new();
     let mut total_operations = 0u64;
     let mut total_errors = 0u64;
-    
-    // Count active vaults
-    ICP_VAULTS.with(|vaults| {
-        vault_counts.insert(VaultType::Icp, vaults.borrow().len() as u64);
-    });
-    
-    CKBTC_VAULTS.with(|vaults| {
-        vault_counts.insert(VaultType::CkBtc, vaults.borrow().len() as u64);
-    });
-    
-    CKUSDT_VAULTS.with(|vaults| {
-        vault_counts.insert(VaultType::CkUsdt, vaults.borrow().len() as u64);
+
+    // Count active vaults per ledger
+    let vault_counts: HashMap<Principal, u64> = TOKEN_VAULTS.with(|vaults| {
+        vaults
+            .borrow()
+            .iter()
+            .map(|(ledger, ledger_vaults)| (*ledger, ledger_vaults.len() as u64))
+            .collect()
     });
-    
+
     // Aggregate metrics from all vault managers
     VAULT_MANAGERS.with(|managers| {
         for manager in managers.borrow().values() {
@@ -892,13 +946,13 @@ pub fn health_check() -> SystemHealth {
             total_errors += manager.metrics.failed_operations;
         }
     });
-    
+
     let error_rate = if total_operations > 0 {
         (total_errors as f64 / total_operations as f64) * 100.0
     } else {
         0.0
     };
-    
+
     SystemHealth {
         active_vaults: vault_counts,
         total_operations,
@@ -910,7 +964,8 @@ pub fn health_check() -> SystemHealth {
 
 #[derive(CandidType, Debug, Clone, Serialize, Deserialize)]
 pub struct SystemHealth {
-    pub active_vaults: HashMap<VaultType, u64>,
+    // Keyed by ledger canister id
+    pub active_vaults: HashMap<Principal, u64>,
     pub total_operations: u64,
     pub error_rate: f64,
     pub last_check: u64,
@@ -920,16 +975,21 @@ pub struct SystemHealth {
 // Backup and restore functions for canister upgrades
 
 pub fn backup_vault_state() -> VaultBackup {
-    let icp_vaults = ICP_VAULTS.with(|vaults| vaults.borrow().clone());
-    let ckbtc_vaults = CKBTC_VAULTS.with(|vaults| vaults.borrow().clone());
-    let ckusdt_vaults = CKUSDT_VAULTS.with(|vaults| vaults.borrow().clone());
+    let tokens = TOKENS.with(|tokens| tokens.borrow().clone());
+    let token_vaults = TOKEN_VAULTS.with(|vaults| vaults.borrow().clone());
     let managers = VAULT_MANAGERS.with(|managers| managers.borrow().clone());
-    
+
     VaultBackup {
-        icp_vaults,
-        ckbtc_vaults,
-        ckusdt_vaults,
+        icp_vaults: None,
+        ckbtc_vaults: None,
+        ckusdt_vaults: None,
         managers,
+        tokens: None,
+        token_vaults: None,
+        token_registry: Some(tokens),
+        vaults: Some(token_vaults),
+        index_cursors: Some(indexer::index_cursors()),
+        skipped_deposits: Some(indexer::skipped_deposits()),
         backup_timestamp: ic_cdk::api::time(),
     }
 }
@@ -942,32 +1002,130 @@ pub fn restore_vault_state(backup: VaultBackup) -> Result<(), WalletError> {
             message: "Invalid backup timestamp".to_string(),
         });
     }
-    
-    ICP_VAULTS.with(|vaults| {
-        *vaults.borrow_mut() = backup.icp_vaults;
-    });
-    
-    CKBTC_VAULTS.with(|vaults| {
-        *vaults.borrow_mut() = backup.ckbtc_vaults;
+
+    // Backups taken before amounts were widened to `Tokens` keep the registry
+    // and vaults in their u64 layout. Tokens added to the defaults since,
+    // such as ckETH, are registered along the way.
+    let tokens = match (backup.token_registry, backup.tokens) {
+        (Some(tokens), _) => tokens,
+        (None, Some(legacy)) => {
+            let mut tokens = default_tokens();
+            tokens.extend(legacy.into_iter().map(|(ledger, config)| (ledger, TokenConfig::from(config))));
+            tokens
+        }
+        (None, None) => default_tokens(),
+    };
+    let mut token_vaults = match backup.vaults {
+        Some(vaults) => vaults,
+        None => backup
+            .token_vaults
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(ledger, ledger_vaults)| {
+                let erc20_minter = match tokens.get(&ledger).and_then(|config| config.minter.as_ref()) {
+                    Some(Minter::CkErc20 { minter_canister_id, .. }) => Some(*minter_canister_id),
+                    _ => None,
+                };
+                let ledger_vaults = ledger_vaults
+                    .into_iter()
+                    .map(|(owner, vault)| (owner, Icrc1Vault::from_legacy(vault, erc20_minter)))
+                    .collect();
+                (ledger, ledger_vaults)
+            })
+            .collect(),
+    };
+
+    // Backups taken before the token registry keep a vault per token type.
+    // Their history carries over; balances are read from the ledger again.
+    let legacy = [
+        (VaultType::Icp, backup.icp_vaults),
+        (VaultType::CkBtc, backup.ckbtc_vaults),
+        (VaultType::CkUsdt, backup.ckusdt_vaults),
+    ];
+    for (vault_type, vaults) in legacy {
+        let ledger = ledger_of(vault_type);
+        let ledger_vaults = token_vaults.entry(ledger).or_default();
+        for (owner, vault) in vaults.unwrap_or_default() {
+            ledger_vaults
+                .entry(owner)
+                .or_insert_with(|| {
+                    let history = vault.completed_transactions.into_iter().map(Transaction::from).collect();
+                    Icrc1Vault::with_history(owner, ledger, history)
+                });
+        }
+    }
+
+    TOKENS.with(|current| {
+        *current.borrow_mut() = tokens;
     });
-    
-    CKUSDT_VAULTS.with(|vaults| {
-        *vaults.borrow_mut() = backup.ckusdt_vaults;
+
+    TOKEN_VAULTS.with(|vaults| {
+        *vaults.borrow_mut() = token_vaults;
     });
-    
+
     VAULT_MANAGERS.with(|managers| {
         *managers.borrow_mut() = backup.managers;
     });
-    
+
+    indexer::restore_index_cursors(backup.index_cursors.unwrap_or_default());
+    indexer::restore_skipped_deposits(backup.skipped_deposits.unwrap_or_default());
+
     ic_cdk::println!("Vault state restored from backup at {}", backup.backup_timestamp);
     Ok(())
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone)]
 pub struct VaultBackup {
-    pub icp_vaults: HashMap<Principal, IcpVault>,
-    pub ckbtc_vaults: HashMap<Principal, CkBtcVault>,
-    pub ckusdt_vaults: HashMap<Principal, CkUsdtVault>,
+    // Per-type vaults from before the token registry, read only to migrate
+    pub icp_vaults: Option<HashMap<Principal, LegacyVault>>,
+    pub ckbtc_vaults: Option<HashMap<Principal, LegacyVault>>,
+    pub ckusdt_vaults: Option<HashMap<Principal, LegacyVault>>,
     pub managers: HashMap<Principal, VaultManager>,
+    // Registry and vaults from before amounts were widened to `Tokens`, read
+    // only to migrate
+    pub tokens: Option<HashMap<Principal, LegacyTokenConfig>>,
+    pub token_vaults: Option<HashMap<Principal, HashMap<Principal, LegacyIcrc1Vault>>>,
+    pub token_registry: Option<HashMap<Principal, TokenConfig>>,
+    // Keyed by ledger canister id, then owner
+    pub vaults: Option<HashMap<Principal, HashMap<Principal, Icrc1Vault>>>,
+    // Next block the deposit indexer scans, keyed by ledger canister id
+    pub index_cursors: Option<HashMap<Principal, u64>>,
+    // Deposits the indexer found but could not credit
+    pub skipped_deposits: Option<Vec<indexer::SkippedDeposit>>,
     pub backup_timestamp: u64,
-}
\ No newline at end of file
+}
+
+// The part of a pre-registry vault worth keeping
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct LegacyVault {
+    pub completed_transactions: Vec<LegacyTransaction>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_operation_lock_is_exclusive_per_owner() {
+        let owner = Principal::from_slice(&[1; 29]);
+        let other = Principal::from_slice(&[2; 29]);
+
+        let lock = OperationLock::acquire(owner).unwrap();
+        assert!(matches!(
+            OperationLock::acquire(owner),
+            Err(WalletError::OperationInProgress { .. })
+        ));
+        // Other members are not blocked
+        assert!(OperationLock::acquire(other).is_ok());
+
+        drop(lock);
+        assert!(OperationLock::acquire(owner).is_ok());
+    }
+
+    #[test]
+    fn test_default_tokens_are_valid() {
+        for config in default_tokens().values() {
+            assert!(config.validate().is_ok(), "{} fails validation", config.symbol);
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use crate::types::*;
+use candid::{CandidType, Principal};
+use futures::future::LocalBoxFuture;
+use serde::{Deserialize, Serialize};
+
+// A ledger or minter call prepared under a vault borrow. It owns its
+// arguments, so the borrow can end before it is awaited.
+pub type LedgerCall<T> = LocalBoxFuture<'static, Result<T, String>>;
+
+// How a token is redeemed off the Internet Computer, for tokens backed by an
+// asset on another chain
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Minter {
+    CkBtc { minter_canister_id: Principal },
+    CkErc20 { minter_canister_id: Principal, erc20_contract: String },
+}
+
+// Registry entry for a token members can hold. Supporting a new ICRC-1 token
+// only takes registering one of these for its ledger.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TokenConfig {
+    pub symbol: String,
+    pub ledger_canister_id: Principal,
+    pub decimals: u8,
+    pub transfer_fee: Tokens,
+    pub min_transfer_amount: Tokens,
+    // Smallest amount the minter accepts, if the token has one
+    pub min_withdrawal_amount: Tokens,
+    // Caps everything leaving a vault in a day, transfers and withdrawals alike
+    pub daily_withdrawal_limit: Tokens,
+    pub minter: Option<Minter>,
+}
+
+// `TokenConfig` as stored before amounts were widened to `Tokens`, read only
+// to migrate
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct LegacyTokenConfig {
+    pub symbol: String,
+    pub ledger_canister_id: Principal,
+    pub decimals: u8,
+    pub transfer_fee: u64,
+    pub min_transfer_amount: u64,
+    pub min_withdrawal_amount: u64,
+    pub daily_withdrawal_limit: u64,
+    pub minter: Option<Minter>,
+}
+
+impl From<LegacyTokenConfig> for TokenConfig {
+    fn from(legacy: LegacyTokenConfig) -> Self {
+        Self {
+            symbol: legacy.symbol,
+            ledger_canister_id: legacy.ledger_canister_id,
+            decimals: legacy.decimals,
+            transfer_fee: legacy.transfer_fee.into(),
+            min_transfer_amount: legacy.min_transfer_amount.into(),
+            min_withdrawal_amount: legacy.min_withdrawal_amount.into(),
+            daily_withdrawal_limit: legacy.daily_withdrawal_limit.into(),
+            minter: legacy.minter,
+        }
+    }
+}
+
+impl TokenConfig {
+    pub fn validate(&self) -> Result<(), WalletError> {
+        if self.symbol.trim().is_empty() || self.symbol.len() > 16 {
+            return Err(WalletError::ValidationError {
+                field: "symbol".to_string(),
+                message: "Symbol must be 1-16 characters".to_string(),
+            });
+        }
+
+        if self.ledger_canister_id == Principal::anonymous() {
+            return Err(WalletError::ValidationError {
+                field: "ledger_canister_id".to_string(),
+                message: "Ledger canister ID is required".to_string(),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+// Metrics structure for monitoring
+#[derive(CandidType, Debug, Clone, Serialize, Deserialize)]
+pub struct VaultMetrics {
+    pub balance: Tokens,
+    pub total_volume_in: Tokens,
+    pub total_volume_out: Tokens,
+    pub operation_count: u64,
+    pub pending_transactions: u64,
+    pub completed_transactions: u64,
+    pub daily_withdrawal_limit: Tokens,
+    pub daily_withdrawn_amount: Tokens,
+    pub last_operation: u64,
+    pub cache_hit_rate: f64,
+}
+
+#[derive(CandidType, Debug, Clone, Serialize, Deserialize)]
+pub struct TokenBalance {
+    pub ledger_canister_id: Principal,
+    pub symbol: String,
+    pub balance: Tokens,
+}
+
+/// One member's holdings of one token.
+///
+/// Every operation that calls out is split in two: a synchronous half that
+/// validates and reserves funds while the vault is borrowed and returns a
+/// `LedgerCall`, and a settle half that reconciles the outcome under a new
+/// borrow once the call has returned.
+pub trait TokenVault {
+    fn owner(&self) -> Principal;
+
+    fn ledger(&self) -> Principal;
+
+    // The owner's subaccount of the wallet factory, which holds the funds
+    fn account(&self) -> Account {
+        Account::member(self.owner())
+    }
+
+    fn balance(&self) -> Tokens;
+
+    /// Debits `amount + fee` and records an in-flight transaction to `to`.
+    /// `amount` counts towards the daily limit.
+    fn reserve(
+        &mut self,
+        config: &TokenConfig,
+        amount: Tokens,
+        fee: Tokens,
+        to: Account,
+        status: TransactionStatus,
+    ) -> Result<Reservation, WalletError>;
+
+    fn commit(&mut self, reservation: &Reservation, block_index: BlockIndex);
+
+    /// Like `commit`, for a withdrawal the minter also tracks by `withdrawal_id`.
+    fn commit_withdrawal(&mut self, reservation: &Reservation, block_index: BlockIndex, withdrawal_id: WithdrawalId);
+
+    /// Refunds a reservation whose call failed.
+    fn release(&mut self, reservation: &Reservation, reason: String);
+
+    /// Ledger balance query, or `None` while the cached balance is fresh.
+    /// Resolves to the balance and the ledger height read with it.
+    fn balance_request(&self) -> Option<LedgerCall<(Tokens, BlockIndex)>>;
+
+    fn apply_balance(
+        &mut self,
+        config: &TokenConfig,
+        outcome: Result<(Tokens, BlockIndex), String>,
+    ) -> Result<Tokens, WalletError>;
+
+    fn reserve_transfer(
+        &mut self,
+        config: &TokenConfig,
+        amount: Tokens,
+        to: Account,
+    ) -> Result<(Reservation, LedgerCall<BlockIndex>), WalletError>;
+
+    fn settle_transfer(
+        &mut self,
+        config: &TokenConfig,
+        reservation: Reservation,
+        outcome: Result<BlockIndex, String>,
+    ) -> Result<BlockIndex, WalletError>;
+
+    fn transaction_history(&self, limit: Option<usize>) -> Vec<Transaction>;
+
+    /// The recorded transaction for a block on this vault's ledger.
+    fn transaction_at_block(&self, block_index: BlockIndex) -> Option<Transaction>;
+
+    /// The recorded withdrawal with a minter's `withdrawal_id`.
+    fn withdrawal(&self, withdrawal_id: WithdrawalId) -> Option<Transaction>;
+
+    fn metrics(&self, config: &TokenConfig) -> VaultMetrics;
+
+    fn cleanup_old_transactions(&mut self, older_than_days: u64) -> u32;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_accepts_18_decimal_tokens() {
+        let config = TokenConfig {
+            symbol: "ckETH".to_string(),
+            ledger_canister_id: Principal::from_slice(&[9; 10]),
+            decimals: 18,
+            transfer_fee: 2_000_000_000_000,
+            min_transfer_amount: 2_000_000_000_000,
+            min_withdrawal_amount: 30_000_000_000_000_000,
+            // 100 ETH, past what a u64 holds
+            daily_withdrawal_limit: 100_000_000_000_000_000_000,
+            minter: None,
+        };
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_legacy_token_config_migrates() {
+        let legacy = LegacyTokenConfig {
+            symbol: "ckBTC".to_string(),
+            ledger_canister_id: Principal::from_slice(&[9; 10]),
+            decimals: 8,
+            transfer_fee: 10,
+            min_transfer_amount: 1000,
+            min_withdrawal_amount: 10_000,
+            daily_withdrawal_limit: u64::MAX,
+            minter: None,
+        };
+        let bytes = candid::encode_one(&legacy).unwrap();
+        // The widened layout doesn't read the old one directly
+        assert!(candid::decode_one::<TokenConfig>(&bytes).is_err());
+
+        let config = TokenConfig::from(candid::decode_one::<LegacyTokenConfig>(&bytes).unwrap());
+        assert_eq!(config.transfer_fee, 10);
+        assert_eq!(config.daily_withdrawal_limit, u64::MAX as Tokens);
+    }
+}